@@ -196,7 +196,7 @@ fn unique_tmp_path(target: &Path) -> PathBuf {
     target.with_file_name(format!(".{name}.{pid}.{ts}.tmp"))
 }
 
-fn write_json_atomic(path: &Path, value: &serde_json::Value, durable: bool) -> Result<()> {
+pub(crate) fn write_json_atomic(path: &Path, value: &serde_json::Value, durable: bool) -> Result<()> {
     if let Some(parent) = path.parent() {
         fs::create_dir_all(parent).with_context(|| format!("mkdir {}", parent.display()))?;
     }
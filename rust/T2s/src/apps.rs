@@ -0,0 +1,216 @@
+//! Per-app (Android UID) accounting and caps.
+//!
+//! REDIRECT/TPROXY hide the UID of the app that opened a connection, but the
+//! app's own socket is still listed in `/proc/net/tcp{,6}` with the accepted
+//! peer address as its local address, so the owner UID can be read there.
+//! Connections from other devices (hotspot/LAN) have no such entry and stay
+//! unattributed.
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+const PACKAGES_LIST: &str = "/data/system/packages.list";
+const PACKAGES_RELOAD: Duration = Duration::from_secs(60);
+
+/// Decode a `/proc/net/tcp` address field (`0100007F:1F90`).
+fn parse_proc_addr(field: &str) -> Option<SocketAddr> {
+    let (ip, port) = field.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    // The kernel prints each 32-bit word of the address in host byte order.
+    let mut words = Vec::with_capacity(4);
+    for chunk in ip.as_bytes().chunks(8) {
+        let word = u32::from_str_radix(std::str::from_utf8(chunk).ok()?, 16).ok()?;
+        words.push(word.to_ne_bytes());
+    }
+    let ip = match words.as_slice() {
+        [a] => IpAddr::V4(Ipv4Addr::from(*a)),
+        [a, b, c, d] => {
+            let mut octets = [0u8; 16];
+            for (i, w) in [a, b, c, d].into_iter().enumerate() {
+                octets[i * 4..i * 4 + 4].copy_from_slice(w);
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// UID of the socket whose local address is `local` in one `/proc/net/tcp*` table.
+fn find_uid(table: &str, local: SocketAddr) -> Option<u32> {
+    table.lines().skip(1).find_map(|line| {
+        let mut cols = line.split_whitespace();
+        let addr = parse_proc_addr(cols.nth(1)?)?;
+        if addr.port() != local.port() || addr.ip().to_canonical() != local.ip().to_canonical() {
+            return None;
+        }
+        // sl local rem st queues tr retrnsmt uid
+        cols.nth(5)?.parse().ok()
+    })
+}
+
+/// Owner UID of the app socket connected from `peer`, if it lives on this device.
+pub fn lookup_uid(peer: SocketAddr) -> Option<u32> {
+    let tables: &[&str] = if peer.is_ipv4() { &["/proc/net/tcp", "/proc/net/tcp6"] } else { &["/proc/net/tcp6"] };
+    tables
+        .iter()
+        .filter_map(|path| std::fs::read_to_string(path).ok())
+        .find_map(|table| find_uid(&table, peer))
+}
+
+fn load_packages() -> HashMap<u32, String> {
+    let mut out: HashMap<u32, String> = HashMap::new();
+    let Ok(text) = std::fs::read_to_string(PACKAGES_LIST) else {
+        return out;
+    };
+    for line in text.lines() {
+        let mut cols = line.split_whitespace();
+        let (Some(name), Some(Ok(uid))) = (cols.next(), cols.next().map(str::parse::<u32>)) else {
+            continue;
+        };
+        // Packages sharing a UID are listed together.
+        out.entry(uid)
+            .and_modify(|names| {
+                names.push(',');
+                names.push_str(name);
+            })
+            .or_insert_with(|| name.to_string());
+    }
+    out
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+pub struct AppLimit {
+    /// Max concurrent connections; 0 = unlimited.
+    #[serde(default)]
+    pub max_conns: u32,
+    /// Download cap shared by all connections of the app, Mbit/s; 0 = unlimited.
+    #[serde(default)]
+    pub download_mbit: f64,
+    /// Upload cap shared by all connections of the app, Mbit/s; 0 = unlimited.
+    #[serde(default)]
+    pub upload_mbit: f64,
+}
+
+#[derive(Clone, Debug, Default)]
+struct AppStats {
+    active: u32,
+    total: u64,
+    bytes_up: u64,
+    bytes_down: u64,
+    limit: AppLimit,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct AppView {
+    pub uid: u32,
+    pub package: Option<String>,
+    pub active_connections: u32,
+    pub total_connections: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub limits: AppLimit,
+}
+
+#[derive(Default)]
+pub struct AppRegistry {
+    apps: Mutex<HashMap<u32, AppStats>>,
+    packages: Mutex<Option<(Instant, HashMap<u32, String>)>>,
+}
+
+/// Keeps an app's active connection count until dropped.
+pub struct AppGuard {
+    apps: Arc<AppRegistry>,
+    uid: u32,
+}
+
+impl Drop for AppGuard {
+    fn drop(&mut self) {
+        if let Some(app) = self.apps.apps.lock().get_mut(&self.uid) {
+            app.active = app.active.saturating_sub(1);
+        }
+    }
+}
+
+impl AppRegistry {
+    /// Package name(s) for `uid`; secondary users map onto the owner's app id.
+    pub fn package(&self, uid: u32) -> Option<String> {
+        let mut cache = self.packages.lock();
+        if cache.as_ref().is_none_or(|(loaded, _)| loaded.elapsed() >= PACKAGES_RELOAD) {
+            *cache = Some((Instant::now(), load_packages()));
+        }
+        cache.as_ref().and_then(|(_, names)| names.get(&(uid % 100_000)).cloned())
+    }
+
+    /// Count a new connection for `uid`, or refuse it when the app is at its cap.
+    pub fn open(self: &Arc<Self>, uid: u32) -> Option<AppGuard> {
+        let mut apps = self.apps.lock();
+        let app = apps.entry(uid).or_default();
+        if app.limit.max_conns > 0 && app.active >= app.limit.max_conns {
+            return None;
+        }
+        app.active += 1;
+        app.total += 1;
+        Some(AppGuard { apps: self.clone(), uid })
+    }
+
+    pub fn add_bytes(&self, uid: u32, up: u64, down: u64) {
+        if let Some(app) = self.apps.lock().get_mut(&uid) {
+            app.bytes_up = app.bytes_up.saturating_add(up);
+            app.bytes_down = app.bytes_down.saturating_add(down);
+        }
+    }
+
+    pub fn set_limit(&self, uid: u32, mut limit: AppLimit) -> AppLimit {
+        for v in [&mut limit.download_mbit, &mut limit.upload_mbit] {
+            if !v.is_finite() || *v < 0.0 {
+                *v = 0.0;
+            }
+        }
+        self.apps.lock().entry(uid).or_default().limit = limit;
+        limit
+    }
+
+    pub fn snapshot(&self) -> Vec<AppView> {
+        let apps: Vec<(u32, AppStats)> = self.apps.lock().iter().map(|(uid, a)| (*uid, a.clone())).collect();
+        let mut out: Vec<AppView> = apps
+            .into_iter()
+            .map(|(uid, a)| AppView {
+                uid,
+                package: self.package(uid),
+                active_connections: a.active,
+                total_connections: a.total,
+                bytes_up: a.bytes_up,
+                bytes_down: a.bytes_down,
+                limits: a.limit,
+            })
+            .collect();
+        out.sort_by_key(|a| std::cmp::Reverse(a.bytes_up + a.bytes_down));
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_socket_owner_in_proc_tables() {
+        let tcp = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n\
+                   0: 0100007F:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000     0        0 1 1\n\
+                   1: 0F02000A:C350 08080808:01BB 01 00000000:00000000 00:00000000 00000000 10123        0 2 1\n";
+        assert_eq!(find_uid(tcp, "10.0.2.15:50000".parse().unwrap()), Some(10123));
+        assert_eq!(find_uid(tcp, "127.0.0.1:8080".parse().unwrap()), Some(0));
+        assert_eq!(find_uid(tcp, "10.0.2.15:50001".parse().unwrap()), None);
+
+        // v4-mapped entry in tcp6
+        let tcp6 = "header\n   0: 0000000000000000FFFF00000F02000A:C351 00000000000000000000000000000000:0000 01 0:0 0:0 0 10200 0 3 1\n";
+        assert_eq!(find_uid(tcp6, "10.0.2.15:50001".parse().unwrap()), Some(10200));
+    }
+}
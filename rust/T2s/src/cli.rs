@@ -49,7 +49,8 @@ NOTES
   * Power save: when there are no active connections and no UI clients, background checks go to sleep
     and poll backends every 1-3 minutes (wakes instantly on new connection).
   * TCP is always available. UDP TPROXY is started when ZDT-D setting tproxy_enabled=true.
-  * t2s does not implement a DNS resolver; DNS policy is managed externally.
+  * DNS is managed externally unless --dns-listen is set: then UDP/TCP queries sent to that address
+    are forwarded as DNS-over-TCP to --dns-upstream through the selected SOCKS5 backend.
 "#,
     arg_required_else_help = true
 )]
@@ -63,6 +64,12 @@ pub struct Args {
     /// Useful when you want to accept traffic from other devices or when local listen_addr must stay 127.0.0.1.
     #[arg(long, default_value_t=0)]
     pub external_port: u16,
+    #[arg(long, default_value_t=false, help="Also accept HTTP proxy clients (CONNECT and absolute-URI requests) on the external listener.")]
+    pub http_proxy: bool,
+    #[arg(long, help="Username required from HTTP proxy clients (Basic auth). Set together with --http-proxy-pass.")]
+    pub http_proxy_user: Option<String>,
+    #[arg(long)]
+    pub http_proxy_pass: Option<String>,
 
     #[arg(long, default_value="", help="Upstream SOCKS5 host(s), comma-separated. Not required for priority direct-only mode (--socks-port 0).")]
     pub socks_host: String,
@@ -72,6 +79,8 @@ pub struct Args {
     pub socks_user: Option<String>,
     #[arg(long)]
     pub socks_pass: Option<String>,
+    #[arg(long, value_enum, default_value = "socks5h", help="Type of the --socks-host/--socks-port backends: socks5 (resolve domains locally), socks5h, http-connect or https-connect. --socks-user/--socks-pass are used as their credentials.")]
+    pub backend_type: crate::upstream::BackendKind,
 
     #[arg(long, default_value="", help="Optional SOCKS5 wrapper host. Empty disables Wrapped SOCKS5.")]
     pub wrapped_socks_host: String,
@@ -115,9 +124,21 @@ pub struct Args {
     #[arg(long, default_value_t=8000)]
     pub web_port: u16,
 
+    #[arg(long, default_value="", help="Optional DNS listener address, e.g. 127.0.0.1:11253. Empty disables the DNS forwarder.")]
+    pub dns_listen: String,
+    #[arg(long, default_value="1.1.1.1:53", help="DNS server queried over TCP through the SOCKS5 backend by the DNS forwarder.")]
+    pub dns_upstream: String,
+    #[arg(long, default_value_t=false, help="Answer DNS A queries from --dns-fake-ip-range and dial connections to those addresses by domain name.")]
+    pub dns_fake_ip: bool,
+    #[arg(long, default_value="198.18.0.0/15", help="IPv4 range used by --dns-fake-ip.")]
+    pub dns_fake_ip_range: String,
+
     #[arg(long, default_value_t=0.0, help="Download throttling in Mbit/s (0 disables)")]
     pub download_limit_mbit: f64,
 
+    #[arg(long, default_value_t=0.0, help="Upload throttling in Mbit/s (0 disables)")]
+    pub upload_limit_mbit: f64,
+
     #[arg(long, default_value="/data/adb/modules/ZDT-D/api", help="ZDT-D API root directory. t2s metadata is written under <api-dir>/t2s.")]
     pub api_dir: String,
     #[arg(long, default_value="", help="Stable t2s instance id for metadata/API responses. Auto-generated when omitted.")]
@@ -147,7 +168,22 @@ impl Args {
         {
             return Err(anyhow!("--wrapped-socks-user and --wrapped-socks-pass must both be set or both empty"));
         }
+        if a.http_proxy_user.as_ref().map(|s| !s.trim().is_empty()).unwrap_or(false)
+            ^ a.http_proxy_pass.as_ref().map(|s| !s.is_empty()).unwrap_or(false)
+        {
+            return Err(anyhow!("--http-proxy-user and --http-proxy-pass must both be set or both empty"));
+        }
+        if a.http_proxy && a.external_port == 0 {
+            return Err(anyhow!("--http-proxy requires --external-port"));
+        }
         a.validate_priority_zero_mode()?;
+        if !a.dns_listen.trim().is_empty() {
+            a.dns_listen.trim().parse::<std::net::SocketAddr>().map_err(|_| anyhow!("--dns-listen must be IP:PORT"))?;
+            crate::dns::upstream_addr(&a.dns_upstream)?;
+        }
+        if a.dns_fake_ip && a.dns_listen.trim().is_empty() {
+            return Err(anyhow!("--dns-fake-ip requires --dns-listen"));
+        }
         if !a.socks_ports().is_empty() && a.socks_hosts().is_empty() {
             return Err(anyhow!("--socks-host is required when --socks-port contains SOCKS5 backend ports"));
         }
@@ -227,6 +263,16 @@ impl Args {
             .collect()
     }
 
+    /// Expected `Proxy-Authorization: Basic` credential for HTTP proxy clients.
+    pub fn http_proxy_auth(&self) -> Option<String> {
+        match (self.http_proxy_user.as_deref(), self.http_proxy_pass.as_deref()) {
+            (Some(u), Some(p)) if !u.trim().is_empty() && !p.is_empty() => {
+                Some(crate::upstream::base64(format!("{}:{}", u.trim(), p).as_bytes()))
+            }
+            _ => None,
+        }
+    }
+
     pub fn wrapped_socks_auth(&self) -> Option<(String, String)> {
         match (self.wrapped_socks_user.clone(), self.wrapped_socks_pass.clone()) {
             (Some(u), Some(p)) if !u.trim().is_empty() && !p.is_empty() => Some((u.trim().to_string(), p)),
@@ -0,0 +1,474 @@
+//! Optional DNS forwarder (`--dns-listen`).
+//!
+//! UDP and TCP queries are forwarded as DNS-over-TCP to `--dns-upstream`
+//! through the same SOCKS5 backend selection as TCP connections, so name
+//! lookups leave through the tunnel instead of the system resolver. Answers
+//! are cached for their TTL, and every A/AAAA answer is remembered so
+//! connections without SNI/Host can still be shown (and routed) by name.
+//!
+//! With `--dns-fake-ip`, A queries are answered from the fake-IP pool instead
+//! (see `fakeip`) and AAAA queries get an empty answer so clients stay on the
+//! fake IPv4 address.
+
+use crate::{fakeip::FakeIpPool, socks5, upstream::ProxyStream, AppState};
+use anyhow::{anyhow, Context, Result};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+use tracing::{debug, info, warn};
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_TTL: u32 = 3600;
+/// Keep names a little past the record TTL: apps connect after resolving.
+const NAME_GRACE: Duration = Duration::from_secs(300);
+const CACHE_MAX: usize = 2048;
+const NAMES_MAX: usize = 8192;
+const UDP_DEFAULT_PAYLOAD: usize = 512;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_OPT: u16 = 41;
+
+struct CachedAnswer {
+    bytes: Vec<u8>,
+    stored: Instant,
+    expires: Instant,
+}
+
+#[derive(Default)]
+pub struct Dns {
+    cache: Mutex<HashMap<(String, u16), CachedAnswer>>,
+    names: Mutex<HashMap<IpAddr, (String, Instant)>>,
+    fake: Option<FakeIpPool>,
+}
+
+impl Dns {
+    pub fn new(fake_ip_range: Option<&str>) -> Result<Self> {
+        let fake = fake_ip_range.map(FakeIpPool::new).transpose()?;
+        Ok(Self { fake, ..Default::default() })
+    }
+
+    /// Whether `ip` belongs to the fake-IP range (mapped or not).
+    pub fn is_fake(&self, ip: IpAddr) -> bool {
+        self.fake.as_ref().is_some_and(|f| f.contains(ip))
+    }
+
+    /// Domain a fake IP was handed out for.
+    pub fn fake_domain(&self, ip: IpAddr) -> Option<String> {
+        self.fake.as_ref()?.domain(ip)
+    }
+
+    /// Name most recently resolved to `ip` through the forwarder.
+    pub fn name_for(&self, ip: IpAddr) -> Option<String> {
+        let names = self.names.lock();
+        let (name, expires) = names.get(&ip.to_canonical())?;
+        (*expires > Instant::now()).then(|| name.clone())
+    }
+
+    fn remember(&self, name: &str, records: &[(IpAddr, u32)]) {
+        if records.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let mut names = self.names.lock();
+        if names.len() >= NAMES_MAX {
+            names.retain(|_, (_, exp)| *exp > now);
+            if names.len() >= NAMES_MAX {
+                names.clear();
+            }
+        }
+        for (ip, ttl) in records {
+            let expires = now + Duration::from_secs((*ttl).min(MAX_TTL) as u64) + NAME_GRACE;
+            names.insert(*ip, (name.to_string(), expires));
+        }
+    }
+
+    fn cached(&self, key: &(String, u16), id: [u8; 2]) -> Option<Vec<u8>> {
+        let now = Instant::now();
+        let mut cache = self.cache.lock();
+        let hit = cache.get(key)?;
+        if hit.expires <= now {
+            cache.remove(key);
+            return None;
+        }
+        let elapsed = now.duration_since(hit.stored).as_secs() as u32;
+        let mut bytes = hit.bytes.clone();
+        bytes[..2].copy_from_slice(&id);
+        // Age the TTLs so downstream caches do not outlive ours.
+        let _ = walk_records(&mut bytes, |rec| {
+            if rec.rtype != TYPE_OPT {
+                *rec.ttl = rec.ttl.saturating_sub(elapsed);
+            }
+        });
+        Some(bytes)
+    }
+
+    fn store(&self, key: (String, u16), bytes: &[u8], ttl: u32) {
+        if ttl == 0 {
+            return;
+        }
+        let now = Instant::now();
+        let mut cache = self.cache.lock();
+        if cache.len() >= CACHE_MAX {
+            cache.retain(|_, v| v.expires > now);
+            if cache.len() >= CACHE_MAX {
+                cache.clear();
+            }
+        }
+        let expires = now + Duration::from_secs(ttl.min(MAX_TTL) as u64);
+        cache.insert(key, CachedAnswer { bytes: bytes.to_vec(), stored: now, expires });
+    }
+}
+
+struct Question {
+    name: String,
+    qtype: u16,
+    /// Offset just past the question section.
+    end: usize,
+    /// Largest UDP answer the client accepts (EDNS0 payload size or 512).
+    udp_payload: usize,
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> Result<u16> {
+    buf.get(pos..pos + 2)
+        .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        .ok_or_else(|| anyhow!("truncated DNS message"))
+}
+
+fn skip_name(buf: &[u8], mut pos: usize) -> Result<usize> {
+    loop {
+        let len = *buf.get(pos).ok_or_else(|| anyhow!("truncated DNS name"))? as usize;
+        match len {
+            0 => return Ok(pos + 1),
+            l if l & 0xc0 == 0xc0 => return Ok(pos + 2),
+            l => pos += 1 + l,
+        }
+    }
+}
+
+fn parse_question(buf: &[u8]) -> Result<Question> {
+    if read_u16(buf, 4)? != 1 {
+        return Err(anyhow!("expected exactly one question"));
+    }
+    let mut labels: Vec<String> = Vec::new();
+    let mut pos = 12;
+    loop {
+        let len = *buf.get(pos).ok_or_else(|| anyhow!("truncated DNS name"))? as usize;
+        if len == 0 {
+            pos += 1;
+            break;
+        }
+        if len & 0xc0 != 0 {
+            return Err(anyhow!("compressed question name"));
+        }
+        let label = buf.get(pos + 1..pos + 1 + len).ok_or_else(|| anyhow!("truncated DNS name"))?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        pos += 1 + len;
+    }
+    let qtype = read_u16(buf, pos)?;
+    let end = pos + 4;
+    if end > buf.len() {
+        return Err(anyhow!("truncated DNS question"));
+    }
+
+    let mut udp_payload = UDP_DEFAULT_PAYLOAD;
+    let (ancount, nscount, arcount) = (read_u16(buf, 6)?, read_u16(buf, 8)?, read_u16(buf, 10)?);
+    if ancount == 0 && nscount == 0 && arcount > 0 {
+        let opt = skip_name(buf, end)?;
+        if read_u16(buf, opt)? == TYPE_OPT {
+            udp_payload = (read_u16(buf, opt + 2)? as usize).max(UDP_DEFAULT_PAYLOAD);
+        }
+    }
+    Ok(Question { name: labels.join("."), qtype, end, udp_payload })
+}
+
+struct Record<'a> {
+    rtype: u16,
+    ttl: &'a mut u32,
+    rdata: &'a [u8],
+}
+
+/// Visit every resource record after the question section. TTL changes made
+/// by `f` are written back into `buf`.
+fn walk_records(buf: &mut [u8], mut f: impl FnMut(Record<'_>)) -> Result<()> {
+    let qdcount = read_u16(buf, 4)?;
+    let total = read_u16(buf, 6)? as usize + read_u16(buf, 8)? as usize + read_u16(buf, 10)? as usize;
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        pos = skip_name(buf, pos)? + 4;
+    }
+    for _ in 0..total {
+        pos = skip_name(buf, pos)?;
+        let rtype = read_u16(buf, pos)?;
+        let rdlen = read_u16(buf, pos + 8)? as usize;
+        let ttl_at = pos + 4;
+        let rdata_at = pos + 10;
+        if rdata_at + rdlen > buf.len() {
+            return Err(anyhow!("truncated DNS record"));
+        }
+        let (head, tail) = buf.split_at_mut(rdata_at);
+        let ttl_bytes: &mut [u8] = &mut head[ttl_at..ttl_at + 4];
+        let mut ttl = u32::from_be_bytes([ttl_bytes[0], ttl_bytes[1], ttl_bytes[2], ttl_bytes[3]]);
+        let before = ttl;
+        f(Record { rtype, ttl: &mut ttl, rdata: &tail[..rdlen] });
+        if ttl != before {
+            ttl_bytes.copy_from_slice(&ttl.to_be_bytes());
+        }
+        pos = rdata_at + rdlen;
+    }
+    Ok(())
+}
+
+/// Address records of an answer and the TTL it may be cached for.
+fn inspect_answer(answer: &[u8]) -> Result<(Vec<(IpAddr, u32)>, u32)> {
+    let rcode = read_u16(answer, 2)? & 0x000f;
+    let mut buf = answer.to_vec();
+    let mut ips = Vec::new();
+    let mut min_ttl = MAX_TTL;
+    walk_records(&mut buf, |rec| {
+        if rec.rtype == TYPE_OPT {
+            return;
+        }
+        min_ttl = min_ttl.min(*rec.ttl);
+        match (rec.rtype, rec.rdata.len()) {
+            (TYPE_A, 4) => {
+                let ip = Ipv4Addr::new(rec.rdata[0], rec.rdata[1], rec.rdata[2], rec.rdata[3]);
+                ips.push((IpAddr::V4(ip), *rec.ttl));
+            }
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rec.rdata);
+                ips.push((IpAddr::V6(Ipv6Addr::from(octets)), *rec.ttl));
+            }
+            _ => {}
+        }
+    })?;
+    // Only successful and NXDOMAIN answers are cached.
+    let ttl = if rcode == 0 || rcode == 3 { min_ttl } else { 0 };
+    Ok((ips, ttl))
+}
+
+/// Answer `query` with `ip` (or no records) and a short TTL: the mapping only
+/// lives as long as the pool slot.
+fn fake_reply(query: &[u8], q: &Question, ip: Option<Ipv4Addr>) -> Vec<u8> {
+    let mut out = query[..q.end].to_vec();
+    out[2] = 0x80 | (out[2] & 0x01); // QR, RD echoed
+    out[3] = 0x80; // RA, NOERROR
+    out[6..12].fill(0);
+    if let Some(ip) = ip {
+        out[7] = 1;
+        out.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 1, 0, 4]);
+        out.extend_from_slice(&ip.octets());
+    }
+    out
+}
+
+/// Header + question of `query` with TC set, telling the client to retry over TCP.
+fn truncated_reply(query: &[u8], q: &Question) -> Vec<u8> {
+    let mut out = query[..q.end].to_vec();
+    out[2] |= 0x82; // QR, TC
+    out[3] = 0x80; // RA, NOERROR
+    out[6..12].fill(0);
+    out
+}
+
+pub fn upstream_addr(raw: &str) -> Result<SocketAddr> {
+    let raw = raw.trim();
+    match raw.parse::<IpAddr>() {
+        Ok(ip) => Ok(SocketAddr::new(ip, 53)),
+        Err(_) => raw.parse::<SocketAddr>().with_context(|| format!("invalid --dns-upstream {raw:?}")),
+    }
+}
+
+async fn exchange(mut stream: ProxyStream, query: &[u8]) -> Result<Vec<u8>> {
+    let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(query);
+    stream.write_all(&framed).await.context("write DNS query")?;
+    let mut len = [0u8; 2];
+    stream.read_exact(&mut len).await.context("read DNS answer length")?;
+    let mut answer = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut answer).await.context("read DNS answer")?;
+    Ok(answer)
+}
+
+async fn forward(state: &AppState, upstream: SocketAddr, query: &[u8]) -> Result<Vec<u8>> {
+    let global_auth = match (state.args.socks_user.clone(), state.args.socks_pass.clone()) {
+        (Some(u), Some(p)) => Some((u, p)),
+        _ => None,
+    };
+    let selected = {
+        let mut b = state.backends.lock();
+        b.select_rr_with_auth(global_auth.as_ref())
+            .and_then(|(idx, backend, auth)| b.upstream_at(idx, auth).ok_or_else(|| anyhow!("backend {} was removed", backend)))
+    };
+    let stream = match selected {
+        Ok(dial) => {
+            dial.wrapped(state.wrapped_socks_addr, state.args.wrapped_socks_auth())
+                .connect(socks5::TargetAddr::Ip(upstream), QUERY_TIMEOUT)
+                .await?
+        }
+        Err(e) => {
+            // Same rule as TCP: without GREEN backends fall back to direct
+            // unless the priority list forbids it.
+            if state.args.priority_zero_mode() == crate::cli::PriorityZeroMode::BlockDirectFallback
+                || !state.runtime.direct_allowed()
+            {
+                return Err(e);
+            }
+            ProxyStream::Tcp(
+                tokio::time::timeout(QUERY_TIMEOUT, TcpStream::connect(upstream))
+                    .await
+                    .context("DNS upstream connect timeout")??,
+            )
+        }
+    };
+    tokio::time::timeout(QUERY_TIMEOUT, exchange(stream, query))
+        .await
+        .context("DNS upstream timeout")?
+}
+
+/// Answer one query; `udp` limits the reply to the client's payload size.
+async fn answer(state: &AppState, upstream: SocketAddr, query: &[u8], udp: bool) -> Result<Vec<u8>> {
+    let q = parse_question(query)?;
+    if let Some(pool) = state.dns.fake.as_ref().filter(|_| !q.name.is_empty()) {
+        match q.qtype {
+            TYPE_A => return Ok(fake_reply(query, &q, Some(pool.assign(&q.name)))),
+            TYPE_AAAA => return Ok(fake_reply(query, &q, None)),
+            _ => {}
+        }
+    }
+    let key = (q.name.clone(), q.qtype);
+    let reply = match state.dns.cached(&key, [query[0], query[1]]) {
+        Some(hit) => hit,
+        None => {
+            let reply = forward(state, upstream, query).await?;
+            if reply.get(..2) != Some(&query[..2]) {
+                return Err(anyhow!("DNS answer id mismatch"));
+            }
+            match inspect_answer(&reply) {
+                Ok((ips, ttl)) => {
+                    state.dns.remember(&q.name, &ips);
+                    state.dns.store(key, &reply, ttl);
+                }
+                Err(e) => debug!("dns: cannot inspect answer for {}: {:#}", q.name, e),
+            }
+            reply
+        }
+    };
+    if udp && reply.len() > q.udp_payload {
+        return Ok(truncated_reply(query, &q));
+    }
+    Ok(reply)
+}
+
+async fn serve_tcp_client(state: AppState, upstream: SocketAddr, mut client: TcpStream) -> Result<()> {
+    loop {
+        let mut len = [0u8; 2];
+        match tokio::time::timeout(Duration::from_secs(30), client.read_exact(&mut len)).await {
+            Ok(Ok(_)) => {}
+            _ => return Ok(()),
+        }
+        let mut query = vec![0u8; u16::from_be_bytes(len) as usize];
+        client.read_exact(&mut query).await?;
+        let reply = answer(&state, upstream, &query, false).await?;
+        let mut framed = (reply.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(&reply);
+        client.write_all(&framed).await?;
+    }
+}
+
+pub async fn run_dns(state: AppState) -> Result<()> {
+    let listen: SocketAddr = state.args.dns_listen.trim().parse().context("parse --dns-listen")?;
+    let upstream = upstream_addr(&state.args.dns_upstream)?;
+    let udp = std::sync::Arc::new(UdpSocket::bind(listen).await.with_context(|| format!("bind DNS UDP {listen}"))?);
+    let tcp = TcpListener::bind(listen).await.with_context(|| format!("bind DNS TCP {listen}"))?;
+    info!("DNS forwarder on {} (upstream {} via SOCKS5)", listen, upstream);
+
+    {
+        let st = state.clone();
+        tokio::spawn(async move {
+            loop {
+                let (client, peer) = match tcp.accept().await {
+                    Ok(v) => v,
+                    Err(e) => {
+                        warn!("dns tcp accept failed: {}", e);
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+                let st = st.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve_tcp_client(st, upstream, client).await {
+                        debug!("dns tcp client {}: {:#}", peer, e);
+                    }
+                });
+            }
+        });
+    }
+
+    let mut buf = vec![0u8; 4096];
+    loop {
+        let (n, peer) = match udp.recv_from(&mut buf).await {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("dns udp recv failed: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let query = buf[..n].to_vec();
+        let (st, sock) = (state.clone(), udp.clone());
+        tokio::spawn(async move {
+            match answer(&st, upstream, &query, true).await {
+                Ok(reply) => {
+                    let _ = sock.send_to(&reply, peer).await;
+                }
+                Err(e) => debug!("dns query from {}: {:#}", peer, e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_are_mapped_and_aged() {
+        // Query for example.com A with EDNS0 (payload 1232).
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1];
+        query.extend_from_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        query.extend_from_slice(&[0, 0, 41, 0x04, 0xd0, 0, 0, 0, 0, 0, 0]);
+        let q = parse_question(&query).unwrap();
+        assert_eq!((q.name.as_str(), q.qtype, q.udp_payload), ("example.com", TYPE_A, 1232));
+
+        let mut reply = query[..q.end].to_vec();
+        reply[2] = 0x81;
+        reply[3] = 0x80;
+        reply[7] = 1;
+        reply[11] = 0;
+        reply.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 120, 0, 4, 93, 184, 216, 34]);
+        let (ips, ttl) = inspect_answer(&reply).unwrap();
+        assert_eq!(ips, vec![(IpAddr::from([93, 184, 216, 34]), 120)]);
+        assert_eq!(ttl, 120);
+
+        let dns = Dns::default();
+        dns.remember(&q.name, &ips);
+        assert_eq!(dns.name_for(IpAddr::from([93, 184, 216, 34])).as_deref(), Some("example.com"));
+        dns.store(("example.com".into(), TYPE_A), &reply, ttl);
+        let hit = dns.cached(&("example.com".into(), TYPE_A), [0xab, 0xcd]).unwrap();
+        assert_eq!(&hit[..2], &[0xab, 0xcd]);
+        assert_eq!(inspect_answer(&hit).unwrap().1, 120);
+
+        let tc = truncated_reply(&query, &q);
+        assert_eq!(tc.len(), q.end);
+        assert_eq!(tc[2] & 0x82, 0x82);
+    }
+}
@@ -0,0 +1,103 @@
+//! Fake-IP pool for the DNS forwarder (`--dns-fake-ip`).
+//!
+//! A queries are answered with an address from a reserved range instead of
+//! being resolved. Transparent connections to such an address are dialed by
+//! name (SOCKS5 ATYP=3), so routing sees the real domain and the proxy does
+//! the resolution. Addresses are handed out round-robin; when the pool wraps,
+//! the oldest mapping is reused.
+
+use anyhow::{anyhow, Context, Result};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr},
+};
+
+#[derive(Default)]
+struct Inner {
+    next: u32,
+    by_offset: HashMap<u32, String>,
+    by_name: HashMap<String, u32>,
+}
+
+pub struct FakeIpPool {
+    base: u32,
+    size: u32,
+    inner: Mutex<Inner>,
+}
+
+impl FakeIpPool {
+    /// `range` is an IPv4 CIDR of at least four addresses, e.g. `198.18.0.0/15`.
+    pub fn new(range: &str) -> Result<Self> {
+        let (addr, prefix) = range
+            .trim()
+            .split_once('/')
+            .ok_or_else(|| anyhow!("fake-IP range must be IPv4 CIDR, got {range:?}"))?;
+        let addr: Ipv4Addr = addr.parse().with_context(|| format!("invalid fake-IP range {range:?}"))?;
+        let prefix: u32 = prefix.parse().with_context(|| format!("invalid fake-IP prefix in {range:?}"))?;
+        if !(1..=30).contains(&prefix) {
+            return Err(anyhow!("fake-IP prefix must be between /1 and /30"));
+        }
+        let size = 1u32 << (32 - prefix);
+        let base = u32::from(addr) & !(size - 1);
+        Ok(Self { base, size, inner: Mutex::new(Inner { next: 1, ..Default::default() }) })
+    }
+
+    fn offset(&self, ip: IpAddr) -> Option<u32> {
+        match ip.to_canonical() {
+            IpAddr::V4(v4) => {
+                let off = u32::from(v4).wrapping_sub(self.base);
+                (off < self.size).then_some(off)
+            }
+            IpAddr::V6(_) => None,
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.offset(ip).is_some()
+    }
+
+    /// Fake address for `name`, allocating one when needed.
+    pub fn assign(&self, name: &str) -> Ipv4Addr {
+        let mut inner = self.inner.lock();
+        if let Some(off) = inner.by_name.get(name) {
+            return Ipv4Addr::from(self.base + off);
+        }
+        // Skip the network and broadcast addresses of the range.
+        let off = inner.next;
+        inner.next = if off + 1 >= self.size - 1 { 1 } else { off + 1 };
+        if let Some(old) = inner.by_offset.insert(off, name.to_string()) {
+            inner.by_name.remove(&old);
+        }
+        inner.by_name.insert(name.to_string(), off);
+        Ipv4Addr::from(self.base + off)
+    }
+
+    /// Domain behind a fake address, if it is still mapped.
+    pub fn domain(&self, ip: IpAddr) -> Option<String> {
+        let off = self.offset(ip)?;
+        self.inner.lock().by_offset.get(&off).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assigns_stable_addresses_and_recycles_oldest() {
+        let pool = FakeIpPool::new("198.18.0.0/30").unwrap();
+        let a = pool.assign("a.example");
+        assert_eq!(a, Ipv4Addr::new(198, 18, 0, 1));
+        assert_eq!(pool.assign("a.example"), a);
+        let b = pool.assign("b.example");
+        assert_eq!(b, Ipv4Addr::new(198, 18, 0, 2));
+        assert_eq!(pool.domain(IpAddr::V4(b)).as_deref(), Some("b.example"));
+
+        // Only two usable addresses: the third name takes over a's slot.
+        assert_eq!(pool.assign("c.example"), a);
+        assert_eq!(pool.domain(IpAddr::V4(a)).as_deref(), Some("c.example"));
+        assert!(pool.contains(IpAddr::V4(Ipv4Addr::new(198, 18, 0, 3))));
+        assert!(!pool.contains(IpAddr::V4(Ipv4Addr::new(198, 18, 0, 4))));
+    }
+}
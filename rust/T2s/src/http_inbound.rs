@@ -0,0 +1,215 @@
+//! Inbound HTTP proxy on the external listener (`--http-proxy`).
+//!
+//! `CONNECT host:port` is answered once the upstream is connected: 200 and
+//! then relayed like a SOCKS5 inbound tunnel, or 502 when no route worked.
+//! Plain requests with an absolute URI (`GET http://host/...`)
+//! are rewritten to origin form and sent to the target first thing after the
+//! upstream connects; `Connection: close` is forced so each connection
+//! carries requests for one host only.
+
+use crate::{stats::{Ingress, Target}, AppState};
+use anyhow::{anyhow, bail, Context, Result};
+use std::time::Duration;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+const MAX_HEAD: usize = 16 * 1024;
+const METHODS: &[&str] = &["GET", "POST", "PUT", "DELETE", "HEAD", "OPTIONS", "PATCH", "TRACE"];
+
+/// Accepted HTTP proxy request.
+pub struct Accepted {
+    pub target: Target,
+    /// Rewritten request head to send upstream (plain HTTP only).
+    pub preamble: Option<Vec<u8>>,
+    /// CONNECT: the client waits for `connect_reply` before sending data.
+    pub connect: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Request {
+    target: (String, u16),
+    /// `None` for CONNECT.
+    forward_head: Option<Vec<u8>>,
+    /// Value of `Proxy-Authorization`, if any.
+    auth: Option<String>,
+}
+
+fn looks_like_proxy_request(peek: &[u8]) -> bool {
+    if peek.starts_with(b"CONNECT ") {
+        return true;
+    }
+    METHODS.iter().any(|m| {
+        peek.len() > m.len() && peek.starts_with(m.as_bytes()) && peek[m.len()..].starts_with(b" http://")
+    })
+}
+
+/// `host[:port]` with an optional bracketed IPv6 literal.
+fn split_authority(authority: &str, default_port: u16) -> Result<(String, u16)> {
+    let (host, port) = if let Some(rest) = authority.strip_prefix('[') {
+        let (host, rest) = rest.split_once(']').ok_or_else(|| anyhow!("bad authority {authority:?}"))?;
+        (host, rest.strip_prefix(':'))
+    } else {
+        match authority.rsplit_once(':') {
+            Some((h, p)) => (h, Some(p)),
+            None => (authority, None),
+        }
+    };
+    let port = match port {
+        Some(p) => p.parse().with_context(|| format!("bad port in {authority:?}"))?,
+        None => default_port,
+    };
+    if host.is_empty() || port == 0 {
+        bail!("bad authority {authority:?}");
+    }
+    Ok((host.to_string(), port))
+}
+
+fn parse_request(head: &[u8]) -> Result<Request> {
+    let text = std::str::from_utf8(head).context("HTTP proxy request is not UTF-8")?;
+    let mut lines = text.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(uri), Some(version)) = (parts.next(), parts.next(), parts.next()) else {
+        bail!("bad HTTP proxy request line {request_line:?}");
+    };
+
+    let mut auth = None;
+    let mut headers = Vec::new();
+    for line in lines.filter(|l| !l.is_empty()) {
+        let (name, value) = line.split_once(':').ok_or_else(|| anyhow!("bad header line {line:?}"))?;
+        if name.eq_ignore_ascii_case("proxy-authorization") {
+            auth = Some(value.trim().to_string());
+        } else if !name.eq_ignore_ascii_case("proxy-connection") && !name.eq_ignore_ascii_case("connection") {
+            headers.push(line);
+        }
+    }
+
+    if method == "CONNECT" {
+        return Ok(Request { target: split_authority(uri, 443)?, forward_head: None, auth });
+    }
+
+    let rest = uri.strip_prefix("http://").ok_or_else(|| anyhow!("only http:// URIs can be proxied, got {uri:?}"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let authority = authority.rsplit_once('@').map(|(_, a)| a).unwrap_or(authority);
+    let target = split_authority(authority, 80)?;
+
+    let mut out = format!("{method} {path} {version}\r\n");
+    if !headers.iter().any(|h| h.split(':').next().is_some_and(|n| n.trim().eq_ignore_ascii_case("host"))) {
+        out.push_str(&format!("Host: {authority}\r\n"));
+    }
+    for h in headers {
+        out.push_str(h);
+        out.push_str("\r\n");
+    }
+    out.push_str("Connection: close\r\n\r\n");
+    Ok(Request { target, forward_head: Some(out.into_bytes()), auth })
+}
+
+async fn reply(client: &mut TcpStream, status: &str, extra: &str) -> Result<()> {
+    client
+        .write_all(format!("HTTP/1.1 {status}\r\n{extra}Content-Length: 0\r\nConnection: close\r\n\r\n").as_bytes())
+        .await
+        .context("write HTTP proxy reply")
+}
+
+/// Take over `client` if HTTP proxy inbound is enabled for this listener and
+/// the client speaks it.
+pub async fn try_accept(state: &AppState, client: &mut TcpStream, ingress: Ingress) -> Result<Option<Accepted>> {
+    if ingress != Ingress::External || !state.args.http_proxy {
+        return Ok(None);
+    }
+    let mut peek = [0u8; 16];
+    let Ok(Ok(n)) = tokio::time::timeout(Duration::from_millis(35), client.peek(&mut peek)).await else {
+        return Ok(None);
+    };
+    if !looks_like_proxy_request(&peek[..n]) {
+        return Ok(None);
+    }
+
+    // Byte-wise up to the blank line so a request body stays in the socket.
+    let mut head = Vec::with_capacity(512);
+    let mut b = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD {
+            let _ = reply(client, "431 Request Header Fields Too Large", "").await;
+            bail!("HTTP proxy inbound: request head too large");
+        }
+        tokio::time::timeout(Duration::from_secs(10), client.read_exact(&mut b))
+            .await
+            .context("HTTP proxy inbound: request head timeout")?
+            .context("read HTTP proxy inbound request")?;
+        head.push(b[0]);
+    }
+
+    let req = match parse_request(&head) {
+        Ok(req) => req,
+        Err(e) => {
+            let _ = reply(client, "400 Bad Request", "").await;
+            return Err(e.context("HTTP proxy inbound"));
+        }
+    };
+
+    if let Some(expected) = state.args.http_proxy_auth() {
+        let given = req.auth.as_deref().and_then(|v| {
+            let (scheme, cred) = v.split_once(' ')?;
+            scheme.eq_ignore_ascii_case("basic").then_some(cred.trim())
+        });
+        if given != Some(expected.as_str()) {
+            let _ = reply(client, "407 Proxy Authentication Required", "Proxy-Authenticate: Basic realm=\"t2s\"\r\n").await;
+            bail!("HTTP proxy inbound: authentication failed");
+        }
+    }
+
+    let (host, port) = req.target;
+    let target = match host.parse::<std::net::IpAddr>() {
+        Ok(ip) => Target::SockAddr(std::net::SocketAddr::new(ip, port)),
+        Err(_) => Target::HostPort(host, port),
+    };
+    let connect = req.forward_head.is_none();
+    Ok(Some(Accepted { target, preamble: req.forward_head, connect }))
+}
+
+/// Answer a CONNECT after the upstream attempt: 200 when it is connected,
+/// 502 when it failed.
+pub async fn connect_reply(client: &mut TcpStream, connected: bool) -> Result<()> {
+    if connected {
+        client
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await
+            .context("write HTTP CONNECT reply")
+    } else {
+        reply(client, "502 Bad Gateway", "").await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_connect_and_rewrites_absolute_uri() {
+        assert!(looks_like_proxy_request(b"CONNECT a:443 HT"));
+        assert!(looks_like_proxy_request(b"GET http://a/ HT"));
+        assert!(!looks_like_proxy_request(b"GET / HTTP/1.1\r\n"));
+
+        let req = parse_request(b"CONNECT [2001:db8::1]:8443 HTTP/1.1\r\nProxy-Authorization: Basic dTpw\r\n\r\n").unwrap();
+        assert_eq!(req.target, ("2001:db8::1".to_string(), 8443));
+        assert_eq!(req.forward_head, None);
+        assert_eq!(req.auth.as_deref(), Some("Basic dTpw"));
+
+        let req = parse_request(
+            b"GET http://example.com:8080/a?b=1 HTTP/1.1\r\nHost: example.com:8080\r\nProxy-Connection: keep-alive\r\nAccept: */*\r\n\r\n",
+        )
+        .unwrap();
+        assert_eq!(req.target, ("example.com".to_string(), 8080));
+        assert_eq!(
+            String::from_utf8(req.forward_head.unwrap()).unwrap(),
+            "GET /a?b=1 HTTP/1.1\r\nHost: example.com:8080\r\nAccept: */*\r\nConnection: close\r\n\r\n"
+        );
+    }
+}
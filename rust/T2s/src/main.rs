@@ -1,4 +1,7 @@
 mod cli;
+mod dns;
+mod fakeip;
+mod http_inbound;
 mod socks5;
 mod transparent;
 mod udp;
@@ -7,7 +10,11 @@ mod stats;
 mod web;
 mod sniff;
 mod api_runtime;
+mod apps;
+mod metrics;
 mod net_utils;
+mod shaper;
+mod upstream;
 
 use anyhow::{anyhow, Context, Result};
 use cli::{Args, PriorityZeroMode};
@@ -22,7 +29,11 @@ pub struct AppState {
     pub stats: Arc<stats::Stats>,
     pub runtime: Arc<stats::RuntimeConfig>,
     pub conns: Arc<stats::ConnRegistry>,
-    pub rules: Arc<rules::Rules>,
+    pub rules: Arc<rules::RuleStore>,
+    pub dns: Arc<dns::Dns>,
+    pub apps: Arc<apps::AppRegistry>,
+    pub shaper: Arc<shaper::Shaper>,
+    pub metrics: Arc<metrics::Metrics>,
     pub backends: Arc<Mutex<stats::SocksBackends>>,
     pub events: broadcast::Sender<stats::Event>,
     pub semaphore: Arc<Semaphore>,
@@ -51,7 +62,7 @@ fn sniff_mode_for(state: &AppState) -> SniffMode {
     let (busy_threshold, overload_threshold) = sniff_thresholds(state.args.max_conns);
 
     if active >= overload_threshold {
-        if state.rules.has_host_rules() {
+        if state.rules.load().has_host_rules() {
             SniffMode::Quick80
         } else {
             SniffMode::Skip
@@ -204,14 +215,15 @@ async fn async_main(workers: usize) -> Result<()> {
         info!("ZDT-D tproxy_enabled=true: enabling TCP TPROXY listener and UDP TPROXY receiver");
     }
 
-    let rules = rules::Rules::load_from_env();
+    let rules = rules::RuleStore::from_env();
+    let dns = Arc::new(
+        dns::Dns::new(args.dns_fake_ip.then_some(args.dns_fake_ip_range.as_str())).context("init DNS forwarder")?,
+    );
     let stats = Arc::new(stats::Stats::default());
     let runtime = Arc::new(stats::RuntimeConfig::default());
-    // initialize download limit from CLI
-    if args.download_limit_mbit > 0.0 {
-        let bps = (args.download_limit_mbit * 1024.0 * 1024.0 / 8.0) as u64;
-        runtime.download_limit_bps.store(bps, std::sync::atomic::Ordering::Relaxed);
-    }
+    let shaper = Arc::new(shaper::Shaper::default());
+    let cap = shaper::Cap { download_mbit: args.download_limit_mbit, upload_mbit: args.upload_limit_mbit, burst_kb: 0 };
+    shaper.set_limits(shaper::Limits { global: cap, ..Default::default() })?;
     let conns = Arc::new(stats::ConnRegistry::new(args.priority_speed_aware));
     let (events, _rx) = broadcast::channel(1024);
 
@@ -224,6 +236,10 @@ async fn async_main(workers: usize) -> Result<()> {
         runtime,
         conns,
         rules: Arc::new(rules),
+        dns,
+        apps: Arc::new(apps::AppRegistry::default()),
+        shaper,
+        metrics: Arc::new(metrics::Metrics::default()),
         backends,
         events,
         semaphore,
@@ -296,6 +312,15 @@ async fn async_main(workers: usize) -> Result<()> {
             });
         }
 
+        if !args.dns_listen.trim().is_empty() {
+            let st = state.clone();
+            tokio::spawn(async move {
+                if let Err(e) = dns::run_dns(st).await {
+                    error!("dns forwarder error: {:#}", e);
+                }
+            });
+        }
+
         // Optional external listener on 0.0.0.0:<external_port>
         if args.external_port != 0 {
             let st = state.clone();
@@ -440,6 +465,7 @@ async fn run_tcp_on(state: AppState, addr: SocketAddr, ingress: stats::Ingress)
                 Ok(()) => {}
                 Err(e) => {
                     st.stats.inc_error();
+                    st.metrics.count_failure(None, &format!("{:#}", e));
                     warn!("[cid={}] connection ended with error: {:#}", cid, e);
                 }
             }
@@ -453,7 +479,7 @@ async fn run_tcp_on(state: AppState, addr: SocketAddr, ingress: stats::Ingress)
 
 async fn proxy_tcp(
     mut client: tokio::net::TcpStream,
-    _peer: SocketAddr,
+    peer: SocketAddr,
     cid: u64,
     state: AppState,
     cancel: tokio_util::sync::CancellationToken,
@@ -464,12 +490,46 @@ async fn proxy_tcp(
 
     state.conns.set_mode(cid, "pending");
 
+    // Owning app of local connections; hotspot/LAN peers have no UID here.
+    let app = if ingress == stats::Ingress::Internal || peer.ip().is_loopback() {
+        let apps = state.apps.clone();
+        tokio::task::spawn_blocking(move || apps::lookup_uid(peer).map(|uid| (uid, apps.package(uid))))
+            .await
+            .ok()
+            .flatten()
+    } else {
+        None
+    };
+    let uid = app.as_ref().map(|(uid, _)| *uid);
+    let _app_guard = match app {
+        Some((uid, package)) => {
+            state.conns.set_app(cid, uid, package);
+            match state.apps.open(uid) {
+                Some(guard) => Some(guard),
+                None => {
+                    state.stats.inc_policy_drop();
+                    reset_tcp_stream(&client);
+                    return Err(anyhow!("app {} is at its connection cap", uid));
+                }
+            }
+        }
+        None => None,
+    };
+
     // Determine target. The same listen_port is mixed-aware: if the peer speaks
     // SOCKS5, the target comes from CONNECT and we must not call SO_ORIGINAL_DST.
+    // With --http-proxy the external port also accepts HTTP proxy clients.
     // Otherwise keep the existing transparent/explicit-target behaviour.
+    let mut preamble = None;
+    let mut pending_connect = false;
     let target = if let Some(socks_target) = try_accept_socks5_inbound(&mut client).await? {
         state.conns.set_mode(cid, "socks_inbound");
         socks_target
+    } else if let Some(http) = http_inbound::try_accept(&state, &mut client, ingress).await? {
+        state.conns.set_mode(cid, "http_inbound");
+        preamble = http.preamble;
+        pending_connect = http.connect;
+        http.target
     } else if let (Some(h), Some(p)) = (state.args.target_host.clone(), state.args.target_port) {
         stats::Target::HostPort(h, p)
     } else {
@@ -478,11 +538,30 @@ async fn proxy_tcp(
         stats::Target::SockAddr(dst)
     };
 
+    // Fake-IP destinations are dialed by the domain they were handed out for,
+    // so the proxy resolves the real address. An unmapped fake IP (e.g. from
+    // before a restart) leads nowhere.
+    let target = match target {
+        stats::Target::SockAddr(sa) if state.dns.is_fake(sa.ip()) => match state.dns.fake_domain(sa.ip()) {
+            Some(name) => stats::Target::HostPort(name, sa.port()),
+            None => {
+                reset_tcp_stream(&client);
+                return Err(anyhow!("fake IP {} is not mapped to a domain", sa.ip()));
+            }
+        },
+        other => other,
+    };
+
     let (target_host, target_port) = target.to_host_port_string();
 
     // Best-effort sniffing: domain from HTTP Host / CONNECT / TLS SNI.
     // Under load we shrink or skip sniffing to avoid adding avoidable latency on new connections.
-    let sniffed = sniff_client_host(&client, sniff_mode_for(&state)).await;
+    // A CONNECT client sends nothing before its 200 and names the host itself.
+    let sniffed = if pending_connect {
+        None
+    } else {
+        sniff_client_host(&client, sniff_mode_for(&state)).await
+    };
     let sniff_host = match &sniffed {
         Some(crate::sniff::SniffResult::HttpHost(h)) => Some(h.clone()),
         Some(crate::sniff::SniffResult::ConnectHost(h)) => Some(h.clone()),
@@ -490,6 +569,14 @@ async fn proxy_tcp(
         None => None,
     };
 
+    let dst_ip = match &target {
+        stats::Target::SockAddr(sa) => Some(sa.ip()),
+        stats::Target::HostPort(host, _) => host.parse::<IpAddr>().ok(),
+    };
+    // Without SNI/Host fall back to the name the DNS forwarder last resolved
+    // to this destination.
+    let sniff_host = sniff_host.or_else(|| dst_ip.and_then(|ip| state.dns.name_for(ip)));
+
     // Expose best-effort domain to the UI (SNI/Host/CONNECT). If absent -> UI will show fallback.
     state.conns.set_domain(cid, sniff_host.clone());
 
@@ -509,7 +596,10 @@ async fn proxy_tcp(
 
     let proto = rules::classify_protocol(target_port);
     let socks_available = state.backends.lock().any_green();
-    let action = state.rules.decide(&proto, &host_for_rules, target_port, socks_available, false);
+    let decision = state.rules.load().decide(&proto, &host_for_rules, dst_ip, target_port, socks_available, false);
+    let action = decision.as_ref().map(|d| d.action);
+    let rule_name = decision.as_ref().and_then(|d| d.name.clone());
+    let group = decision.and_then(|d| d.group);
 
     // Resolve mode: socks vs direct
     let mut use_direct = false;
@@ -562,16 +652,19 @@ async fn proxy_tcp(
     
     let priority_zero_mode = state.args.priority_zero_mode();
 
-    // Enforce: when there is at least one GREEN backend, do NOT allow direct connections.
+    // Enforce: when there is at least one GREEN backend, do NOT fall back to direct.
     // Only bypass the proxy when no GREEN backends are available.
     // Priority mode can explicitly put `0` at the beginning of --socks-port to make
     // direct access the first priority before SOCKS backends.
-    if socks_available && priority_zero_mode != PriorityZeroMode::DirectFirst {
-        use_direct = false;
-    }
+    // An explicit `direct` rule is split routing rather than a fallback: it is
+    // honoured while backends are GREEN and tracked as `direct_rule`, so the
+    // proxy enforcement loop does not cancel it when backends recover.
     let mut chosen_mode = "socks";
     let mut chosen_backend: Option<SocketAddr> = None;
-    let upstream = if priority_zero_mode == PriorityZeroMode::DirectOnly {
+    let upstream = async { Ok::<_, anyhow::Error>(if use_direct {
+        chosen_mode = "direct_rule";
+        connect_direct(&target, state.args.connect_timeout).await?
+    } else if priority_zero_mode == PriorityZeroMode::DirectOnly {
         chosen_mode = "direct";
         if !ensure_direct_path_ready(&state, Duration::from_millis(1200)).await {
             return Err(anyhow::anyhow!("direct-only priority mode has no confirmed direct Internet route"));
@@ -607,7 +700,7 @@ async fn proxy_tcp(
                     if state.backends.lock().any_green()
                         || stats::wait_for_backend_recovery(state.clone(), Duration::from_millis(1200)).await
                     {
-                        let (s, be) = connect_socks(&target, sniff_host.as_deref(), state.clone(), cid).await?;
+                        let (s, be) = connect_socks(&target, group.as_deref(), state.clone(), cid).await?;
                         chosen_backend = Some(be);
                         s
                     } else {
@@ -618,7 +711,7 @@ async fn proxy_tcp(
         } else if state.backends.lock().any_green()
             || stats::wait_for_backend_recovery(state.clone(), Duration::from_millis(1200)).await
         {
-            let (s, be) = connect_socks(&target, sniff_host.as_deref(), state.clone(), cid).await?;
+            let (s, be) = connect_socks(&target, group.as_deref(), state.clone(), cid).await?;
             chosen_backend = Some(be);
             s
         } else {
@@ -628,17 +721,17 @@ async fn proxy_tcp(
         if state.backends.lock().any_green()
             || stats::wait_for_backend_recovery(state.clone(), Duration::from_millis(1200)).await
         {
-            let (s, be) = connect_socks(&target, sniff_host.as_deref(), state.clone(), cid).await?;
+            let (s, be) = connect_socks(&target, group.as_deref(), state.clone(), cid).await?;
             chosen_backend = Some(be);
             s
         } else {
             state.stats.inc_policy_drop();
             return Err(anyhow::anyhow!("priority mode blocks direct fallback and no GREEN SOCKS5 backends are available"));
         }
-    } else if use_direct || !socks_available {
+    } else if !socks_available {
         let refreshed = stats::wait_for_backend_recovery(state.clone(), Duration::from_millis(1200)).await;
         if refreshed {
-            let (s, be) = connect_socks(&target, sniff_host.as_deref(), state.clone(), cid).await?;
+            let (s, be) = connect_socks(&target, group.as_deref(), state.clone(), cid).await?;
             chosen_backend = Some(be);
             s
         } else {
@@ -655,7 +748,7 @@ async fn proxy_tcp(
             }
         }
     } else {
-        match connect_socks(&target, sniff_host.as_deref(), state.clone(), cid).await {
+        match connect_socks(&target, group.as_deref(), state.clone(), cid).await {
             Ok((s, be)) => {
                 chosen_backend = Some(be);
                 s
@@ -669,7 +762,7 @@ async fn proxy_tcp(
 
                 let refreshed = stats::wait_for_backend_recovery(state.clone(), Duration::from_millis(1200)).await;
                 if refreshed {
-                    let (s, be) = connect_socks(&target, sniff_host.as_deref(), state.clone(), cid).await?;
+                    let (s, be) = connect_socks(&target, group.as_deref(), state.clone(), cid).await?;
                     chosen_backend = Some(be);
                     s
                 } else {
@@ -687,7 +780,19 @@ async fn proxy_tcp(
                 }
             }
         }
-    };
+    }) }
+    .await;
+    // HTTP CONNECT clients learn the outcome only now, so a dead upstream is a
+    // 502 rather than a tunnel that closes right after 200.
+    if pending_connect {
+        http_inbound::connect_reply(&mut client, upstream.is_ok()).await?;
+    }
+    let mut upstream = upstream?;
+
+    // Plain HTTP proxy requests: the rewritten request head goes first.
+    if let Some(head) = preamble {
+        upstream.write_all(&head).await.context("proxy upstream write failed")?;
+    }
 
     // Expose chosen backend (if any) to the UI.
     state.conns.set_backend(cid, chosen_backend);
@@ -697,12 +802,12 @@ async fn proxy_tcp(
         let _ = state.events.send(stats::Event::conn_target(cid, target_host.clone(), target_port, chosen_mode.to_string()));
     }
 
-    // Proxy with simple throttling on downstream (upstream->client)
+    // Proxy with token-bucket shaping in both directions; caps are
+    // runtime-adjustable via the web API (0 = unlimited).
     let (mut cr, mut cw) = client.into_split();
-    let (mut ur, mut uw) = upstream.into_split();
+    let (mut ur, mut uw) = tokio::io::split(upstream);
     let buf_sz = state.args.buffer_size as usize;
-
-    // download limit is runtime-adjustable via web UI (0 = unlimited)
+    let shape = Arc::new(state.shaper.conn(chosen_backend, rule_name.as_ref(), uid));
 
     let idle = if state.args.idle_timeout == 0 {
         None
@@ -713,6 +818,7 @@ async fn proxy_tcp(
     let st1 = state.clone();
     let be1 = chosen_backend;
     let c1 = cancel.clone();
+    let shape1 = shape.clone();
     let upload = async move {
         // client -> upstream (upload)
         let mut buf = vec![0u8; buf_sz];
@@ -738,6 +844,14 @@ async fn proxy_tcp(
             };
             if n == 0 { break; }
 
+            let owed = shape1.charge(shaper::Dir::Up, n);
+            if !owed.is_zero() {
+                tokio::select! {
+                    _ = c1.cancelled() => break,
+                    _ = tokio::time::sleep(owed) => {}
+                }
+            }
+
             if let Some(idle_d) = idle {
                 let sleep = idle_sleep.as_mut().expect("idle sleep present");
                 tokio::select! {
@@ -758,6 +872,9 @@ async fn proxy_tcp(
             conn_acc = conn_acc.saturating_add(n as u64);
             if conn_acc >= conn_flush_threshold || conn_last_flush.elapsed() >= conn_flush_interval {
                 st1.conns.add_bytes_up(cid, conn_acc);
+                if let Some(uid) = uid {
+                    st1.apps.add_bytes(uid, conn_acc, 0);
+                }
                 conn_acc = 0;
                 conn_last_flush = Instant::now();
             }
@@ -771,6 +888,9 @@ async fn proxy_tcp(
         }
         if conn_acc > 0 {
             st1.conns.add_bytes_up(cid, conn_acc);
+            if let Some(uid) = uid {
+                st1.apps.add_bytes(uid, conn_acc, 0);
+            }
         }
         if let Some(b) = be1 {
             if be_acc > 0 {
@@ -784,6 +904,7 @@ async fn proxy_tcp(
     let st2 = state.clone();
     let be2 = chosen_backend;
     let c2 = cancel.clone();
+    let shape2 = shape;
     let download = async move {
         // upstream -> client (download)
         let mut buf = vec![0u8; buf_sz];
@@ -792,8 +913,6 @@ async fn proxy_tcp(
         let conn_flush_threshold = conn_stats_flush_threshold();
         let conn_flush_interval = conn_stats_flush_interval();
         let mut conn_last_flush = Instant::now();
-        let mut window_start = Instant::now();
-        let mut window_bytes: u64 = 0;
         let mut idle_sleep = idle.map(|d| Box::pin(tokio::time::sleep(d)));
 
         loop {
@@ -812,26 +931,11 @@ async fn proxy_tcp(
             };
             if n == 0 { break; }
 
-            let bps = st2.runtime.download_limit_bps.load(std::sync::atomic::Ordering::Relaxed);
-            if bps > 0 {
-                window_bytes += n as u64;
-                let elapsed = window_start.elapsed().as_secs_f64();
-                if elapsed > 0.0 {
-                    let cur_bps = (window_bytes as f64) / elapsed;
-                    if cur_bps > (bps as f64) {
-                        let target_elapsed = (window_bytes as f64) / (bps as f64);
-                        let sleep_s = target_elapsed - elapsed;
-                        if sleep_s > 0.0 {
-                            tokio::select! {
-                                _ = c2.cancelled() => break,
-                                _ = tokio::time::sleep(Duration::from_secs_f64(sleep_s.min(0.5))) => {}
-                            }
-                        }
-                    }
-                }
-                if window_start.elapsed() > Duration::from_secs(1) {
-                    window_start = Instant::now();
-                    window_bytes = 0;
+            let owed = shape2.charge(shaper::Dir::Down, n);
+            if !owed.is_zero() {
+                tokio::select! {
+                    _ = c2.cancelled() => break,
+                    _ = tokio::time::sleep(owed) => {}
                 }
             }
 
@@ -854,6 +958,9 @@ async fn proxy_tcp(
             conn_acc = conn_acc.saturating_add(n as u64);
             if conn_acc >= conn_flush_threshold || conn_last_flush.elapsed() >= conn_flush_interval {
                 st2.conns.add_bytes_down(cid, conn_acc);
+                if let Some(uid) = uid {
+                    st2.apps.add_bytes(uid, 0, conn_acc);
+                }
                 conn_acc = 0;
                 conn_last_flush = Instant::now();
             }
@@ -867,6 +974,9 @@ async fn proxy_tcp(
         }
         if conn_acc > 0 {
             st2.conns.add_bytes_down(cid, conn_acc);
+            if let Some(uid) = uid {
+                st2.apps.add_bytes(uid, 0, conn_acc);
+            }
         }
         if let Some(b) = be2 {
             if be_acc > 0 {
@@ -1010,14 +1120,14 @@ async fn write_socks5_inbound_reply(client: &mut tokio::net::TcpStream, rep: u8)
         .context("write SOCKS5 inbound reply")
 }
 
-async fn connect_direct(target: &stats::Target, timeout_s: u32) -> Result<tokio::net::TcpStream> {
+async fn connect_direct(target: &stats::Target, timeout_s: u32) -> Result<upstream::ProxyStream> {
     let timeout = Duration::from_secs(timeout_s as u64);
     let addr = target.resolve_socket_addr().await?;
     let s = tokio::time::timeout(timeout, tokio::net::TcpStream::connect(addr))
         .await
         .context("direct connect timeout")?
         .context("direct connect failed")?;
-    Ok(s)
+    Ok(upstream::ProxyStream::Tcp(s))
 }
 
 async fn ensure_direct_path_ready(state: &AppState, wait: Duration) -> bool {
@@ -1130,6 +1240,10 @@ fn is_backend_runtime_failure(err: &str) -> bool {
         || e.contains("no acceptable auth methods")
         || e.contains("server requires auth")
         || e.contains("unsupported auth method")
+        || e.contains("proxy tcp connect timeout")
+        || e.contains("proxy tcp connect failed")
+        || e.contains("proxy tls handshake")
+        || e.contains("proxy auth failed")
 }
 
 fn is_proxy_backend_suspect_error(err: &str) -> bool {
@@ -1143,10 +1257,10 @@ fn is_proxy_backend_suspect_error(err: &str) -> bool {
 
 async fn connect_socks(
     target: &stats::Target,
-    _observed_domain: Option<&str>,
+    group: Option<&[u16]>,
     state: AppState,
     cid: u64,
-) -> Result<(tokio::net::TcpStream, SocketAddr)> {
+) -> Result<(upstream::ProxyStream, SocketAddr)> {
     let timeout = Duration::from_secs(state.args.connect_timeout as u64);
 
     let setup_now = state.conns.count_modes(&["pending", "wait_backend", "socks_connecting"]);
@@ -1174,7 +1288,12 @@ async fn connect_socks(
     for _ in 0..max_tries {
         let (backend_idx, backend, auth) = {
             let mut b = state.backends.lock();
-            b.select_rr_with_auth(global_auth.as_ref()).context("no GREEN SOCKS5 backends")?
+            match group {
+                Some(ports) => b
+                    .select_group_with_auth(ports, global_auth.as_ref(), false)
+                    .context("no GREEN SOCKS5 backends in the rule group")?,
+                None => b.select_rr_with_auth(global_auth.as_ref()).context("no GREEN SOCKS5 backends")?,
+            }
         };
 
         if !tried.insert(backend) {
@@ -1192,12 +1311,14 @@ async fn connect_socks(
         state.conns.set_mode(cid, "socks_connecting");
         state.conns.set_backend(cid, Some(backend));
 
-        let wrapper = state.wrapped_socks_addr;
-        let wrapper_auth = state.args.wrapped_socks_auth();
-        let attempt = if let Some(wrapper) = wrapper {
-            socks5::connect_via_socks5_wrapped(wrapper, backend, taddr.clone(), wrapper_auth, auth, timeout).await
-        } else {
-            socks5::connect_via_socks5(backend, taddr.clone(), auth, timeout).await
+        let dial = state
+            .backends
+            .lock()
+            .upstream_at(backend_idx, auth)
+            .map(|u| u.wrapped(state.wrapped_socks_addr, state.args.wrapped_socks_auth()));
+        let attempt = match dial {
+            Some(dial) => dial.connect(taddr.clone(), timeout).await,
+            None => Err(anyhow::anyhow!("backend {} was removed", backend)),
         };
         {
             let mut b = state.backends.lock();
@@ -1213,6 +1334,7 @@ async fn connect_socks(
             Err(e) => {
                 state.stats.inc_socks_fail();
                 let err_text = format!("{:#}", e);
+                state.metrics.count_failure(Some(backend), &err_text);
                 if is_backend_runtime_failure(&err_text) {
                     let mut b = state.backends.lock();
                     let before_state = b.raw_state_for_addr(backend);
@@ -0,0 +1,206 @@
+//! Prometheus text exposition for `/metrics`.
+//!
+//! Most values are read from the existing counters at scrape time; only the
+//! health probe RTT histograms, probe outcomes and failure classes are kept
+//! here, since nothing else remembers them.
+
+use crate::AppState;
+use parking_lot::Mutex;
+use std::{collections::BTreeMap, fmt::Write, net::SocketAddr, sync::atomic::Ordering};
+
+/// Upper bounds of the probe RTT histogram, seconds.
+const RTT_BUCKETS: [f64; 10] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+#[derive(Default)]
+struct Histogram {
+    counts: [u64; RTT_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, v: f64) {
+        if let Some(i) = RTT_BUCKETS.iter().position(|b| v <= *b) {
+            self.counts[i] += 1;
+        }
+        self.count += 1;
+        self.sum += v;
+    }
+}
+
+#[derive(Default)]
+struct Inner {
+    /// (backend, probe kind) -> RTT.
+    probe_rtt: BTreeMap<(String, &'static str), Histogram>,
+    /// (backend, outcome) -> count.
+    probes: BTreeMap<(String, &'static str), u64>,
+    failures: BTreeMap<&'static str, u64>,
+    /// (backend, class) -> count.
+    backend_failures: BTreeMap<(String, &'static str), u64>,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    inner: Mutex<Inner>,
+}
+
+/// Coarse failure class of an error chain, for labels.
+pub fn failure_class(err: &str) -> &'static str {
+    let e = err.to_ascii_lowercase();
+    if e.contains("auth") {
+        "auth"
+    } else if e.contains("timed out") || e.contains("timeout") {
+        "timeout"
+    } else if e.contains("refused") {
+        "refused"
+    } else if e.contains("reset") || e.contains("broken pipe") || e.contains("unexpected eof") {
+        "reset"
+    } else if e.contains("resolve") || e.contains("lookup") || e.contains("dns") {
+        "dns"
+    } else if e.contains("no green") || e.contains("cooling down") || e.contains("blocks direct") || e.contains("no confirmed direct") {
+        "no_route"
+    } else {
+        "other"
+    }
+}
+
+impl Metrics {
+    /// Record one backend health probe; RTTs are in ms as the probes report them.
+    pub fn observe_probe(&self, backend: SocketAddr, socks_ms: Option<f64>, internet_ms: Option<f64>, failed: bool) {
+        let outcome = match (socks_ms, failed) {
+            (None, _) => "unreachable",
+            (Some(_), true) => "degraded",
+            (Some(_), false) => "ok",
+        };
+        let key = backend.to_string();
+        let mut inner = self.inner.lock();
+        *inner.probes.entry((key.clone(), outcome)).or_default() += 1;
+        for (kind, ms) in [("socks", socks_ms), ("internet", internet_ms)] {
+            if let Some(ms) = ms {
+                inner.probe_rtt.entry((key.clone(), kind)).or_default().observe(ms / 1000.0);
+            }
+        }
+    }
+
+    /// Count a failed connection, or a failed connect through `backend`.
+    pub fn count_failure(&self, backend: Option<SocketAddr>, err: &str) {
+        let class = failure_class(err);
+        let mut inner = self.inner.lock();
+        match backend {
+            Some(b) => *inner.backend_failures.entry((b.to_string(), class)).or_default() += 1,
+            None => *inner.failures.entry(class).or_default() += 1,
+        }
+    }
+}
+
+fn escape(v: &str) -> String {
+    v.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+struct Out(String);
+
+impl Out {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {name} {help}\n# TYPE {name} {kind}");
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels.iter().map(|(k, v)| format!("{k}=\"{}\"", escape(v))).collect();
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.0, " {value}");
+    }
+}
+
+pub fn render(state: &AppState) -> String {
+    let mut out = Out(String::with_capacity(8192));
+    let s = &state.stats;
+
+    out.family("t2s_bytes_total", "counter", "Bytes relayed, by ingress listener and direction.");
+    for (ingress, port) in [("internal", &s.internal), ("external", &s.external)] {
+        out.sample("t2s_bytes_total", &[("ingress", ingress), ("direction", "up")], port.bytes_up.load(Ordering::Relaxed));
+        out.sample("t2s_bytes_total", &[("ingress", ingress), ("direction", "down")], port.bytes_down.load(Ordering::Relaxed));
+    }
+    out.family("t2s_active_connections", "gauge", "Open TCP connections.");
+    out.sample("t2s_active_connections", &[], state.conns.len());
+    out.family("t2s_policy_drops_total", "counter", "Connections dropped by rules or policy.");
+    out.sample("t2s_policy_drops_total", &[], s.policy_drop.load(Ordering::Relaxed));
+    out.family("t2s_backend_connects_total", "counter", "Connect attempts through backends.");
+    out.sample("t2s_backend_connects_total", &[("result", "ok")], s.socks_ok.load(Ordering::Relaxed));
+    out.sample("t2s_backend_connects_total", &[("result", "fail")], s.socks_fail.load(Ordering::Relaxed));
+
+    let backends = state.backends.lock().snapshot();
+    out.family("t2s_backend_state", "gauge", "1 for the current health state of each backend.");
+    for b in &backends {
+        let current = format!("{:?}", b.state).to_ascii_lowercase();
+        for st in ["green", "yellow", "red"] {
+            out.sample("t2s_backend_state", &[("backend", &b.addr), ("type", b.kind.as_str()), ("state", st)], u8::from(current == st));
+        }
+    }
+    out.family("t2s_backend_udp_healthy", "gauge", "1 when the backend's UDP relay passed its last probe.");
+    for b in &backends {
+        out.sample("t2s_backend_udp_healthy", &[("backend", &b.addr)], u8::from(b.udp_healthy));
+    }
+    out.family("t2s_backend_bytes_total", "counter", "Bytes relayed through each backend, both directions.");
+    for b in &backends {
+        out.sample("t2s_backend_bytes_total", &[("backend", &b.addr)], b.total_bytes);
+    }
+    out.family("t2s_backend_rtt_seconds", "gauge", "Last probe round trip of each backend.");
+    for b in &backends {
+        for (kind, ms) in [("socks", b.socks_ping_ms), ("internet", b.internet_ping_ms), ("udp", b.udp_ping_ms)] {
+            if let Some(ms) = ms {
+                out.sample("t2s_backend_rtt_seconds", &[("backend", &b.addr), ("kind", kind)], ms / 1000.0);
+            }
+        }
+    }
+
+    let inner = state.metrics.inner.lock();
+    out.family("t2s_backend_probe_rtt_seconds", "histogram", "Health probe round trips.");
+    for ((backend, kind), h) in &inner.probe_rtt {
+        let mut cumulative = 0;
+        for (le, n) in RTT_BUCKETS.iter().zip(h.counts) {
+            cumulative += n;
+            out.sample("t2s_backend_probe_rtt_seconds_bucket", &[("backend", backend), ("kind", kind), ("le", &le.to_string())], cumulative);
+        }
+        out.sample("t2s_backend_probe_rtt_seconds_bucket", &[("backend", backend), ("kind", kind), ("le", "+Inf")], h.count);
+        out.sample("t2s_backend_probe_rtt_seconds_sum", &[("backend", backend), ("kind", kind)], h.sum);
+        out.sample("t2s_backend_probe_rtt_seconds_count", &[("backend", backend), ("kind", kind)], h.count);
+    }
+    out.family("t2s_backend_probes_total", "counter", "Health probes by outcome (ok, degraded, unreachable).");
+    for ((backend, outcome), n) in &inner.probes {
+        out.sample("t2s_backend_probes_total", &[("backend", backend), ("outcome", outcome)], n);
+    }
+    out.family("t2s_backend_connect_failures_total", "counter", "Failed connects through each backend, by class.");
+    for ((backend, class), n) in &inner.backend_failures {
+        out.sample("t2s_backend_connect_failures_total", &[("backend", backend), ("class", class)], n);
+    }
+    out.family("t2s_connection_failures_total", "counter", "Connections that ended with an error, by class.");
+    for (class, n) in &inner.failures {
+        out.sample("t2s_connection_failures_total", &[("class", class)], n);
+    }
+    out.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_and_failure_classes() {
+        let mut h = Histogram::default();
+        for v in [0.005, 0.04, 0.04, 20.0] {
+            h.observe(v);
+        }
+        assert_eq!(h.counts[0], 1);
+        assert_eq!(h.counts[2], 2);
+        assert_eq!(h.counts.iter().sum::<u64>(), 3);
+        assert_eq!(h.count, 4);
+
+        assert_eq!(failure_class("proxy tcp connect timeout"), "timeout");
+        assert_eq!(failure_class("connect: Connection refused (os error 111)"), "refused");
+        assert_eq!(failure_class("proxy auth failed"), "auth");
+        assert_eq!(escape("a\"b\\"), "a\\\"b\\\\");
+    }
+}
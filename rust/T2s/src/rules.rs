@@ -1,5 +1,18 @@
+mod sets;
+
+use anyhow::{anyhow, Context, Result};
+use parking_lot::RwLock;
 use regex::Regex;
 use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    path::PathBuf,
+    sync::Arc,
+};
+
+pub use sets::{CidrSet, DomainSet};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Action {
@@ -10,13 +23,28 @@ pub enum Action {
     Wait,
 }
 
+/// Result of a rule match. `group` restricts SOCKS selection to the backends
+/// listening on these ports; `name` selects the rule's shaping caps.
 #[derive(Clone, Debug)]
+pub struct Decision {
+    pub action: Action,
+    pub group: Option<Arc<[u16]>>,
+    pub name: Option<Arc<str>>,
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct Rules {
     rules: Vec<Rule>,
+    groups: BTreeMap<String, Arc<[u16]>>,
+    /// Document the rules were compiled from, returned by the API.
+    source: Value,
+    loaded_ts: u64,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 struct RawRules {
+    #[serde(default)]
+    groups: BTreeMap<String, Vec<u16>>,
     rules: Vec<RawRule>,
 }
 
@@ -24,9 +52,13 @@ struct RawRules {
 struct RawRule {
     when: Option<When>,
     action: String,
+    #[serde(default)]
+    group: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize)]
 struct When {
     proto: Option<String>,
     port: Option<u16>,
@@ -34,12 +66,26 @@ struct When {
     host_regex: Option<String>,
     socks_available: Option<bool>,
     is_udp: Option<bool>,
+    #[serde(default)]
+    domain: Vec<String>,
+    #[serde(default)]
+    domain_suffix: Vec<String>,
+    #[serde(default)]
+    domain_keyword: Vec<String>,
+    #[serde(default)]
+    domain_file: Vec<String>,
+    #[serde(default)]
+    ip_cidr: Vec<String>,
+    #[serde(default)]
+    cidr_file: Vec<String>,
 }
 
 #[derive(Clone, Debug)]
 struct Rule {
     when: WhenNorm,
     action: Action,
+    group: Option<Arc<[u16]>>,
+    name: Option<Arc<str>>,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -50,50 +96,57 @@ struct WhenNorm {
     host_regex: Option<Regex>,
     socks_available: Option<bool>,
     is_udp: Option<bool>,
+    domains: Option<DomainSet>,
+    cidrs: Option<CidrSet>,
 }
 
 impl Rules {
-    pub fn load_from_env() -> Self {
-        let raw = std::env::var("TRAFFIC_RULES").unwrap_or_default();
-        if raw.trim().is_empty() {
-            return Self { rules: vec![] };
-        }
-        let parsed = serde_json::from_str::<serde_json::Value>(&raw).ok()
-            .and_then(|v| {
-                if v.is_object() && v.get("rules").is_some() { v.get("rules").cloned() } else { Some(v) }
-            });
+    /// Compile a rules document: either a bare array of rules or an object
+    /// with `rules` and optional `groups` (`name -> [socks ports]`).
+    ///
+    /// With `strict` any invalid rule fails the whole document (API updates);
+    /// otherwise it is skipped with a warning, as `TRAFFIC_RULES` always was.
+    pub fn compile_document(doc: Value, strict: bool) -> Result<Self> {
+        let raw = if doc.is_array() {
+            RawRules { groups: BTreeMap::new(), rules: serde_json::from_value(doc.clone()).context("parse rules array")? }
+        } else {
+            serde_json::from_value::<RawRules>(doc.clone()).context("parse rules object")?
+        };
+
+        let groups: BTreeMap<String, Arc<[u16]>> = raw
+            .groups
+            .into_iter()
+            .map(|(name, ports)| (name.trim().to_string(), Arc::from(ports)))
+            .collect();
 
         let mut out = vec![];
-        if let Some(v) = parsed {
-            if let Ok(arr) = serde_json::from_value::<Vec<RawRule>>(v) {
-                for rr in arr {
-                    if let Some(r) = Self::compile(rr) {
-                        out.push(r);
-                    }
-                }
-            } else if let Ok(obj) = serde_json::from_str::<RawRules>(&raw) {
-                for rr in obj.rules {
-                    if let Some(r) = Self::compile(rr) {
-                        out.push(r);
-                    }
-                }
+        for (i, rr) in raw.rules.into_iter().enumerate() {
+            match Self::compile(rr, &groups) {
+                Ok(r) => out.push(r),
+                Err(e) if strict => return Err(e.context(format!("rule #{i}"))),
+                Err(e) => tracing::warn!("traffic rule #{} skipped: {:#}", i, e),
             }
         }
-        Self { rules: out }
+        Ok(Self { rules: out, groups, source: doc, loaded_ts: crate::stats::now_ts() })
     }
 
-    fn compile(rr: RawRule) -> Option<Rule> {
+    fn compile(rr: RawRule, groups: &BTreeMap<String, Arc<[u16]>>) -> Result<Rule> {
         let action = match rr.action.trim().to_lowercase().as_str() {
             "socks" => Action::Socks,
             "direct" => Action::Direct,
             "drop" => Action::Drop,
             "reset" => Action::Reset,
             "wait" => Action::Wait,
-            _ => return None,
+            other => return Err(anyhow!("unknown action {other:?}")),
         };
-        let w = rr.when.unwrap_or(When{
-            proto: None, port: None, port_range: None, host_regex: None, socks_available: None, is_udp: None,
-        });
+        let group = match rr.group.as_deref().map(str::trim).filter(|s| !s.is_empty()) {
+            None => None,
+            Some(_) if !matches!(action, Action::Socks | Action::Wait) => {
+                return Err(anyhow!("group is only valid with socks or wait"));
+            }
+            Some(name) => Some(groups.get(name).cloned().ok_or_else(|| anyhow!("unknown group {name:?}"))?),
+        };
+        let w = rr.when.unwrap_or_default();
 
         let pr = w.port_range.as_ref().and_then(|s| {
             let parts: Vec<_> = s.split('-').collect();
@@ -103,9 +156,42 @@ impl Rules {
             Some((lo, hi))
         });
 
-        let hr = w.host_regex.as_ref().and_then(|s| Regex::new(&format!("(?i){}", s)).ok());
+        let hr = w
+            .host_regex
+            .as_ref()
+            .map(|s| Regex::new(&format!("(?i){}", s)).with_context(|| format!("invalid host_regex {s:?}")))
+            .transpose()?;
 
-        Some(Rule{
+        let mut domains = DomainSet::default();
+        for d in &w.domain {
+            domains.add_exact(d)?;
+        }
+        for d in &w.domain_suffix {
+            domains.add_suffix(d)?;
+        }
+        for d in &w.domain_keyword {
+            domains.add_keyword(d)?;
+        }
+        for path in &w.domain_file {
+            let skipped = sets::load_domain_file(&mut domains, path)?;
+            if skipped > 0 {
+                tracing::warn!("domain list {}: skipped {} malformed line(s)", path, skipped);
+            }
+        }
+        let mut cidrs = CidrSet::default();
+        for c in &w.ip_cidr {
+            cidrs.add(c)?;
+        }
+        for path in &w.cidr_file {
+            let skipped = sets::load_cidr_file(&mut cidrs, path)?;
+            if skipped > 0 {
+                tracing::warn!("CIDR list {}: skipped {} malformed line(s)", path, skipped);
+            }
+        }
+        let has_domains = !w.domain.is_empty() || !w.domain_suffix.is_empty() || !w.domain_keyword.is_empty() || !w.domain_file.is_empty();
+        let has_cidrs = !w.ip_cidr.is_empty() || !w.cidr_file.is_empty();
+
+        Ok(Rule{
             when: WhenNorm{
                 proto: w.proto.map(|s| s.to_lowercase()),
                 port: w.port,
@@ -113,30 +199,49 @@ impl Rules {
                 host_regex: hr,
                 socks_available: w.socks_available,
                 is_udp: w.is_udp,
+                domains: has_domains.then_some(domains),
+                cidrs: has_cidrs.then_some(cidrs),
             },
             action,
+            group,
+            name: rr.name.as_deref().map(str::trim).filter(|s| !s.is_empty()).map(Arc::from),
         })
     }
 
-    pub fn decide(&self, proto: &str, host: &str, port: u16, socks_available: bool, is_udp: bool) -> Option<Action> {
+    /// `host` is the sniffed name (or the literal target), `ip` the
+    /// destination address when known.
+    pub fn decide(&self, proto: &str, host: &str, ip: Option<IpAddr>, port: u16, socks_available: bool, is_udp: bool) -> Option<Decision> {
         if self.rules.is_empty() {
             return None;
         }
         for r in &self.rules {
-            if r.matches(proto, host, port, socks_available, is_udp) {
-                return Some(r.action);
+            if r.matches(proto, host, ip, port, socks_available, is_udp) {
+                return Some(Decision { action: r.action, group: r.group.clone(), name: r.name.clone() });
             }
         }
         None
     }
 
     pub fn has_host_rules(&self) -> bool {
-        self.rules.iter().any(|r| r.when.host_regex.is_some())
+        self.rules.iter().any(|r| r.when.host_regex.is_some() || r.when.domains.is_some())
+    }
+
+    pub fn summary(&self) -> Value {
+        let domain_entries: usize = self.rules.iter().filter_map(|r| r.when.domains.as_ref()).map(DomainSet::entries).sum();
+        let cidr_entries: usize = self.rules.iter().filter_map(|r| r.when.cidrs.as_ref()).map(CidrSet::entries).sum();
+        let groups: BTreeMap<&str, &[u16]> = self.groups.iter().map(|(k, v)| (k.as_str(), &v[..])).collect();
+        serde_json::json!({
+            "rules": self.rules.len(),
+            "groups": groups,
+            "domain_entries": domain_entries,
+            "cidr_entries": cidr_entries,
+            "loaded_ts": self.loaded_ts,
+        })
     }
 }
 
 impl Rule {
-    fn matches(&self, proto: &str, host: &str, port: u16, socks_available: bool, is_udp: bool) -> bool {
+    fn matches(&self, proto: &str, host: &str, ip: Option<IpAddr>, port: u16, socks_available: bool, is_udp: bool) -> bool {
         if let Some(p) = &self.when.proto {
             if p != &proto.to_lowercase() {
                 return false;
@@ -157,10 +262,90 @@ impl Rule {
         if let Some(re) = &self.when.host_regex {
             if !re.is_match(host) { return false; }
         }
+        // Domain and CIDR sets of one rule are alternatives: either may match.
+        if self.when.domains.is_some() || self.when.cidrs.is_some() {
+            let by_name = host.parse::<IpAddr>().is_err()
+                && self.when.domains.as_ref().is_some_and(|d| d.matches(host));
+            let by_ip = || {
+                let ip = ip.or_else(|| host.parse().ok());
+                matches!((&self.when.cidrs, ip), (Some(c), Some(ip)) if c.contains(ip))
+            };
+            if !by_name && !by_ip() { return false; }
+        }
         true
     }
 }
 
+/// Hot-swappable rule set shared by the listeners and the API.
+///
+/// Rules come from `TRAFFIC_RULES_FILE` when set (and API updates are written
+/// back to it), otherwise from the inline `TRAFFIC_RULES` value.
+pub struct RuleStore {
+    current: RwLock<Arc<Rules>>,
+    file: Option<PathBuf>,
+}
+
+impl RuleStore {
+    pub fn from_env() -> Self {
+        let file = std::env::var("TRAFFIC_RULES_FILE").ok().map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).map(PathBuf::from);
+        let raw = match &file {
+            Some(path) => std::fs::read_to_string(path).unwrap_or_else(|e| {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    tracing::warn!("failed to read {}: {}", path.display(), e);
+                }
+                String::new()
+            }),
+            None => std::env::var("TRAFFIC_RULES").unwrap_or_default(),
+        };
+        let rules = if raw.trim().is_empty() {
+            Rules::default()
+        } else {
+            serde_json::from_str::<Value>(&raw)
+                .map_err(anyhow::Error::from)
+                .and_then(|doc| Rules::compile_document(doc, false))
+                .unwrap_or_else(|e| {
+                    tracing::warn!("traffic rules ignored: {:#}", e);
+                    Rules::default()
+                })
+        };
+        Self { current: RwLock::new(Arc::new(rules)), file }
+    }
+
+    pub fn load(&self) -> Arc<Rules> {
+        self.current.read().clone()
+    }
+
+    /// Compile `doc` (reading any list files) and swap it in. The running set
+    /// is kept when compilation fails.
+    pub fn replace(&self, doc: Value) -> Result<Arc<Rules>> {
+        let rules = Arc::new(Rules::compile_document(doc, true)?);
+        if let Some(path) = &self.file {
+            crate::api_runtime::write_json_atomic(path, &rules.source, true)?;
+        }
+        *self.current.write() = rules.clone();
+        Ok(rules)
+    }
+
+    /// Recompile the current document, picking up edited list files (and the
+    /// rules file itself when one is configured).
+    pub fn reload(&self) -> Result<Arc<Rules>> {
+        let doc = match &self.file {
+            Some(path) => {
+                let raw = std::fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+                serde_json::from_str(&raw).with_context(|| format!("parse {}", path.display()))?
+            }
+            None => self.load().source.clone(),
+        };
+        let rules = Arc::new(Rules::compile_document(doc, true)?);
+        *self.current.write() = rules.clone();
+        Ok(rules)
+    }
+
+    pub fn source(&self) -> Value {
+        self.load().source.clone()
+    }
+}
+
 pub fn classify_protocol(port: u16) -> String {
     match port {
         80 | 8080 | 8000 => "http".to_string(),
@@ -169,3 +354,48 @@ pub fn classify_protocol(port: u16) -> String {
         _ => "tcp".to_string(),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn domain_and_cidr_sets_pick_group() {
+        let doc = serde_json::json!({
+            "groups": {"blocked": [1080, 1081]},
+            "rules": [
+                {"when": {"domain_suffix": ["example.com"], "domain": ["exact.org"], "ip_cidr": ["10.1.0.0/16"]},
+                 "action": "socks", "group": "blocked"},
+                {"action": "direct"}
+            ]
+        });
+        let rules = Rules::compile_document(doc, true).unwrap();
+        let socks = |host: &str, ip: Option<&str>| {
+            let d = rules.decide("https", host, ip.map(|s| s.parse().unwrap()), 443, true, false).unwrap();
+            d.action == Action::Socks && d.group.as_deref() == Some(&[1080, 1081][..])
+        };
+        assert!(socks("example.com", None));
+        assert!(socks("a.b.EXAMPLE.com.", None));
+        assert!(!socks("badexample.com", None));
+        assert!(socks("exact.org", None));
+        assert!(!socks("www.exact.org", None));
+        assert!(socks("10.1.2.3", None));
+        assert!(socks("unrelated.net", Some("10.1.200.1")));
+        assert!(!socks("unrelated.net", Some("10.2.0.1")));
+        assert!(socks("x", Some("::ffff:10.1.0.9")));
+
+        let bad = serde_json::json!([{"action": "socks", "group": "missing"}]);
+        assert!(Rules::compile_document(bad, true).is_err());
+    }
+
+    #[test]
+    fn invalid_host_regex_fails_strict_and_is_skipped_otherwise() {
+        let doc = serde_json::json!([
+            {"when": {"host_regex": "("}, "action": "drop"},
+            {"action": "direct"}
+        ]);
+        assert!(Rules::compile_document(doc.clone(), true).is_err());
+        let rules = Rules::compile_document(doc, false).unwrap();
+        assert_eq!(rules.decide("https", "example.com", None, 443, true, false).unwrap().action, Action::Direct);
+    }
+}
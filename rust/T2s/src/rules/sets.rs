@@ -0,0 +1,274 @@
+//! Compiled matchers for domain and CIDR lists.
+//!
+//! Domains are stored in a trie keyed by labels from the TLD down, so a lookup
+//! costs one step per label of the queried host no matter how many entries a
+//! geosite list has. CIDRs live in a binary trie per address family.
+
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use std::{collections::HashMap, net::IpAddr};
+
+#[derive(Clone, Debug, Default)]
+struct Label {
+    children: HashMap<Box<str>, usize>,
+    /// `full:` entry ends here.
+    exact: bool,
+    /// `domain:` entry ends here; matches the name and every subdomain.
+    suffix: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct DomainSet {
+    nodes: Vec<Label>,
+    keywords: Vec<String>,
+    regexes: Vec<Regex>,
+    entries: usize,
+}
+
+impl Default for DomainSet {
+    fn default() -> Self {
+        Self { nodes: vec![Label::default()], keywords: Vec::new(), regexes: Vec::new(), entries: 0 }
+    }
+}
+
+fn normalize_domain(s: &str) -> String {
+    s.trim().trim_matches('.').to_ascii_lowercase()
+}
+
+impl DomainSet {
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+
+    fn insert(&mut self, domain: &str, suffix: bool) -> Result<()> {
+        let domain = normalize_domain(domain);
+        if domain.is_empty() || domain.split('.').any(str::is_empty) {
+            bail!("invalid domain {domain:?}");
+        }
+        let mut at = 0;
+        for label in domain.rsplit('.') {
+            at = match self.nodes[at].children.get(label) {
+                Some(next) => *next,
+                None => {
+                    self.nodes.push(Label::default());
+                    let next = self.nodes.len() - 1;
+                    self.nodes[at].children.insert(label.into(), next);
+                    next
+                }
+            };
+        }
+        if suffix {
+            self.nodes[at].suffix = true;
+        } else {
+            self.nodes[at].exact = true;
+        }
+        self.entries += 1;
+        Ok(())
+    }
+
+    pub fn add_exact(&mut self, domain: &str) -> Result<()> {
+        self.insert(domain, false)
+    }
+
+    pub fn add_suffix(&mut self, domain: &str) -> Result<()> {
+        self.insert(domain, true)
+    }
+
+    pub fn add_keyword(&mut self, keyword: &str) -> Result<()> {
+        let keyword = normalize_domain(keyword);
+        if keyword.is_empty() {
+            bail!("empty domain keyword");
+        }
+        if !self.keywords.contains(&keyword) {
+            self.keywords.push(keyword);
+        }
+        self.entries += 1;
+        Ok(())
+    }
+
+    pub fn add_regex(&mut self, pattern: &str) -> Result<()> {
+        let re = Regex::new(&format!("(?i){}", pattern.trim())).with_context(|| format!("invalid regexp {pattern:?}"))?;
+        self.regexes.push(re);
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Add one geosite-style line: `full:`, `domain:`, `keyword:` or `regexp:`
+    /// prefix, a bare name meaning `domain:`, and an optional trailing
+    /// `@attr` list which is ignored.
+    pub fn add_line(&mut self, line: &str) -> Result<()> {
+        let line = line.split_whitespace().next().unwrap_or_default();
+        if let Some(v) = line.strip_prefix("full:") {
+            self.add_exact(v)
+        } else if let Some(v) = line.strip_prefix("domain:") {
+            self.add_suffix(v)
+        } else if let Some(v) = line.strip_prefix("keyword:") {
+            self.add_keyword(v)
+        } else if let Some(v) = line.strip_prefix("regexp:") {
+            self.add_regex(v)
+        } else {
+            self.add_suffix(line.trim_start_matches("+.").trim_start_matches("*."))
+        }
+    }
+
+    pub fn matches(&self, host: &str) -> bool {
+        if self.entries == 0 {
+            return false;
+        }
+        let host = normalize_domain(host);
+        if host.is_empty() {
+            return false;
+        }
+        let mut at = 0;
+        let mut walked_all = true;
+        for label in host.rsplit('.') {
+            match self.nodes[at].children.get(label) {
+                Some(next) => at = *next,
+                None => {
+                    walked_all = false;
+                    break;
+                }
+            }
+            if self.nodes[at].suffix {
+                return true;
+            }
+        }
+        if walked_all && self.nodes[at].exact {
+            return true;
+        }
+        self.keywords.iter().any(|k| host.contains(k.as_str())) || self.regexes.iter().any(|re| re.is_match(&host))
+    }
+}
+
+#[derive(Clone, Debug)]
+struct BitTrie {
+    /// Child indexes for bit 0 / bit 1; 0 means none (the root is never a child).
+    nodes: Vec<[usize; 2]>,
+    terminal: Vec<bool>,
+}
+
+impl Default for BitTrie {
+    fn default() -> Self {
+        Self { nodes: vec![[0, 0]], terminal: vec![false] }
+    }
+}
+
+impl BitTrie {
+    fn insert(&mut self, bits: u128, prefix: u8, width: u8) {
+        let mut at = 0;
+        for i in 0..prefix {
+            let bit = ((bits >> (width - 1 - i)) & 1) as usize;
+            if self.nodes[at][bit] == 0 {
+                self.nodes.push([0, 0]);
+                self.terminal.push(false);
+                let next = self.nodes.len() - 1;
+                self.nodes[at][bit] = next;
+            }
+            at = self.nodes[at][bit];
+            if self.terminal[at] {
+                // Already covered by a shorter prefix.
+                return;
+            }
+        }
+        self.terminal[at] = true;
+    }
+
+    fn contains(&self, bits: u128, width: u8) -> bool {
+        let mut at = 0;
+        if self.terminal[at] {
+            return true;
+        }
+        for i in 0..width {
+            let bit = ((bits >> (width - 1 - i)) & 1) as usize;
+            at = self.nodes[at][bit];
+            if at == 0 {
+                return false;
+            }
+            if self.terminal[at] {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CidrSet {
+    v4: BitTrie,
+    v6: BitTrie,
+    entries: usize,
+}
+
+impl CidrSet {
+    pub fn entries(&self) -> usize {
+        self.entries
+    }
+
+    /// Add `addr/prefix` or a bare address.
+    pub fn add(&mut self, cidr: &str) -> Result<()> {
+        let cidr = cidr.trim();
+        let (addr, prefix) = match cidr.split_once('/') {
+            Some((a, p)) => (a, Some(p.parse::<u8>().with_context(|| format!("invalid prefix in {cidr:?}"))?)),
+            None => (cidr, None),
+        };
+        match addr.parse::<IpAddr>().with_context(|| format!("invalid CIDR {cidr:?}"))? {
+            IpAddr::V4(ip) => {
+                let prefix = prefix.unwrap_or(32);
+                if prefix > 32 {
+                    bail!("invalid prefix in {cidr:?}");
+                }
+                self.v4.insert(u32::from(ip) as u128, prefix, 32);
+            }
+            IpAddr::V6(ip) => {
+                let prefix = prefix.unwrap_or(128);
+                if prefix > 128 {
+                    bail!("invalid prefix in {cidr:?}");
+                }
+                self.v6.insert(u128::from(ip), prefix, 128);
+            }
+        }
+        self.entries += 1;
+        Ok(())
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        if self.entries == 0 {
+            return false;
+        }
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => self.v4.contains(u32::from(ip) as u128, 32),
+            IpAddr::V6(ip) => self.v6.contains(u128::from(ip), 128),
+        }
+    }
+}
+
+/// Iterate the meaningful lines of a list file (`#` starts a comment).
+pub fn list_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .map(|l| l.split('#').next().unwrap_or_default().trim())
+        .filter(|l| !l.is_empty())
+}
+
+/// Load a domain list file. Malformed lines are skipped and counted so one bad
+/// entry in a large third-party list does not reject the whole rule set.
+pub fn load_domain_file(set: &mut DomainSet, path: &str) -> Result<usize> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("read domain list {path}"))?;
+    let mut skipped = 0;
+    for line in list_lines(&text) {
+        if set.add_line(line).is_err() {
+            skipped += 1;
+        }
+    }
+    Ok(skipped)
+}
+
+pub fn load_cidr_file(set: &mut CidrSet, path: &str) -> Result<usize> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("read CIDR list {path}"))?;
+    let mut skipped = 0;
+    for line in list_lines(&text) {
+        if set.add(line).is_err() {
+            skipped += 1;
+        }
+    }
+    Ok(skipped)
+}
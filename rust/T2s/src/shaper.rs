@@ -0,0 +1,367 @@
+//! Token-bucket traffic shaping.
+//!
+//! Every byte a connection moves is charged to each bucket that applies to
+//! it: global, per-connection, its backend, its named rule and its app. A
+//! bucket may go into debt by one read; TCP relays then sleep until the debt
+//! is repaid, so a heavy flow is slowed instead of cut. UDP datagrams are
+//! dropped rather than queued when a bucket has no room. Caps change at
+//! runtime and open connections pick them up on their next read.
+
+use anyhow::{anyhow, Result};
+use parking_lot::{Mutex, RwLock};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Burst when a cap does not set `burst_kb`.
+const DEFAULT_BURST: u64 = 64 * 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Dir {
+    Up = 0,
+    Down = 1,
+}
+
+/// Rate cap for one shaping scope; 0 = unlimited.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Cap {
+    #[serde(default)]
+    pub download_mbit: f64,
+    #[serde(default)]
+    pub upload_mbit: f64,
+    /// Bucket size in KiB; 0 = 64 KiB.
+    #[serde(default)]
+    pub burst_kb: u64,
+}
+
+impl Cap {
+    fn normalized(mut self) -> Self {
+        for v in [&mut self.download_mbit, &mut self.upload_mbit] {
+            if !v.is_finite() || *v < 0.0 {
+                *v = 0.0;
+            }
+        }
+        self
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.download_mbit == 0.0 && self.upload_mbit == 0.0
+    }
+}
+
+/// Runtime limits document served by `/api/v1/limits`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(from = "LimitsDoc")]
+pub struct Limits {
+    /// Caps shared by all traffic.
+    #[serde(flatten)]
+    pub global: Cap,
+    /// Caps applied to every connection or UDP flow separately.
+    #[serde(default)]
+    pub per_connection: Cap,
+    /// Caps per backend, keyed by `ip:port`.
+    #[serde(default)]
+    pub backends: BTreeMap<String, Cap>,
+    /// Caps per traffic rule, keyed by rule `name`.
+    #[serde(default)]
+    pub rules: BTreeMap<String, Cap>,
+}
+
+/// Wire form `Limits` is read from. The global caps are spelled out instead of
+/// flattened because serde can't combine `flatten` with `deny_unknown_fields`,
+/// and an unknown key (a typo) must be rejected rather than read as "no cap".
+/// `mbit` is the older spelling of `download_mbit` that the app still sends.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LimitsDoc {
+    #[serde(default, alias = "mbit")]
+    download_mbit: f64,
+    #[serde(default)]
+    upload_mbit: f64,
+    #[serde(default)]
+    burst_kb: u64,
+    #[serde(default)]
+    per_connection: Cap,
+    #[serde(default)]
+    backends: BTreeMap<String, Cap>,
+    #[serde(default)]
+    rules: BTreeMap<String, Cap>,
+}
+
+impl From<LimitsDoc> for Limits {
+    fn from(d: LimitsDoc) -> Self {
+        Self {
+            global: Cap { download_mbit: d.download_mbit, upload_mbit: d.upload_mbit, burst_kb: d.burst_kb },
+            per_connection: d.per_connection,
+            backends: d.backends,
+            rules: d.rules,
+        }
+    }
+}
+
+fn mbit_to_bps(mbit: f64) -> u64 {
+    if mbit > 0.0 { (mbit * 1024.0 * 1024.0 / 8.0).max(1.0) as u64 } else { 0 }
+}
+
+#[derive(Default)]
+struct Rate {
+    bps: AtomicU64,
+    burst: AtomicU64,
+}
+
+impl Rate {
+    fn set(&self, mbit: f64, burst_kb: u64) {
+        self.bps.store(mbit_to_bps(mbit), Ordering::Relaxed);
+        let burst = if burst_kb > 0 { burst_kb.saturating_mul(1024) } else { DEFAULT_BURST };
+        self.burst.store(burst, Ordering::Relaxed);
+    }
+}
+
+/// Upload and download rates of one scope.
+type Rates = [Arc<Rate>; 2];
+
+fn rates(cap: &Cap) -> Rates {
+    let rates: Rates = Default::default();
+    set_rates(&rates, cap);
+    rates
+}
+
+fn set_rates(rates: &Rates, cap: &Cap) {
+    rates[Dir::Up as usize].set(cap.upload_mbit, cap.burst_kb);
+    rates[Dir::Down as usize].set(cap.download_mbit, cap.burst_kb);
+}
+
+struct Bucket {
+    rate: Arc<Rate>,
+    /// Available bytes (negative while in debt) and the last refill.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    fn new(rate: Arc<Rate>) -> Self {
+        let burst = rate.burst.load(Ordering::Relaxed) as f64;
+        Self { rate, state: Mutex::new((burst, Instant::now())) }
+    }
+
+    /// Refill and return the current token count, or `None` when unlimited.
+    fn refill(&self, state: &mut (f64, Instant)) -> Option<f64> {
+        let bps = self.rate.bps.load(Ordering::Relaxed);
+        if bps == 0 {
+            return None;
+        }
+        let burst = self.rate.burst.load(Ordering::Relaxed) as f64;
+        let now = Instant::now();
+        state.0 = (state.0 + now.duration_since(state.1).as_secs_f64() * bps as f64).min(burst);
+        state.1 = now;
+        Some(bps as f64)
+    }
+
+    /// Take `n` bytes; returns how long the caller must wait to stay within the rate.
+    fn charge(&self, n: u64) -> Duration {
+        let mut state = self.state.lock();
+        let Some(bps) = self.refill(&mut state) else { return Duration::ZERO };
+        state.0 -= n as f64;
+        if state.0 >= 0.0 { Duration::ZERO } else { Duration::from_secs_f64(-state.0 / bps) }
+    }
+
+    fn has_room(&self, n: u64) -> bool {
+        let mut state = self.state.lock();
+        self.refill(&mut state).is_none_or(|_| state.0 >= n as f64)
+    }
+}
+
+type Buckets = [Arc<Bucket>; 2];
+
+fn buckets(rates: &Rates) -> Buckets {
+    [Arc::new(Bucket::new(rates[0].clone())), Arc::new(Bucket::new(rates[1].clone()))]
+}
+
+/// Buckets of one connection or UDP flow.
+pub struct ConnShaper {
+    buckets: Vec<Buckets>,
+}
+
+impl ConnShaper {
+    /// Charge `n` bytes moved in `dir`; returns the delay owed before the next read.
+    pub fn charge(&self, dir: Dir, n: usize) -> Duration {
+        self.buckets.iter().map(|b| b[dir as usize].charge(n as u64)).max().unwrap_or_default()
+    }
+
+    /// Charge `n` bytes only if every bucket has room; a datagram that does
+    /// not fit is dropped by the caller.
+    pub fn admit(&self, dir: Dir, n: usize) -> bool {
+        if !self.buckets.iter().all(|b| b[dir as usize].has_room(n as u64)) {
+            return false;
+        }
+        for b in &self.buckets {
+            b[dir as usize].charge(n as u64);
+        }
+        true
+    }
+}
+
+fn scope(cap: &Cap) -> (Rates, Buckets) {
+    let r = rates(cap);
+    let b = buckets(&r);
+    (r, b)
+}
+
+/// Shared buckets of one kind of scope. Entries are also created for scopes
+/// without a cap, so a cap set later applies to connections already open.
+struct Scopes<K>(Mutex<HashMap<K, (Rates, Buckets)>>);
+
+impl<K: std::hash::Hash + Eq> Scopes<K> {
+    fn get(&self, key: K) -> Buckets {
+        self.0.lock().entry(key).or_insert_with(|| scope(&Cap::default())).1.clone()
+    }
+
+    fn set(&self, key: K, cap: &Cap) {
+        let mut map = self.0.lock();
+        set_rates(&map.entry(key).or_insert_with(|| scope(cap)).0, cap);
+    }
+
+    /// Apply `caps`; scopes missing from it become unlimited.
+    fn set_all(&self, caps: HashMap<K, Cap>) {
+        let mut map = self.0.lock();
+        for (key, (r, _)) in map.iter() {
+            set_rates(r, caps.get(key).unwrap_or(&Cap::default()));
+        }
+        for (key, cap) in caps {
+            map.entry(key).or_insert_with(|| scope(&cap));
+        }
+    }
+}
+
+impl<K> Default for Scopes<K> {
+    fn default() -> Self {
+        Self(Mutex::new(HashMap::new()))
+    }
+}
+
+pub struct Shaper {
+    limits: RwLock<Limits>,
+    global_rates: Rates,
+    global: Buckets,
+    per_connection: Rates,
+    backends: Scopes<SocketAddr>,
+    rules: Scopes<Arc<str>>,
+    apps: Scopes<u32>,
+}
+
+impl Default for Shaper {
+    fn default() -> Self {
+        let global_rates = rates(&Cap::default());
+        Self {
+            limits: RwLock::new(Limits::default()),
+            global: buckets(&global_rates),
+            global_rates,
+            per_connection: rates(&Cap::default()),
+            backends: Scopes::default(),
+            rules: Scopes::default(),
+            apps: Scopes::default(),
+        }
+    }
+}
+
+impl Shaper {
+    pub fn limits(&self) -> Limits {
+        self.limits.read().clone()
+    }
+
+    /// Replace all limits. Backend keys must be `ip:port`.
+    pub fn set_limits(&self, mut limits: Limits) -> Result<Limits> {
+        limits.global = limits.global.normalized();
+        limits.per_connection = limits.per_connection.normalized();
+        let mut backends = HashMap::new();
+        for (key, cap) in limits.backends.iter_mut() {
+            let addr: SocketAddr = key.trim().parse().map_err(|_| anyhow!("backend key must be ip:port, got {key:?}"))?;
+            *cap = cap.normalized();
+            backends.insert(addr, *cap);
+        }
+        for cap in limits.rules.values_mut() {
+            *cap = cap.normalized();
+        }
+        limits.backends.retain(|_, cap| !cap.is_unlimited());
+        limits.rules.retain(|_, cap| !cap.is_unlimited());
+
+        let rules: HashMap<Arc<str>, Cap> = limits.rules.iter().map(|(k, c)| (Arc::from(k.as_str()), *c)).collect();
+
+        set_rates(&self.global_rates, &limits.global);
+        set_rates(&self.per_connection, &limits.per_connection);
+        self.backends.set_all(backends);
+        self.rules.set_all(rules);
+        *self.limits.write() = limits.clone();
+        Ok(limits)
+    }
+
+    /// Set the global download cap only (legacy endpoints); returns the stored value.
+    pub fn set_download_mbit(&self, mbit: f64) -> f64 {
+        let mut limits = self.limits();
+        limits.global.download_mbit = mbit;
+        let limits = self.set_limits(limits).expect("stored backend keys are valid");
+        limits.global.download_mbit
+    }
+
+    pub fn set_app(&self, uid: u32, cap: Cap) {
+        self.apps.set(uid, &cap.normalized());
+    }
+
+    /// Buckets for a new connection or UDP flow.
+    pub fn conn(&self, backend: Option<SocketAddr>, rule: Option<&Arc<str>>, uid: Option<u32>) -> ConnShaper {
+        let mut out = vec![self.global.clone(), buckets(&self.per_connection)];
+        if let Some(addr) = backend {
+            out.push(self.backends.get(addr));
+        }
+        if let Some(name) = rule {
+            out.push(self.rules.get(name.clone()));
+        }
+        if let Some(uid) = uid {
+            out.push(self.apps.get(uid));
+        }
+        ConnShaper { buckets: out }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_burst_then_owes_time() {
+        let shaper = Shaper::default();
+        let rule: Arc<str> = Arc::from("video");
+        // 1 Mbit/s = 131072 B/s, 64 KiB burst.
+        let conn = shaper.conn(None, Some(&rule), None);
+        shaper
+            .set_limits(Limits { rules: BTreeMap::from([("video".to_string(), Cap { download_mbit: 1.0, ..Cap::default() })]), ..Limits::default() })
+            .unwrap();
+
+        assert_eq!(conn.charge(Dir::Up, 1 << 20), Duration::ZERO);
+        assert_eq!(conn.charge(Dir::Down, 64 * 1024), Duration::ZERO);
+        let owed = conn.charge(Dir::Down, 65536);
+        assert!(owed > Duration::from_millis(490) && owed <= Duration::from_millis(500), "{owed:?}");
+        assert!(!conn.admit(Dir::Down, 1200));
+
+        assert!(shaper.set_limits(Limits { backends: BTreeMap::from([("proxy:1080".to_string(), Cap::default())]), ..Limits::default() }).is_err());
+    }
+
+    #[test]
+    fn limits_document_rejects_unknown_keys() {
+        let parse = |v: serde_json::Value| serde_json::from_value::<Limits>(v);
+        let limits = parse(serde_json::json!({"download_mbit": 50, "rules": {"video": {"upload_mbit": 1}}})).unwrap();
+        assert_eq!(limits.global.download_mbit, 50.0);
+        assert_eq!(limits.rules["video"].upload_mbit, 1.0);
+        assert_eq!(parse(serde_json::json!({"mbit": 10})).unwrap().global.download_mbit, 10.0);
+        assert!(parse(serde_json::json!({"mbit": 10, "download_mbit": 5})).is_err());
+        assert!(parse(serde_json::json!({"download_mbps": 10})).is_err());
+        assert!(parse(serde_json::json!({"per_connection": {"download_mbps": 5}})).is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use crate::cli::{Args, BackendMode};
 use crate::socks5::TargetAddr;
+use crate::upstream::{BackendKind, Upstream};
 use anyhow::{anyhow, Context, Result};
 use parking_lot::Mutex;
 use rand::RngCore;
@@ -16,6 +16,8 @@ enum RuntimeFailureClass {
 #[derive(Clone, Debug, Serialize)]
 pub struct BackendStatus {
     pub addr: String,
+    #[serde(rename = "type")]
+    pub kind: BackendKind,
     pub state: BackendState,
     pub healthy: bool,
     pub last_check: u64,
@@ -46,9 +48,14 @@ pub struct BackendStatus {
     pub speed_shift_target: bool,
 }
 
+/// Backend index, address and effective SOCKS5 credentials.
+pub type SelectedBackend = (usize, SocketAddr, Option<(String, String)>);
+
 #[derive(Clone)]
 pub struct SocksBackends {
     addrs: Vec<SocketAddr>,
+    /// Backend type and configured host name (TLS server name), per address.
+    kinds: Vec<(BackendKind, String)>,
     auth_override: Vec<Option<(String, String)>>,
     status: Vec<BackendStatus>,
     ttl_hist: Vec<VecDeque<u32>>,
@@ -87,6 +94,7 @@ impl SocksBackends {
     fn empty(args: &Args) -> Self {
         Self {
             addrs: Vec::new(),
+            kinds: Vec::new(),
             auth_override: Vec::new(),
             status: Vec::new(),
             ttl_hist: Vec::new(),
@@ -129,12 +137,14 @@ impl SocksBackends {
             return Err(anyhow!("socks-host/socks-port parse produced empty list"));
         }
         let mut addrs = vec![];
+        let mut kinds = vec![];
         for h in hosts {
             for p in &ports {
                 let sa = crate::net_utils::resolve_prefer_ipv4(h.as_str(), *p)
                     .await
                     .with_context(|| format!("resolve socks backend {}:{}", h, p))?;
                 addrs.push(sa);
+                kinds.push((args.backend_type, h.clone()));
             }
         }
         if addrs.is_empty() {
@@ -150,6 +160,7 @@ impl SocksBackends {
 
         let status = addrs.iter().map(|sa| BackendStatus{
             addr: sa.to_string(),
+            kind: args.backend_type,
             state: BackendState::Red,
             healthy: false,
             last_check: now,
@@ -183,6 +194,7 @@ impl SocksBackends {
 
         Ok(Self{
             addrs,
+            kinds,
             auth_override,
             status,
             ttl_hist,
@@ -737,6 +749,25 @@ impl SocksBackends {
         Some((idx, self.addrs[idx], self.effective_auth_at(idx, global_ref)))
     }
 
+    /// Round-robin over the GREEN backends listening on `ports` (a traffic
+    /// rule backend group), preferring ones outside runtime cooldown.
+    pub fn select_group_with_auth(&mut self, ports: &[u16], global_auth: Option<&(String, String)>, udp: bool) -> Option<SelectedBackend> {
+        let now = now_ts();
+        let pick = |respect_cooldown| {
+            if udp {
+                self.udp_indices_by_ports(ports, now, respect_cooldown)
+            } else {
+                self.healthy_indices_by_ports(ports, now, respect_cooldown)
+            }
+        };
+        let mut candidates = pick(true);
+        if candidates.is_empty() {
+            candidates = pick(false);
+        }
+        let idx = Self::pick_from_bucket(&candidates, &mut self.rr)?;
+        Some((idx, self.addrs[idx], self.effective_auth_at(idx, global_auth)))
+    }
+
     pub fn update_udp(&mut self, idx: usize, udp_ping_ms: Option<f64>, err: Option<String>) -> bool {
         if idx >= self.status.len() { return false; }
         let prev_ping = self.status[idx].udp_ping_ms;
@@ -786,6 +817,12 @@ impl SocksBackends {
         }
     }
 
+    /// Dial spec for a backend; the caller adds the wrapper SOCKS5 if any.
+    pub fn upstream_at(&self, idx: usize, auth: Option<(String, String)>) -> Option<Upstream> {
+        let (kind, host) = self.kinds.get(idx)?.clone();
+        Some(Upstream { addr: *self.addrs.get(idx)?, kind, host, auth, wrapper: None, wrapper_auth: None })
+    }
+
     pub fn effective_auth_at(&self, idx: usize, global_auth: Option<&(String, String)>) -> Option<(String, String)> {
         if let Some(Some((u, p))) = self.auth_override.get(idx) {
             return Some((u.clone(), p.clone()));
@@ -1044,12 +1081,14 @@ impl SocksBackends {
         self.status.clone()
     }
 
-    pub fn add(&mut self, addr: SocketAddr, auth: Option<(String, String)>) {
+    pub fn add(&mut self, addr: SocketAddr, kind: BackendKind, host: &str, auth: Option<(String, String)>) {
         if self.addrs.iter().any(|a| *a == addr) { return; }
         self.addrs.push(addr);
+        self.kinds.push((kind, host.to_string()));
         self.auth_override.push(auth);
         self.status.push(BackendStatus{
             addr: addr.to_string(),
+            kind,
             state: BackendState::Red,
             healthy: false,
             last_check: now_ts(),
@@ -1090,6 +1129,7 @@ impl SocksBackends {
     pub fn remove(&mut self, addr: SocketAddr) {
         if let Some(pos) = self.addrs.iter().position(|a| *a == addr) {
             self.addrs.remove(pos);
+            if pos < self.kinds.len() { self.kinds.remove(pos); }
             if pos < self.auth_override.len() { self.auth_override.remove(pos); }
             if pos < self.status.len() { self.status.remove(pos); }
             if pos < self.ttl_hist.len() { self.ttl_hist.remove(pos); }
@@ -81,19 +81,16 @@ impl TrafficCadence {
 }
 
 async fn probe_backend_once(
-    backend: SocketAddr,
+    dial: &Upstream,
     timeout: Duration,
-    auth: Option<(String, String)>,
     internet_ttl: Option<u32>,
     probe_mode: ProbeMode,
-    wrapper: Option<SocketAddr>,
-    wrapper_auth: Option<(String, String)>,
 ) -> (Option<String>, Option<f64>, Option<f64>, Option<u32>) {
-    let socks_ping_ms = check_backend_rtt(backend, timeout, auth.clone(), wrapper, wrapper_auth.clone()).await;
+    let socks_ping_ms = check_backend_rtt(dial, timeout).await;
     let mut err = if socks_ping_ms.is_some() { None } else { Some("connect/greeting/auth failed".to_string()) };
-    let full_internet_probe = probe_mode == ProbeMode::Full && backend_requires_full_internet_probe(backend);
+    let full_internet_probe = probe_mode == ProbeMode::Full && backend_requires_full_internet_probe(dial.addr);
     let internet_ping_ms = if socks_ping_ms.is_some() && full_internet_probe {
-        let summary = check_internet_via_backend(backend, timeout, auth, wrapper, wrapper_auth).await;
+        let summary = check_internet_via_backend(dial, timeout).await;
         if summary.ok {
             summary.best_ping_ms
         } else {
@@ -214,23 +211,25 @@ async fn refresh_backend_index_once(
     auth: Option<(String, String)>,
     probe_mode: ProbeMode,
 ) -> bool {
-    let backend = {
+    let dial = {
         let b = state.backends.lock();
-        b.addr_at(idx)
+        b.upstream_at(idx, auth.clone())
     };
 
-    let Some(backend) = backend else { return false; };
+    let Some(dial) = dial.map(|d| d.wrapped(state.wrapped_socks_addr, state.args.wrapped_socks_auth())) else { return false; };
+    let backend = dial.addr;
 
-    let wrapper = state.wrapped_socks_addr;
-    let wrapper_auth = state.args.wrapped_socks_auth();
     let (err, socks_ping_ms, internet_ping_ms, ttl) =
-        probe_backend_once(backend, timeout, auth.clone(), internet_ttl, probe_mode, wrapper, wrapper_auth).await;
+        probe_backend_once(&dial, timeout, internet_ttl, probe_mode).await;
+    state.metrics.observe_probe(backend, socks_ping_ms, internet_ping_ms, err.is_some());
 
-    let udp_probe_supported = state.tproxy_enabled && state.wrapped_socks_addr.is_none();
+    let udp_probe_supported = state.tproxy_enabled && state.wrapped_socks_addr.is_none() && dial.kind.is_socks();
     let udp_ping_ms = if udp_probe_supported && socks_ping_ms.is_some() {
         crate::socks5::check_udp_associate(backend, auth.clone(), timeout.min(Duration::from_secs(3))).await
     } else { None };
-    let udp_err = if state.tproxy_enabled && state.wrapped_socks_addr.is_some() {
+    let udp_err = if state.tproxy_enabled && !dial.kind.is_socks() {
+        Some(format!("{} backends do not relay UDP; UDP will use direct fallback", dial.kind.as_str()))
+    } else if state.tproxy_enabled && state.wrapped_socks_addr.is_some() {
         Some("UDP ASSOCIATE through wrapped SOCKS is unsupported; UDP will use direct fallback".to_string())
     } else if udp_probe_supported && socks_ping_ms.is_some() && udp_ping_ms.is_none() {
         Some("SOCKS5 UDP data-plane probe failed or unsupported".to_string())
@@ -779,21 +778,9 @@ struct InternetProbeSummary {
     error_summary: String,
 }
 
-async fn probe_one_target(
-    backend: SocketAddr,
-    target: TargetAddr,
-    timeout: Duration,
-    auth: Option<(String, String)>,
-    wrapper: Option<SocketAddr>,
-    wrapper_auth: Option<(String, String)>,
-) -> Option<f64> {
+async fn probe_one_target(dial: &Upstream, target: TargetAddr, timeout: Duration) -> Option<f64> {
     let start = Instant::now();
-    let connect = if let Some(wrapper) = wrapper {
-        crate::socks5::connect_via_socks5_wrapped(wrapper, backend, target.clone(), wrapper_auth, auth, timeout).await
-    } else {
-        crate::socks5::connect_via_socks5(backend, target.clone(), auth, timeout).await
-    };
-    let mut stream = match connect {
+    let mut stream = match dial.connect(target.clone(), timeout).await {
         Ok(stream) => stream,
         Err(_) => return None,
     };
@@ -809,11 +796,14 @@ async fn probe_one_target(
     }
 }
 
-async fn verify_backend_data_plane(
-    stream: &mut TcpStream,
+async fn verify_backend_data_plane<S>(
+    stream: &mut S,
     target: &TargetAddr,
     timeout: Duration,
-) -> bool {
+) -> bool
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
     use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
     let probe_timeout = timeout.min(Duration::from_millis(1500)).max(Duration::from_millis(700));
@@ -912,19 +902,16 @@ fn build_tls_client_hello(sni: Option<&str>) -> Vec<u8> {
 }
 
 async fn first_successful_probe(
-    backend: SocketAddr,
+    dial: &Upstream,
     timeout: Duration,
-    auth: Option<(String, String)>,
     targets: Vec<TargetAddr>,
     stop_after: usize,
-    wrapper: Option<SocketAddr>,
-    wrapper_auth: Option<(String, String)>,
 ) -> (usize, Option<f64>) {
     let mut ok_count = 0usize;
     let mut best: Option<f64> = None;
 
     for target in targets {
-        if let Some(ping_ms) = probe_one_target(backend, target, timeout, auth.clone(), wrapper, wrapper_auth.clone()).await {
+        if let Some(ping_ms) = probe_one_target(dial, target, timeout).await {
             ok_count += 1;
             best = Some(match best {
                 Some(prev) => prev.min(ping_ms),
@@ -1162,31 +1149,15 @@ pub async fn proxy_enforce_loop(state: crate::AppState) {
 }
 
 
-/// Best-effort RTT to SOCKS backend: TCP connect + SOCKS greeting.
+/// Best-effort RTT to a backend: TCP connect + SOCKS greeting (or TLS
+/// handshake for https-connect).
 ///
 /// Returns RTT in ms.
-async fn check_backend_rtt(
-    backend: SocketAddr,
-    timeout: Duration,
-    auth: Option<(String, String)>,
-    wrapper: Option<SocketAddr>,
-    wrapper_auth: Option<(String, String)>,
-) -> Option<f64> {
+async fn check_backend_rtt(dial: &Upstream, timeout: Duration) -> Option<f64> {
     let start = Instant::now();
-    let res = if let Some(wrapper) = wrapper {
-        crate::socks5::connect_to_socks5_server_wrapped(wrapper, backend, wrapper_auth, auth, timeout).await
-    } else {
-        crate::socks5::connect_to_socks5_server(backend, auth, timeout).await
-    };
-    res.ok().map(|_| start.elapsed().as_secs_f64() * 1000.0)
+    dial.handshake(timeout).await.ok().map(|_| start.elapsed().as_secs_f64() * 1000.0)
 }
-async fn check_internet_via_backend(
-    backend: SocketAddr,
-    timeout: Duration,
-    auth: Option<(String, String)>,
-    wrapper: Option<SocketAddr>,
-    wrapper_auth: Option<(String, String)>,
-) -> InternetProbeSummary {
+async fn check_internet_via_backend(dial: &Upstream, timeout: Duration) -> InternetProbeSummary {
     // Keep detailed validation energy-efficient: one strict data-plane probe.
     // SOCKS CONNECT alone is not enough; the remote side must answer our TLS
     // ClientHello.  Stability is handled by the Green hysteresis in update(), not
@@ -1199,15 +1170,7 @@ async fn check_internet_via_backend(
         TargetAddr::Ip(SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(1, 1, 1, 1)), 443)),
     ];
 
-    let (ok_count, best_ping_ms) = first_successful_probe(
-        backend,
-        per_probe_timeout,
-        auth,
-        targets,
-        1,
-        wrapper,
-        wrapper_auth,
-    ).await;
+    let (ok_count, best_ping_ms) = first_successful_probe(dial, per_probe_timeout, targets, 1).await;
 
     let ok = ok_count >= 1;
     let error_summary = if ok {
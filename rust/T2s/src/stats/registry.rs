@@ -15,6 +15,10 @@ pub struct ConnInfo {
     pub mode: Option<String>,
     /// Selected SOCKS backend (when in SOCKS mode).
     pub backend: Option<String>,
+    /// Android UID of the local app that opened the connection, when known.
+    pub uid: Option<u32>,
+    /// Package name(s) for `uid`.
+    pub app: Option<String>,
     pub started_ts: u64,
     pub last_progress_ts: u64,
     pub bytes_up: u64,
@@ -160,6 +164,8 @@ impl ConnRegistry {
             target: None,
             mode: Some("pending".to_string()),
             backend: None,
+            uid: None,
+            app: None,
             started_ts: now,
             last_progress_ts: now,
             bytes_up: 0,
@@ -332,6 +338,13 @@ impl ConnRegistry {
         }
     }
 
+    pub fn set_app(&self, cid: u64, uid: u32, app: Option<String>) {
+        if let Some((info, _)) = self.inner.lock().get_mut(&cid) {
+            info.uid = Some(uid);
+            info.app = app;
+        }
+    }
+
     pub fn add_bytes_up(&self, cid: u64, n: u64) {
         if let Some((info, _)) = self.inner.lock().get_mut(&cid) {
             info.bytes_up += n;
@@ -88,8 +88,6 @@ impl Stats {
 }
 
 pub struct RuntimeConfig {
-    /// 0 = unlimited
-    pub download_limit_bps: AtomicU64,
     /// Connected Web UI clients (SSE/WS).
     pub ui_clients: std::sync::atomic::AtomicU64,
 
@@ -165,7 +163,6 @@ pub struct RuntimeConfig {
 impl Default for RuntimeConfig {
     fn default() -> Self {
         Self {
-            download_limit_bps: AtomicU64::new(0),
             ui_clients: std::sync::atomic::AtomicU64::new(0),
             ui_wakeup: tokio::sync::Notify::new(),
             backend_wakeup: tokio::sync::Notify::new(),
@@ -1,4 +1,4 @@
-use crate::{cli::PriorityZeroMode, rules, socks5, stats, AppState};
+use crate::{cli::PriorityZeroMode, rules, shaper, socks5, stats, AppState};
 use anyhow::{Context, Result};
 use once_cell::sync::Lazy;
 use parking_lot::Mutex;
@@ -263,6 +263,7 @@ enum PreparedUdpSession {
         backend: SocketAddr,
         control: tokio::net::TcpStream,
         udp: tokio::net::UdpSocket,
+        shape: shaper::ConnShaper,
     },
     Direct {
         handle: UdpSessionHandle,
@@ -270,6 +271,7 @@ enum PreparedUdpSession {
         key: UdpSessionKey,
         id: u64,
         outbound: tokio::net::UdpSocket,
+        shape: shaper::ConnShaper,
     },
 }
 
@@ -291,9 +293,12 @@ impl PreparedUdpSession {
                 backend,
                 control,
                 udp,
+                shape,
             } => {
                 let queue = handle.queue.clone();
                 tokio::spawn(async move {
+                    // The association lives as long as its TCP control stream.
+                    let _control = control;
                     let task_guard =
                         UdpSessionTaskGuard::new(sessions, key, id, queue.clone());
                     let result = socks_session_loop(
@@ -301,9 +306,9 @@ impl PreparedUdpSession {
                         spoof_cache,
                         key,
                         backend,
-                        control,
                         udp,
                         queue,
+                        shape,
                     )
                     .await;
                     // Close the queue and remove the map entry before health/log
@@ -332,13 +337,14 @@ impl PreparedUdpSession {
                 key,
                 id,
                 outbound,
+                shape,
             } => {
                 let queue = handle.queue.clone();
                 tokio::spawn(async move {
                     let task_guard =
                         UdpSessionTaskGuard::new(sessions, key, id, queue.clone());
                     let result =
-                        direct_session_loop(state, spoof_cache, key, outbound, queue).await;
+                        direct_session_loop(state, spoof_cache, key, outbound, queue, shape).await;
                     drop(task_guard);
                     if let Err(e) = result {
                         tracing::debug!(
@@ -909,13 +915,29 @@ async fn create_udp_session(
     key: UdpSessionKey,
     id: u64,
 ) -> Result<Option<PreparedUdpSession>> {
-    let target = stats::Target::SockAddr(key.original_dst);
+    let fake_domain = state.dns.fake_domain(key.original_dst.ip());
+    if fake_domain.is_none() && state.dns.is_fake(key.original_dst.ip()) {
+        state.stats.inc_policy_drop();
+        return Ok(None);
+    }
+    let target = match &fake_domain {
+        Some(name) => stats::Target::HostPort(name.clone(), key.original_dst.port()),
+        None => stats::Target::SockAddr(key.original_dst),
+    };
     let (target_host, target_port) = target.to_host_port_string();
     let proto = rules::classify_protocol(target_port);
     let mut udp_socks_available = state.backends.lock().udp_available();
-    let action = state
-        .rules
-        .decide(&proto, &target_host, target_port, udp_socks_available, true);
+    let decision = state.rules.load().decide(
+        &proto,
+        &target_host,
+        fake_domain.is_none().then(|| key.original_dst.ip()),
+        target_port,
+        udp_socks_available,
+        true,
+    );
+    let action = decision.as_ref().map(|d| d.action);
+    let rule_name = decision.as_ref().and_then(|d| d.name.clone());
+    let group = decision.and_then(|d| d.group);
 
     match action {
         Some(rules::Action::Drop) | Some(rules::Action::Reset) => {
@@ -930,14 +952,14 @@ async fn create_udp_session(
             udp_socks_available = true;
         }
         Some(rules::Action::Direct) => {
-            return prepare_direct_session(state, key, id).await.map(Some);
+            return prepare_direct_session(state, key, id, rule_name).await.map(Some);
         }
         Some(rules::Action::Socks) | None => {}
     }
 
     let priority_zero_mode = state.args.priority_zero_mode();
     if priority_zero_mode == PriorityZeroMode::DirectOnly {
-        return prepare_direct_session(state, key, id).await.map(Some);
+        return prepare_direct_session(state, key, id, rule_name).await.map(Some);
     }
     if priority_zero_mode == PriorityZeroMode::DirectFirst {
         // Port 0 means DIRECT is preferred, not that UDP must be pinned to
@@ -945,7 +967,7 @@ async fn create_udp_session(
         // path is healthy and UDP has not failed recently. Otherwise continue
         // into the normal GREEN SOCKS selection below.
         if state.runtime.udp_direct_allowed() && state.runtime.direct_path_available() {
-            return prepare_direct_session(state, key, id).await.map(Some);
+            return prepare_direct_session(state, key, id, rule_name).await.map(Some);
         }
     }
 
@@ -960,7 +982,10 @@ async fn create_udp_session(
         for _ in 0..backend_count {
             let selected = {
                 let mut b = state.backends.lock();
-                b.select_udp_with_auth(global_auth(&state), true)
+                match group.as_deref() {
+                    Some(ports) => b.select_group_with_auth(ports, global_auth(&state).as_ref(), true),
+                    None => b.select_udp_with_auth(global_auth(&state), true),
+                }
             };
             let Some((idx, backend, auth)) = selected else {
                 break;
@@ -972,6 +997,7 @@ async fn create_udp_session(
                 idx,
                 backend,
                 auth,
+                rule_name.clone(),
             )
             .await
             {
@@ -1011,7 +1037,7 @@ async fn create_udp_session(
     if priority_zero_mode == PriorityZeroMode::DirectFirst && !state.runtime.udp_direct_allowed() {
         return Ok(None);
     }
-    prepare_direct_session(state, key, id).await.map(Some)
+    prepare_direct_session(state, key, id, rule_name).await.map(Some)
 }
 
 async fn wait_for_udp_backend(state: &AppState, timeout: Duration) -> bool {
@@ -1063,6 +1089,7 @@ async fn prepare_socks_session(
     idx: usize,
     backend: SocketAddr,
     auth: Option<(String, String)>,
+    rule: Option<Arc<str>>,
 ) -> Result<PreparedUdpSession> {
     if state.wrapped_socks_addr.is_some() {
         return Err(anyhow::anyhow!(
@@ -1092,6 +1119,7 @@ async fn prepare_socks_session(
 
     Ok(PreparedUdpSession::Socks {
         handle: new_session_handle(id),
+        shape: state.shaper.conn(Some(backend), rule.as_ref(), None),
         state,
         key,
         id,
@@ -1107,12 +1135,19 @@ async fn socks_session_loop(
     spoof_cache: Arc<SpoofSocketCache>,
     key: UdpSessionKey,
     backend: SocketAddr,
-    _control: tokio::net::TcpStream,
     udp: tokio::net::UdpSocket,
     queue: Arc<UdpSessionQueue>,
+    shape: shaper::ConnShaper,
 ) -> Result<()> {
     let mut buf = vec![0u8; UDP_RECV_BUF_SIZE];
     let mut encoded = Vec::with_capacity(UDP_SMALL_PAYLOAD_CAPACITY + 22);
+    // Fake-IP flows are relayed by name; replies must still come from the
+    // fake address the client sent to.
+    let fake_domain = state.dns.fake_domain(key.original_dst.ip());
+    let relay_target = match &fake_domain {
+        Some(name) => socks5::TargetAddr::Domain(name.clone(), key.original_dst.port()),
+        None => socks5::TargetAddr::Ip(key.original_dst),
+    };
     let mut idle_sleep = Box::pin(tokio::time::sleep(UDP_SESSION_IDLE));
     let mut response_deadline: Option<tokio::time::Instant> = None;
     let mut received_any = false;
@@ -1131,9 +1166,12 @@ async fn socks_session_loop(
         tokio::select! {
             maybe_data = queue.recv() => {
                 let Some(data) = maybe_data else { break; };
+                if !shape.admit(shaper::Dir::Up, data.len()) {
+                    continue;
+                }
                 socks5::encode_udp_packet_into(
                     &mut encoded,
-                    socks5::TargetAddr::Ip(key.original_dst),
+                    relay_target.clone(),
                     data.as_slice(),
                 )?;
                 udp.send(&encoded).await.context("send socks udp packet")?;
@@ -1155,12 +1193,15 @@ async fn socks_session_loop(
                 let n = res.context("recv socks udp response")?;
                 let (src, payload) = socks5::decode_udp_packet(&buf[..n])?;
                 let source = match src {
+                    _ if fake_domain.is_some() => key.original_dst,
                     socks5::TargetAddr::Ip(sa) => sa,
                     socks5::TargetAddr::Domain(_, port) => SocketAddr::new(key.original_dst.ip(), port),
                 };
-                send_spoofed_udp(&spoof_cache, source, key.peer, payload).await?;
-                state.stats.add_down(payload.len() as u64);
-                state.backends.lock().add_bytes(backend, payload.len() as u64);
+                if shape.admit(shaper::Dir::Down, payload.len()) {
+                    send_spoofed_udp(&spoof_cache, source, key.peer, payload).await?;
+                    state.stats.add_down(payload.len() as u64);
+                    state.backends.lock().add_bytes(backend, payload.len() as u64);
+                }
                 received_any = true;
                 response_deadline = None;
                 touch_session(&mut idle_sleep);
@@ -1182,6 +1223,7 @@ async fn prepare_direct_session(
     state: AppState,
     key: UdpSessionKey,
     id: u64,
+    rule: Option<Arc<str>>,
 ) -> Result<PreparedUdpSession> {
     let outbound = tokio::net::UdpSocket::bind(if key.original_dst.is_ipv4() {
         "0.0.0.0:0"
@@ -1192,6 +1234,7 @@ async fn prepare_direct_session(
     .context("bind direct udp outbound")?;
     Ok(PreparedUdpSession::Direct {
         handle: new_session_handle(id),
+        shape: state.shaper.conn(None, rule.as_ref(), None),
         state,
         key,
         id,
@@ -1205,11 +1248,22 @@ async fn direct_session_loop(
     key: UdpSessionKey,
     outbound: tokio::net::UdpSocket,
     queue: Arc<UdpSessionQueue>,
+    shape: shaper::ConnShaper,
 ) -> Result<()> {
     let mut buf = vec![0u8; UDP_RECV_BUF_SIZE];
     let mut idle_sleep = Box::pin(tokio::time::sleep(UDP_SESSION_IDLE));
     let mut response_deadline: Option<tokio::time::Instant> = None;
     let mut received_any = false;
+    // A fake-IP destination is resolved here; replies are spoofed from the
+    // fake address.
+    let remote = match state.dns.fake_domain(key.original_dst.ip()) {
+        Some(name) => tokio::net::lookup_host((name.as_str(), key.original_dst.port()))
+            .await
+            .with_context(|| format!("resolve {}", name))?
+            .find(|sa| sa.is_ipv4() == key.original_dst.is_ipv4())
+            .ok_or_else(|| anyhow::anyhow!("{} has no address of the fake IP family", name))?,
+        None => key.original_dst,
+    };
 
     loop {
         let deadline_snapshot = response_deadline;
@@ -1225,7 +1279,10 @@ async fn direct_session_loop(
         tokio::select! {
             maybe_data = queue.recv() => {
                 let Some(data) = maybe_data else { break; };
-                if let Err(e) = outbound.send_to(data.as_slice(), key.original_dst).await {
+                if !shape.admit(shaper::Dir::Up, data.len()) {
+                    continue;
+                }
+                if let Err(e) = outbound.send_to(data.as_slice(), remote).await {
                     state.runtime.note_udp_direct_failure(20);
                     return Err(e).context("send direct udp");
                 }
@@ -1246,9 +1303,11 @@ async fn direct_session_loop(
                         return Err(e).context("recv direct udp response");
                     }
                 };
-                let source = if from.port() == key.original_dst.port() { from } else { key.original_dst };
-                send_spoofed_udp(&spoof_cache, source, key.peer, &buf[..n]).await?;
-                state.stats.add_down(n as u64);
+                let source = if remote == key.original_dst && from.port() == key.original_dst.port() { from } else { key.original_dst };
+                if shape.admit(shaper::Dir::Down, n) {
+                    send_spoofed_udp(&spoof_cache, source, key.peer, &buf[..n]).await?;
+                    state.stats.add_down(n as u64);
+                }
                 received_any = true;
                 state.runtime.clear_udp_direct_cooldown();
                 response_deadline = None;
@@ -0,0 +1,296 @@
+//! Typed backends: how t2s reaches a target through one configured proxy.
+//!
+//! SOCKS5 backends keep using `socks5.rs`. HTTP proxies are driven with a
+//! `CONNECT host:port` request, optionally inside TLS to the proxy itself
+//! (`https-connect`). Every kind can sit behind the wrapper SOCKS5.
+
+use crate::socks5::{self, TargetAddr};
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{client::TlsStream, rustls, TlsConnector};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendKind {
+    /// SOCKS5; domain targets are resolved by t2s and sent as IPs.
+    Socks5,
+    /// SOCKS5 with remote resolution; domain targets are sent as names (the historical behavior).
+    #[default]
+    Socks5h,
+    /// Plain HTTP proxy, tunnels with CONNECT.
+    HttpConnect,
+    /// HTTP proxy reached over TLS, tunnels with CONNECT.
+    HttpsConnect,
+}
+
+impl BackendKind {
+    pub fn is_socks(self) -> bool {
+        matches!(self, Self::Socks5 | Self::Socks5h)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Socks5 => "socks5",
+            Self::Socks5h => "socks5h",
+            Self::HttpConnect => "http-connect",
+            Self::HttpsConnect => "https-connect",
+        }
+    }
+}
+
+/// Everything needed to dial one backend.
+#[derive(Clone, Debug)]
+pub struct Upstream {
+    pub addr: SocketAddr,
+    pub kind: BackendKind,
+    /// Configured host name, used as TLS server name for `https-connect`.
+    pub host: String,
+    pub auth: Option<(String, String)>,
+    pub wrapper: Option<SocketAddr>,
+    pub wrapper_auth: Option<(String, String)>,
+}
+
+/// Tunnel to the target: plain TCP, or TLS for `https-connect` backends.
+pub enum ProxyStream {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for ProxyStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+            Self::Tls(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ProxyStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+            Self::Tls(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_flush(cx),
+            Self::Tls(s) => Pin::new(s.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+            Self::Tls(s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+static TLS: Lazy<TlsConnector> = Lazy::new(|| {
+    let roots = rustls::RootCertStore::from_iter(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .expect("ring supports the default TLS versions")
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+});
+
+impl Upstream {
+    pub fn wrapped(mut self, wrapper: Option<SocketAddr>, wrapper_auth: Option<(String, String)>) -> Self {
+        self.wrapper = wrapper;
+        self.wrapper_auth = wrapper_auth;
+        self
+    }
+
+    /// TCP to the proxy itself, through the wrapper when configured.
+    async fn dial(&self, timeout: Duration) -> Result<TcpStream> {
+        match self.wrapper {
+            Some(wrapper) => socks5::connect_via_socks5(wrapper, TargetAddr::Ip(self.addr), self.wrapper_auth.clone(), timeout)
+                .await
+                .with_context(|| format!("wrapped proxy: connect wrapper {} -> {}", wrapper, self.addr)),
+            None => tokio::time::timeout(timeout, TcpStream::connect(self.addr))
+                .await
+                .context("proxy tcp connect timeout")?
+                .context("proxy tcp connect failed"),
+        }
+    }
+
+    async fn tls(&self, tcp: TcpStream, timeout: Duration) -> Result<TlsStream<TcpStream>> {
+        let name = rustls::pki_types::ServerName::try_from(self.host.clone())
+            .map_err(|_| anyhow!("proxy tls handshake failed: invalid server name {:?}", self.host))?;
+        tokio::time::timeout(timeout, TLS.connect(name, tcp))
+            .await
+            .context("proxy tls handshake timeout")?
+            .context("proxy tls handshake failed")
+    }
+
+    /// Open a tunnel to `target` through this backend.
+    pub async fn connect(&self, target: TargetAddr, timeout: Duration) -> Result<ProxyStream> {
+        match self.kind {
+            BackendKind::Socks5 | BackendKind::Socks5h => {
+                let target = match (self.kind, target) {
+                    (BackendKind::Socks5, TargetAddr::Domain(host, port)) => TargetAddr::Ip(
+                        crate::net_utils::resolve_prefer_ipv4(&host, port).await.context("resolve target for socks5 backend")?,
+                    ),
+                    (_, target) => target,
+                };
+                let stream = match self.wrapper {
+                    Some(wrapper) => {
+                        socks5::connect_via_socks5_wrapped(wrapper, self.addr, target, self.wrapper_auth.clone(), self.auth.clone(), timeout)
+                            .await?
+                    }
+                    None => socks5::connect_via_socks5(self.addr, target, self.auth.clone(), timeout).await?,
+                };
+                Ok(ProxyStream::Tcp(stream))
+            }
+            BackendKind::HttpConnect => {
+                let mut tcp = self.dial(timeout).await?;
+                http_connect(&mut tcp, &target, self.auth.as_ref(), timeout).await?;
+                Ok(ProxyStream::Tcp(tcp))
+            }
+            BackendKind::HttpsConnect => {
+                let tcp = self.dial(timeout).await?;
+                let mut tls = self.tls(tcp, timeout).await?;
+                http_connect(&mut tls, &target, self.auth.as_ref(), timeout).await?;
+                Ok(ProxyStream::Tls(Box::new(tls)))
+            }
+        }
+    }
+
+    /// Cheap liveness check: SOCKS5 greeting/auth, or TCP (+TLS) to an HTTP
+    /// proxy. HTTP proxies only check credentials on CONNECT, so auth problems
+    /// show up in the Internet probe.
+    pub async fn handshake(&self, timeout: Duration) -> Result<()> {
+        match self.kind {
+            BackendKind::Socks5 | BackendKind::Socks5h => {
+                match self.wrapper {
+                    Some(wrapper) => {
+                        socks5::connect_to_socks5_server_wrapped(wrapper, self.addr, self.wrapper_auth.clone(), self.auth.clone(), timeout)
+                            .await?
+                    }
+                    None => socks5::connect_to_socks5_server(self.addr, self.auth.clone(), timeout).await?,
+                };
+            }
+            BackendKind::HttpConnect => {
+                self.dial(timeout).await?;
+            }
+            BackendKind::HttpsConnect => {
+                let tcp = self.dial(timeout).await?;
+                self.tls(tcp, timeout).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn base64(input: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(input.len().div_ceil(3) * 4);
+    for chunk in input.chunks(3) {
+        let n = (chunk[0] as u32) << 16 | (*chunk.get(1).unwrap_or(&0) as u32) << 8 | *chunk.get(2).unwrap_or(&0) as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn connect_request(target: &TargetAddr, auth: Option<&(String, String)>) -> String {
+    let authority = match target {
+        TargetAddr::Ip(sa) => sa.to_string(),
+        TargetAddr::Domain(host, port) if host.contains(':') => format!("[{}]:{}", host, port),
+        TargetAddr::Domain(host, port) => format!("{}:{}", host, port),
+    };
+    let mut req = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some((user, pass)) = auth {
+        req.push_str(&format!("Proxy-Authorization: Basic {}\r\n", base64(format!("{user}:{pass}").as_bytes())));
+    }
+    req.push_str("\r\n");
+    req
+}
+
+/// Status code of a CONNECT reply head.
+fn parse_connect_status(head: &[u8]) -> Result<u16> {
+    let line = head.split(|b| *b == b'\n').next().unwrap_or_default();
+    let line = std::str::from_utf8(line).context("invalid HTTP proxy reply")?.trim_end();
+    let mut parts = line.splitn(3, ' ');
+    if !parts.next().unwrap_or_default().starts_with("HTTP/1.") {
+        bail!("invalid HTTP proxy reply {:?}", line);
+    }
+    parts
+        .next()
+        .and_then(|c| c.parse().ok())
+        .ok_or_else(|| anyhow!("invalid HTTP proxy reply {:?}", line))
+}
+
+async fn http_connect<S>(stream: &mut S, target: &TargetAddr, auth: Option<&(String, String)>, timeout: Duration) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let req = connect_request(target, auth);
+    tokio::time::timeout(timeout, stream.write_all(req.as_bytes()))
+        .await
+        .context("http connect request timeout")?
+        .context("http connect request write failed")?;
+
+    // Read byte by byte up to the blank line so no tunnel data is consumed.
+    let mut head = Vec::with_capacity(256);
+    let read_head = async {
+        let mut b = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() >= 8192 {
+                bail!("HTTP proxy reply head too large");
+            }
+            stream.read_exact(&mut b).await.context("http connect reply read failed")?;
+            head.push(b[0]);
+        }
+        Ok(())
+    };
+    tokio::time::timeout(timeout, read_head).await.context("http connect reply timeout")??;
+
+    match parse_connect_status(&head)? {
+        200..=299 => Ok(()),
+        407 => Err(anyhow!("proxy auth failed: HTTP 407")),
+        code => Err(anyhow!("HTTP CONNECT failed, status {}", code)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_connect_request_and_parses_reply() {
+        let auth = ("user".to_string(), "pass".to_string());
+        let req = connect_request(&TargetAddr::Domain("example.com".into(), 443), Some(&auth));
+        assert_eq!(
+            req,
+            "CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\nProxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n"
+        );
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(parse_connect_status(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap(), 200);
+        assert_eq!(parse_connect_status(b"HTTP/1.0 407 Proxy Authentication Required\r\n\r\n").unwrap(), 407);
+        assert!(parse_connect_status(b"SSH-2.0-OpenSSH\r\n").is_err());
+    }
+}
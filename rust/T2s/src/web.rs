@@ -24,12 +24,22 @@ struct DownloadLimitReq {
 struct BackendReq {
     host: String,
     port: u16,
+    /// socks5, socks5h (default), http-connect or https-connect.
+    #[serde(default, rename = "type")]
+    kind: crate::upstream::BackendKind,
     #[serde(default)]
     username: Option<String>,
     #[serde(default)]
     password: Option<String>,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+struct AppLimitReq {
+    uid: u32,
+    #[serde(flatten)]
+    limit: crate::apps::AppLimit,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 struct KillReq {
     cid: String,
@@ -91,6 +101,8 @@ struct ConnView {
     bytes_up: u64,
     bytes_down: u64,
     server: String,
+    uid: Option<u32>,
+    app: Option<String>,
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -128,6 +140,7 @@ pub async fn serve(state: AppState) -> Result<()> {
         .route("/", get(index))
         .route("/ws", get(ws_upgrade))
         .route("/api/version", get(api_version))
+        .route("/metrics", get(metrics))
         .route("/api/state", get(api_state))
         .route("/api/download_limit", post(api_download_limit))
         .route("/api/backends/add", post(api_backend_add))
@@ -141,9 +154,13 @@ pub async fn serve(state: AppState) -> Result<()> {
         .route("/api/v1/limits", get(api_v1_limits).put(api_v1_limits_put))
         .route("/api/v1/download-limit", post(api_v1_download_limit))
         .route("/api/v1/connections/kill", post(api_v1_kill_connection))
+        .route("/api/v1/apps", get(api_v1_apps))
+        .route("/api/v1/apps/limits", post(api_v1_apps_limits))
         .route("/api/v1/backends/add", post(api_v1_backend_add))
         .route("/api/v1/backends/remove", post(api_v1_backend_remove))
         .route("/api/v1/backends/recheck", post(api_v1_backends_recheck))
+        .route("/api/v1/rules", get(api_v1_rules).put(api_v1_rules_put))
+        .route("/api/v1/rules/reload", post(api_v1_rules_reload))
         .with_state(state.clone());
 
     let addr: SocketAddr = format!("{}:{}", state.args.web_addr, state.args.web_port)
@@ -175,6 +192,16 @@ async fn api_version() -> impl IntoResponse {
     (StatusCode::OK, Json(serde_json::json!({"build": BUILD_TAG})))
 }
 
+async fn metrics(headers: HeaderMap, State(state): State<AppState>) -> Response {
+    if !state.api.is_authorized(&headers) { return empty_404(); }
+    (
+        StatusCode::OK,
+        [(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        crate::metrics::render(&state),
+    )
+        .into_response()
+}
+
 async fn api_state(headers: HeaderMap, State(state): State<AppState>) -> Response {
     if !authorize_legacy_http(&state, &headers) { return legacy_unauthorized(); }
     if state.runtime.try_begin_forced_refresh(30_000) {
@@ -218,10 +245,7 @@ fn json_response(status: StatusCode, body: serde_json::Value) -> Response {
 }
 
 fn set_download_limit(state: &AppState, req: &DownloadLimitReq) -> f64 {
-    let mbit = if req.mbit.is_finite() && req.mbit > 0.0 { req.mbit } else { 0.0 };
-    let bps = if mbit > 0.0 { (mbit * 1024.0 * 1024.0 / 8.0) as u64 } else { 0u64 };
-    state.runtime.download_limit_bps.store(bps, std::sync::atomic::Ordering::Relaxed);
-    mbit
+    state.shaper.set_download_mbit(req.mbit)
 }
 
 async fn backend_add_impl(state: &AppState, req: &BackendReq) -> Response {
@@ -239,7 +263,7 @@ async fn backend_add_impl(state: &AppState, req: &BackendReq) -> Response {
             if b.snapshot().iter().any(|s| s.addr == sa.to_string()) {
                 return json_response(StatusCode::OK, serde_json::json!({"result":"ok","note":"already exists"}));
             }
-            b.add(sa, auth);
+            b.add(sa, req.kind, req.host.trim(), auth);
             json_response(StatusCode::OK, serde_json::json!({"result":"ok"}))
         }
         Err(e) => json_response(StatusCode::BAD_REQUEST, serde_json::json!({"error": e})),
@@ -336,7 +360,7 @@ async fn api_v1_poll(headers: HeaderMap, State(state): State<AppState>) -> Respo
             "ports": st.ports,
             "connections": st.conns,
             "backends": st.backends,
-            "limits": { "download_mbit": st.download_limit_mbit },
+            "limits": state.shaper.limits(),
             "runtime": {
                 "max_conns": state.args.max_conns,
                 "backend_mode": format!("{:?}", state.args.backend_mode).to_ascii_lowercase(),
@@ -364,7 +388,7 @@ async fn api_v1_state(headers: HeaderMap, State(state): State<AppState>) -> Resp
         "ports": st.ports,
         "connections": st.conns,
         "backends": st.backends,
-        "limits": { "download_mbit": st.download_limit_mbit },
+        "limits": state.shaper.limits(),
         "runtime": {
             "max_conns": state.args.max_conns,
             "backend_mode": format!("{:?}", state.args.backend_mode).to_ascii_lowercase(),
@@ -390,6 +414,28 @@ async fn api_v1_connections(headers: HeaderMap, State(state): State<AppState>) -
     }))
 }
 
+async fn api_v1_apps(headers: HeaderMap, State(state): State<AppState>) -> Response {
+    if !state.api.is_authorized(&headers) { return empty_404(); }
+    let apps = state.apps.clone();
+    let apps = tokio::task::spawn_blocking(move || apps.snapshot()).await.unwrap_or_default();
+    json_response(StatusCode::OK, serde_json::json!({
+        "schema_version": 1,
+        "api_name": "t2s",
+        "api_version": 1,
+        "ok": true,
+        "instance": state.api.refreshed_instance(),
+        "apps": apps,
+    }))
+}
+
+async fn api_v1_apps_limits(headers: HeaderMap, State(state): State<AppState>, Json(req): Json<AppLimitReq>) -> Response {
+    if !state.api.is_authorized(&headers) { return empty_404(); }
+    let limit = state.apps.set_limit(req.uid, req.limit);
+    let cap = crate::shaper::Cap { download_mbit: limit.download_mbit, upload_mbit: limit.upload_mbit, burst_kb: 0 };
+    state.shaper.set_app(req.uid, cap);
+    json_response(StatusCode::OK, serde_json::json!({"schema_version":1,"api_name":"t2s","api_version":1,"ok":true,"uid":req.uid,"limits":limit}))
+}
+
 async fn api_v1_backends(headers: HeaderMap, State(state): State<AppState>) -> Response {
     if !state.api.is_authorized(&headers) { return empty_404(); }
     let backends = state.backends.lock().snapshot();
@@ -405,22 +451,22 @@ async fn api_v1_backends(headers: HeaderMap, State(state): State<AppState>) -> R
 
 async fn api_v1_limits(headers: HeaderMap, State(state): State<AppState>) -> Response {
     if !state.api.is_authorized(&headers) { return empty_404(); }
-    let bps = state.runtime.download_limit_bps.load(std::sync::atomic::Ordering::Relaxed);
-    let download_mbit = if bps > 0 { (bps as f64) * 8.0 / (1024.0 * 1024.0) } else { 0.0 };
     json_response(StatusCode::OK, serde_json::json!({
         "schema_version": 1,
         "api_name": "t2s",
         "api_version": 1,
         "ok": true,
         "instance": state.api.refreshed_instance(),
-        "limits": { "download_mbit": download_mbit },
+        "limits": state.shaper.limits(),
     }))
 }
 
-async fn api_v1_limits_put(headers: HeaderMap, State(state): State<AppState>, Json(req): Json<DownloadLimitReq>) -> Response {
+async fn api_v1_limits_put(headers: HeaderMap, State(state): State<AppState>, Json(req): Json<crate::shaper::Limits>) -> Response {
     if !state.api.is_authorized(&headers) { return empty_404(); }
-    let mbit = set_download_limit(&state, &req);
-    json_response(StatusCode::OK, serde_json::json!({"schema_version":1,"api_name":"t2s","api_version":1,"ok":true,"limits":{"download_mbit":mbit}}))
+    match state.shaper.set_limits(req) {
+        Ok(limits) => json_response(StatusCode::OK, serde_json::json!({"schema_version":1,"api_name":"t2s","api_version":1,"ok":true,"limits":limits})),
+        Err(e) => json_response(StatusCode::BAD_REQUEST, serde_json::json!({"ok": false, "error": format!("{:#}", e)})),
+    }
 }
 
 async fn api_v1_download_limit(headers: HeaderMap, State(state): State<AppState>, Json(req): Json<DownloadLimitReq>) -> Response {
@@ -457,6 +503,46 @@ async fn api_v1_backends_recheck(headers: HeaderMap, State(state): State<AppStat
     }))
 }
 
+fn rules_response(state: &AppState, res: Result<std::sync::Arc<crate::rules::Rules>>) -> Response {
+    match res {
+        Ok(rules) => json_response(StatusCode::OK, serde_json::json!({
+            "schema_version": 1,
+            "api_name": "t2s",
+            "api_version": 1,
+            "ok": true,
+            "instance": state.api.refreshed_instance(),
+            "summary": rules.summary(),
+            "rules": state.rules.source(),
+        })),
+        Err(e) => json_response(StatusCode::BAD_REQUEST, serde_json::json!({"ok": false, "error": format!("{:#}", e)})),
+    }
+}
+
+async fn api_v1_rules(headers: HeaderMap, State(state): State<AppState>) -> Response {
+    if !state.api.is_authorized(&headers) { return empty_404(); }
+    let rules = state.rules.load();
+    rules_response(&state, Ok(rules))
+}
+
+/// Replace the rule set. List files are read here, off the runtime threads.
+async fn api_v1_rules_put(headers: HeaderMap, State(state): State<AppState>, Json(doc): Json<serde_json::Value>) -> Response {
+    if !state.api.is_authorized(&headers) { return empty_404(); }
+    let store = state.rules.clone();
+    let res = tokio::task::spawn_blocking(move || store.replace(doc))
+        .await
+        .unwrap_or_else(|e| Err(anyhow::anyhow!("rules update task failed: {}", e)));
+    rules_response(&state, res)
+}
+
+async fn api_v1_rules_reload(headers: HeaderMap, State(state): State<AppState>) -> Response {
+    if !state.api.is_authorized(&headers) { return empty_404(); }
+    let store = state.rules.clone();
+    let res = tokio::task::spawn_blocking(move || store.reload())
+        .await
+        .unwrap_or_else(|e| Err(anyhow::anyhow!("rules reload task failed: {}", e)));
+    rules_response(&state, res)
+}
+
 fn parse_cid(s: &str) -> Option<u64> {
     let t = s.trim();
     if t.is_empty() {
@@ -725,11 +811,12 @@ fn build_state(state: &AppState) -> ApiState {
                 bytes_up: c.bytes_up,
                 bytes_down: c.bytes_down,
                 server,
+                uid: c.uid,
+                app: c.app,
             }
         })
         .collect();
-    let bps = state.runtime.download_limit_bps.load(std::sync::atomic::Ordering::Relaxed);
-    let download_limit_mbit = if bps > 0 { (bps as f64) * 8.0 / (1024.0 * 1024.0) } else { 0.0 };
+    let download_limit_mbit = state.shaper.limits().global.download_mbit;
 
     ApiState {
         schema_version: 1,
@@ -1,5 +1,7 @@
 use crate::{
-    config::Config,
+    config::{Backend, Config},
+    metrics::BackendMetrics,
+    session_pool::{target_size, PooledSession, SessionSlot, DEMAND_WINDOW},
     socks5::{
         connect_to_socks5_server, connect_via_socks5, verify_tls_data_plane,
         RuntimeFailureClass,
@@ -12,10 +14,10 @@ use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     net::SocketAddr,
-    sync::Arc,
+    sync::{atomic::{AtomicU8, Ordering}, Arc, PoisonError, RwLock},
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tokio::{sync::{Mutex, Notify}, task::JoinSet};
+use tokio::{net::TcpStream, sync::{watch, Mutex, Notify}, task::JoinSet};
 use tracing::{debug, info, warn};
 
 const FULL_PROBE_INTERVAL: Duration = Duration::from_secs(15 * 60);
@@ -75,6 +77,12 @@ struct BackendEntry {
     force_full_probe: bool,
     next_forced_probe_after: Instant,
     runtime_cooldown_until: Instant,
+    metrics: BackendMetrics,
+    /// Set to true when a reload removes the backend; relays through it are
+    /// closed after the shutdown grace period.
+    retired: watch::Sender<bool>,
+    /// Operator override from the control API: not selected until then.
+    disabled_until: Option<Instant>,
 }
 
 #[derive(Debug)]
@@ -82,14 +90,72 @@ struct PoolInner {
     entries: Vec<BackendEntry>,
     index: HashMap<SocketAddr, usize>,
     no_green_since: Option<Instant>,
+    /// Operator override from the control API: tried first while GREEN.
+    pinned: Option<(SocketAddr, Instant)>,
+    /// Pre-negotiated SOCKS5 sessions by backend.
+    sessions: HashMap<SocketAddr, SessionSlot>,
+}
+
+const DIRECT_FALLBACK_CONFIG: u8 = 0;
+const DIRECT_FALLBACK_ON: u8 = 1;
+const DIRECT_FALLBACK_OFF: u8 = 2;
+
+/// Configuration the pool runs with; replaced as a whole on reload.
+struct Current {
+    config: Arc<Config>,
+    probe_targets: Arc<Vec<TargetAddr>>,
 }
 
 #[derive(Clone)]
 pub struct BackendPool {
     inner: Arc<Mutex<PoolInner>>,
-    config: Arc<Config>,
-    probe_targets: Arc<Vec<TargetAddr>>,
+    current: Arc<RwLock<Current>>,
     health_wake: Arc<Notify>,
+    session_wake: Arc<Notify>,
+    direct_fallback_override: Arc<AtomicU8>,
+}
+
+/// Backend changes applied by [`BackendPool::reload`].
+#[derive(Debug, Default)]
+pub struct ReloadReport {
+    pub kept: Vec<SocketAddr>,
+    /// New backends and backends whose credentials changed; both start
+    /// UNKNOWN and are not selected before a Full probe turns them GREEN.
+    pub added: Vec<SocketAddr>,
+    pub removed: Vec<SocketAddr>,
+}
+
+fn new_entry(addr: SocketAddr, now: Instant) -> BackendEntry {
+    BackendEntry {
+        addr,
+        state: BackendState::Unknown,
+        consecutive_failures: 0,
+        runtime_failure_streak: 0,
+        last_runtime_failure: None,
+        last_error: Some("not checked yet".to_string()),
+        last_check_unix: None,
+        last_success_unix: None,
+        last_full_probe_unix: None,
+        last_latency_ms: None,
+        internet_latency_ms: None,
+        runtime_latency_ewma_ms: None,
+        last_runtime_success: None,
+        selection_credit: 0.0,
+        internet_probe_fail_streak: 0,
+        next_internet_probe_after: now,
+        next_probe: now,
+        selected_connections: 0,
+        successful_connections: 0,
+        failed_connections: 0,
+        revision: 0,
+        probe_in_flight: false,
+        force_full_probe: true,
+        next_forced_probe_after: now,
+        runtime_cooldown_until: now,
+        metrics: BackendMetrics::default(),
+        retired: watch::channel(false).0,
+        disabled_until: None,
+    }
 }
 
 #[derive(Debug)]
@@ -107,34 +173,7 @@ impl BackendPool {
         let entries: Vec<_> = config
             .backends
             .iter()
-            .copied()
-            .map(|addr| BackendEntry {
-                addr,
-                state: BackendState::Unknown,
-                consecutive_failures: 0,
-                runtime_failure_streak: 0,
-                last_runtime_failure: None,
-                last_error: Some("not checked yet".to_string()),
-                last_check_unix: None,
-                last_success_unix: None,
-                last_full_probe_unix: None,
-                last_latency_ms: None,
-                internet_latency_ms: None,
-                runtime_latency_ewma_ms: None,
-                last_runtime_success: None,
-                selection_credit: 0.0,
-                internet_probe_fail_streak: 0,
-                next_internet_probe_after: now,
-                next_probe: now,
-                selected_connections: 0,
-                successful_connections: 0,
-                failed_connections: 0,
-                revision: 0,
-                probe_in_flight: false,
-                force_full_probe: true,
-                next_forced_probe_after: now,
-                runtime_cooldown_until: now,
-            })
+            .map(|backend| new_entry(backend.address, now))
             .collect();
         let index = entries
             .iter()
@@ -147,19 +186,141 @@ impl BackendPool {
                 entries,
                 index,
                 no_green_since,
+                pinned: None,
+                sessions: HashMap::new(),
             })),
-            config,
-            probe_targets,
+            current: Arc::new(RwLock::new(Current { config, probe_targets })),
             health_wake: Arc::new(Notify::new()),
+            session_wake: Arc::new(Notify::new()),
+            direct_fallback_override: Arc::new(AtomicU8::new(DIRECT_FALLBACK_CONFIG)),
         })
     }
 
+    /// The configuration currently in effect.
+    pub fn config(&self) -> Arc<Config> {
+        self.current.read().unwrap_or_else(PoisonError::into_inner).config.clone()
+    }
+
+    fn probe_targets(&self) -> Arc<Vec<TargetAddr>> {
+        self.current.read().unwrap_or_else(PoisonError::into_inner).probe_targets.clone()
+    }
+
+    /// Switch to a new configuration without touching open relays. Unchanged
+    /// backends keep their health state and runtime history; removed ones stop
+    /// receiving new connections immediately and their open relays are
+    /// signalled to drain.
+    pub async fn reload(&self, config: Arc<Config>) -> Result<ReloadReport> {
+        let probe_targets = Arc::new(config.parsed_probe_targets()?);
+        let old = self.config();
+        let now = Instant::now();
+        let mut report = ReloadReport::default();
+        {
+            let mut inner = self.inner.lock().await;
+            let mut previous: HashMap<SocketAddr, BackendEntry> =
+                inner.entries.drain(..).map(|entry| (entry.addr, entry)).collect();
+            let mut entries = Vec::with_capacity(config.backends.len());
+            for backend in &config.backends {
+                let unchanged = old.backends.iter().any(|item| item == backend);
+                match previous.remove(&backend.address) {
+                    Some(entry) if unchanged => {
+                        report.kept.push(backend.address);
+                        entries.push(entry);
+                    }
+                    previous_entry => {
+                        let mut entry = new_entry(backend.address, now);
+                        // A probe still in flight for the old entry must not
+                        // land on the new one.
+                        if let Some(old_entry) = previous_entry {
+                            entry.revision = old_entry.revision.wrapping_add(1);
+                        }
+                        report.added.push(backend.address);
+                        entries.push(entry);
+                    }
+                }
+            }
+            for (addr, entry) in previous {
+                entry.retired.send_replace(true);
+                report.removed.push(addr);
+            }
+            if inner.pinned.is_some_and(|(addr, _)| report.removed.contains(&addr)) {
+                inner.pinned = None;
+            }
+            // Pooled sessions were negotiated with the old credentials.
+            for addr in report.added.iter().chain(&report.removed) {
+                inner.sessions.remove(addr);
+            }
+            inner.index = entries.iter().enumerate().map(|(i, entry)| (entry.addr, i)).collect();
+            inner.entries = entries;
+            refresh_no_green_epoch(&mut inner);
+        }
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = Current { config, probe_targets };
+        self.health_wake.notify_one();
+        Ok(report)
+    }
+
+    /// Resolves to true once `addr` has been removed by a reload. A backend
+    /// that is already gone yields a receiver that is retired from the start.
+    pub async fn retired_signal(&self, addr: SocketAddr) -> watch::Receiver<bool> {
+        let inner = self.inner.lock().await;
+        match inner.index.get(&addr) {
+            Some(&index) => inner.entries[index].retired.subscribe(),
+            None => watch::channel(true).1,
+        }
+    }
+
     pub async fn initial_probe(&self) {
         let addresses = self.addresses().await;
         let plan = addresses.into_iter().map(|addr| (addr, ProbeMode::Full)).collect();
         self.probe_many(plan).await;
     }
 
+    /// `direct_fallback` from the configuration unless the control API
+    /// overrides it.
+    pub fn direct_fallback(&self) -> bool {
+        match self.direct_fallback_override.load(Ordering::Relaxed) {
+            DIRECT_FALLBACK_ON => true,
+            DIRECT_FALLBACK_OFF => false,
+            _ => self.config().direct_fallback,
+        }
+    }
+
+    /// `None` returns to the configured value.
+    pub fn set_direct_fallback_override(&self, value: Option<bool>) {
+        let raw = match value {
+            Some(true) => DIRECT_FALLBACK_ON,
+            Some(false) => DIRECT_FALLBACK_OFF,
+            None => DIRECT_FALLBACK_CONFIG,
+        };
+        self.direct_fallback_override.store(raw, Ordering::Relaxed);
+    }
+
+    /// Keep `addr` out of selection for `duration`, or re-enable it with
+    /// `None`. Health probes continue either way. False for an unknown backend.
+    pub async fn set_disabled(&self, addr: SocketAddr, duration: Option<Duration>) -> bool {
+        let mut inner = self.inner.lock().await;
+        let Some(index) = inner.index.get(&addr).copied() else { return false; };
+        inner.entries[index].disabled_until = duration.map(|duration| Instant::now() + duration);
+        true
+    }
+
+    /// Try `addr` first for `duration` whenever it is GREEN; other GREEN
+    /// backends stay as failover. `None` clears the pin. False for an unknown
+    /// backend.
+    pub async fn set_pinned(&self, pin: Option<(SocketAddr, Duration)>) -> bool {
+        let mut inner = self.inner.lock().await;
+        match pin {
+            Some((addr, _)) if !inner.index.contains_key(&addr) => false,
+            Some((addr, duration)) => {
+                inner.pinned = Some((addr, Instant::now() + duration));
+                true
+            }
+            None => {
+                inner.pinned = None;
+                true
+            }
+        }
+    }
+
     pub async fn candidate_order(&self) -> Vec<SocketAddr> {
         let mut inner = self.inner.lock().await;
         let now = Instant::now();
@@ -169,6 +330,7 @@ impl BackendPool {
             .iter()
             .enumerate()
             .filter(|(_, entry)| entry.state == BackendState::Green)
+            .filter(|(_, entry)| entry.disabled_until.map_or(true, |until| until <= now))
             .map(|(index, _)| index)
             .collect();
         if all_green.is_empty() {
@@ -294,10 +456,18 @@ impl BackendPool {
                 )
         });
 
-        std::iter::once(preferred)
+        let mut order: Vec<SocketAddr> = std::iter::once(preferred)
             .chain(rest)
             .map(|index| inner.entries[index].addr)
-            .collect()
+            .collect();
+        if let Some((pinned, until)) = inner.pinned {
+            if until <= now {
+                inner.pinned = None;
+            } else if let Some(position) = order.iter().position(|addr| *addr == pinned) {
+                order[..=position].rotate_right(1);
+            }
+        }
+        order
     }
 
     pub async fn mark_attempt(&self, addr: SocketAddr) {
@@ -342,7 +512,7 @@ impl BackendPool {
                 if now >= entry.next_forced_probe_after {
                     entry.force_full_probe = true;
                     entry.next_probe = now;
-                    entry.next_forced_probe_after = now + self.config.runtime_cooldown();
+                    entry.next_forced_probe_after = now + self.config().runtime_cooldown();
                     wake = true;
                 }
             }
@@ -369,6 +539,12 @@ impl BackendPool {
             let entry = &mut inner.entries[index];
             let old = entry.state;
             entry.failed_connections = entry.failed_connections.saturating_add(1);
+            let class_label = match class {
+                RuntimeFailureClass::TargetPath => "target_path",
+                RuntimeFailureClass::Soft => "soft",
+                RuntimeFailureClass::Hard => "hard",
+            };
+            *entry.metrics.failures.entry(class_label).or_default() += 1;
             entry.last_error = Some(error.to_string());
             entry.last_check_unix = Some(unix_now());
             let now = Instant::now();
@@ -377,7 +553,7 @@ impl BackendPool {
                 wake_health = true;
                 entry.force_full_probe = true;
                 entry.next_probe = now;
-                entry.next_forced_probe_after = now + self.config.runtime_cooldown();
+                entry.next_forced_probe_after = now + self.config().runtime_cooldown();
             }
 
             if class != RuntimeFailureClass::TargetPath {
@@ -410,7 +586,7 @@ impl BackendPool {
                 entry.runtime_failure_streak = entry.runtime_failure_streak.saturating_add(1);
                 entry.last_runtime_failure = Some(now);
 
-                let hard_threshold = self.config.failure_threshold.max(1);
+                let hard_threshold = self.config().failure_threshold.max(1);
                 let soft_threshold = hard_threshold.saturating_mul(2).saturating_add(2);
                 let threshold = match class {
                     RuntimeFailureClass::Soft => soft_threshold,
@@ -465,7 +641,7 @@ impl BackendPool {
             if now >= entry.next_forced_probe_after {
                 entry.force_full_probe = true;
                 entry.next_probe = now;
-                entry.next_forced_probe_after = now + self.config.runtime_cooldown();
+                entry.next_forced_probe_after = now + self.config().runtime_cooldown();
                 wake = true;
             }
         }
@@ -486,7 +662,7 @@ impl BackendPool {
             if now >= entry.next_forced_probe_after {
                 entry.force_full_probe = true;
                 entry.next_probe = now;
-                entry.next_forced_probe_after = now + self.config.runtime_cooldown();
+                entry.next_forced_probe_after = now + self.config().runtime_cooldown();
                 wake = true;
             }
         }
@@ -529,11 +705,11 @@ impl BackendPool {
             entry.force_full_probe = false;
 
             entry.next_probe = if entry.state == BackendState::Green {
-                now + self.config.healthy_probe_interval()
+                now + self.config().healthy_probe_interval()
             } else if !any_green {
                 now + no_green_recovery_interval(recovery_age.unwrap_or_default())
             } else {
-                now + self.config.recovery_probe_interval()
+                now + self.config().recovery_probe_interval()
             };
             due.push((entry.addr, mode));
         }
@@ -559,14 +735,17 @@ impl BackendPool {
             return;
         };
 
+        let config = self.config();
+        let probe_targets = self.probe_targets();
         let stage1_started = Instant::now();
         let stage1 = tokio::time::timeout(
-            self.config.probe_timeout(),
+            config.probe_timeout(),
             connect_to_socks5_server(
                 addr,
-                self.config.connect_timeout(),
-                self.config.upstream_handshake_timeout(),
-                self.config.tcp_nodelay,
+                config.backend_auth(addr),
+                config.connect_timeout(),
+                config.upstream_handshake_timeout(),
+                config.tcp_nodelay,
             ),
         )
         .await;
@@ -593,7 +772,7 @@ impl BackendPool {
                     mode,
                     ProbeOutcome::SocksUnavailable(format!(
                         "SOCKS reachability probe exceeded {} ms",
-                        self.config.probe_timeout_ms
+                        config.probe_timeout_ms
                     )),
                 )
                 .await;
@@ -612,7 +791,7 @@ impl BackendPool {
             return;
         }
 
-        if self.probe_targets.is_empty() {
+        if probe_targets.is_empty() {
             self.finish_probe(
                 addr,
                 revision,
@@ -632,16 +811,17 @@ impl BackendPool {
         // mobile operator blocks one public probe endpoint but the SOCKS route
         // itself still has working Internet access.
         let mut failures = Vec::new();
-        for target in self.probe_targets.iter().cloned() {
+        for target in probe_targets.iter() {
             let attempt_started = Instant::now();
             let connect = tokio::time::timeout(
-                self.config.probe_timeout(),
+                config.probe_timeout(),
                 connect_via_socks5(
                     addr,
-                    &target,
-                    self.config.connect_timeout(),
-                    self.config.upstream_handshake_timeout(),
-                    self.config.tcp_nodelay,
+                    config.backend_auth(addr),
+                    target,
+                    config.connect_timeout(),
+                    config.upstream_handshake_timeout(),
+                    config.tcp_nodelay,
                 ),
             )
             .await;
@@ -655,13 +835,13 @@ impl BackendPool {
                 Err(_) => {
                     failures.push(format!(
                         "{target}: Internet CONNECT probe exceeded {} ms",
-                        self.config.probe_timeout_ms
+                        config.probe_timeout_ms
                     ));
                     continue;
                 }
             };
 
-            if verify_tls_data_plane(&mut stream, &target, self.config.probe_timeout()).await {
+            if verify_tls_data_plane(&mut stream, target, config.probe_timeout()).await {
                 self.finish_probe(
                     addr,
                     revision,
@@ -714,6 +894,23 @@ impl BackendPool {
                 entry.last_full_probe_unix = Some(now_unix);
             }
 
+            let outcome_label = match &outcome {
+                ProbeOutcome::SocksUnavailable(_) => "socks_unavailable",
+                ProbeOutcome::LightReachable { latency } => {
+                    entry.metrics.socks_rtt.observe(*latency);
+                    "socks_reachable"
+                }
+                ProbeOutcome::InternetUnavailable { socks_latency, .. } => {
+                    entry.metrics.socks_rtt.observe(*socks_latency);
+                    "internet_unavailable"
+                }
+                ProbeOutcome::InternetVerified { latency } => {
+                    entry.metrics.internet_rtt.observe(*latency);
+                    "internet_verified"
+                }
+            };
+            *entry.metrics.probes.entry(outcome_label).or_default() += 1;
+
             match outcome {
                 ProbeOutcome::SocksUnavailable(error) => {
                     entry.state = BackendState::Red;
@@ -786,11 +983,11 @@ impl BackendPool {
             let recovery_age = inner.no_green_since.map(|since| Instant::now().duration_since(since));
             let entry = &mut inner.entries[index];
             entry.next_probe = if entry.state == BackendState::Green {
-                Instant::now() + self.config.healthy_probe_interval()
+                Instant::now() + self.config().healthy_probe_interval()
             } else if !any_green {
                 Instant::now() + no_green_recovery_interval(recovery_age.unwrap_or_default())
             } else {
-                Instant::now() + self.config.recovery_probe_interval()
+                Instant::now() + self.config().recovery_probe_interval()
             };
 
             if old != entry.state {
@@ -835,13 +1032,119 @@ impl BackendPool {
         self.health_wake.notified().await;
     }
 
+    pub(crate) async fn wait_for_session_wake(&self) {
+        self.session_wake.notified().await;
+    }
+
+    /// Pop the oldest usable pooled session for `addr`, recording the demand
+    /// that sizes the pool. Also returns how many dead sessions were dropped
+    /// on the way.
+    pub(crate) async fn take_pooled_session(&self, addr: SocketAddr) -> (Option<TcpStream>, usize) {
+        let idle = self.config().session_pool_idle();
+        let now = Instant::now();
+        let mut discarded = 0;
+        let mut taken = None;
+        {
+            let mut inner = self.inner.lock().await;
+            let slot = inner.sessions.entry(addr).or_default();
+            slot.demand.push_back(now);
+            while let Some(session) = slot.sessions.pop_front() {
+                if session.usable(idle, now) {
+                    taken = Some(session.stream);
+                    break;
+                }
+                discarded += 1;
+            }
+        }
+        self.session_wake.notify_one();
+        (taken, discarded)
+    }
+
+    /// Drop expired and dead sessions, empty the pools of backends that are
+    /// not selectable, and reserve the opens needed to reach each target size.
+    /// Returns the plan and the number of sessions dropped.
+    pub(crate) async fn session_refill_plan(&self) -> (Vec<(Backend, usize)>, usize) {
+        let config = self.config();
+        let idle = config.session_pool_idle();
+        let now = Instant::now();
+        let mut plan = Vec::new();
+        let mut discarded = 0;
+        let mut inner = self.inner.lock().await;
+        let PoolInner { entries, index, sessions, .. } = &mut *inner;
+        sessions.retain(|addr, _| index.contains_key(addr));
+        for backend in &config.backends {
+            let Some(&position) = index.get(&backend.address) else { continue; };
+            let entry = &entries[position];
+            let selectable = entry.state == BackendState::Green
+                && entry.disabled_until.map_or(true, |until| until <= now);
+            let slot = sessions.entry(backend.address).or_default();
+            while slot.demand.front().is_some_and(|at| now.duration_since(*at) >= DEMAND_WINDOW) {
+                slot.demand.pop_front();
+            }
+            let before = slot.sessions.len();
+            if selectable {
+                slot.sessions.retain(|session| session.usable(idle, now));
+            } else {
+                slot.sessions.clear();
+            }
+            discarded += before - slot.sessions.len();
+            if !selectable {
+                continue;
+            }
+            let target = target_size(slot.demand.len(), idle, config.session_pool_size);
+            let needed = target.saturating_sub(slot.sessions.len() + slot.pending);
+            if needed > 0 {
+                slot.pending += needed;
+                plan.push((backend.clone(), needed));
+            }
+        }
+        (plan, discarded)
+    }
+
+    /// Returns false, dropping the stream, if the backend was removed or its
+    /// credentials changed while the session was being negotiated.
+    pub(crate) async fn store_pooled_session(&self, backend: &Backend, stream: TcpStream) -> bool {
+        let current = self.config().backends.contains(backend);
+        let mut inner = self.inner.lock().await;
+        let Some(slot) = inner.sessions.get_mut(&backend.address) else { return false; };
+        slot.pending = slot.pending.saturating_sub(1);
+        if current {
+            slot.sessions.push_back(PooledSession { stream, opened: Instant::now() });
+        }
+        current
+    }
+
+    pub(crate) async fn pooled_session_failed(&self, addr: SocketAddr) {
+        let mut inner = self.inner.lock().await;
+        if let Some(slot) = inner.sessions.get_mut(&addr) {
+            slot.pending = slot.pending.saturating_sub(1);
+        }
+    }
+
+    pub async fn metrics(&self) -> Vec<(String, BackendMetrics)> {
+        let inner = self.inner.lock().await;
+        inner
+            .entries
+            .iter()
+            .map(|entry| (entry.addr.to_string(), entry.metrics.clone()))
+            .collect()
+    }
+
     pub async fn snapshots(&self) -> Vec<BackendSnapshot> {
+        let config = self.config();
         let inner = self.inner.lock().await;
+        let now = Instant::now();
+        let pinned = inner.pinned.filter(|(_, until)| *until > now).map(|(addr, _)| addr);
         inner
             .entries
             .iter()
             .map(|entry| BackendSnapshot {
                 address: entry.addr.to_string(),
+                auth: config
+                    .backends
+                    .iter()
+                    .find(|backend| backend.address == entry.addr)
+                    .and_then(|backend| backend.masked_auth()),
                 state: entry.state,
                 consecutive_failures: entry.consecutive_failures,
                 runtime_failure_streak: entry.runtime_failure_streak,
@@ -858,6 +1161,9 @@ impl BackendPool {
                 selected_connections: entry.selected_connections,
                 successful_connections: entry.successful_connections,
                 failed_connections: entry.failed_connections,
+                pooled_sessions: inner.sessions.get(&entry.addr).map_or(0, |slot| slot.sessions.len()),
+                pinned: pinned == Some(entry.addr),
+                disabled: entry.disabled_until.is_some_and(|until| until > now),
             })
             .collect()
     }
@@ -915,7 +1221,7 @@ probe_targets = ["1.1.1.1:443", "8.8.8.8:443"]
         .unwrap();
         config.listen = "127.0.0.1:11990".parse().unwrap();
         config.dnscrypt_timeout_ms = 5_000;
-        config.backends = backends;
+        config.backends = backends.into_iter().map(Into::into).collect();
         Arc::new(config)
     }
 
@@ -24,11 +24,119 @@ fn default_runtime_cooldown_ms() -> u64 { 2_000 }
 fn default_max_connections() -> usize { 1_024 }
 fn default_status_interval_secs() -> u64 { 5 }
 fn default_shutdown_grace_period_ms() -> u64 { 5_000 }
+fn default_session_pool_size() -> usize { 2 }
+fn default_session_pool_idle_ms() -> u64 { 3_000 }
+fn default_race_stagger_ms() -> u64 { 200 }
 fn default_log_level() -> String { "info".to_string() }
 fn default_probe_targets() -> Vec<String> {
     vec!["1.1.1.1:443".to_string(), "8.8.8.8:443".to_string()]
 }
 
+/// How `Router` establishes a route.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RouteStrategy {
+    /// One backend at a time in weighted order, then DIRECT.
+    #[default]
+    Sequential,
+    /// Happy Eyeballs: the next candidate (DIRECT last) starts every
+    /// `race_stagger_ms` or as soon as one fails; the first CONNECT wins.
+    Race,
+}
+
+/// One SOCKS5 backend. d2s.toml accepts either a bare `"HOST:PORT"` string or
+/// a table with RFC 1929 credentials:
+/// `{ address = "127.0.0.1:1080", username = "user", password = "pass" }`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(from = "BackendSpec", into = "BackendSpec")]
+pub struct Backend {
+    pub address: SocketAddr,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum BackendSpec {
+    Address(SocketAddr),
+    Table(BackendTable),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct BackendTable {
+    address: SocketAddr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+}
+
+impl From<BackendSpec> for Backend {
+    fn from(spec: BackendSpec) -> Self {
+        match spec {
+            BackendSpec::Address(address) => address.into(),
+            BackendSpec::Table(table) => Self {
+                address: table.address,
+                username: table.username,
+                password: table.password,
+            },
+        }
+    }
+}
+
+impl From<Backend> for BackendSpec {
+    fn from(backend: Backend) -> Self {
+        if backend.username.is_none() && backend.password.is_none() {
+            return Self::Address(backend.address);
+        }
+        Self::Table(BackendTable {
+            address: backend.address,
+            username: backend.username,
+            password: backend.password,
+        })
+    }
+}
+
+impl From<SocketAddr> for Backend {
+    fn from(address: SocketAddr) -> Self {
+        Self { address, username: None, password: None }
+    }
+}
+
+impl Backend {
+    /// Username/password pair for the RFC 1929 sub-negotiation, if configured.
+    pub fn auth(&self) -> Option<(&str, &str)> {
+        Some((self.username.as_deref()?, self.password.as_deref()?))
+    }
+
+    /// Username with the password hidden, for status output.
+    pub fn masked_auth(&self) -> Option<String> {
+        self.auth().map(|(username, _)| format!("{username}:********"))
+    }
+
+    fn validate(&self) -> Result<()> {
+        match (&self.username, &self.password) {
+            (None, None) => Ok(()),
+            (Some(username), Some(password)) => {
+                for (field, value) in [("username", username), ("password", password)] {
+                    if value.is_empty() || value.len() > 255 {
+                        return Err(anyhow!(
+                            "backend {} {field} must be 1..=255 bytes",
+                            self.address
+                        ));
+                    }
+                }
+                Ok(())
+            }
+            _ => Err(anyhow!(
+                "backend {} needs both username and password or neither",
+                self.address
+            )),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
@@ -44,7 +152,7 @@ pub struct Config {
     pub dnscrypt_timeout_ms: u64,
 
     #[serde(default)]
-    pub backends: Vec<SocketAddr>,
+    pub backends: Vec<Backend>,
 
     #[serde(default = "default_true")]
     pub direct_fallback: bool,
@@ -58,6 +166,13 @@ pub struct Config {
     #[serde(default = "default_backend_attempt_timeout_ms")]
     pub backend_attempt_timeout_ms: u64,
 
+    #[serde(default)]
+    pub route_strategy: RouteStrategy,
+
+    /// Delay before the next contestant joins a race.
+    #[serde(default = "default_race_stagger_ms")]
+    pub race_stagger_ms: u64,
+
     #[serde(default = "default_direct_connect_timeout_ms")]
     pub direct_connect_timeout_ms: u64,
 
@@ -104,6 +219,15 @@ pub struct Config {
     #[serde(default = "default_max_connections")]
     pub max_connections: usize,
 
+    /// Upper bound of pre-negotiated SOCKS5 sessions kept per GREEN backend;
+    /// 0 disables the pool.
+    #[serde(default = "default_session_pool_size")]
+    pub session_pool_size: usize,
+
+    /// Pooled sessions older than this are closed instead of used.
+    #[serde(default = "default_session_pool_idle_ms")]
+    pub session_pool_idle_ms: u64,
+
     #[serde(default = "default_true")]
     pub tcp_nodelay: bool,
 
@@ -116,6 +240,19 @@ pub struct Config {
     #[serde(default = "default_status_interval_secs")]
     pub status_interval_secs: u64,
 
+    /// Optional loopback address serving Prometheus metrics at `/metrics`.
+    #[serde(default)]
+    pub metrics_listen: Option<SocketAddr>,
+
+    /// Optional loopback address of the HTTP control API.
+    #[serde(default)]
+    pub control_listen: Option<SocketAddr>,
+
+    /// Bearer token every control API request must present. Required with
+    /// `control_listen`: any app on the device can reach loopback.
+    #[serde(default)]
+    pub control_token: Option<String>,
+
     #[serde(default = "default_shutdown_grace_period_ms")]
     pub shutdown_grace_period_ms: u64,
 }
@@ -134,11 +271,27 @@ struct DnscryptRuntime {
 
 impl Config {
     pub fn load(path: impl AsRef<Path>, dnscrypt_path: impl AsRef<Path>) -> Result<Self> {
+        Self::load_with_token_file(path, dnscrypt_path, None)
+    }
+
+    /// Like `load`, with `control_token` read from `control_token_file` when
+    /// one is given. zdtd passes its token this way so the user's d2s.toml is
+    /// never rewritten and never holds the secret.
+    pub fn load_with_token_file(
+        path: impl AsRef<Path>,
+        dnscrypt_path: impl AsRef<Path>,
+        control_token_file: Option<&Path>,
+    ) -> Result<Self> {
         let path = path.as_ref();
         let raw = std::fs::read_to_string(path)
             .with_context(|| format!("read configuration {}", path.display()))?;
         let mut config: Self = toml::from_str(&raw)
             .with_context(|| format!("parse configuration {}", path.display()))?;
+        if let Some(token_path) = control_token_file {
+            let token = std::fs::read_to_string(token_path)
+                .with_context(|| format!("read control token {}", token_path.display()))?;
+            config.control_token = Some(token.trim().to_string());
+        }
         let dnscrypt = read_dnscrypt_runtime(dnscrypt_path)?;
         config.listen = dnscrypt.listen;
         config.dnscrypt_timeout_ms = dnscrypt.timeout_ms;
@@ -155,14 +308,28 @@ impl Config {
                 "at least one SOCKS5 backend is required when direct_fallback=false"
             ));
         }
-        if self.backends.contains(&self.listen) {
+        if let Some(metrics) = self.metrics_listen {
+            if !metrics.ip().is_loopback() {
+                return Err(anyhow!("metrics_listen must be loopback: {metrics}"));
+            }
+        }
+        if let Some(control) = self.control_listen {
+            if !control.ip().is_loopback() {
+                return Err(anyhow!("control_listen must be loopback: {control}"));
+            }
+            if self.control_token.as_deref().map_or(true, |token| token.trim().is_empty()) {
+                return Err(anyhow!("control_listen requires a non-empty control_token"));
+            }
+        }
+        if self.is_backend(&self.listen) {
             return Err(anyhow!("a backend points to the D2S listener itself: {}", self.listen));
         }
         let mut unique = HashSet::new();
         for backend in &self.backends {
-            if !unique.insert(*backend) {
-                return Err(anyhow!("duplicate backend: {backend}"));
+            if !unique.insert(backend.address) {
+                return Err(anyhow!("duplicate backend: {}", backend.address));
             }
+            backend.validate()?;
         }
         if self.connect_timeout_ms == 0
             || self.upstream_handshake_timeout_ms == 0
@@ -182,6 +349,12 @@ impl Config {
         if self.max_connections == 0 {
             return Err(anyhow!("max_connections must be greater than zero"));
         }
+        if self.session_pool_size > 16 {
+            return Err(anyhow!("session_pool_size must be at most 16"));
+        }
+        if self.session_pool_idle_ms < 100 {
+            return Err(anyhow!("session_pool_idle_ms must be at least 100"));
+        }
         if self.status_interval_secs == 0 {
             return Err(anyhow!("status_interval_secs must be greater than zero"));
         }
@@ -202,6 +375,14 @@ impl Config {
         Ok(())
     }
 
+    pub fn is_backend(&self, addr: &SocketAddr) -> bool {
+        self.backends.iter().any(|backend| backend.address == *addr)
+    }
+
+    pub fn backend_auth(&self, addr: SocketAddr) -> Option<(&str, &str)> {
+        self.backends.iter().find(|backend| backend.address == addr)?.auth()
+    }
+
     pub fn connect_timeout(&self) -> Duration { Duration::from_millis(self.connect_timeout_ms) }
     pub fn upstream_handshake_timeout(&self) -> Duration { Duration::from_millis(self.upstream_handshake_timeout_ms) }
     pub fn backend_attempt_timeout(&self) -> Duration { Duration::from_millis(self.backend_attempt_timeout_ms) }
@@ -212,6 +393,8 @@ impl Config {
     pub fn recovery_probe_interval(&self) -> Duration { Duration::from_secs(self.recovery_probe_interval_secs) }
     pub fn runtime_cooldown(&self) -> Duration { Duration::from_millis(self.runtime_cooldown_ms) }
     pub fn shutdown_grace_period(&self) -> Duration { Duration::from_millis(self.shutdown_grace_period_ms) }
+    pub fn session_pool_idle(&self) -> Duration { Duration::from_millis(self.session_pool_idle_ms) }
+    pub fn race_stagger(&self) -> Duration { Duration::from_millis(self.race_stagger_ms) }
 
     /// Maximum time after the client has sent the first relay payload for the
     /// remote side to prove that the established tunnel actually carries data.
@@ -421,4 +604,54 @@ idle_after_secs = 60
         assert_eq!(config.route_budget(), Duration::from_millis(600));
     }
 
+    #[test]
+    fn backends_accept_plain_and_authenticated_entries() {
+        let mut config: Config = toml::from_str(
+            r#"
+backends = [
+    "127.0.0.1:1080",
+    { address = "127.0.0.1:1081", username = "user", password = "secret" },
+]
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.backends[0], Backend::from("127.0.0.1:1080".parse::<SocketAddr>().unwrap()));
+        assert_eq!(config.backend_auth("127.0.0.1:1081".parse().unwrap()), Some(("user", "secret")));
+        assert_eq!(config.backends[1].masked_auth().as_deref(), Some("user:********"));
+        config.validate().unwrap();
+
+        let raw = toml::to_string(&config).unwrap();
+        assert!(raw.contains("\"127.0.0.1:1080\""));
+        assert!(raw.contains("username = \"user\""));
+
+        config.backends[1].password = None;
+        assert!(config.validate().is_err());
+        config.backends[1].password = Some("x".repeat(256));
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn control_token_file_overrides_the_config_token() {
+        let dir = std::env::temp_dir();
+        let id = std::process::id();
+        let config = dir.join(format!("d2s-token-config-{id}.toml"));
+        let dnscrypt = dir.join(format!("d2s-token-dnscrypt-{id}.toml"));
+        let token = dir.join(format!("d2s-token-{id}"));
+        std::fs::write(&config, "backends = []
+control_listen = '127.0.0.1:11992'
+").unwrap();
+        std::fs::write(&dnscrypt, "proxy = 'socks5://127.0.0.1:11990'
+").unwrap();
+        std::fs::write(&token, "secret\n").unwrap();
+
+        assert!(Config::load(&config, &dnscrypt).is_err(), "control_listen without a token");
+        let loaded = Config::load_with_token_file(&config, &dnscrypt, Some(&token)).unwrap();
+        assert_eq!(loaded.control_token.as_deref(), Some("secret"));
+        assert!(Config::load_with_token_file(&config, &dnscrypt, Some(&dir.join(format!("d2s-token-missing-{id}")))).is_err());
+
+        for path in [config, dnscrypt, token] {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
 }
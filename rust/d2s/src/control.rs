@@ -0,0 +1,332 @@
+//! Optional local control API (`control_listen`).
+//!
+//! A minimal HTTP/1.1 responder with JSON bodies, for zdtd and for debugging
+//! on the device. Like the metrics listener it must be loopback: anything
+//! that can reach it can steer DNS traffic.
+//!
+//! ```text
+//! GET  /status                          runtime stats and backend snapshots
+//! GET  /backends                        backend snapshots
+//! POST /probe[?backend=ADDR]            Full probe now, answers when done
+//! POST /pin?backend=ADDR[&secs=N]       try ADDR first while it is GREEN
+//! POST /unpin
+//! POST /disable?backend=ADDR[&secs=N]   keep ADDR out of selection
+//! POST /enable?backend=ADDR
+//! POST /direct-fallback?enabled=true|false|config
+//! POST /reload                          reread the configuration file
+//! GET  /events                          relay events, text/event-stream
+//! ```
+//!
+//! Every request must carry `Authorization: Bearer <control_token>`; others
+//! get 401. Overrides are runtime only and default to ten minutes; a restart
+//! drops them.
+
+use crate::{backend::{BackendPool, ProbeMode}, metrics::read_request_head, status::RuntimeStats};
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, watch, Notify},
+};
+use tracing::{debug, info};
+
+const DEFAULT_OVERRIDE_SECS: u64 = 600;
+const MAX_OVERRIDE_SECS: u64 = 86_400;
+const EVENTS_KEEPALIVE: Duration = Duration::from_secs(15);
+pub(crate) const EVENTS_CAPACITY: usize = 256;
+
+/// One finished client connection, published on `/events`.
+#[derive(Clone, Debug, Serialize)]
+pub struct RelayEvent {
+    pub unix_ms: u64,
+    pub target: String,
+    /// `socks` or `direct`; absent when no route could be established.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+    pub client_to_remote: u64,
+    pub remote_to_client: u64,
+    pub duration_ms: u64,
+    /// `clean`, `stalled`, `half_close_timeout`, `client_error`,
+    /// `remote_error`, `backend_removed` or `no_route`.
+    pub outcome: &'static str,
+}
+
+impl RelayEvent {
+    pub fn now_unix_ms() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis() as u64)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct ControlState {
+    pub pool: BackendPool,
+    pub stats: Arc<RuntimeStats>,
+    pub events: broadcast::Sender<RelayEvent>,
+    pub reload: Arc<Notify>,
+}
+
+pub(crate) async fn serve(listener: TcpListener, state: ControlState, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        tokio::select! {
+            changed = shutdown.changed() => {
+                if changed.is_err() || *shutdown.borrow() {
+                    break;
+                }
+            }
+            accepted = listener.accept() => {
+                let Ok((stream, peer)) = accepted else { continue; };
+                let state = state.clone();
+                let shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    if let Err(error) = respond(stream, &state, shutdown).await {
+                        debug!(%peer, %error, "control request failed");
+                    }
+                });
+            }
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    bearer: Option<String>,
+}
+
+impl Request {
+    fn parse(head: &[u8]) -> Option<Self> {
+        let mut lines = std::str::from_utf8(head).ok()?.lines();
+        let line = lines.next()?;
+        let bearer = lines.find_map(|header| {
+            let (name, value) = header.split_once(':')?;
+            let token = value.trim().strip_prefix("Bearer ")?;
+            name.trim().eq_ignore_ascii_case("authorization").then(|| token.trim().to_string())
+        });
+        let mut parts = line.split_whitespace();
+        let method = parts.next()?.to_string();
+        let target = parts.next()?;
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let query = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (percent_decode(key), percent_decode(value))
+            })
+            .collect();
+        Some(Self { method, path: path.to_string(), query, bearer })
+    }
+
+    fn is_authorized(&self, pool: &BackendPool) -> bool {
+        let config = pool.config();
+        let Some(token) = config.control_token.as_deref().map(str::trim).filter(|token| !token.is_empty()) else {
+            return false;
+        };
+        self.bearer.as_deref() == Some(token)
+    }
+
+    fn param(&self, name: &str) -> Option<&str> {
+        self.query.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    fn backend(&self, pool: &BackendPool) -> Result<SocketAddr, Reply> {
+        let raw = self.param("backend").ok_or_else(|| Reply::error("400 Bad Request", "missing backend"))?;
+        let addr: SocketAddr = raw
+            .parse()
+            .map_err(|_| Reply::error("400 Bad Request", format!("invalid backend address: {raw}")))?;
+        if !pool.config().is_backend(&addr) {
+            return Err(Reply::error("404 Not Found", format!("unknown backend: {addr}")));
+        }
+        Ok(addr)
+    }
+
+    fn duration(&self) -> Result<Duration, Reply> {
+        let secs = match self.param("secs") {
+            None => DEFAULT_OVERRIDE_SECS,
+            Some(raw) => raw
+                .parse()
+                .ok()
+                .filter(|secs| (1..=MAX_OVERRIDE_SECS).contains(secs))
+                .ok_or_else(|| Reply::error("400 Bad Request", format!("secs must be 1..={MAX_OVERRIDE_SECS}")))?,
+        };
+        Ok(Duration::from_secs(secs))
+    }
+}
+
+fn percent_decode(raw: &str) -> String {
+    let bytes = raw.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let decoded = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match decoded {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(if bytes[i] == b'+' { b' ' } else { bytes[i] });
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+struct Reply {
+    status: &'static str,
+    body: serde_json::Value,
+}
+
+impl Reply {
+    fn ok(body: serde_json::Value) -> Self {
+        Self { status: "200 OK", body }
+    }
+
+    fn error(status: &'static str, message: impl Into<String>) -> Self {
+        Self { status, body: json!({ "ok": false, "error": message.into() }) }
+    }
+}
+
+async fn respond(mut stream: TcpStream, state: &ControlState, shutdown: watch::Receiver<bool>) -> std::io::Result<()> {
+    let Some(head) = read_request_head(&mut stream).await? else { return Ok(()); };
+    let Some(request) = Request::parse(&head) else {
+        return write_reply(&mut stream, Reply::error("400 Bad Request", "malformed request")).await;
+    };
+    if !request.is_authorized(&state.pool) {
+        return write_reply(&mut stream, Reply::error("401 Unauthorized", "missing or invalid bearer token")).await;
+    }
+    if request.method == "GET" && request.path == "/events" {
+        return stream_events(stream, state.events.subscribe(), shutdown).await;
+    }
+    let reply = route(&request, state).await.unwrap_or_else(|reply| reply);
+    write_reply(&mut stream, reply).await
+}
+
+async fn route(request: &Request, state: &ControlState) -> Result<Reply, Reply> {
+    let pool = &state.pool;
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/status") => Ok(Reply::ok(to_value(&state.stats.live_snapshot(pool, true).await))),
+        ("GET", "/backends") => Ok(Reply::ok(to_value(&pool.snapshots().await))),
+        ("POST", "/probe") => {
+            let plan = match request.param("backend") {
+                Some(_) => vec![(request.backend(pool)?, ProbeMode::Full)],
+                None => pool.config().backends.iter().map(|b| (b.address, ProbeMode::Full)).collect(),
+            };
+            info!(backends = plan.len(), "control API requested a Full probe");
+            pool.probe_many(plan).await;
+            Ok(backends_reply(pool).await)
+        }
+        ("POST", "/pin") => {
+            let addr = request.backend(pool)?;
+            let duration = request.duration()?;
+            pool.set_pinned(Some((addr, duration))).await;
+            info!(backend = %addr, secs = duration.as_secs(), "control API pinned backend");
+            Ok(backends_reply(pool).await)
+        }
+        ("POST", "/unpin") => {
+            pool.set_pinned(None).await;
+            info!("control API cleared backend pin");
+            Ok(backends_reply(pool).await)
+        }
+        ("POST", "/disable") => {
+            let addr = request.backend(pool)?;
+            let duration = request.duration()?;
+            pool.set_disabled(addr, Some(duration)).await;
+            info!(backend = %addr, secs = duration.as_secs(), "control API disabled backend");
+            Ok(backends_reply(pool).await)
+        }
+        ("POST", "/enable") => {
+            let addr = request.backend(pool)?;
+            pool.set_disabled(addr, None).await;
+            info!(backend = %addr, "control API enabled backend");
+            Ok(backends_reply(pool).await)
+        }
+        ("POST", "/direct-fallback") => {
+            let value = match request.param("enabled") {
+                Some("true") => Some(true),
+                Some("false") => Some(false),
+                Some("config") => None,
+                _ => return Err(Reply::error("400 Bad Request", "enabled must be true, false or config")),
+            };
+            pool.set_direct_fallback_override(value);
+            let effective = pool.direct_fallback();
+            info!(direct_fallback = effective, overridden = value.is_some(), "control API changed direct_fallback");
+            Ok(Reply::ok(json!({ "ok": true, "direct_fallback": effective })))
+        }
+        ("POST", "/reload") => {
+            state.reload.notify_one();
+            Ok(Reply { status: "202 Accepted", body: json!({ "ok": true }) })
+        }
+        (_, "/status" | "/backends" | "/probe" | "/pin" | "/unpin" | "/disable" | "/enable" | "/direct-fallback" | "/reload" | "/events") => {
+            Err(Reply::error("405 Method Not Allowed", "method not allowed"))
+        }
+        _ => Err(Reply::error("404 Not Found", "not found")),
+    }
+}
+
+async fn backends_reply(pool: &BackendPool) -> Reply {
+    Reply::ok(json!({ "ok": true, "backends": pool.snapshots().await }))
+}
+
+fn to_value(value: &impl Serialize) -> serde_json::Value {
+    serde_json::to_value(value).unwrap_or_default()
+}
+
+async fn write_reply(stream: &mut TcpStream, reply: Reply) -> std::io::Result<()> {
+    let body = reply.body.to_string();
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        reply.status,
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+async fn stream_events(
+    mut stream: TcpStream,
+    mut events: broadcast::Receiver<RelayEvent>,
+    mut shutdown: watch::Receiver<bool>,
+) -> std::io::Result<()> {
+    stream
+        .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n")
+        .await?;
+    let mut keepalive = tokio::time::interval(EVENTS_KEEPALIVE);
+    keepalive.tick().await;
+    loop {
+        // Keepalive comments also notice a subscriber that went away while
+        // DNS traffic is idle.
+        let chunk = tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => format!("data: {}\n\n", serde_json::to_string(&event).unwrap_or_default()),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => format!("event: lagged\ndata: {skipped}\n\n"),
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = keepalive.tick() => ": keepalive\n\n".to_string(),
+            changed = shutdown.changed() => {
+                if changed.is_err() || *shutdown.borrow() {
+                    break;
+                }
+                continue;
+            }
+        };
+        stream.write_all(chunk.as_bytes()).await?;
+    }
+    stream.shutdown().await
+}
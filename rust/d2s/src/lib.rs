@@ -1,8 +1,11 @@
 pub mod backend;
 pub mod config;
+pub mod control;
+pub mod metrics;
 pub mod router;
 mod relay;
 pub mod server;
+mod session_pool;
 pub mod socks5;
 pub mod status;
 pub mod target;
@@ -1,7 +1,7 @@
 use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use d2s::{backend::BackendPool, config::Config, server::start};
-use std::{path::PathBuf, sync::Arc};
+use std::{path::{Path, PathBuf}, sync::Arc};
 use tracing::{info, warn};
 use tracing_subscriber::EnvFilter;
 
@@ -14,6 +14,11 @@ struct Cli {
     #[arg(long, global = true, default_value = "dnscrypt-proxy.toml")]
     dnscrypt_config: PathBuf,
 
+    /// File holding the control API token; overrides `control_token` and is
+    /// reread on every reload.
+    #[arg(long, global = true)]
+    control_token_file: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Option<Command>,
 }
@@ -26,27 +31,30 @@ enum Command {
     Check,
     /// Probe all configured SOCKS5 backends once and print JSON status.
     Probe,
+    /// Validate the configuration and ask the running D2S that uses it to
+    /// reload (SIGHUP).
+    Reload,
     /// Print the example configuration to stdout.
     ExampleConfig,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let cli = Cli::parse();
-    match cli.command.unwrap_or(Command::Run) {
+    let mut cli = Cli::parse();
+    match cli.command.take().unwrap_or(Command::Run) {
         Command::ExampleConfig => {
             print!("{}", include_str!("../d2s.example.toml"));
             Ok(())
         }
         Command::Check => {
-            let config = Config::load(&cli.config, &cli.dnscrypt_config)?;
+            let config = load_config(&cli)?;
             init_logging(&config.log_level)?;
             info!(path = %cli.config.display(), backends = config.backends.len(), "configuration is valid");
             println!("OK: {}", cli.config.display());
             Ok(())
         }
         Command::Probe => {
-            let config = Config::load(&cli.config, &cli.dnscrypt_config)?;
+            let config = load_config(&cli)?;
             init_logging(&config.log_level)?;
             let config = Arc::new(config);
             let pool = BackendPool::new(config)?;
@@ -54,17 +62,53 @@ async fn main() -> Result<()> {
             println!("{}", serde_json::to_string_pretty(&pool.snapshots().await)?);
             Ok(())
         }
+        Command::Reload => {
+            let config = load_config(&cli)?;
+            init_logging(&config.log_level)?;
+            let pids = signal_running_instances(&cli.config)?;
+            if pids.is_empty() {
+                anyhow::bail!("no running d2s uses {}", cli.config.display());
+            }
+            println!("OK: reload requested for pid {pids:?}");
+            Ok(())
+        }
         Command::Run => {
-            let config = Config::load(&cli.config, &cli.dnscrypt_config)?;
+            let config = load_config(&cli)?;
             init_logging(&config.log_level)?;
             let server = start(config).await?;
             info!(listen = %server.listen_addr, "D2S started");
-            wait_for_shutdown_signal().await?;
+            let mut signals = Signals::install()?;
+            loop {
+                let signal = tokio::select! {
+                    signal = signals.recv() => signal?,
+                    _ = server.reload_requested() => {
+                        info!("reload requested through the control API");
+                        Signal::Reload
+                    }
+                };
+                if signal == Signal::Shutdown {
+                    break;
+                }
+                // A broken edit must not take the resolver down: keep running
+                // with the previous configuration and report why.
+                match load_config(&cli) {
+                    Ok(config) => {
+                        if let Err(error) = server.reload(config).await {
+                            warn!(error = %format!("{error:#}"), "D2S reload rejected; keeping the current configuration");
+                        }
+                    }
+                    Err(error) => warn!(error = %format!("{error:#}"), "D2S reload failed; keeping the current configuration"),
+                }
+            }
             server.shutdown().await
         }
     }
 }
 
+fn load_config(cli: &Cli) -> Result<Config> {
+    Config::load_with_token_file(&cli.config, &cli.dnscrypt_config, cli.control_token_file.as_deref())
+}
+
 fn init_logging(level: &str) -> Result<()> {
     let filter = EnvFilter::try_from_default_env()
         .or_else(|_| EnvFilter::try_new(level))
@@ -78,21 +122,115 @@ fn init_logging(level: &str) -> Result<()> {
     Ok(())
 }
 
-async fn wait_for_shutdown_signal() -> Result<()> {
+#[derive(Debug, PartialEq, Eq)]
+enum Signal {
+    Shutdown,
+    Reload,
+}
+
+/// Signal streams installed once for the lifetime of `run`, so a signal
+/// arriving between two waits is queued instead of hitting the default action.
+struct Signals {
+    #[cfg(unix)]
+    terminate: tokio::signal::unix::Signal,
+    #[cfg(unix)]
+    interrupt: tokio::signal::unix::Signal,
     #[cfg(unix)]
-    {
-        use tokio::signal::unix::{signal, SignalKind};
-        let mut terminate = signal(SignalKind::terminate()).context("install SIGTERM handler")?;
-        let mut interrupt = signal(SignalKind::interrupt()).context("install SIGINT handler")?;
-        tokio::select! {
-            _ = terminate.recv() => warn!("received SIGTERM"),
-            _ = interrupt.recv() => warn!("received SIGINT"),
+    hangup: tokio::signal::unix::Signal,
+}
+
+impl Signals {
+    fn install() -> Result<Self> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            Ok(Self {
+                terminate: signal(SignalKind::terminate()).context("install SIGTERM handler")?,
+                interrupt: signal(SignalKind::interrupt()).context("install SIGINT handler")?,
+                hangup: signal(SignalKind::hangup()).context("install SIGHUP handler")?,
+            })
+        }
+
+        #[cfg(not(unix))]
+        {
+            Ok(Self {})
         }
-        Ok(())
     }
 
-    #[cfg(not(unix))]
-    {
-        tokio::signal::ctrl_c().await.context("wait for Ctrl-C")
+    async fn recv(&mut self) -> Result<Signal> {
+        #[cfg(unix)]
+        {
+            tokio::select! {
+                _ = self.terminate.recv() => warn!("received SIGTERM"),
+                _ = self.interrupt.recv() => warn!("received SIGINT"),
+                _ = self.hangup.recv() => {
+                    info!("received SIGHUP; reloading configuration");
+                    return Ok(Signal::Reload);
+                }
+            }
+            Ok(Signal::Shutdown)
+        }
+
+        #[cfg(not(unix))]
+        {
+            tokio::signal::ctrl_c().await.context("wait for Ctrl-C")?;
+            Ok(Signal::Shutdown)
+        }
     }
 }
+
+/// Send SIGHUP to every `d2s run` process whose `--config` resolves to the
+/// same file as `config`. Returns the signalled pids.
+#[cfg(unix)]
+fn signal_running_instances(config: &Path) -> Result<Vec<u32>> {
+    let wanted = std::fs::canonicalize(config)
+        .with_context(|| format!("resolve configuration {}", config.display()))?;
+    let own = std::process::id();
+    let mut pids = Vec::new();
+    for entry in std::fs::read_dir("/proc").context("read /proc")?.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|name| name.parse::<u32>().ok()) else { continue; };
+        if pid == own {
+            continue;
+        }
+        let Ok(raw) = std::fs::read(entry.path().join("cmdline")) else { continue; };
+        let argv: Vec<String> = raw
+            .split(|byte| *byte == 0)
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect();
+        let is_d2s = argv
+            .first()
+            .and_then(|arg0| Path::new(arg0).file_name())
+            .is_some_and(|name| name == "d2s");
+        if !is_d2s || !argv.iter().any(|arg| arg == "run") {
+            continue;
+        }
+        let config_arg = argv
+            .windows(2)
+            .find(|pair| pair[0] == "--config" || pair[0] == "-c")
+            .map(|pair| pair[1].clone())
+            .or_else(|| argv.iter().find_map(|arg| arg.strip_prefix("--config=").map(str::to_string)))
+            .unwrap_or_else(|| "d2s.toml".to_string());
+        let resolved = std::fs::read_link(entry.path().join("cwd"))
+            .map(|cwd| cwd.join(&config_arg))
+            .unwrap_or_else(|_| PathBuf::from(&config_arg));
+        if std::fs::canonicalize(resolved).ok().as_ref() != Some(&wanted) {
+            continue;
+        }
+        let status = std::process::Command::new("kill")
+            .args(["-HUP", &pid.to_string()])
+            .status()
+            .context("run kill -HUP")?;
+        if status.success() {
+            pids.push(pid);
+        } else {
+            warn!(pid, %status, "unable to signal d2s");
+        }
+    }
+    Ok(pids)
+}
+
+#[cfg(not(unix))]
+fn signal_running_instances(_config: &Path) -> Result<Vec<u32>> {
+    anyhow::bail!("reload is only supported on Unix; restart d2s instead")
+}
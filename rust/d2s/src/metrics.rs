@@ -0,0 +1,219 @@
+//! Optional Prometheus metrics listener (`metrics_listen`).
+//!
+//! A minimal HTTP/1.1 responder: `GET /metrics` returns the text exposition
+//! format, everything else gets 404. It is meant for a collector on the same
+//! device, so the listener must be loopback like the SOCKS5 one.
+
+use crate::{
+    backend::{BackendPool, BackendState},
+    status::RuntimeStats,
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::watch,
+};
+use tracing::debug;
+
+/// Upper bounds of the probe RTT histograms, seconds.
+const RTT_BUCKETS: [f64; 10] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const MAX_REQUEST_HEAD: usize = 4096;
+
+#[derive(Clone, Debug, Default)]
+pub struct Histogram {
+    counts: [u64; RTT_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, value: Duration) {
+        let secs = value.as_secs_f64();
+        if let Some(i) = RTT_BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.counts[i] += 1;
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+}
+
+/// Probe and failure history of one backend, kept by the pool.
+#[derive(Clone, Debug, Default)]
+pub struct BackendMetrics {
+    pub socks_rtt: Histogram,
+    pub internet_rtt: Histogram,
+    /// Probe outcome -> count.
+    pub probes: BTreeMap<&'static str, u64>,
+    /// Runtime failure class -> count.
+    pub failures: BTreeMap<&'static str, u64>,
+}
+
+struct Out(String);
+
+impl Out {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.0, "# HELP {name} {help}\n# TYPE {name} {kind}");
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl std::fmt::Display) {
+        self.0.push_str(name);
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(k, v)| format!("{k}=\"{}\"", v.replace('\\', "\\\\").replace('"', "\\\"")))
+                .collect();
+            let _ = write!(self.0, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.0, " {value}");
+    }
+
+    fn histogram(&mut self, name: &str, backend: &str, h: &Histogram) {
+        let mut cumulative = 0;
+        for (bound, n) in RTT_BUCKETS.iter().zip(h.counts) {
+            cumulative += n;
+            self.sample(&format!("{name}_bucket"), &[("backend", backend), ("le", &bound.to_string())], cumulative);
+        }
+        self.sample(&format!("{name}_bucket"), &[("backend", backend), ("le", "+Inf")], h.count);
+        self.sample(&format!("{name}_sum"), &[("backend", backend)], h.sum);
+        self.sample(&format!("{name}_count"), &[("backend", backend)], h.count);
+    }
+}
+
+pub async fn render(pool: &BackendPool, stats: &RuntimeStats) -> String {
+    let mut out = Out(String::with_capacity(4096));
+    let counters = [
+        ("d2s_accepted_connections_total", "Accepted client connections.", &stats.accepted_connections),
+        ("d2s_completed_connections_total", "Client connections that ended cleanly.", &stats.completed_connections),
+        ("d2s_failed_connections_total", "Client connections that ended with an error.", &stats.failed_connections),
+        ("d2s_connection_limit_drops_total", "Clients dropped at max_connections.", &stats.connection_limit_drops),
+        ("d2s_upstream_connections_total", "Connections routed through a SOCKS5 backend.", &stats.upstream_connections),
+        ("d2s_direct_connections_total", "Connections routed DIRECT.", &stats.direct_connections),
+        ("d2s_relay_stalled_total", "Relays closed waiting for a first response.", &stats.relay_stalled),
+        ("d2s_relay_forced_closes_total", "Relays closed by the supervisor.", &stats.relay_forced_closes),
+        ("d2s_session_pool_hits_total", "SOCKS5 attempts that used a pooled session.", &stats.session_pool_hits),
+        ("d2s_session_pool_misses_total", "SOCKS5 attempts that found no pooled session.", &stats.session_pool_misses),
+        ("d2s_session_pool_opened_total", "Pooled SOCKS5 sessions negotiated.", &stats.session_pool_opened),
+        ("d2s_session_pool_discarded_total", "Pooled SOCKS5 sessions closed unused.", &stats.session_pool_discarded),
+        ("d2s_route_races_total", "Raced routes that started more than one contestant.", &stats.route_races),
+        ("d2s_route_race_later_wins_total", "Raced routes won by a contestant other than the first.", &stats.route_race_later_wins),
+    ];
+    for (name, help, value) in counters {
+        out.family(name, "counter", help);
+        out.sample(name, &[], value.load(Ordering::Relaxed));
+    }
+    out.family("d2s_active_connections", "gauge", "Open client connections.");
+    out.sample("d2s_active_connections", &[], stats.active_connections.load(Ordering::Relaxed));
+    out.family("d2s_bytes_total", "counter", "Bytes relayed, by direction.");
+    out.sample("d2s_bytes_total", &[("direction", "up")], stats.client_to_remote_bytes.load(Ordering::Relaxed));
+    out.sample("d2s_bytes_total", &[("direction", "down")], stats.remote_to_client_bytes.load(Ordering::Relaxed));
+
+    let snapshots = pool.snapshots().await;
+    out.family("d2s_backend_state", "gauge", "1 for the current health state of each backend.");
+    for b in &snapshots {
+        for state in [BackendState::Unknown, BackendState::Green, BackendState::Yellow, BackendState::Red] {
+            let label = format!("{state:?}").to_ascii_lowercase();
+            out.sample("d2s_backend_state", &[("backend", &b.address), ("state", &label)], u8::from(b.state == state));
+        }
+    }
+    out.family("d2s_backend_latency_seconds", "gauge", "Last measured latency of each backend.");
+    for b in &snapshots {
+        for (kind, ms) in [("socks", b.latency_ms), ("internet", b.internet_latency_ms), ("runtime_ewma", b.runtime_latency_ewma_ms)] {
+            if let Some(ms) = ms {
+                out.sample("d2s_backend_latency_seconds", &[("backend", &b.address), ("kind", kind)], ms / 1000.0);
+            }
+        }
+    }
+    out.family("d2s_backend_connections_total", "counter", "Connections through each backend, by result.");
+    for b in &snapshots {
+        out.sample("d2s_backend_connections_total", &[("backend", &b.address), ("result", "selected")], b.selected_connections);
+        out.sample("d2s_backend_connections_total", &[("backend", &b.address), ("result", "ok")], b.successful_connections);
+        out.sample("d2s_backend_connections_total", &[("backend", &b.address), ("result", "fail")], b.failed_connections);
+    }
+
+    let metrics = pool.metrics().await;
+    out.family("d2s_backend_socks_rtt_seconds", "histogram", "SOCKS5 reachability probe round trips.");
+    for (backend, m) in &metrics {
+        out.histogram("d2s_backend_socks_rtt_seconds", backend, &m.socks_rtt);
+    }
+    out.family("d2s_backend_internet_rtt_seconds", "histogram", "Full Internet probe round trips.");
+    for (backend, m) in &metrics {
+        out.histogram("d2s_backend_internet_rtt_seconds", backend, &m.internet_rtt);
+    }
+    out.family("d2s_backend_probes_total", "counter", "Health probes by outcome.");
+    for (backend, m) in &metrics {
+        for (outcome, n) in &m.probes {
+            out.sample("d2s_backend_probes_total", &[("backend", backend), ("outcome", outcome)], n);
+        }
+    }
+    out.family("d2s_backend_runtime_failures_total", "counter", "Runtime failures by class (target_path, soft, hard).");
+    for (backend, m) in &metrics {
+        for (class, n) in &m.failures {
+            out.sample("d2s_backend_runtime_failures_total", &[("backend", backend), ("class", class)], n);
+        }
+    }
+    out.0
+}
+
+pub async fn serve(
+    listener: TcpListener,
+    pool: BackendPool,
+    stats: Arc<RuntimeStats>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        tokio::select! {
+            changed = shutdown.changed() => {
+                if changed.is_err() || *shutdown.borrow() {
+                    break;
+                }
+            }
+            accepted = listener.accept() => {
+                let Ok((stream, peer)) = accepted else { continue; };
+                let pool = pool.clone();
+                let stats = stats.clone();
+                tokio::spawn(async move {
+                    if let Err(error) = respond(stream, &pool, &stats).await {
+                        debug!(%peer, %error, "metrics request failed");
+                    }
+                });
+            }
+        }
+    }
+}
+
+/// Read an HTTP request head; `None` if the peer closed before sending one.
+pub(crate) async fn read_request_head(stream: &mut TcpStream) -> std::io::Result<Option<Vec<u8>>> {
+    let mut head = Vec::with_capacity(512);
+    let mut buf = [0u8; 512];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < MAX_REQUEST_HEAD {
+        let n = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf))
+            .await
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "request head timeout"))??;
+        if n == 0 {
+            return Ok(None);
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(Some(head))
+}
+
+async fn respond(mut stream: TcpStream, pool: &BackendPool, stats: &RuntimeStats) -> std::io::Result<()> {
+    let Some(head) = read_request_head(&mut stream).await? else { return Ok(()); };
+    let (status, body) = if head.starts_with(b"GET /metrics ") || head.starts_with(b"GET /metrics?") {
+        ("200 OK", render(pool, stats).await)
+    } else {
+        ("404 Not Found", String::new())
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
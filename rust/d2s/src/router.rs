@@ -1,7 +1,7 @@
 use crate::{
     backend::BackendPool,
-    config::Config,
-    socks5::{connect_via_socks5, RuntimeFailureClass},
+    config::{Config, RouteStrategy},
+    socks5::{connect_via_socks5, socks5_connect, RuntimeFailureClass, SocksClientError},
     status::RuntimeStats,
     target::TargetAddr,
 };
@@ -14,7 +14,7 @@ use std::{
     },
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
-use tokio::{net::TcpStream, time::Instant as TokioInstant};
+use tokio::{net::TcpStream, task::JoinSet, time::Instant as TokioInstant};
 use tracing::{debug, info, warn};
 
 const DIRECT_FAILURE_THRESHOLD: u32 = 3;
@@ -59,7 +59,6 @@ impl DirectHealth {
 
 #[derive(Clone)]
 pub struct Router {
-    config: Arc<Config>,
     pool: BackendPool,
     stats: Arc<RuntimeStats>,
     direct_fallback_active: Arc<AtomicBool>,
@@ -67,9 +66,10 @@ pub struct Router {
 }
 
 impl Router {
-    pub fn new(config: Arc<Config>, pool: BackendPool, stats: Arc<RuntimeStats>) -> Self {
+    /// The router reads its configuration from the pool on every request, so
+    /// a reload applies to the next connection.
+    pub fn new(pool: BackendPool, stats: Arc<RuntimeStats>) -> Self {
         Self {
-            config,
             pool,
             stats,
             direct_fallback_active: Arc::new(AtomicBool::new(false)),
@@ -78,12 +78,16 @@ impl Router {
     }
 
     pub async fn connect(&self, target: &TargetAddr) -> Result<RoutedStream> {
-        self.reject_recursive_target(target)?;
+        let config = self.pool.config();
+        reject_recursive_target(&config, target)?;
 
         // dnscrypt-proxy uses a plain SOCKS Dialer in several paths and that
         // dial can outlive the caller context. Keep route establishment inside
         // DNSCrypt's own query timeout.
-        let deadline = TokioInstant::now() + self.config.route_budget();
+        let deadline = TokioInstant::now() + config.route_budget();
+        if config.route_strategy == RouteStrategy::Race {
+            return self.connect_race(config, target, deadline).await;
+        }
         let candidates = self.pool.candidate_order().await;
         let single_backend_mode = candidates.len() == 1;
         let mut failures = Vec::new();
@@ -95,19 +99,13 @@ impl Router {
                 break;
             }
             let remaining = deadline - now;
-            let attempt_timeout = self.config.backend_attempt_timeout().min(remaining);
+            let attempt_timeout = config.backend_attempt_timeout().min(remaining);
 
             self.pool.mark_attempt(backend).await;
             let started = Instant::now();
             let attempt = tokio::time::timeout(
                 attempt_timeout,
-                connect_via_socks5(
-                    backend,
-                    target,
-                    self.config.connect_timeout(),
-                    self.config.upstream_handshake_timeout(),
-                    self.config.tcp_nodelay,
-                ),
+                self.open_socks(&config, backend, target),
             )
             .await;
             match attempt {
@@ -138,17 +136,11 @@ impl Router {
                             let now = TokioInstant::now();
                             if now < deadline {
                                 let remaining = deadline - now;
-                                let retry_timeout = self.config.backend_attempt_timeout().min(remaining);
+                                let retry_timeout = config.backend_attempt_timeout().min(remaining);
                                 let retry_started = Instant::now();
                                 match tokio::time::timeout(
                                     retry_timeout,
-                                    connect_via_socks5(
-                                        backend,
-                                        target,
-                                        self.config.connect_timeout(),
-                                        self.config.upstream_handshake_timeout(),
-                                        self.config.tcp_nodelay,
-                                    ),
+                                    self.open_socks(&config, backend, target),
                                 )
                                 .await
                                 {
@@ -204,7 +196,7 @@ impl Router {
             }
         }
 
-        if !self.config.direct_fallback {
+        if !self.pool.direct_fallback() {
             return Err(anyhow!(
                 "no SOCKS5 backend could reach {target}; direct fallback is disabled; failures: {}",
                 failures.join(" | ")
@@ -222,7 +214,7 @@ impl Router {
         }
 
         self.note_direct_fallback(target, &failures);
-        let stream = match connect_direct(target, &self.config, deadline).await {
+        let stream = match connect_direct(target, &config, deadline).await {
             Ok(stream) => stream,
             Err(error) => {
                 self.direct_health.note_failure();
@@ -237,6 +229,154 @@ impl Router {
         })
     }
 
+    /// Happy Eyeballs over the candidate order, DIRECT last when allowed. A
+    /// contestant that loses is cancelled and leaves no health evidence; the
+    /// winner's CONNECT time feeds the runtime EWMA like a sequential success.
+    async fn connect_race(&self, config: Arc<Config>, target: &TargetAddr, deadline: TokioInstant) -> Result<RoutedStream> {
+        let mut contestants: std::collections::VecDeque<Option<SocketAddr>> =
+            self.pool.candidate_order().await.into_iter().map(Some).collect();
+        let direct_allowed = self.pool.direct_fallback() && self.direct_health.allowed();
+        if direct_allowed {
+            contestants.push_back(None);
+        }
+        let mut running = JoinSet::new();
+        let mut failures = Vec::new();
+        let mut launched = 0u32;
+        let stagger = config.race_stagger();
+        let mut next_launch = TokioInstant::now();
+
+        while !contestants.is_empty() || !running.is_empty() {
+            tokio::select! {
+                _ = tokio::time::sleep_until(next_launch), if !contestants.is_empty() => {
+                    let Some(contestant) = contestants.pop_front() else { continue; };
+                    launched += 1;
+                    if launched == 2 {
+                        self.stats.route_races.fetch_add(1, Ordering::Relaxed);
+                    }
+                    if let Some(backend) = contestant {
+                        self.pool.mark_attempt(backend).await;
+                    }
+                    let router = self.clone();
+                    let config = config.clone();
+                    let target = target.clone();
+                    running.spawn(async move {
+                        let started = Instant::now();
+                        let result = match contestant {
+                            Some(backend) => {
+                                let remaining = deadline.saturating_duration_since(TokioInstant::now());
+                                let attempt_timeout = config.backend_attempt_timeout().min(remaining);
+                                match tokio::time::timeout(attempt_timeout, router.open_socks(&config, backend, &target)).await {
+                                    Ok(Ok(stream)) => Ok(stream),
+                                    Ok(Err(error)) => Err((error.runtime_failure_class(), error.to_string())),
+                                    Err(_) => Err((
+                                        RuntimeFailureClass::Soft,
+                                        format!("backend attempt exceeded {} ms", attempt_timeout.as_millis()),
+                                    )),
+                                }
+                            }
+                            None => connect_direct(&target, &config, deadline)
+                                .await
+                                .map_err(|error| (RuntimeFailureClass::Soft, format!("{error:#}"))),
+                        };
+                        (contestant, launched, started.elapsed(), result)
+                    });
+                    next_launch = TokioInstant::now() + stagger;
+                }
+                Some(joined) = running.join_next() => {
+                    let Ok((contestant, order, elapsed, result)) = joined else { continue; };
+                    match (contestant, result) {
+                        (Some(backend), Ok(stream)) => {
+                            self.pool.mark_runtime_success(backend, elapsed).await;
+                            self.stats.upstream_connections.fetch_add(1, Ordering::Relaxed);
+                            if order > 1 {
+                                self.stats.route_race_later_wins.fetch_add(1, Ordering::Relaxed);
+                            }
+                            self.note_socks_restored();
+                            debug!(%backend, %target, order, "raced connection won by SOCKS5 backend");
+                            return Ok(RoutedStream { stream, route: RouteKind::Socks, backend: Some(backend) });
+                        }
+                        (None, Ok(stream)) => {
+                            self.stats.direct_connections.fetch_add(1, Ordering::Relaxed);
+                            if order > 1 {
+                                self.stats.route_race_later_wins.fetch_add(1, Ordering::Relaxed);
+                            }
+                            self.note_direct_fallback(target, &failures);
+                            return Ok(RoutedStream { stream, route: RouteKind::Direct, backend: None });
+                        }
+                        (Some(backend), Err((class, message))) => {
+                            self.pool.mark_runtime_failure(backend, class, &message).await;
+                            failures.push(format!("{backend}: {message}"));
+                            // Like Happy Eyeballs: a failure starts the next
+                            // contestant without waiting for the stagger.
+                            next_launch = TokioInstant::now();
+                        }
+                        (None, Err((_, message))) => {
+                            self.direct_health.note_failure();
+                            failures.push(format!("DIRECT: {message}"));
+                            next_launch = TokioInstant::now();
+                        }
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline) => {
+                    failures.push("dnscrypt route budget exhausted during the race".to_string());
+                    break;
+                }
+            }
+        }
+
+        let direct_note = if direct_allowed {
+            ""
+        } else if self.pool.direct_fallback() {
+            "; DIRECT temporarily suppressed after repeated failures"
+        } else {
+            "; direct fallback is disabled"
+        };
+        Err(anyhow!(
+            "no route to {target} won the race{direct_note}; failures: {}",
+            failures.join(" | ")
+        ))
+    }
+
+    /// CONNECT over a pooled session when one is ready, otherwise over a
+    /// fresh connection.
+    async fn open_socks(
+        &self,
+        config: &Config,
+        backend: SocketAddr,
+        target: &TargetAddr,
+    ) -> std::result::Result<TcpStream, SocksClientError> {
+        if config.session_pool_size > 0 {
+            let (session, discarded) = self.pool.take_pooled_session(backend).await;
+            self.stats
+                .session_pool_discarded
+                .fetch_add(discarded as u64, Ordering::Relaxed);
+            if let Some(session) = session {
+                match socks5_connect(session, target, config.upstream_handshake_timeout()).await {
+                    // The backend closed the idle session just as we used it.
+                    // That says nothing about its health; connect anew.
+                    Err(SocksClientError::Io(_, error)) => {
+                        self.stats.session_pool_discarded.fetch_add(1, Ordering::Relaxed);
+                        debug!(%backend, %error, "pooled SOCKS5 session was closed; reconnecting");
+                    }
+                    result => {
+                        self.stats.session_pool_hits.fetch_add(1, Ordering::Relaxed);
+                        return result;
+                    }
+                }
+            }
+            self.stats.session_pool_misses.fetch_add(1, Ordering::Relaxed);
+        }
+        connect_via_socks5(
+            backend,
+            config.backend_auth(backend),
+            target,
+            config.connect_timeout(),
+            config.upstream_handshake_timeout(),
+            config.tcp_nodelay,
+        )
+        .await
+    }
+
     pub async fn report_relay_failure(
         &self,
         route: RouteKind,
@@ -280,27 +420,27 @@ impl Router {
             info!("SOCKS5 routing restored; leaving DIRECT fallback");
         }
     }
+}
 
-    fn reject_recursive_target(&self, target: &TargetAddr) -> Result<()> {
-        match target {
-            TargetAddr::Ip(addr) => {
-                let listener_loop = addr.port() == self.config.listen.port()
-                    && addr.ip().is_loopback()
-                    && (self.config.listen.ip().is_loopback() || self.config.listen.ip().is_unspecified());
-                if listener_loop || self.config.backends.contains(addr) {
-                    return Err(anyhow!("refusing recursive D2S target {addr}"));
-                }
+fn reject_recursive_target(config: &Config, target: &TargetAddr) -> Result<()> {
+    match target {
+        TargetAddr::Ip(addr) => {
+            let listener_loop = addr.port() == config.listen.port()
+                && addr.ip().is_loopback()
+                && (config.listen.ip().is_loopback() || config.listen.ip().is_unspecified());
+            if listener_loop || config.is_backend(addr) {
+                return Err(anyhow!("refusing recursive D2S target {addr}"));
             }
-            TargetAddr::Domain(host, port) => {
-                if *port == self.config.listen.port()
-                    && (host.eq_ignore_ascii_case("localhost") || host == "127.0.0.1" || host == "::1")
-                {
-                    return Err(anyhow!("refusing recursive D2S target {host}:{port}"));
-                }
+        }
+        TargetAddr::Domain(host, port) => {
+            if *port == config.listen.port()
+                && (host.eq_ignore_ascii_case("localhost") || host == "127.0.0.1" || host == "::1")
+            {
+                return Err(anyhow!("refusing recursive D2S target {host}:{port}"));
             }
         }
-        Ok(())
     }
+    Ok(())
 }
 
 async fn connect_direct(target: &TargetAddr, config: &Config, deadline: TokioInstant) -> Result<TcpStream> {
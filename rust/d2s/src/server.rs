@@ -1,29 +1,77 @@
 use crate::{
-    backend::BackendPool,
+    backend::{BackendPool, ReloadReport},
     config::Config,
+    control::{self, ControlState, RelayEvent},
+    metrics,
     relay::{relay_bidirectional, RelayEndpoint, RelayTermination},
-    router::Router,
+    router::{RouteKind, Router},
     socks5::{read_client_request, send_failure, send_success},
+    session_pool,
     status::{status_writer, RuntimeStats},
+    target::TargetAddr,
 };
 use anyhow::{Context, Result};
-use std::{net::SocketAddr, sync::{Arc, atomic::Ordering}, time::Duration};
+use std::{net::SocketAddr, sync::{Arc, atomic::Ordering}, time::{Duration, Instant}};
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::{watch, Semaphore},
+    sync::{broadcast, watch, Notify, Semaphore},
     task::{JoinHandle, JoinSet},
 };
 use tracing::{debug, error, info, warn};
 
 pub struct RunningServer {
     pub listen_addr: SocketAddr,
+    pub metrics_addr: Option<SocketAddr>,
+    pub control_addr: Option<SocketAddr>,
     pub pool: BackendPool,
     pub stats: Arc<RuntimeStats>,
+    reload_requested: Arc<Notify>,
     shutdown_tx: watch::Sender<bool>,
     task: JoinHandle<Result<()>>,
 }
 
 impl RunningServer {
+    /// Apply a new configuration to the running server. Backends, timeouts,
+    /// probe targets and `direct_fallback` take effect for the next connection;
+    /// open relays are left alone unless their backend was removed, in which
+    /// case they get `shutdown_grace_period_ms` to finish. The listeners,
+    /// `max_connections`, `log_level` and the status file settings only change
+    /// on restart.
+    pub async fn reload(&self, mut config: Config) -> Result<ReloadReport> {
+        let current = self.pool.config();
+        if config.listen != current.listen && config.listen.port() != 0 {
+            warn!(old = %current.listen, new = %config.listen, "D2S listener change requires a restart; keeping the current listener");
+        }
+        config.listen = current.listen;
+        for (name, changed) in [
+            ("metrics_listen", config.metrics_listen != current.metrics_listen),
+            ("control_listen", config.control_listen != current.control_listen),
+            ("max_connections", config.max_connections != current.max_connections),
+            ("log_level", config.log_level != current.log_level),
+            ("status_file", config.status_file != current.status_file),
+            ("status_interval_secs", config.status_interval_secs != current.status_interval_secs),
+        ] {
+            if changed {
+                warn!(setting = name, "setting change requires a D2S restart");
+            }
+        }
+        config.validate()?;
+        let report = self.pool.reload(Arc::new(config)).await?;
+        info!(
+            kept = report.kept.len(),
+            added = ?report.added,
+            removed = ?report.removed,
+            "D2S configuration reloaded"
+        );
+        Ok(report)
+    }
+
+    /// Resolves when the control API asks for a reload; the caller rereads
+    /// the configuration and passes it to [`RunningServer::reload`].
+    pub async fn reload_requested(&self) {
+        self.reload_requested.notified().await;
+    }
+
     pub async fn shutdown(self) -> Result<()> {
         let _ = self.shutdown_tx.send(true);
         self.task.await.context("D2S server task join failed")?
@@ -38,6 +86,30 @@ pub async fn start(mut config: Config) -> Result<RunningServer> {
         .with_context(|| format!("bind D2S listener {requested_listen}"))?;
     let listen_addr = listener.local_addr().context("read D2S listener address")?;
     config.listen = listen_addr;
+    let metrics_listener = match config.metrics_listen {
+        Some(addr) => Some(
+            TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("bind D2S metrics listener {addr}"))?,
+        ),
+        None => None,
+    };
+    let metrics_addr = match &metrics_listener {
+        Some(listener) => Some(listener.local_addr().context("read D2S metrics listener address")?),
+        None => None,
+    };
+    let control_listener = match config.control_listen {
+        Some(addr) => Some(
+            TcpListener::bind(addr)
+                .await
+                .with_context(|| format!("bind D2S control listener {addr}"))?,
+        ),
+        None => None,
+    };
+    let control_addr = match &control_listener {
+        Some(listener) => Some(listener.local_addr().context("read D2S control listener address")?),
+        None => None,
+    };
     let config = Arc::new(config);
 
     let pool = BackendPool::new(config.clone())?;
@@ -57,28 +129,49 @@ pub async fn start(mut config: Config) -> Result<RunningServer> {
     }
 
     let stats = Arc::new(RuntimeStats::default());
-    let router = Router::new(config.clone(), pool.clone(), stats.clone());
+    let router = Router::new(pool.clone(), stats.clone());
+    let reload_requested = Arc::new(Notify::new());
+    let control = ControlState {
+        pool: pool.clone(),
+        stats: stats.clone(),
+        events: broadcast::channel(control::EVENTS_CAPACITY).0,
+        reload: reload_requested.clone(),
+    };
     let (shutdown_tx, shutdown_rx) = watch::channel(false);
     let task = tokio::spawn(run_loop(
         listener,
+        metrics_listener,
+        control_listener,
         config,
-        pool.clone(),
-        stats.clone(),
+        control,
         router,
         shutdown_rx,
     ));
 
-    Ok(RunningServer { listen_addr, pool, stats, shutdown_tx, task })
+    Ok(RunningServer {
+        listen_addr,
+        metrics_addr,
+        control_addr,
+        pool,
+        stats,
+        reload_requested,
+        shutdown_tx,
+        task,
+    })
 }
 
 async fn run_loop(
     listener: TcpListener,
+    metrics_listener: Option<TcpListener>,
+    control_listener: Option<TcpListener>,
     config: Arc<Config>,
-    pool: BackendPool,
-    stats: Arc<RuntimeStats>,
+    control: ControlState,
     router: Router,
     mut shutdown: watch::Receiver<bool>,
 ) -> Result<()> {
+    let pool = control.pool.clone();
+    let stats = control.stats.clone();
+    let events = control.events.clone();
     let semaphore = Arc::new(Semaphore::new(config.max_connections));
     let mut clients = JoinSet::new();
     let health_pool = pool.clone();
@@ -86,6 +179,7 @@ async fn run_loop(
     let health_task = tokio::spawn(async move {
         health_loop(health_pool, health_shutdown).await
     });
+    let session_task = tokio::spawn(session_pool::maintain(pool.clone(), stats.clone(), shutdown.clone()));
 
     // Keep status lifetime separate from the external shutdown signal. The
     // final `running=false` snapshot is written only after client tasks have
@@ -98,6 +192,15 @@ async fn run_loop(
         status_shutdown_rx,
     ));
 
+    let metrics_task = metrics_listener.map(|listener| {
+        info!(listen = ?listener.local_addr().ok(), "D2S metrics listener is ready");
+        tokio::spawn(metrics::serve(listener, pool.clone(), stats.clone(), shutdown.clone()))
+    });
+    let control_task = control_listener.map(|listener| {
+        info!(listen = ?listener.local_addr().ok(), "D2S control listener is ready");
+        tokio::spawn(control::serve(listener, control, shutdown.clone()))
+    });
+
     info!(listen = %config.listen, "D2S SOCKS5 listener is ready");
 
     loop {
@@ -125,13 +228,15 @@ async fn run_loop(
                                 continue;
                             }
                         };
-                        let config = config.clone();
+                        let config = pool.config();
+                        let pool = pool.clone();
                         let router = router.clone();
                         let stats = stats.clone();
+                        let events = events.clone();
                         clients.spawn(async move {
                             let _permit = permit;
                             let _active = stats.begin_connection();
-                            let result = handle_client(stream, peer, config, router, stats.clone()).await;
+                            let result = handle_client(stream, peer, config, &pool, router, stats.clone(), &events).await;
                             match result {
                                 Ok(()) => { stats.completed_connections.fetch_add(1, Ordering::Relaxed); }
                                 Err(error) => {
@@ -151,7 +256,7 @@ async fn run_loop(
     }
 
     info!(active_clients = clients.len(), "D2S shutdown requested");
-    let grace = tokio::time::sleep(config.shutdown_grace_period());
+    let grace = tokio::time::sleep(pool.config().shutdown_grace_period());
     tokio::pin!(grace);
     while !clients.is_empty() {
         tokio::select! {
@@ -169,6 +274,10 @@ async fn run_loop(
         }
     }
     let _ = health_task.await;
+    let _ = session_task.await;
+    for task in [metrics_task, control_task].into_iter().flatten() {
+        let _ = task.await;
+    }
     let _ = status_shutdown_tx.send(true);
     let _ = status_task.await;
     Ok(())
@@ -178,9 +287,12 @@ async fn handle_client(
     mut client: TcpStream,
     peer: SocketAddr,
     config: Arc<Config>,
+    pool: &BackendPool,
     router: Router,
     stats: Arc<RuntimeStats>,
+    events: &broadcast::Sender<RelayEvent>,
 ) -> Result<()> {
+    let started = Instant::now();
     let _ = client.set_nodelay(config.tcp_nodelay);
     let target = match read_client_request(&mut client, config.client_handshake_timeout()).await {
         Ok(target) => target,
@@ -193,6 +305,7 @@ async fn handle_client(
     let routed = match router.connect(&target).await {
         Ok(routed) => routed,
         Err(error) => {
+            publish_event(events, &target, None, None, (0, 0), started, "no_route");
             let _ = tokio::time::timeout(
                 config.client_handshake_timeout(),
                 send_failure(&mut client, 0x04),
@@ -207,13 +320,30 @@ async fn handle_client(
         .await
         .map_err(|_| anyhow::anyhow!("SOCKS5 success reply to {peer} timed out"))??;
 
-    let report = relay_bidirectional(
+    let relay = relay_bidirectional(
         client,
         routed.stream,
         config.relay_first_response_timeout(),
         config.relay_half_close_timeout(),
-    )
-    .await;
+    );
+    let report = match routed.backend {
+        Some(backend) => {
+            let mut retired = pool.retired_signal(backend).await;
+            tokio::select! {
+                report = relay => report,
+                _ = async {
+                    let _ = retired.wait_for(|retired| *retired).await;
+                    tokio::time::sleep(pool.config().shutdown_grace_period()).await;
+                } => {
+                    stats.relay_forced_closes.fetch_add(1, Ordering::Relaxed);
+                    publish_event(events, &target, Some(routed.route), Some(backend), (0, 0), started, "backend_removed");
+                    debug!(%peer, %target, %backend, "closed relay through a backend removed by reload");
+                    return Ok(());
+                }
+            }
+        }
+        None => relay.await,
+    };
 
     stats
         .client_to_remote_bytes
@@ -228,6 +358,23 @@ async fn handle_client(
         stats.relay_remote_eof.fetch_add(1, Ordering::Relaxed);
     }
 
+    let outcome = match &report.termination {
+        RelayTermination::Clean => "clean",
+        RelayTermination::FirstResponseTimeout => "stalled",
+        RelayTermination::HalfCloseTimeout { .. } => "half_close_timeout",
+        RelayTermination::IoError { endpoint: RelayEndpoint::Client, .. } => "client_error",
+        RelayTermination::IoError { endpoint: RelayEndpoint::Remote, .. } => "remote_error",
+    };
+    publish_event(
+        events,
+        &target,
+        Some(routed.route),
+        routed.backend,
+        (report.client_to_remote, report.remote_to_client),
+        started,
+        outcome,
+    );
+
     let no_downstream_after_request =
         report.client_to_remote > 0 && report.remote_to_client == 0;
 
@@ -345,6 +492,33 @@ async fn handle_client(
     }
 }
 
+fn publish_event(
+    events: &broadcast::Sender<RelayEvent>,
+    target: &TargetAddr,
+    route: Option<RouteKind>,
+    backend: Option<SocketAddr>,
+    (client_to_remote, remote_to_client): (u64, u64),
+    started: Instant,
+    outcome: &'static str,
+) {
+    if events.receiver_count() == 0 {
+        return;
+    }
+    let _ = events.send(RelayEvent {
+        unix_ms: RelayEvent::now_unix_ms(),
+        target: target.to_string(),
+        route: route.map(|route| match route {
+            RouteKind::Socks => "socks",
+            RouteKind::Direct => "direct",
+        }),
+        backend: backend.map(|backend| backend.to_string()),
+        client_to_remote,
+        remote_to_client,
+        duration_ms: started.elapsed().as_millis() as u64,
+        outcome,
+    });
+}
+
 async fn health_loop(
     pool: BackendPool,
     mut shutdown: watch::Receiver<bool>,
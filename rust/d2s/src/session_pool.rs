@@ -0,0 +1,129 @@
+//! Pre-negotiated SOCKS5 sessions: TCP connected, method negotiation (and
+//! RFC 1929 auth) done, waiting for a CONNECT.
+//!
+//! A DNS query that finds a pooled session pays one round trip to the backend
+//! for the CONNECT instead of TCP connect + greeting + CONNECT. Sessions are
+//! kept only for GREEN, enabled backends and only while DNS traffic asks for
+//! them; they are closed after `session_pool_idle_ms` because local proxies
+//! commonly drop connections that linger in the handshake phase.
+
+use crate::{backend::BackendPool, config::Backend, socks5::connect_to_socks5_server, status::RuntimeStats};
+use std::{
+    collections::VecDeque,
+    io::ErrorKind,
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+use tokio::{net::TcpStream, sync::watch, task::JoinSet};
+use tracing::debug;
+
+/// Takes older than this no longer count towards a backend's pool size.
+pub(crate) const DEMAND_WINDOW: Duration = Duration::from_secs(10);
+const REFILL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug)]
+pub(crate) struct PooledSession {
+    pub stream: TcpStream,
+    pub opened: Instant,
+}
+
+impl PooledSession {
+    /// Young enough and still silent. A backend that gave up on the session
+    /// has closed or reset it, and any byte before our CONNECT would be a
+    /// protocol violation anyway.
+    pub fn usable(&self, idle: Duration, now: Instant) -> bool {
+        if now.duration_since(self.opened) >= idle {
+            return false;
+        }
+        let mut probe = [0u8; 1];
+        matches!(self.stream.try_read(&mut probe), Err(error) if error.kind() == ErrorKind::WouldBlock)
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct SessionSlot {
+    pub sessions: VecDeque<PooledSession>,
+    /// Opens in flight, so a slow backend is not asked twice.
+    pub pending: usize,
+    /// Recent takes, hits and misses alike.
+    pub demand: VecDeque<Instant>,
+}
+
+/// Sessions to keep for one backend: enough for the takes expected within one
+/// idle period at the recent rate, and at least one while there is any demand.
+pub(crate) fn target_size(recent_takes: usize, idle: Duration, max: usize) -> usize {
+    if recent_takes == 0 || max == 0 {
+        return 0;
+    }
+    let per_idle = (recent_takes as f64 * idle.as_secs_f64() / DEMAND_WINDOW.as_secs_f64()).ceil() as usize;
+    per_idle.clamp(1, max)
+}
+
+pub(crate) async fn maintain(pool: BackendPool, stats: Arc<RuntimeStats>, mut shutdown: watch::Receiver<bool>) {
+    let mut ticker = tokio::time::interval(REFILL_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut opening = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {}
+            _ = pool.wait_for_session_wake() => {}
+            Some(_) = opening.join_next(), if !opening.is_empty() => continue,
+            changed = shutdown.changed() => {
+                if changed.is_err() || *shutdown.borrow() {
+                    break;
+                }
+                continue;
+            }
+        }
+
+        let (plan, discarded) = pool.session_refill_plan().await;
+        stats.session_pool_discarded.fetch_add(discarded as u64, Ordering::Relaxed);
+        for (backend, count) in plan {
+            for _ in 0..count {
+                opening.spawn(open(pool.clone(), stats.clone(), backend.clone()));
+            }
+        }
+    }
+    opening.abort_all();
+}
+
+async fn open(pool: BackendPool, stats: Arc<RuntimeStats>, backend: Backend) {
+    let config = pool.config();
+    let result = connect_to_socks5_server(
+        backend.address,
+        backend.auth(),
+        config.connect_timeout(),
+        config.upstream_handshake_timeout(),
+        config.tcp_nodelay,
+    )
+    .await;
+    match result {
+        Ok(stream) => {
+            if pool.store_pooled_session(&backend, stream).await {
+                stats.session_pool_opened.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Err(error) => {
+            // Health is the probes' call; a failed pool open only means the
+            // next query connects the usual way.
+            pool.pooled_session_failed(backend.address).await;
+            debug!(backend = %backend.address, %error, "unable to open pooled SOCKS5 session");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pool_size_follows_recent_demand() {
+        let idle = Duration::from_secs(3);
+        assert_eq!(target_size(0, idle, 4), 0);
+        assert_eq!(target_size(1, idle, 4), 1);
+        assert_eq!(target_size(10, idle, 4), 3);
+        assert_eq!(target_size(100, idle, 4), 4);
+        assert_eq!(target_size(100, idle, 0), 0);
+    }
+}
@@ -25,6 +25,8 @@ pub enum SocksClientError {
     Io(&'static str, #[source] std::io::Error),
     #[error("invalid SOCKS5 response: {0}")]
     Protocol(String),
+    #[error("SOCKS5 username/password authentication rejected with status 0x{0:02x}")]
+    AuthRejected(u8),
     #[error("SOCKS5 CONNECT failed with reply code 0x{0:02x}")]
     ConnectReply(u8),
 }
@@ -44,8 +46,11 @@ impl SocksClientError {
             {
                 RuntimeFailureClass::Soft
             }
-            Self::BackendConnect(_) | Self::Io(_, _) | Self::Protocol(_) | Self::ConnectReply(_) =>
-                RuntimeFailureClass::Hard,
+            Self::BackendConnect(_)
+            | Self::Io(_, _)
+            | Self::Protocol(_)
+            | Self::AuthRejected(_)
+            | Self::ConnectReply(_) => RuntimeFailureClass::Hard,
         }
     }
 
@@ -83,8 +88,50 @@ where
     }
 }
 
+/// Method negotiation, plus the RFC 1929 sub-negotiation when the backend
+/// has credentials. With credentials both NO-AUTH and USERNAME/PASSWORD are
+/// offered, so a backend that does not require them still works.
+async fn negotiate(
+    stream: &mut TcpStream,
+    auth: Option<(&str, &str)>,
+    handshake_timeout: Duration,
+) -> std::result::Result<(), SocksClientError> {
+    let greeting: &[u8] = if auth.is_some() { &[0x05, 0x02, 0x00, 0x02] } else { &[0x05, 0x01, 0x00] };
+    io_step(handshake_timeout, "greeting write", stream.write_all(greeting)).await?;
+    let mut reply = [0u8; 2];
+    io_step(handshake_timeout, "greeting read", stream.read_exact(&mut reply)).await?;
+    match (reply, auth) {
+        ([0x05, 0x00], _) => Ok(()),
+        ([0x05, 0x02], Some((username, password))) => {
+            let mut request = Vec::with_capacity(3 + username.len() + password.len());
+            request.push(0x01);
+            request.push(username.len() as u8);
+            request.extend_from_slice(username.as_bytes());
+            request.push(password.len() as u8);
+            request.extend_from_slice(password.as_bytes());
+            io_step(handshake_timeout, "auth request write", stream.write_all(&request)).await?;
+            let mut status = [0u8; 2];
+            io_step(handshake_timeout, "auth reply read", stream.read_exact(&mut status)).await?;
+            if status[0] != 0x01 {
+                return Err(SocksClientError::Protocol(format!("auth reply version is {}", status[0])));
+            }
+            if status[1] != 0x00 {
+                return Err(SocksClientError::AuthRejected(status[1]));
+            }
+            Ok(())
+        }
+        _ => Err(SocksClientError::Protocol(format!(
+            "expected [05,{}], got [{:02x},{:02x}]",
+            if auth.is_some() { "00|02" } else { "00" },
+            reply[0],
+            reply[1]
+        ))),
+    }
+}
+
 pub async fn connect_via_socks5(
     backend: SocketAddr,
+    auth: Option<(&str, &str)>,
     target: &TargetAddr,
     connect_timeout: Duration,
     handshake_timeout: Duration,
@@ -96,17 +143,17 @@ pub async fn connect_via_socks5(
         Err(_) => return Err(SocksClientError::Timeout("backend TCP connect")),
     };
     let _ = stream.set_nodelay(tcp_nodelay);
+    negotiate(&mut stream, auth, handshake_timeout).await?;
+    socks5_connect(stream, target, handshake_timeout).await
+}
 
-    io_step(handshake_timeout, "greeting write", stream.write_all(&[0x05, 0x01, 0x00])).await?;
-    let mut greeting = [0u8; 2];
-    io_step(handshake_timeout, "greeting read", stream.read_exact(&mut greeting)).await?;
-    if greeting != [0x05, 0x00] {
-        return Err(SocksClientError::Protocol(format!(
-            "expected [05,00], got [{:02x},{:02x}]",
-            greeting[0], greeting[1]
-        )));
-    }
-
+/// CONNECT on a stream that has completed method negotiation, e.g. one from
+/// [`connect_to_socks5_server`].
+pub async fn socks5_connect(
+    mut stream: TcpStream,
+    target: &TargetAddr,
+    handshake_timeout: Duration,
+) -> std::result::Result<TcpStream, SocksClientError> {
     let mut request = vec![0x05, 0x01, 0x00];
     target
         .encode_socks5(&mut request)
@@ -126,9 +173,11 @@ pub async fn connect_via_socks5(
 }
 
 /// Stage-1 health check: prove that the local SOCKS listener accepts TCP and
-/// completes a NO-AUTH greeting, without making an Internet connection.
+/// completes the greeting (and authentication, if configured), without making
+/// an Internet connection.
 pub async fn connect_to_socks5_server(
     backend: SocketAddr,
+    auth: Option<(&str, &str)>,
     connect_timeout: Duration,
     handshake_timeout: Duration,
     tcp_nodelay: bool,
@@ -139,14 +188,7 @@ pub async fn connect_to_socks5_server(
         Err(_) => return Err(SocksClientError::Timeout("backend TCP connect")),
     };
     let _ = stream.set_nodelay(tcp_nodelay);
-    io_step(handshake_timeout, "greeting write", stream.write_all(&[0x05, 0x01, 0x00])).await?;
-    let mut greeting = [0u8; 2];
-    io_step(handshake_timeout, "greeting read", stream.read_exact(&mut greeting)).await?;
-    if greeting != [0x05, 0x00] {
-        return Err(SocksClientError::Protocol(format!(
-            "expected [05,00], got [{:02x},{:02x}]", greeting[0], greeting[1]
-        )));
-    }
+    negotiate(&mut stream, auth, handshake_timeout).await?;
     Ok(stream)
 }
 
@@ -402,5 +444,6 @@ mod tests {
             .backend_unavailable_before_handshake());
         assert!(SocksClientError::Timeout("backend TCP connect").backend_unavailable_before_handshake());
         assert!(!SocksClientError::Timeout("CONNECT reply header read").backend_unavailable_before_handshake());
+        assert_eq!(SocksClientError::AuthRejected(0x01).runtime_failure_class(), super::RuntimeFailureClass::Hard);
     }
 }
@@ -32,6 +32,16 @@ pub struct RuntimeStats {
     pub relay_remote_eof: AtomicU64,
     pub relay_client_io_errors: AtomicU64,
     pub relay_remote_io_errors: AtomicU64,
+    /// SOCKS5 attempts that found / did not find a pooled session.
+    pub session_pool_hits: AtomicU64,
+    pub session_pool_misses: AtomicU64,
+    pub session_pool_opened: AtomicU64,
+    /// Pooled sessions closed unused: expired, dead, or their backend left GREEN.
+    pub session_pool_discarded: AtomicU64,
+    /// Raced routes that started more than one contestant, and those won by
+    /// a later one.
+    pub route_races: AtomicU64,
+    pub route_race_later_wins: AtomicU64,
     next_connection_id: AtomicU64,
     active_started: StdMutex<HashMap<u64, Instant>>,
 }
@@ -53,6 +63,9 @@ impl Drop for ActiveConnectionGuard {
 #[derive(Clone, Debug, Serialize)]
 pub struct BackendSnapshot {
     pub address: String,
+    /// `username:********` when the backend uses SOCKS5 authentication.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auth: Option<String>,
     pub state: BackendState,
     pub consecutive_failures: u32,
     pub runtime_failure_streak: u32,
@@ -67,6 +80,11 @@ pub struct BackendSnapshot {
     pub selected_connections: u64,
     pub successful_connections: u64,
     pub failed_connections: u64,
+    /// Idle pre-negotiated SOCKS5 sessions.
+    pub pooled_sessions: usize,
+    /// Control API overrides.
+    pub pinned: bool,
+    pub disabled: bool,
 }
 
 #[derive(Debug, Serialize)]
@@ -95,6 +113,14 @@ pub struct StatusSnapshot {
     pub relay_remote_eof: u64,
     pub relay_client_io_errors: u64,
     pub relay_remote_io_errors: u64,
+    pub session_pool_hits: u64,
+    pub session_pool_misses: u64,
+    pub session_pool_opened: u64,
+    pub session_pool_discarded: u64,
+    /// hits / (hits + misses); absent before the first SOCKS5 attempt.
+    pub session_pool_hit_rate: Option<f64>,
+    pub route_races: u64,
+    pub route_race_later_wins: u64,
     pub backends: Vec<BackendSnapshot>,
 }
 
@@ -122,6 +148,9 @@ impl RuntimeStats {
     }
 
     pub fn snapshot(&self, config: &Config, backends: Vec<BackendSnapshot>, running: bool) -> StatusSnapshot {
+        let session_pool_hits = self.session_pool_hits.load(Ordering::Relaxed);
+        let session_pool_misses = self.session_pool_misses.load(Ordering::Relaxed);
+        let session_pool_takes = session_pool_hits + session_pool_misses;
         StatusSnapshot {
             name: "D2S",
             version: env!("CARGO_PKG_VERSION"),
@@ -147,9 +176,24 @@ impl RuntimeStats {
             relay_remote_eof: self.relay_remote_eof.load(Ordering::Relaxed),
             relay_client_io_errors: self.relay_client_io_errors.load(Ordering::Relaxed),
             relay_remote_io_errors: self.relay_remote_io_errors.load(Ordering::Relaxed),
+            session_pool_hits,
+            session_pool_misses,
+            session_pool_opened: self.session_pool_opened.load(Ordering::Relaxed),
+            session_pool_discarded: self.session_pool_discarded.load(Ordering::Relaxed),
+            session_pool_hit_rate: (session_pool_takes > 0)
+                .then(|| session_pool_hits as f64 / session_pool_takes as f64),
+            route_races: self.route_races.load(Ordering::Relaxed),
+            route_race_later_wins: self.route_race_later_wins.load(Ordering::Relaxed),
             backends,
         }
     }
+
+    /// Snapshot of the running server, with control API overrides applied.
+    pub async fn live_snapshot(&self, pool: &crate::backend::BackendPool, running: bool) -> StatusSnapshot {
+        let mut snapshot = self.snapshot(&pool.config(), pool.snapshots().await, running);
+        snapshot.direct_fallback = pool.direct_fallback();
+        snapshot
+    }
 }
 
 fn update_max(target: &AtomicU64, value: u64) {
@@ -162,6 +206,8 @@ fn update_max(target: &AtomicU64, value: u64) {
     }
 }
 
+/// `status_file` and `status_interval_secs` are fixed at start; the snapshot
+/// itself follows configuration reloads.
 pub async fn status_writer(
     config: Arc<Config>,
     pool: crate::backend::BackendPool,
@@ -175,14 +221,14 @@ pub async fn status_writer(
     loop {
         tokio::select! {
             _ = interval.tick() => {
-                let snapshot = stats.snapshot(&config, pool.snapshots().await, true);
+                let snapshot = stats.live_snapshot(&pool, true).await;
                 if let Err(error) = write_atomic_json(&path, &snapshot).await {
                     warn!(path = %path.display(), %error, "unable to update D2S status file");
                 }
             }
             changed = shutdown.changed() => {
                 if changed.is_err() || *shutdown.borrow() {
-                    let snapshot = stats.snapshot(&config, pool.snapshots().await, false);
+                    let snapshot = stats.live_snapshot(&pool, false).await;
                     if let Err(error) = write_atomic_json(&path, &snapshot).await {
                         warn!(path = %path.display(), %error, "unable to write final D2S status file");
                     }
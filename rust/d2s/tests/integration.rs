@@ -1,4 +1,4 @@
-use d2s::{backend::BackendState, start, Config};
+use d2s::{backend::BackendState, config::RouteStrategy, start, Config};
 use std::{
     net::{IpAddr, SocketAddr},
     sync::{Arc, atomic::{AtomicBool, AtomicUsize, Ordering}},
@@ -57,25 +57,34 @@ struct MockSocks {
     fail: Arc<AtomicBool>,
     fail_once: Arc<AtomicBool>,
     blackhole: Arc<AtomicBool>,
+    stall: Arc<AtomicBool>,
     accepts: Arc<AtomicUsize>,
     connects: Arc<AtomicUsize>,
     shutdown: watch::Sender<bool>,
     task: JoinHandle<()>,
 }
 
+type MockAuth = Option<(&'static str, &'static str)>;
+
 impl MockSocks {
     async fn start(initially_failing: bool) -> Self {
+        Self::start_with_auth(initially_failing, None).await
+    }
+
+    async fn start_with_auth(initially_failing: bool, auth: MockAuth) -> Self {
         let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
         let addr = listener.local_addr().unwrap();
         let fail = Arc::new(AtomicBool::new(initially_failing));
         let fail_once = Arc::new(AtomicBool::new(false));
         let blackhole = Arc::new(AtomicBool::new(false));
+        let stall = Arc::new(AtomicBool::new(false));
         let accepts = Arc::new(AtomicUsize::new(0));
         let connects = Arc::new(AtomicUsize::new(0));
         let (shutdown, mut rx) = watch::channel(false);
         let fail_task = fail.clone();
         let fail_once_task = fail_once.clone();
         let blackhole_task = blackhole.clone();
+        let stall_task = stall.clone();
         let accepts_task = accepts.clone();
         let connects_task = connects.clone();
         let task = tokio::spawn(async move {
@@ -88,15 +97,16 @@ impl MockSocks {
                         let fail = fail_task.clone();
                         let fail_once = fail_once_task.clone();
                         let blackhole = blackhole_task.clone();
+                        let stall = stall_task.clone();
                         let connects = connects_task.clone();
                         tokio::spawn(async move {
-                            let _ = handle_mock_socks(stream, fail, fail_once, blackhole, connects).await;
+                            let _ = handle_mock_socks(stream, auth, fail, fail_once, blackhole, stall, connects).await;
                         });
                     }
                 }
             }
         });
-        Self { addr, fail, fail_once, blackhole, accepts, connects, shutdown, task }
+        Self { addr, fail, fail_once, blackhole, stall, accepts, connects, shutdown, task }
     }
 
     fn set_failing(&self, value: bool) {
@@ -111,6 +121,11 @@ impl MockSocks {
         self.blackhole.store(value, Ordering::Relaxed);
     }
 
+    /// Accept CONNECT requests but never answer them.
+    fn set_stall(&self, value: bool) {
+        self.stall.store(value, Ordering::Relaxed);
+    }
+
     fn reset_count(&self) {
         self.connects.store(0, Ordering::Relaxed);
     }
@@ -135,22 +150,50 @@ impl MockSocks {
 
 async fn handle_mock_socks(
     mut client: TcpStream,
+    auth: MockAuth,
     fail: Arc<AtomicBool>,
     fail_once: Arc<AtomicBool>,
     blackhole: Arc<AtomicBool>,
+    stall: Arc<AtomicBool>,
     connects: Arc<AtomicUsize>,
 ) -> std::io::Result<()> {
     let mut greeting = [0u8; 2];
     client.read_exact(&mut greeting).await?;
     let mut methods = vec![0u8; greeting[1] as usize];
     client.read_exact(&mut methods).await?;
-    client.write_all(&[0x05, 0x00]).await?;
+    if let Some((username, password)) = auth {
+        if !methods.contains(&0x02) {
+            client.write_all(&[0x05, 0xff]).await?;
+            return Ok(());
+        }
+        client.write_all(&[0x05, 0x02]).await?;
+        let mut head = [0u8; 2];
+        client.read_exact(&mut head).await?;
+        let mut user = vec![0u8; head[1] as usize];
+        client.read_exact(&mut user).await?;
+        let mut len = [0u8; 1];
+        client.read_exact(&mut len).await?;
+        let mut pass = vec![0u8; len[0] as usize];
+        client.read_exact(&mut pass).await?;
+        if user != username.as_bytes() || pass != password.as_bytes() {
+            client.write_all(&[0x01, 0x01]).await?;
+            return Ok(());
+        }
+        client.write_all(&[0x01, 0x00]).await?;
+    } else {
+        client.write_all(&[0x05, 0x00]).await?;
+    }
 
     let mut request = [0u8; 4];
     client.read_exact(&mut request).await?;
     let target = read_target(&mut client, request[3]).await?;
     connects.fetch_add(1, Ordering::Relaxed);
 
+    if stall.load(Ordering::Relaxed) {
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        return Ok(());
+    }
+
     if fail.load(Ordering::Relaxed) || fail_once.swap(false, Ordering::Relaxed) {
         client.write_all(&[0x05, 0x04, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await?;
         return Ok(());
@@ -190,10 +233,11 @@ async fn read_target(stream: &mut TcpStream, atyp: u8) -> std::io::Result<Socket
             let port = read_port(stream).await?;
             let host = String::from_utf8(host)
                 .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error))?;
-            tokio::net::lookup_host((host.as_str(), port))
+            let resolved = tokio::net::lookup_host((host.as_str(), port))
                 .await?
                 .next()
-                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "domain resolved to no addresses"))
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "domain resolved to no addresses"));
+            resolved
         }
         _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "unsupported test ATYP")),
     }
@@ -209,11 +253,13 @@ fn config(backends: Vec<SocketAddr>, probe_target: SocketAddr) -> Config {
     Config {
         listen: "127.0.0.1:0".parse().unwrap(),
         dnscrypt_timeout_ms: 5_000,
-        backends,
+        backends: backends.into_iter().map(Into::into).collect(),
         direct_fallback: true,
         connect_timeout_ms: 500,
         upstream_handshake_timeout_ms: 500,
         backend_attempt_timeout_ms: 700,
+        route_strategy: RouteStrategy::Sequential,
+        race_stagger_ms: 200,
         direct_connect_timeout_ms: 700,
         route_timeout_ms: None,
         max_backend_attempts: None,
@@ -227,10 +273,15 @@ fn config(backends: Vec<SocketAddr>, probe_target: SocketAddr) -> Config {
         idle_after_secs: None,
         probe_targets: vec![probe_target.to_string()],
         max_connections: 64,
+        session_pool_size: 2,
+        session_pool_idle_ms: 3_000,
         tcp_nodelay: true,
         log_level: "error".to_string(),
         status_file: None,
         status_interval_secs: 1,
+        metrics_listen: None,
+        control_listen: None,
+        control_token: None,
         shutdown_grace_period_ms: 1000,
     }
 }
@@ -675,3 +726,274 @@ async fn shutdown_abort_releases_active_connection_accounting() {
     backend.stop().await;
     echo.stop().await;
 }
+
+#[tokio::test]
+async fn metrics_listener_exposes_backend_health() {
+    let echo = EchoServer::start().await;
+    let backend = MockSocks::start(false).await;
+    let mut cfg = config(vec![backend.addr], echo.addr);
+    cfg.metrics_listen = Some("127.0.0.1:0".parse().unwrap());
+    let server = start(cfg).await.unwrap();
+    wait_for_green(&server, 1).await;
+    roundtrip(server.listen_addr, echo.addr, b"metrics").await;
+
+    let mut stream = TcpStream::connect(server.metrics_addr.unwrap()).await.unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+    assert!(response.contains(&format!("d2s_backend_state{{backend=\"{}\",state=\"green\"}} 1", backend.addr)), "{response}");
+    assert!(response.contains("d2s_backend_socks_rtt_seconds_count"), "{response}");
+    assert!(response.contains("d2s_upstream_connections_total 1"), "{response}");
+
+    server.shutdown().await.unwrap();
+    backend.stop().await;
+    echo.stop().await;
+}
+
+#[tokio::test]
+async fn pooled_sessions_serve_repeat_requests_and_expire_when_idle() {
+    let echo = EchoServer::start().await;
+    let backend = MockSocks::start(false).await;
+    let mut cfg = config(vec![backend.addr], echo.addr);
+    cfg.session_pool_idle_ms = 400;
+    let server = start(cfg).await.unwrap();
+    wait_for_green(&server, 1).await;
+
+    // The first request creates demand; the pool fills behind it.
+    roundtrip(server.listen_addr, echo.addr, b"cold").await;
+    assert_eq!(server.stats.session_pool_misses.load(Ordering::Relaxed), 1);
+    for _ in 0..40 {
+        if server.pool.snapshots().await[0].pooled_sessions > 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(25)).await;
+    }
+    assert_eq!(server.pool.snapshots().await[0].pooled_sessions, 1);
+
+    backend.reset_count();
+    roundtrip(server.listen_addr, echo.addr, b"warm").await;
+    assert_eq!(backend.count(), 1);
+    assert_eq!(server.stats.session_pool_hits.load(Ordering::Relaxed), 1);
+    let snapshot = server.stats.live_snapshot(&server.pool, true).await;
+    assert_eq!(snapshot.session_pool_hit_rate, Some(0.5));
+
+    tokio::time::sleep(Duration::from_millis(1_000)).await;
+    assert!(server.stats.session_pool_discarded.load(Ordering::Relaxed) > 0);
+
+    server.shutdown().await.unwrap();
+    backend.stop().await;
+    echo.stop().await;
+}
+
+#[tokio::test]
+async fn race_strategy_routes_around_a_stalled_backend() {
+    let echo = EchoServer::start().await;
+    let stalled = MockSocks::start(false).await;
+    let healthy = MockSocks::start(false).await;
+    let mut cfg = config(vec![stalled.addr, healthy.addr], echo.addr);
+    cfg.route_strategy = RouteStrategy::Race;
+    cfg.race_stagger_ms = 50;
+    cfg.session_pool_size = 0;
+    let server = start(cfg).await.unwrap();
+    wait_for_green(&server, 2).await;
+
+    // GREEN by its last probe, but no longer answering CONNECT.
+    stalled.set_stall(true);
+    server.pool.set_pinned(Some((stalled.addr, Duration::from_secs(60)))).await;
+    stalled.reset_count();
+    healthy.reset_count();
+    let started = std::time::Instant::now();
+    roundtrip(server.listen_addr, echo.addr, b"race").await;
+    assert!(started.elapsed() < Duration::from_millis(600), "{:?}", started.elapsed());
+    assert_eq!((stalled.count(), healthy.count()), (1, 1));
+    assert_eq!(server.stats.route_races.load(Ordering::Relaxed), 1);
+    assert_eq!(server.stats.route_race_later_wins.load(Ordering::Relaxed), 1);
+
+    // The cancelled contestant is not a failure.
+    let snapshots = server.pool.snapshots().await;
+    let stalled_snapshot = snapshots.iter().find(|item| item.address == stalled.addr.to_string()).unwrap();
+    assert_eq!(stalled_snapshot.failed_connections, 0);
+    assert_eq!(stalled_snapshot.state, BackendState::Green);
+
+    server.shutdown().await.unwrap();
+    stalled.stop().await;
+    healthy.stop().await;
+    echo.stop().await;
+}
+
+const CONTROL_TOKEN: &str = "control-secret";
+
+async fn control_request(addr: SocketAddr, request_line: &str) -> String {
+    control_request_with(addr, request_line, &format!("Authorization: Bearer {CONTROL_TOKEN}\r\n")).await
+}
+
+async fn control_request_with(addr: SocketAddr, request_line: &str, headers: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(format!("{request_line} HTTP/1.1\r\nHost: localhost\r\n{headers}\r\n").as_bytes())
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn control_api_overrides_selection_and_streams_events() {
+    let echo = EchoServer::start().await;
+    let first = MockSocks::start(false).await;
+    let second = MockSocks::start(false).await;
+    let mut cfg = config(vec![first.addr, second.addr], echo.addr);
+    cfg.control_listen = Some("127.0.0.1:0".parse().unwrap());
+    cfg.control_token = Some(CONTROL_TOKEN.to_string());
+    let server = start(cfg).await.unwrap();
+    let control = server.control_addr.unwrap();
+    wait_for_green(&server, 2).await;
+
+    let status = control_request(control, "GET /status").await;
+    assert!(status.starts_with("HTTP/1.1 200 OK"), "{status}");
+    assert!(status.contains(&format!("\"address\":\"{}\"", first.addr)), "{status}");
+
+    let mut events = TcpStream::connect(control).await.unwrap();
+    events
+        .write_all(format!("GET /events HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {CONTROL_TOKEN}\r\n\r\n").as_bytes())
+        .await
+        .unwrap();
+
+    let disabled = control_request(control, &format!("POST /disable?backend={}&secs=60", first.addr)).await;
+    assert!(disabled.contains("\"disabled\":true"), "{disabled}");
+    first.reset_count();
+    second.reset_count();
+    roundtrip(server.listen_addr, echo.addr, b"disabled").await;
+    assert_eq!((first.count(), second.count()), (0, 1));
+
+    let mut streamed = String::new();
+    let mut buf = [0u8; 1024];
+    while !streamed.contains("data: ") || !streamed.ends_with("\n\n") {
+        let n = tokio::time::timeout(Duration::from_secs(2), events.read(&mut buf)).await.unwrap().unwrap();
+        assert!(n > 0, "{streamed}");
+        streamed.push_str(std::str::from_utf8(&buf[..n]).unwrap());
+    }
+    assert!(streamed.contains("text/event-stream"), "{streamed}");
+    assert!(streamed.contains(&format!("\"backend\":\"{}\"", second.addr)), "{streamed}");
+
+    control_request(control, &format!("POST /enable?backend={}", first.addr)).await;
+    let pinned = control_request(control, &format!("POST /pin?backend={}", second.addr)).await;
+    assert!(pinned.contains("\"pinned\":true"), "{pinned}");
+    second.reset_count();
+    roundtrip(server.listen_addr, echo.addr, b"pinned").await;
+    assert_eq!(second.count(), 1);
+
+    let fallback = control_request(control, "POST /direct-fallback?enabled=false").await;
+    assert!(fallback.contains("\"direct_fallback\":false"), "{fallback}");
+    assert!(!server.pool.direct_fallback());
+    let unknown = control_request(control, "POST /disable?backend=127.0.0.1:9").await;
+    assert!(unknown.starts_with("HTTP/1.1 404"), "{unknown}");
+
+    server.shutdown().await.unwrap();
+    first.stop().await;
+    second.stop().await;
+    echo.stop().await;
+}
+
+#[tokio::test]
+async fn control_api_rejects_requests_without_the_token() {
+    let echo = EchoServer::start().await;
+    let backend = MockSocks::start(false).await;
+    let mut cfg = config(vec![backend.addr], echo.addr);
+    cfg.control_listen = Some("127.0.0.1:0".parse().unwrap());
+    cfg.control_token = Some(CONTROL_TOKEN.to_string());
+    let server = start(cfg).await.unwrap();
+    let control = server.control_addr.unwrap();
+
+    let anonymous = control_request_with(control, "POST /direct-fallback?enabled=false", "").await;
+    assert!(anonymous.starts_with("HTTP/1.1 401"), "{anonymous}");
+    let wrong = control_request_with(control, "POST /unpin", "Authorization: Bearer nope\r\n").await;
+    assert!(wrong.starts_with("HTTP/1.1 401"), "{wrong}");
+    let events = control_request_with(control, "GET /events", "").await;
+    assert!(events.starts_with("HTTP/1.1 401"), "{events}");
+    assert!(server.pool.direct_fallback());
+
+    let mut missing = config(vec![backend.addr], echo.addr);
+    missing.control_listen = Some("127.0.0.1:0".parse().unwrap());
+    assert!(start(missing).await.is_err());
+
+    server.shutdown().await.unwrap();
+    backend.stop().await;
+    echo.stop().await;
+}
+
+#[tokio::test]
+async fn authenticated_backends_use_username_password() {
+    let echo = EchoServer::start().await;
+    let good = MockSocks::start_with_auth(false, Some(("user", "secret"))).await;
+    let wrong = MockSocks::start_with_auth(false, Some(("user", "secret"))).await;
+    let mut cfg = config(vec![good.addr, wrong.addr], echo.addr);
+    cfg.backends[0].username = Some("user".to_string());
+    cfg.backends[0].password = Some("secret".to_string());
+    cfg.backends[1].username = Some("user".to_string());
+    cfg.backends[1].password = Some("wrong".to_string());
+    let server = start(cfg).await.unwrap();
+    wait_for_state(&server, good.addr, BackendState::Green).await;
+    wait_for_state(&server, wrong.addr, BackendState::Red).await;
+
+    good.reset_count();
+    roundtrip(server.listen_addr, echo.addr, b"auth").await;
+    assert_eq!(good.count(), 1);
+
+    let snapshots = server.pool.snapshots().await;
+    let good_snapshot = snapshots.iter().find(|item| item.address == good.addr.to_string()).unwrap();
+    assert_eq!(good_snapshot.auth.as_deref(), Some("user:********"));
+    let wrong_snapshot = snapshots.iter().find(|item| item.address == wrong.addr.to_string()).unwrap();
+    assert!(wrong_snapshot.last_error.as_deref().unwrap_or_default().contains("authentication rejected"));
+
+    server.shutdown().await.unwrap();
+    good.stop().await;
+    wrong.stop().await;
+    echo.stop().await;
+}
+
+#[tokio::test]
+async fn reload_keeps_unchanged_backends_and_drains_removed_ones() {
+    let echo = EchoServer::start().await;
+    let first = MockSocks::start(false).await;
+    let second = MockSocks::start(false).await;
+    let mut cfg = config(vec![first.addr], echo.addr);
+    cfg.shutdown_grace_period_ms = 200;
+    let server = start(cfg).await.unwrap();
+    wait_for_green(&server, 1).await;
+    let mut lingering = open_tunnel(server.listen_addr, echo.addr).await;
+    wait_for_active_connections(&server, 1).await;
+
+    let mut cfg = config(vec![first.addr, second.addr], echo.addr);
+    cfg.shutdown_grace_period_ms = 200;
+    let report = server.reload(cfg).await.unwrap();
+    assert_eq!(report.kept, vec![first.addr]);
+    assert_eq!(report.added, vec![second.addr]);
+    let snapshots = server.pool.snapshots().await;
+    assert_eq!(snapshots[0].state, BackendState::Green);
+    assert_ne!(snapshots[1].state, BackendState::Green);
+    wait_for_state(&server, second.addr, BackendState::Green).await;
+
+    let mut cfg = config(vec![second.addr], echo.addr);
+    cfg.shutdown_grace_period_ms = 200;
+    let report = server.reload(cfg).await.unwrap();
+    assert_eq!(report.removed, vec![first.addr]);
+
+    let mut byte = [0u8; 1];
+    let closed = tokio::time::timeout(Duration::from_millis(1_000), lingering.read(&mut byte)).await;
+    assert!(matches!(closed, Ok(Ok(0)) | Ok(Err(_))), "relay through removed backend was not drained");
+    wait_for_active_connections(&server, 0).await;
+
+    second.reset_count();
+    first.reset_count();
+    roundtrip(server.listen_addr, echo.addr, b"reloaded").await;
+    assert_eq!(second.count(), 1);
+    assert_eq!(first.count(), 0);
+
+    server.shutdown().await.unwrap();
+    first.stop().await;
+    second.stop().await;
+    echo.stop().await;
+}
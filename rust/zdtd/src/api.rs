@@ -6,7 +6,7 @@ use sha2::{Digest, Sha256};
 use std::{
     collections::{HashMap, BTreeMap, BTreeSet},
     fs,
-    io::{Read, Write},
+    io::{BufReader, Write},
     net::{SocketAddr, TcpListener, TcpStream},
     path::{Component, Path, PathBuf},
     sync::{Arc, OnceLock, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}},
@@ -14,12 +14,7 @@ use std::{
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use crate::{api_status, daemon, daemon::SharedState, energy_saver, power_mode, protector, settings, stats, traffic_total};
-
-const MAX_HEADER: usize = 16 * 1024;
-// Allow uploading strategic files (including binaries). The API is local-only and authenticated,
-// but we still cap body size to avoid accidental memory blowups.
-const MAX_BODY: usize = 512 * 1024 * 1024;
+use crate::{api_http::{self, Request, Responder}, api_router::{Match, Params, Router}, api_status, daemon, daemon::SharedState, energy_saver, power_mode, protector, settings, stats, traffic_total};
 
 // Safety guard for dnscrypt setting files (some lists can be enormous and will crash the app/UI if returned whole).
 // Per Danil: limit reads/edits to ~200KB.
@@ -133,90 +128,6 @@ fn get_status_snapshot(services_running: bool) -> (stats::Report, bool, bool) {
 }
 
 
-fn find_header_end(data: &[u8]) -> Option<usize> {
-    if data.len() < 4 {
-        return None;
-    }
-    for i in 0..=(data.len() - 4) {
-        if &data[i..i + 4] == b"\r\n\r\n" {
-            return Some(i + 4);
-        }
-    }
-    None
-}
-
-fn parse_http_request(
-    s: &mut TcpStream,
-) -> Result<(String, String, HashMap<String, String>, Vec<u8>)> {
-    s.set_read_timeout(Some(Duration::from_secs(2)))?;
-    let mut data = Vec::with_capacity(2048);
-    let mut buf = [0_u8; 1024];
-
-    // Read until we have headers.
-    loop {
-        let n = s.read(&mut buf)?;
-        if n == 0 {
-            break;
-        }
-        data.extend_from_slice(&buf[..n]);
-        if find_header_end(&data).is_some() {
-            break;
-        }
-        if data.len() >= MAX_HEADER {
-            anyhow::bail!("HTTP header too large");
-        }
-    }
-
-    let header_end = find_header_end(&data).unwrap_or(data.len());
-    let head_bytes = &data[..header_end];
-    let mut body = if header_end < data.len() {
-        data[header_end..].to_vec()
-    } else {
-        Vec::new()
-    };
-
-    // Parse head.
-    let text = String::from_utf8_lossy(head_bytes);
-    let head_end_text = text.find("\r\n\r\n").unwrap_or(text.len());
-    let head = &text[..head_end_text];
-    let mut lines = head.split("\r\n");
-    let req = lines.next().unwrap_or("");
-    let mut it = req.split_whitespace();
-    let method = it.next().unwrap_or("").to_string();
-    let path = it.next().unwrap_or("").to_string();
-
-    let mut headers = HashMap::new();
-    for l in lines {
-        if let Some((k, v)) = l.split_once(':') {
-            headers.insert(k.trim().to_ascii_lowercase(), v.trim().to_string());
-        }
-    }
-
-    // Read body, if Content-Length is present.
-    if let Some(cl) = headers.get("content-length") {
-        if let Ok(len) = cl.trim().parse::<usize>() {
-            if len > MAX_BODY {
-                anyhow::bail!("HTTP body too large");
-            }
-            while body.len() < len {
-                let n = s.read(&mut buf)?;
-                if n == 0 {
-                    break;
-                }
-                body.extend_from_slice(&buf[..n]);
-                if body.len() > MAX_BODY {
-                    anyhow::bail!("HTTP body too large");
-                }
-            }
-            if body.len() > len {
-                body.truncate(len);
-            }
-        }
-    }
-
-    Ok((method, path, headers, body))
-}
-
 fn safe_module_path(rel: &str) -> Result<PathBuf> {
     let rel = rel.trim();
     if rel.is_empty() {
@@ -314,15 +225,63 @@ fn d2s_default_log_level() -> String { "info".to_string() }
 fn d2s_default_status_interval_secs() -> u64 { 5 }
 fn d2s_status_interval_is_default(value: &u64) -> bool { *value == d2s_default_status_interval_secs() }
 fn d2s_default_shutdown_grace_period_ms() -> u64 { 5_000 }
+fn d2s_default_session_pool_size() -> usize { 2 }
+fn d2s_default_session_pool_idle_ms() -> u64 { 3_000 }
+fn d2s_default_route_strategy() -> String { "sequential".to_string() }
+fn d2s_default_race_stagger_ms() -> u64 { 200 }
+
+/// Returned instead of a D2S backend password; sending it back unchanged keeps
+/// the stored password.
+const D2S_PASSWORD_MASK: &str = "********";
+
+/// A d2s.toml backend: either a bare "HOST:PORT" string or a table with
+/// SOCKS5 username/password credentials.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum D2sBackend {
+    Address(String),
+    Table(D2sBackendTable),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct D2sBackendTable {
+    address: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+}
+
+impl D2sBackend {
+    fn address(&self) -> &str {
+        match self {
+            Self::Address(address) => address,
+            Self::Table(table) => &table.address,
+        }
+    }
+
+    fn masked(&self) -> Self {
+        match self {
+            Self::Table(table) if table.password.is_some() => Self::Table(D2sBackendTable {
+                password: Some(D2S_PASSWORD_MASK.to_string()),
+                ..table.clone()
+            }),
+            other => other.clone(),
+        }
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(default, deny_unknown_fields)]
 struct D2sFileConfig {
-    backends: Vec<String>,
+    backends: Vec<D2sBackend>,
     direct_fallback: bool,
     connect_timeout_ms: u64,
     upstream_handshake_timeout_ms: u64,
     backend_attempt_timeout_ms: u64,
+    route_strategy: String,
+    race_stagger_ms: u64,
     direct_connect_timeout_ms: u64,
     client_handshake_timeout_ms: u64,
     probe_timeout_ms: u64,
@@ -332,12 +291,23 @@ struct D2sFileConfig {
     runtime_cooldown_ms: u64,
     probe_targets: Vec<String>,
     max_connections: usize,
+    session_pool_size: usize,
+    session_pool_idle_ms: u64,
     tcp_nodelay: bool,
     log_level: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     status_file: Option<PathBuf>,
     #[serde(skip_serializing_if = "d2s_status_interval_is_default")]
     status_interval_secs: u64,
+    // Hand-edited only; kept so manual metrics/control listeners survive API saves.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metrics_listen: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    control_listen: Option<String>,
+    // Hand-edited only and never returned by the API. zdtd-started d2s reads
+    // its own token from api/d2s_control_token instead.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    control_token: Option<String>,
     shutdown_grace_period_ms: u64,
     // Compatibility-only fields from experimental D2S builds. They are not
     // exposed by the API and the stable D2S transport ignores them.
@@ -359,6 +329,8 @@ impl Default for D2sFileConfig {
             connect_timeout_ms: d2s_default_connect_timeout_ms(),
             upstream_handshake_timeout_ms: d2s_default_upstream_handshake_timeout_ms(),
             backend_attempt_timeout_ms: d2s_default_backend_attempt_timeout_ms(),
+            route_strategy: d2s_default_route_strategy(),
+            race_stagger_ms: d2s_default_race_stagger_ms(),
             direct_connect_timeout_ms: d2s_default_direct_connect_timeout_ms(),
             client_handshake_timeout_ms: d2s_default_client_handshake_timeout_ms(),
             probe_timeout_ms: d2s_default_probe_timeout_ms(),
@@ -368,10 +340,15 @@ impl Default for D2sFileConfig {
             runtime_cooldown_ms: d2s_default_runtime_cooldown_ms(),
             probe_targets: d2s_default_probe_targets(),
             max_connections: d2s_default_max_connections(),
+            session_pool_size: d2s_default_session_pool_size(),
+            session_pool_idle_ms: d2s_default_session_pool_idle_ms(),
             tcp_nodelay: d2s_default_true(),
             log_level: d2s_default_log_level(),
             status_file: None,
             status_interval_secs: d2s_default_status_interval_secs(),
+            metrics_listen: None,
+            control_listen: None,
+            control_token: None,
             shutdown_grace_period_ms: d2s_default_shutdown_grace_period_ms(),
             idle_after_secs: None,
             route_timeout_ms: None,
@@ -383,11 +360,16 @@ impl Default for D2sFileConfig {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct D2sConfigReq {
-    backends: Vec<String>,
+    backends: Vec<D2sBackend>,
     direct_fallback: bool,
     connect_timeout_ms: u64,
     upstream_handshake_timeout_ms: u64,
     backend_attempt_timeout_ms: u64,
+    // Optional so clients written before these settings keep working.
+    #[serde(default = "d2s_default_route_strategy")]
+    route_strategy: String,
+    #[serde(default = "d2s_default_race_stagger_ms")]
+    race_stagger_ms: u64,
     direct_connect_timeout_ms: u64,
     client_handshake_timeout_ms: u64,
     probe_timeout_ms: u64,
@@ -397,6 +379,10 @@ struct D2sConfigReq {
     runtime_cooldown_ms: u64,
     probe_targets: Vec<String>,
     max_connections: usize,
+    #[serde(default = "d2s_default_session_pool_size")]
+    session_pool_size: usize,
+    #[serde(default = "d2s_default_session_pool_idle_ms")]
+    session_pool_idle_ms: u64,
     tcp_nodelay: bool,
     log_level: String,
     shutdown_grace_period_ms: u64,
@@ -405,11 +391,13 @@ struct D2sConfigReq {
 impl From<&D2sFileConfig> for D2sConfigReq {
     fn from(value: &D2sFileConfig) -> Self {
         Self {
-            backends: value.backends.clone(),
+            backends: value.backends.iter().map(D2sBackend::masked).collect(),
             direct_fallback: value.direct_fallback,
             connect_timeout_ms: value.connect_timeout_ms,
             upstream_handshake_timeout_ms: value.upstream_handshake_timeout_ms,
             backend_attempt_timeout_ms: value.backend_attempt_timeout_ms,
+            route_strategy: value.route_strategy.clone(),
+            race_stagger_ms: value.race_stagger_ms,
             direct_connect_timeout_ms: value.direct_connect_timeout_ms,
             client_handshake_timeout_ms: value.client_handshake_timeout_ms,
             probe_timeout_ms: value.probe_timeout_ms,
@@ -419,6 +407,8 @@ impl From<&D2sFileConfig> for D2sConfigReq {
             runtime_cooldown_ms: value.runtime_cooldown_ms,
             probe_targets: value.probe_targets.clone(),
             max_connections: value.max_connections,
+            session_pool_size: value.session_pool_size,
+            session_pool_idle_ms: value.session_pool_idle_ms,
             tcp_nodelay: value.tcp_nodelay,
             log_level: value.log_level.clone(),
             shutdown_grace_period_ms: value.shutdown_grace_period_ms,
@@ -427,12 +417,27 @@ impl From<&D2sFileConfig> for D2sConfigReq {
 }
 
 impl D2sFileConfig {
-    fn apply_request(&mut self, req: D2sConfigReq) {
+    fn apply_request(&mut self, mut req: D2sConfigReq) {
+        // A masked or omitted password keeps the one already stored for the
+        // same backend address.
+        for backend in &mut req.backends {
+            let D2sBackend::Table(table) = backend else { continue; };
+            if table.password.as_deref().is_some_and(|p| p != D2S_PASSWORD_MASK) {
+                continue;
+            }
+            let stored = self.backends.iter().find_map(|old| match old {
+                D2sBackend::Table(old) if old.address.trim() == table.address.trim() => old.password.clone(),
+                _ => None,
+            });
+            table.password = stored;
+        }
         self.backends = req.backends;
         self.direct_fallback = req.direct_fallback;
         self.connect_timeout_ms = req.connect_timeout_ms;
         self.upstream_handshake_timeout_ms = req.upstream_handshake_timeout_ms;
         self.backend_attempt_timeout_ms = req.backend_attempt_timeout_ms;
+        self.route_strategy = req.route_strategy;
+        self.race_stagger_ms = req.race_stagger_ms;
         self.direct_connect_timeout_ms = req.direct_connect_timeout_ms;
         self.client_handshake_timeout_ms = req.client_handshake_timeout_ms;
         self.probe_timeout_ms = req.probe_timeout_ms;
@@ -442,6 +447,8 @@ impl D2sFileConfig {
         self.runtime_cooldown_ms = req.runtime_cooldown_ms;
         self.probe_targets = req.probe_targets;
         self.max_connections = req.max_connections;
+        self.session_pool_size = req.session_pool_size;
+        self.session_pool_idle_ms = req.session_pool_idle_ms;
         self.tcp_nodelay = req.tcp_nodelay;
         self.log_level = req.log_level;
         self.shutdown_grace_period_ms = req.shutdown_grace_period_ms;
@@ -491,6 +498,18 @@ fn validate_d2s_file_config(config: &D2sFileConfig, listener: Option<SocketAddr>
 
     let mut seen_backends = BTreeSet::new();
     for backend in &config.backends {
+        if let D2sBackend::Table(table) = backend {
+            match (&table.username, &table.password) {
+                (None, None) => {}
+                (Some(username), Some(password)) => {
+                    if username.is_empty() || username.len() > 255 || password.is_empty() || password.len() > 255 {
+                        anyhow::bail!("D2S backend username and password must be 1..255 bytes: {}", table.address);
+                    }
+                }
+                _ => anyhow::bail!("D2S backend username and password must both be set or both be empty: {}", table.address),
+            }
+        }
+        let backend = backend.address();
         let addr: SocketAddr = backend.trim().parse()
             .map_err(|_| anyhow::anyhow!("invalid D2S backend: {backend}"))?;
         if !addr.ip().is_loopback() || addr.port() == 0 {
@@ -526,6 +545,15 @@ fn validate_d2s_file_config(config: &D2sFileConfig, listener: Option<SocketAddr>
     if config.max_connections == 0 {
         anyhow::bail!("max_connections must be greater than zero");
     }
+    if !matches!(config.route_strategy.as_str(), "sequential" | "race") {
+        anyhow::bail!("route_strategy must be sequential or race");
+    }
+    if config.session_pool_size > 16 {
+        anyhow::bail!("session_pool_size must be at most 16");
+    }
+    if config.session_pool_idle_ms < 100 {
+        anyhow::bail!("session_pool_idle_ms must be at least 100");
+    }
     if config.status_interval_secs == 0 {
         anyhow::bail!("status_interval_secs must be greater than zero");
     }
@@ -851,7 +879,7 @@ fn list_files_only(dir: &Path) -> Result<Vec<String>> {
     Ok(out)
 }
 
-fn handle_strategic(stream: TcpStream, method: &str, path: &str, headers: &HashMap<String, String>, body: &[u8]) -> Result<()> {
+fn handle_strategic(stream: Responder, method: &str, path: &str, headers: &HashMap<String, String>, body: &[u8]) -> Result<()> {
     // Routes:
     //   GET    /api/strategic/{list|lua|bin}
     //   GET    /api/strategic/{list|lua}/{name}
@@ -966,7 +994,7 @@ struct ApplyStrategicVarReq {
     file: String,
 }
 
-fn handle_strategicvar(stream: TcpStream, method: &str, path: &str, body: &[u8]) -> Result<()> {
+fn handle_strategicvar(stream: Responder, method: &str, path: &str, body: &[u8]) -> Result<()> {
     // Routes:
     //   GET  /api/strategicvar/{program}
     //   POST /api/strategicvar/apply   (JSON {program, profile, file})
@@ -2974,16 +3002,16 @@ fn validate_sing_box_setting(v: &serde_json::Value) -> Result<()> {
     Ok(())
 }
 
-fn write_ok(mut stream: TcpStream) -> Result<()> {
+fn write_ok(stream: Responder) -> Result<()> {
     write_json(stream, 200, json!({"ok": true}))
 }
 
-fn write_err(mut stream: TcpStream, e: anyhow::Error) -> Result<()> {
+fn write_err(stream: Responder, e: anyhow::Error) -> Result<()> {
     write_json(stream, 200, json!({"ok": false, "error": format!("{e:#}")}))
 }
 
 /// GET /api/programs
-fn handle_get_programs(stream: TcpStream) -> Result<()> {
+fn handle_get_programs(stream: Responder) -> Result<()> {
     // Profile-based programs
     let profile_ids = ["nfqws", "nfqws2", "byedpi", "dpitunnel"];
     let mut out = Vec::new();
@@ -3210,7 +3238,7 @@ fn handle_get_programs(stream: TcpStream) -> Result<()> {
 }
 
 /// Handles subroutes under /api/programs/*
-fn handle_programs_subroutes(stream: TcpStream, method: &str, path: &str, headers: &HashMap<String, String>, body: &[u8], services_running: bool) -> Result<()> {
+fn handle_programs_subroutes(stream: Responder, method: &str, path: &str, headers: &HashMap<String, String>, body: &[u8], services_running: bool) -> Result<()> {
     let seg: Vec<&str> = path.trim_start_matches('/').split('/').collect();
 
     match (method, seg.as_slice()) {
@@ -5226,172 +5254,6 @@ fn handle_programs_subroutes(stream: TcpStream, method: &str, path: &str, header
             }
         }
 
-        // --- profiles: enable/disable
-        ("PUT", ["api", "programs", id @ ("nfqws" | "nfqws2" | "byedpi" | "dpitunnel"), "profiles", profile, "enabled"]) => {
-            let res = (|| -> Result<()> {
-                ensure_safe_segment(id, "program id")?;
-                ensure_safe_segment(profile, "profile name")?;
-                if !matches!(*id, "nfqws" | "nfqws2" | "byedpi" | "dpitunnel") {
-                    anyhow::bail!("program has no profiles");
-                }
-                let req: EnabledReq = serde_json::from_slice(body)
-                    .map_err(|e| anyhow::anyhow!("bad JSON body: {e}"))?;
-                let p = active_json_path(id);
-                let mut active: ProfilesActive = read_json(&p)?;
-                let st = active.profiles.get_mut(*profile)
-                    .ok_or_else(|| anyhow::anyhow!("profile not found"))?;
-                st.enabled = req.enabled;
-                write_json_pretty(&p, &active)?;
-                Ok(())
-            })();
-            match res {
-                Ok(_) => write_ok(stream),
-                Err(e) => write_err(stream, e),
-            }
-        }
-
-        // --- profiles: delete (soft delete by moving to .deleted/)
-        ("DELETE", ["api", "programs", id @ ("nfqws" | "nfqws2" | "byedpi" | "dpitunnel"), "profiles", profile]) => {
-            let res = (|| -> Result<()> {
-                ensure_safe_segment(id, "program id")?;
-                ensure_safe_segment(profile, "profile name")?;
-                if !matches!(*id, "nfqws" | "nfqws2" | "byedpi" | "dpitunnel") {
-                    anyhow::bail!("program has no profiles");
-                }
-                let p = active_json_path(id);
-                let mut active: ProfilesActive = read_json(&p)?;
-                if active.profiles.remove(*profile).is_none() {
-                    anyhow::bail!("profile not found");
-                }
-                write_json_pretty(&p, &active)?;
-
-                let src = profile_root(id, profile);
-                if src.exists() {
-                    let deleted_dir = program_root(id).join(".deleted");
-                    fs::create_dir_all(&deleted_dir).ok();
-                    let ts = SystemTime::now()
-                        .duration_since(UNIX_EPOCH)
-                        .unwrap_or_default()
-                        .as_secs();
-                    let dst = deleted_dir.join(format!("{profile}.{ts}"));
-                    fs::rename(&src, &dst)
-                        .map_err(|e| anyhow::anyhow!("move failed {} -> {}: {e}", src.display(), dst.display()))?;
-                }
-                Ok(())
-            })();
-            match res {
-                Ok(_) => write_ok(stream),
-                Err(e) => write_err(stream, e),
-            }
-        }
-
-        // --- profiles: config (text)
-        ("GET", ["api", "programs", id @ ("nfqws" | "nfqws2" | "byedpi" | "dpitunnel"), "profiles", profile, "config"]) => {
-            let res = (|| -> Result<String> {
-                ensure_safe_segment(id, "program id")?;
-                ensure_safe_segment(profile, "profile name")?;
-                if !matches!(*id, "nfqws" | "nfqws2" | "byedpi" | "dpitunnel") {
-                    anyhow::bail!("program has no profiles");
-                }
-                let p = profile_root(id, profile).join("config/config.txt");
-                read_text(&p)
-            })();
-            match res {
-                Ok(content) => write_json(stream, 200, json!({"ok": true, "content": content})),
-                Err(e) => write_err(stream, e),
-            }
-        }
-        ("PUT", ["api", "programs", id @ ("nfqws" | "nfqws2" | "byedpi" | "dpitunnel"), "profiles", profile, "config"]) => {
-            let res = (|| -> Result<()> {
-                ensure_safe_segment(id, "program id")?;
-                ensure_safe_segment(profile, "profile name")?;
-                let req: ContentReq = serde_json::from_slice(body)
-                    .map_err(|e| anyhow::anyhow!("bad JSON body: {e}"))?;
-                if !matches!(*id, "nfqws" | "nfqws2" | "byedpi" | "dpitunnel") {
-                    anyhow::bail!("program has no profiles");
-                }
-                let p = profile_root(id, profile).join("config/config.txt");
-                write_text_atomic(&p, &req.content)?;
-                Ok(())
-            })();
-            match res {
-                Ok(_) => write_ok(stream),
-                Err(e) => write_err(stream, e),
-            }
-        }
-
-        // --- profiles: apps lists (text)
-        ("GET", ["api", "programs", id @ ("nfqws" | "nfqws2" | "byedpi" | "dpitunnel"), "profiles", profile, "apps", kind]) => {
-            let res = (|| -> Result<String> {
-                ensure_safe_segment(id, "program id")?;
-                ensure_safe_segment(profile, "profile name")?;
-                ensure_safe_segment(kind, "apps kind")?;
-                if !matches!(*id, "nfqws" | "nfqws2" | "byedpi" | "dpitunnel") {
-                    anyhow::bail!("program has no profiles");
-                }
-                let fname = match (*id, *kind) {
-                    ("nfqws", "user") => "user_program",
-                    ("nfqws", "mobile") => "mobile_program",
-                    ("nfqws", "wifi") => "wifi_program",
-                    ("nfqws2", "user") => "user_program",
-                    ("nfqws2", "mobile") => "mobile_program",
-                    ("nfqws2", "wifi") => "wifi_program",
-                    ("byedpi", "user") => "user_program",
-                    ("dpitunnel", "user") => "user_program",
-                    ("dpitunnel", "mobile") => "mobile_program",
-                    ("dpitunnel", "wifi") => "wifi_program",
-                    _ => anyhow::bail!("invalid apps kind for program"),
-                };
-                let p = profile_root(id, profile).join(format!("app/uid/{fname}"));
-                if *id == "dpitunnel" {
-                    read_text_or_empty(&p)
-                } else {
-                    read_text(&p)
-                }
-            })();
-            match res {
-                Ok(content) => write_json(stream, 200, json!({"ok": true, "content": content})),
-                Err(e) => write_err(stream, e),
-            }
-        }
-        ("PUT", ["api", "programs", id @ ("nfqws" | "nfqws2" | "byedpi" | "dpitunnel"), "profiles", profile, "apps", kind]) => {
-            let res = (|| -> Result<()> {
-                ensure_safe_segment(id, "program id")?;
-                ensure_safe_segment(profile, "profile name")?;
-                ensure_safe_segment(kind, "apps kind")?;
-                if !matches!(*id, "nfqws" | "nfqws2" | "byedpi" | "dpitunnel") {
-                    anyhow::bail!("program has no profiles");
-                }
-                let req: ContentReq = serde_json::from_slice(body)
-                    .map_err(|e| anyhow::anyhow!("bad JSON body: {e}"))?;
-                let fname = match (*id, *kind) {
-                    ("nfqws", "user") => "user_program",
-                    ("nfqws", "mobile") => "mobile_program",
-                    ("nfqws", "wifi") => "wifi_program",
-                    ("nfqws2", "user") => "user_program",
-                    ("nfqws2", "mobile") => "mobile_program",
-                    ("nfqws2", "wifi") => "wifi_program",
-                    ("byedpi", "user") => "user_program",
-                    ("dpitunnel", "user") => "user_program",
-                    ("dpitunnel", "mobile") => "mobile_program",
-                    ("dpitunnel", "wifi") => "wifi_program",
-                    _ => anyhow::bail!("invalid apps kind for program"),
-                };
-                let api_path = format!("/api/programs/{}/profiles/{}/apps/{}", id, profile, kind);
-                let slot = slot_from_kind(kind).ok_or_else(|| anyhow::anyhow!("invalid apps kind"))?;
-                validate_program_apps_content(&req.content, &api_path, id, slot)?;
-                let p = profile_root(id, profile).join(format!("app/uid/{fname}"));
-                write_text_atomic(&p, &req.content)?;
-                invalidate_assignment_cache();
-                refresh_apps_after_save_if_running(services_running, id, Some(profile), slot)?;
-                Ok(())
-            })();
-            match res {
-                Ok(_) => write_ok(stream),
-                Err(e) => write_err(stream, e),
-            }
-        }
-
         // --- dnscrypt enabled/config
         ("GET", ["api", "programs", "dnscrypt", "enabled"]) => {
             let p = active_json_path("dnscrypt");
@@ -5456,7 +5318,7 @@ fn handle_programs_subroutes(stream: TcpStream, method: &str, path: &str, header
             }
         }
         ("PUT", ["api", "programs", "dnscrypt", "d2s-config"]) => {
-            let res = (|| -> Result<()> {
+            let res = (|| -> Result<bool> {
                 let req: D2sConfigReq = serde_json::from_slice(body)
                     .map_err(|e| anyhow::anyhow!("bad D2S JSON body: {e}"))?;
                 let p = program_root("dnscrypt").join("d2set/d2s.toml");
@@ -5466,10 +5328,18 @@ fn handle_programs_subroutes(stream: TcpStream, method: &str, path: &str, header
                 let listener = crate::programs::dnscrypt::configured_d2s_listen_addr()?;
                 validate_d2s_file_config(&config, listener)?;
                 write_d2s_file_config(&p, &config)?;
-                Ok(())
+                // A running d2s picks the change up in place; the file is
+                // already saved, so a failed signal is not an API error.
+                match crate::programs::dnscrypt::reload_d2s() {
+                    Ok(n) => Ok(n > 0),
+                    Err(e) => {
+                        log::warn!("d2s reload after config save failed: {e:#}");
+                        Ok(false)
+                    }
+                }
             })();
             match res {
-                Ok(_) => write_ok(stream),
+                Ok(reloaded) => write_json(stream, 200, json!({"ok": true, "reloaded": reloaded})),
                 Err(e) => write_err(stream, e),
             }
         }
@@ -6381,37 +6251,16 @@ fn handle_programs_subroutes(stream: TcpStream, method: &str, path: &str, header
         _ => write_empty_404(stream),
     }
 }
-fn is_authorized(headers: &HashMap<String, String>, token: &str) -> bool {
-    if let Some(v) = headers.get("x-api-key") {
-        return v.trim() == token;
-    }
-    if let Some(v) = headers.get("authorization") {
-        let v = v.trim();
-        if let Some(rest) = v.strip_prefix("Bearer ") {
-            return rest.trim() == token;
-        }
-    }
-    false
+fn write_json(stream: Responder, status: u16, body: serde_json::Value) -> Result<()> {
+    stream.send(status, Some("application/json"), body.to_string().as_bytes())
 }
 
-fn write_json(mut stream: TcpStream, status: u16, body: serde_json::Value) -> Result<()> {
-    let body_s = body.to_string();
-    let hdr = format!(
-        "HTTP/1.1 {status} OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
-        body_s.len()
-    );
-    stream.write_all(hdr.as_bytes())?;
-    stream.write_all(body_s.as_bytes())?;
-    Ok(())
+fn write_empty_404(stream: Responder) -> Result<()> {
+    stream.send(404, None, b"")
 }
 
-fn write_empty_404(mut stream: TcpStream) -> Result<()> {
-    stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")?;
-    Ok(())
-}
 
-
-fn handle_construction_subroutes(stream: TcpStream, method: &str, path: &str, body: &[u8], _services_running: bool) -> Result<()> {
+fn handle_construction_subroutes(stream: Responder, method: &str, path: &str, body: &[u8], _services_running: bool) -> Result<()> {
     match (method, path) {
         ("GET", "/api/construction/proxy-endpoints") => {
             let res = collect_construction_proxy_endpoint_candidates();
@@ -6777,9 +6626,489 @@ fn construction_operaproxy_server_count(root: &Path) -> usize {
     v.as_array().map(|a| a.len()).or_else(|| v.get("items").and_then(|x| x.as_array()).map(|a| a.len())).unwrap_or(1)
 }
 
-fn handle_connection(mut stream: TcpStream, state: SharedState) -> Result<()> {
+/// Per-request state handed to typed route handlers.
+struct RouteCtx {
+    req: Request,
+    services_running: bool,
+    services_partial: bool,
+    runtime_state: String,
+    start_in_progress: bool,
+    stop_in_progress: bool,
+}
+
+fn router() -> &'static Router<RouteCtx> {
+    static ROUTER: OnceLock<Router<RouteCtx>> = OnceLock::new();
+    ROUTER.get_or_init(|| {
+        Router::new()
+            .route("GET", "/api/system/capabilities", route_capabilities)
+            .route("GET", "/api/runtime-apply/status", route_runtime_apply_status)
+            .route("GET", "/api/plan", route_plan)
+            .route("POST", "/api/plan/apply", route_plan_apply)
+            .route("GET", "/api/events", route_events)
+            .route("GET", "/api/tokens", route_tokens_list)
+            .route("POST", "/api/tokens", route_tokens_mint)
+            .route("DELETE", "/api/tokens/{id}", route_tokens_revoke)
+            .route("GET", "/api/supervisor", route_supervisor)
+            .route("GET", "/api/traffic/history", route_traffic_history)
+            .route("GET", "/api/quotas", route_quotas)
+            .route("PUT", "/api/quotas", route_quotas_put)
+            .route("GET", "/api/backup/export", route_backup_export)
+            .route("POST", "/api/backup/import", route_backup_import)
+            .route("PUT", "/api/programs/{id:nfqws|nfqws2|byedpi|dpitunnel}/profiles/{profile}/enabled", route_profile_enabled_put)
+            .route("DELETE", "/api/programs/{id:nfqws|nfqws2|byedpi|dpitunnel}/profiles/{profile}", route_profile_delete)
+            .route("POST", "/api/programs/{id}/profiles/{profile}/restart", route_profile_restart)
+            .route("POST", "/api/programs/{id}/profiles/{profile}/reload", route_profile_reload)
+            .route("GET", "/api/programs/{id:nfqws|nfqws2|byedpi|dpitunnel}/profiles/{profile}/config", route_profile_config_get)
+            .route("PUT", "/api/programs/{id:nfqws|nfqws2|byedpi|dpitunnel}/profiles/{profile}/config", route_profile_config_put)
+            .route("GET", "/api/programs/{id:nfqws|nfqws2|byedpi|dpitunnel}/profiles/{profile}/apps/{kind}", route_profile_apps_get)
+            .route("PUT", "/api/programs/{id:nfqws|nfqws2|byedpi|dpitunnel}/profiles/{profile}/apps/{kind}", route_profile_apps_put)
+    })
+}
+
+fn route_capabilities(stream: Responder, _cx: &RouteCtx, _p: &Params) -> Result<()> {
+    write_json(stream, 200, crate::capabilities::collect())
+}
+
+fn route_runtime_apply_status(stream: Responder, _cx: &RouteCtx, _p: &Params) -> Result<()> {
+    write_json(stream, 200, crate::runtime_apply::status_json())
+}
+
+fn route_plan(stream: Responder, cx: &RouteCtx, _p: &Params) -> Result<()> {
+    let plan = crate::plan::build();
+    let diff = crate::plan::diff(&plan);
+    write_json(stream, 200, json!({"ok": true, "services_running": cx.services_running, "plan": plan, "diff": diff}))
+}
+
+fn route_plan_apply(stream: Responder, cx: &RouteCtx, _p: &Params) -> Result<()> {
+    if cx.start_in_progress || cx.stop_in_progress {
+        return write_json(stream, 200, json!({"ok": false, "error": "start/stop in progress"}));
+    }
+    if !cx.services_running {
+        return write_json(stream, 200, json!({"ok": false, "error": "services are not running"}));
+    }
+    let (report, diff) = crate::plan::reconcile();
+    write_json(stream, 200, json!({"ok": report.ok(), "report": report, "diff": diff}))
+}
+
+/// POST /api/programs/{id}/profiles/{profile}/restart
+fn route_profile_restart(stream: Responder, cx: &RouteCtx, p: &Params) -> Result<()> {
+    profile_runtime_action(stream, cx, p, crate::plan::restart_profile)
+}
+
+/// POST /api/programs/{id}/profiles/{profile}/reload
+fn route_profile_reload(stream: Responder, cx: &RouteCtx, p: &Params) -> Result<()> {
+    profile_runtime_action(stream, cx, p, crate::plan::reload_profile)
+}
+
+fn profile_runtime_action(
+    stream: Responder,
+    cx: &RouteCtx,
+    p: &Params,
+    action: fn(&str, &str) -> Result<crate::plan::ApplyReport>,
+) -> Result<()> {
+    let (program, profile) = (crate::plan::program_key(p.get("id")), p.get("profile"));
+    if !is_safe_segment(program) || !is_safe_segment(profile) {
+        return write_json(stream, 200, json!({"ok": false, "error": "bad program or profile name"}));
+    }
+    if cx.start_in_progress || cx.stop_in_progress {
+        return write_json(stream, 200, json!({"ok": false, "error": "start/stop in progress"}));
+    }
+    if !cx.services_running {
+        return write_json(stream, 200, json!({"ok": false, "error": "services are not running"}));
+    }
+    match action(program, profile) {
+        Ok(report) => write_json(stream, 200, json!({"ok": report.ok(), "report": report})),
+        Err(e) if e.is::<crate::plan::NotRestartable>() => {
+            write_json(stream, 409, json!({"ok": false, "error": format!("{e:#}")}))
+        }
+        Err(e) => write_err(stream, e),
+    }
+}
+
+/// GET /api/tokens (secrets are never returned here)
+fn route_tokens_list(stream: Responder, _cx: &RouteCtx, _p: &Params) -> Result<()> {
+    let tokens: Vec<serde_json::Value> = crate::api_tokens::list()
+        .into_iter()
+        .map(|t| json!({"id": t.id, "name": t.name, "scopes": t.scopes, "created_at": t.created_at}))
+        .collect();
+    let scopes: Vec<&str> = crate::api_tokens::Scope::ALL.iter().map(|s| s.as_str()).collect();
+    write_json(stream, 200, json!({"ok": true, "tokens": tokens, "scopes": scopes}))
+}
+
+/// POST /api/tokens {"name": "...", "scopes": ["status", ...]}
+fn route_tokens_mint(stream: Responder, cx: &RouteCtx, _p: &Params) -> Result<()> {
+    let res = (|| -> Result<serde_json::Value> {
+        #[derive(Deserialize)]
+        struct Req {
+            name: String,
+            scopes: Vec<crate::api_tokens::Scope>,
+        }
+        let req: Req = serde_json::from_slice(&cx.req.body)
+            .map_err(|e| anyhow::anyhow!("bad JSON body: {e}"))?;
+        let (entry, secret) = crate::api_tokens::mint(&req.name, &req.scopes)?;
+        Ok(json!({"ok": true, "id": entry.id, "name": entry.name, "scopes": entry.scopes, "token": secret}))
+    })();
+    match res {
+        Ok(v) => write_json(stream, 200, v),
+        Err(e) => write_err(stream, e),
+    }
+}
+
+/// DELETE /api/tokens/{id}
+fn route_tokens_revoke(stream: Responder, _cx: &RouteCtx, p: &Params) -> Result<()> {
+    match crate::api_tokens::revoke(p.get("id")) {
+        Ok(()) => write_ok(stream),
+        Err(e) => write_err(stream, e),
+    }
+}
+
+/// GET /api/supervisor: supervised processes and their restart state, plus
+/// the programs that are never supervised
+fn route_supervisor(stream: Responder, _cx: &RouteCtx, _p: &Params) -> Result<()> {
+    let unsupervised: Vec<_> = crate::supervisor::UNSUPERVISED
+        .iter()
+        .map(|(program, reason)| json!({"program": program, "reason": reason}))
+        .collect();
+    write_json(
+        stream,
+        200,
+        json!({
+            "ok": true,
+            "degraded": crate::supervisor::degraded(),
+            "processes": crate::supervisor::snapshot(),
+            "unsupervised": unsupervised,
+        }),
+    )
+}
+
+/// GET /api/traffic/history?scope=...&from=...&to=...&resolution=...
+fn route_traffic_history(stream: Responder, cx: &RouteCtx, _p: &Params) -> Result<()> {
+    let res = (|| -> Result<serde_json::Value> {
+        let unix = |key: &str| -> Result<Option<u64>> {
+            cx.req
+                .query_param(key)
+                .map(|v| v.trim().parse::<u64>().map_err(|_| anyhow::anyhow!("{key} must be unix seconds")))
+                .transpose()
+        };
+        let resolution = match cx.req.query_param("resolution") {
+            Some(raw) => Some(
+                crate::traffic_history::Resolution::parse(&raw)
+                    .ok_or_else(|| anyhow::anyhow!("resolution must be minute, hour, day or month"))?,
+            ),
+            None => None,
+        };
+        let scope = cx.req.query_param("scope").unwrap_or_else(|| "all".to_string());
+        crate::traffic_history::history_json(&scope, unix("from")?, unix("to")?, resolution)
+    })();
+    match res {
+        Ok(v) => write_json(stream, 200, v),
+        Err(e) => write_err(stream, e),
+    }
+}
+
+/// GET /api/quotas: quota configuration with current usage
+fn route_quotas(stream: Responder, _cx: &RouteCtx, _p: &Params) -> Result<()> {
+    match crate::quota::status_json() {
+        Ok(v) => write_json(stream, 200, v),
+        Err(e) => write_err(stream, e),
+    }
+}
+
+/// PUT /api/quotas {"quotas": [...]}
+fn route_quotas_put(stream: Responder, cx: &RouteCtx, _p: &Params) -> Result<()> {
+    let res = (|| -> Result<crate::quota::QuotaConfig> {
+        let cfg: crate::quota::QuotaConfig = serde_json::from_slice(&cx.req.body)
+            .map_err(|e| anyhow::anyhow!("bad JSON body: {e}"))?;
+        crate::quota::save_config(cfg)
+    })();
+    match res {
+        Ok(cfg) => write_json(stream, 200, json!({"ok": true, "quotas": cfg.quotas})),
+        Err(e) => write_err(stream, e),
+    }
+}
+
+/// GET /api/backup/export
+fn route_backup_export(stream: Responder, _cx: &RouteCtx, _p: &Params) -> Result<()> {
+    match crate::backup::export_json() {
+        Ok(v) => write_json(stream, 200, v),
+        Err(e) => write_err(stream, e),
+    }
+}
+
+/// POST /api/backup/import (service must be stopped)
+fn route_backup_import(stream: Responder, cx: &RouteCtx, _p: &Params) -> Result<()> {
+    let res = (|| -> Result<serde_json::Value> {
+        let (bundle, dry_run) = crate::backup::parse_import_body(&cx.req.body)?;
+        if !dry_run && (cx.services_running || cx.start_in_progress || cx.stop_in_progress) {
+            anyhow::bail!("stop services before importing a backup");
+        }
+        let report = crate::backup::import(&bundle, dry_run)?;
+        if !dry_run {
+            invalidate_assignment_cache();
+        }
+        Ok(json!({"ok": true, "report": report}))
+    })();
+    match res {
+        Ok(v) => write_json(stream, 200, v),
+        Err(e) => write_err(stream, e),
+    }
+}
+
+/// GET /api/events: server-sent event stream of daemon state changes.
+///
+/// The first frame is a `hello` snapshot of the current state. A client that
+/// reconnects with `Last-Event-ID` gets the buffered events it missed, or a
+/// `resync` frame when they are gone and it should re-read `/api/status`.
+fn route_events(stream: Responder, cx: &RouteCtx, _p: &Params) -> Result<()> {
+    // A 200 here would look like a stream that ended at once, and EventSource
+    // reconnects immediately; a 503 makes it back off.
+    let Some(sub) = crate::events::subscribe() else {
+        let body = json!({"ok": false, "error": "too many event subscribers"}).to_string();
+        return stream.send_with_headers(503, Some("application/json"), &[("Retry-After", "30")], body.as_bytes());
+    };
+    let resume = cx
+        .req
+        .headers
+        .get("last-event-id")
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    let hello = json!({
+        "runtime_state": cx.runtime_state,
+        "services_running": cx.services_running,
+        "services_partial": cx.services_partial,
+        "start_in_progress": cx.start_in_progress,
+        "stop_in_progress": cx.stop_in_progress,
+    });
+    let mut out = stream.start_stream("text/event-stream", &[("Cache-Control", "no-cache")])?;
+    thread::spawn(move || {
+        let _sub = sub;
+        if let Err(e) = pump_events(&mut out, resume, &hello) {
+            log::debug!("api events stream closed: {e:#}");
+        }
+    });
+    Ok(())
+}
+
+fn write_sse(out: &mut TcpStream, id: Option<u64>, kind: &str, data: &serde_json::Value) -> Result<()> {
+    let mut frame = String::new();
+    if let Some(id) = id {
+        frame.push_str(&format!("id: {id}\n"));
+    }
+    frame.push_str(&format!("event: {kind}\ndata: {data}\n\n"));
+    out.write_all(frame.as_bytes())?;
+    Ok(())
+}
+
+fn pump_events(out: &mut TcpStream, resume: Option<u64>, hello: &serde_json::Value) -> Result<()> {
+    // Comment frame keeps proxies from timing out and detects dead clients.
+    const HEARTBEAT: Duration = Duration::from_secs(25);
+
+    out.write_all(b"retry: 3000\n\n")?;
+    write_sse(out, None, "hello", hello)?;
+    let newest = crate::events::last_id();
+    let mut last = match resume {
+        // Ids restart with the daemon; a newer id than ours belongs to a previous instance.
+        Some(id) if id > newest => {
+            write_sse(out, None, "resync", &json!({}))?;
+            newest
+        }
+        Some(id) => id,
+        None => newest,
+    };
+    loop {
+        let batch = crate::events::wait_after(last, HEARTBEAT);
+        if batch.gap {
+            write_sse(out, None, "resync", &json!({}))?;
+        }
+        if batch.events.is_empty() {
+            out.write_all(b": ping\n\n")?;
+            continue;
+        }
+        for ev in batch.events {
+            last = ev.id;
+            write_sse(out, Some(ev.id), ev.kind, &json!({"ts": ev.ts, "data": ev.data}))?;
+        }
+    }
+}
+
+/// Map `apps/{kind}` of an exact-profile program to its uid list file.
+fn exact_profile_apps_file(id: &str, kind: &str) -> Result<&'static str> {
+    Ok(match (id, kind) {
+        ("nfqws", "user") => "user_program",
+        ("nfqws", "mobile") => "mobile_program",
+        ("nfqws", "wifi") => "wifi_program",
+        ("nfqws2", "user") => "user_program",
+        ("nfqws2", "mobile") => "mobile_program",
+        ("nfqws2", "wifi") => "wifi_program",
+        ("byedpi", "user") => "user_program",
+        ("dpitunnel", "user") => "user_program",
+        ("dpitunnel", "mobile") => "mobile_program",
+        ("dpitunnel", "wifi") => "wifi_program",
+        _ => anyhow::bail!("invalid apps kind for program"),
+    })
+}
+
+/// PUT /api/programs/{id}/profiles/{profile}/enabled
+fn route_profile_enabled_put(stream: Responder, cx: &RouteCtx, p: &Params) -> Result<()> {
+    let res = (|| -> Result<()> {
+        let (id, profile) = (p.get("id"), p.get("profile"));
+        ensure_safe_segment(id, "program id")?;
+        ensure_safe_segment(profile, "profile name")?;
+        let req: EnabledReq = serde_json::from_slice(&cx.req.body)
+            .map_err(|e| anyhow::anyhow!("bad JSON body: {e}"))?;
+        let path = active_json_path(id);
+        let mut active: ProfilesActive = read_json(&path)?;
+        let st = active.profiles.get_mut(profile)
+            .ok_or_else(|| anyhow::anyhow!("profile not found"))?;
+        st.enabled = req.enabled;
+        write_json_pretty(&path, &active)?;
+        Ok(())
+    })();
+    match res {
+        Ok(_) => write_ok(stream),
+        Err(e) => write_err(stream, e),
+    }
+}
+
+/// DELETE /api/programs/{id}/profiles/{profile} (soft delete by moving to .deleted/)
+fn route_profile_delete(stream: Responder, _cx: &RouteCtx, p: &Params) -> Result<()> {
+    let res = (|| -> Result<()> {
+        let (id, profile) = (p.get("id"), p.get("profile"));
+        ensure_safe_segment(id, "program id")?;
+        ensure_safe_segment(profile, "profile name")?;
+        let path = active_json_path(id);
+        let mut active: ProfilesActive = read_json(&path)?;
+        if active.profiles.remove(profile).is_none() {
+            anyhow::bail!("profile not found");
+        }
+        write_json_pretty(&path, &active)?;
+
+        let src = profile_root(id, profile);
+        if src.exists() {
+            let deleted_dir = program_root(id).join(".deleted");
+            fs::create_dir_all(&deleted_dir).ok();
+            let ts = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            let dst = deleted_dir.join(format!("{profile}.{ts}"));
+            fs::rename(&src, &dst)
+                .map_err(|e| anyhow::anyhow!("move failed {} -> {}: {e}", src.display(), dst.display()))?;
+        }
+        Ok(())
+    })();
+    match res {
+        Ok(_) => write_ok(stream),
+        Err(e) => write_err(stream, e),
+    }
+}
+
+/// GET /api/programs/{id}/profiles/{profile}/config
+fn route_profile_config_get(stream: Responder, _cx: &RouteCtx, p: &Params) -> Result<()> {
+    let res = (|| -> Result<String> {
+        let (id, profile) = (p.get("id"), p.get("profile"));
+        ensure_safe_segment(id, "program id")?;
+        ensure_safe_segment(profile, "profile name")?;
+        read_text(&profile_root(id, profile).join("config/config.txt"))
+    })();
+    match res {
+        Ok(content) => write_json(stream, 200, json!({"ok": true, "content": content})),
+        Err(e) => write_err(stream, e),
+    }
+}
+
+/// PUT /api/programs/{id}/profiles/{profile}/config
+fn route_profile_config_put(stream: Responder, cx: &RouteCtx, p: &Params) -> Result<()> {
+    let res = (|| -> Result<()> {
+        let (id, profile) = (p.get("id"), p.get("profile"));
+        ensure_safe_segment(id, "program id")?;
+        ensure_safe_segment(profile, "profile name")?;
+        let req: ContentReq = serde_json::from_slice(&cx.req.body)
+            .map_err(|e| anyhow::anyhow!("bad JSON body: {e}"))?;
+        write_text_atomic(&profile_root(id, profile).join("config/config.txt"), &req.content)?;
+        Ok(())
+    })();
+    match res {
+        Ok(_) => write_ok(stream),
+        Err(e) => write_err(stream, e),
+    }
+}
+
+/// GET /api/programs/{id}/profiles/{profile}/apps/{kind}
+fn route_profile_apps_get(stream: Responder, _cx: &RouteCtx, p: &Params) -> Result<()> {
+    let res = (|| -> Result<String> {
+        let (id, profile, kind) = (p.get("id"), p.get("profile"), p.get("kind"));
+        ensure_safe_segment(id, "program id")?;
+        ensure_safe_segment(profile, "profile name")?;
+        ensure_safe_segment(kind, "apps kind")?;
+        let fname = exact_profile_apps_file(id, kind)?;
+        let path = profile_root(id, profile).join(format!("app/uid/{fname}"));
+        if id == "dpitunnel" {
+            read_text_or_empty(&path)
+        } else {
+            read_text(&path)
+        }
+    })();
+    match res {
+        Ok(content) => write_json(stream, 200, json!({"ok": true, "content": content})),
+        Err(e) => write_err(stream, e),
+    }
+}
+
+/// PUT /api/programs/{id}/profiles/{profile}/apps/{kind}
+fn route_profile_apps_put(stream: Responder, cx: &RouteCtx, p: &Params) -> Result<()> {
+    let res = (|| -> Result<()> {
+        let (id, profile, kind) = (p.get("id"), p.get("profile"), p.get("kind"));
+        ensure_safe_segment(id, "program id")?;
+        ensure_safe_segment(profile, "profile name")?;
+        ensure_safe_segment(kind, "apps kind")?;
+        let req: ContentReq = serde_json::from_slice(&cx.req.body)
+            .map_err(|e| anyhow::anyhow!("bad JSON body: {e}"))?;
+        let fname = exact_profile_apps_file(id, kind)?;
+        let api_path = format!("/api/programs/{}/profiles/{}/apps/{}", id, profile, kind);
+        let slot = slot_from_kind(kind).ok_or_else(|| anyhow::anyhow!("invalid apps kind"))?;
+        validate_program_apps_content(&req.content, &api_path, id, slot)?;
+        let path = profile_root(id, profile).join(format!("app/uid/{fname}"));
+        write_text_atomic(&path, &req.content)?;
+        invalidate_assignment_cache();
+        refresh_apps_after_save_if_running(cx.services_running, id, Some(profile), slot)?;
+        Ok(())
+    })();
+    match res {
+        Ok(_) => write_ok(stream),
+        Err(e) => write_err(stream, e),
+    }
+}
+
+fn handle_connection(stream: TcpStream, state: SharedState) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    for served in 0..api_http::MAX_REQUESTS_PER_CONN {
+        let idle = if served == 0 { api_http::REQUEST_TIMEOUT } else { api_http::KEEP_ALIVE_IDLE };
+        let req = match api_http::read_request(&mut reader, idle) {
+            Ok(Some(req)) => req,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                if let Some(he) = e.downcast_ref::<api_http::HttpError>() {
+                    let _ = Responder::new(stream.try_clone()?, false, Arc::default()).send(he.status, None, b"");
+                }
+                return Err(e);
+            }
+        };
+        if served > 0 {
+            crate::idle::touch();
+        }
+        // The last request allowed on this connection is answered with `Connection: close`.
+        let keep_alive = req.keep_alive && served + 1 < api_http::MAX_REQUESTS_PER_CONN;
+        let reusable = Arc::new(AtomicBool::new(false));
+        handle_request(Responder::new(stream.try_clone()?, keep_alive, reusable.clone()), req, &state)?;
+        if !reusable.load(Ordering::Acquire) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+fn handle_request(stream: Responder, req: Request, state: &SharedState) -> Result<()> {
     let request_started = Instant::now();
-    let (method, path, headers, body) = parse_http_request(&mut stream)?;
 
     struct SlowRequestGuard {
         started: Instant,
@@ -6801,12 +7130,12 @@ fn handle_connection(mut stream: TcpStream, state: SharedState) -> Result<()> {
     }
     let _slow_request_guard = SlowRequestGuard {
         started: request_started,
-        method: method.clone(),
-        path: path.clone(),
+        method: req.method.clone(),
+        path: req.path.clone(),
     };
 
     let (token, services_running, memory_runtime_state, memory_start_in_progress, memory_stop_in_progress, memory_services_partial) = {
-        let st = daemon::lock_state(&state);
+        let st = daemon::lock_state(state);
         (
             st.token.clone(),
             st.services_running,
@@ -6832,14 +7161,37 @@ fn handle_connection(mut stream: TcpStream, state: SharedState) -> Result<()> {
         .unwrap_or(memory_services_partial);
 
     // Only /api/* is exposed. Everything else -> empty 404.
-    if !path.starts_with("/api/") {
+    if !req.path.starts_with("/api/") {
         return write_empty_404(stream);
     }
 
-    if !is_authorized(&headers, &token) {
+    let Some(grant) = crate::api_tokens::authorize(&req.headers, &token) else {
         // Hide API from unauthenticated clients: empty 404.
         return write_empty_404(stream);
+    };
+    let need = crate::api_tokens::required_scope(&req.method, &req.path);
+    if !grant.allows(need) {
+        log::info!("api: token {} denied {} {} (needs {})", grant.label(), req.method, req.path, need.as_str());
+        return write_json(stream, 403, json!({"ok": false, "error": format!("token lacks scope {}", need.as_str())}));
+    }
+
+    // Typed routes first; whatever they do not know falls through to the legacy dispatch below.
+    let cx = RouteCtx {
+        req,
+        services_running,
+        services_partial,
+        runtime_state: runtime_state.clone(),
+        start_in_progress,
+        stop_in_progress,
+    };
+    match router().find(&cx.req.method, &cx.req.path) {
+        Match::Found(handler, params) => return handler(stream, &cx, &params),
+        Match::MethodNotAllowed(allowed) => {
+            return stream.send_with_headers(405, None, &[("Allow", &allowed.join(", "))], b"");
+        }
+        Match::NotFound => {}
     }
+    let Request { method, path, headers, body, .. } = cx.req;
 
     
     // Construction Studio API
@@ -6866,14 +7218,6 @@ fn handle_connection(mut stream: TcpStream, state: SharedState) -> Result<()> {
     }
 
 match (method.as_str(), path.as_str()) {
-        ("GET", "/api/system/capabilities") => {
-            write_json(stream, 200, crate::capabilities::collect())
-        }
-
-        ("GET", "/api/runtime-apply/status") => {
-            write_json(stream, 200, crate::runtime_apply::status_json())
-        }
-
         ("GET", "/api/status") => {
             let (report, cached, degraded) = get_status_snapshot(services_running);
             let mut value = match serde_json::to_value(report) {
@@ -6963,6 +7307,18 @@ match (method.as_str(), path.as_str()) {
                 selinux_permissive_enabled: Option<bool>,
                 #[serde(default)]
                 ip_forward_enabled: Option<bool>,
+                #[serde(default)]
+                supervisor_enabled: Option<bool>,
+                #[serde(default)]
+                supervisor_bypass_when_down: Option<bool>,
+                #[serde(default)]
+                firewall_backend: Option<String>,
+                #[serde(default)]
+                dns_upstreams: Option<Vec<String>>,
+                #[serde(default)]
+                traffic_history_enabled: Option<bool>,
+                #[serde(default)]
+                traffic_history_interval_secs: Option<u64>,
             }
 
             let patch: SettingPatch = serde_json::from_slice(&body)
@@ -7047,6 +7403,27 @@ match (method.as_str(), path.as_str()) {
                 setting.ip_forward_enabled = enabled;
                 apply_ip_forward = Some(enabled);
             }
+            if let Some(enabled) = patch.supervisor_enabled {
+                setting.supervisor_enabled = enabled;
+            }
+            if let Some(enabled) = patch.supervisor_bypass_when_down {
+                setting.supervisor_bypass_when_down = enabled;
+            }
+            if let Some(backend) = patch.firewall_backend {
+                setting.firewall_backend = backend;
+            }
+            if let Some(upstreams) = patch.dns_upstreams {
+                for item in upstreams.iter().filter(|s| !s.trim().is_empty()) {
+                    crate::dns::check_upstream(item)?;
+                }
+                setting.dns_upstreams = upstreams;
+            }
+            if let Some(enabled) = patch.traffic_history_enabled {
+                setting.traffic_history_enabled = enabled;
+            }
+            if let Some(secs) = patch.traffic_history_interval_secs {
+                setting.traffic_history_interval_secs = secs;
+            }
             if setting.hotspot_t2s_enabled && !setting.ip_forward_enabled {
                 setting.ip_forward_enabled = true;
                 apply_ip_forward = Some(true);
@@ -7189,14 +7566,14 @@ match (method.as_str(), path.as_str()) {
         }
 
         ("POST", "/api/start") => {
-            let res = daemon::handle_start_async(&state);
+            let res = daemon::handle_start_async(state);
             match res {
                 Ok(accepted) => write_json(stream, 200, json!({"ok": true, "accepted": accepted})),
                 Err(e) => write_json(stream, 200, json!({"ok": false, "error": format!("{e:#}")})),
             }
         }
         ("POST", "/api/stop") => {
-            let res = daemon::handle_stop_async(&state);
+            let res = daemon::handle_stop_async(state);
             match res {
                 Ok(accepted) => write_json(stream, 200, json!({"ok": true, "accepted": accepted})),
                 Err(e) => write_json(stream, 200, json!({"ok": false, "error": format!("{e:#}")})),
@@ -0,0 +1,409 @@
+use anyhow::Result;
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+pub const MAX_HEADER: usize = 16 * 1024;
+// Allow uploading strategic files (including binaries). The API is local-only and authenticated,
+// but we still cap body size to avoid accidental memory blowups.
+pub const MAX_BODY: usize = 512 * 1024 * 1024;
+
+/// Time allowed to receive the rest of a request once its first line arrived.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long an idle keep-alive connection waits for the next request.
+pub const KEEP_ALIVE_IDLE: Duration = Duration::from_secs(5);
+/// Requests served over one connection before it is closed.
+pub const MAX_REQUESTS_PER_CONN: usize = 100;
+
+/// Protocol-level failure that should be answered with `status` before closing.
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: u16,
+    pub msg: &'static str,
+}
+
+impl HttpError {
+    fn err(status: u16, msg: &'static str) -> anyhow::Error {
+        anyhow::Error::new(Self { status, msg })
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} {})", self.msg, self.status, reason_phrase(self.status))
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+pub struct Request {
+    pub method: String,
+    /// Request target without the query string.
+    pub path: String,
+    /// Raw query string, without the leading `?`.
+    pub query: String,
+    /// Header names are lower-cased.
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+    pub keep_alive: bool,
+}
+
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        409 => "Conflict",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        415 => "Unsupported Media Type",
+        429 => "Too Many Requests",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        s if (200..300).contains(&s) => "OK",
+        s if (400..500).contains(&s) => "Bad Request",
+        _ => "Internal Server Error",
+    }
+}
+
+impl Request {
+    /// First value of query parameter `key`, percent-decoded.
+    pub fn query_param(&self, key: &str) -> Option<String> {
+        self.query.split('&').find_map(|pair| {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            (percent_decode(k) == key).then(|| percent_decode(v))
+        })
+    }
+}
+
+fn percent_decode(raw: &str) -> String {
+    let bytes = raw.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                    Some(b) => {
+                        out.push(b);
+                        i += 2;
+                    }
+                    None => out.push(b'%'),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// Read one CRLF/LF terminated line, charging its length against `budget`.
+/// Returns `None` on EOF before any byte.
+fn read_line<R: BufRead>(r: &mut R, budget: &mut usize, too_long: u16) -> Result<Option<String>> {
+    let mut buf = Vec::new();
+    let n = r.by_ref().take(*budget as u64 + 1).read_until(b'\n', &mut buf)?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if n > *budget {
+        return Err(HttpError::err(too_long, "HTTP header too large"));
+    }
+    *budget -= n;
+    if buf.last() != Some(&b'\n') {
+        return Err(HttpError::err(400, "unexpected end of request"));
+    }
+    while matches!(buf.last(), Some(b'\n' | b'\r')) {
+        buf.pop();
+    }
+    Ok(Some(String::from_utf8_lossy(&buf).into_owned()))
+}
+
+fn has_token(value: Option<&String>, token: &str) -> bool {
+    value
+        .map(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+        .unwrap_or(false)
+}
+
+fn read_chunked<R: BufRead>(r: &mut R) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let mut budget = MAX_HEADER;
+        let line = read_line(r, &mut budget, 400)?
+            .ok_or_else(|| HttpError::err(400, "unexpected end of chunked body"))?;
+        let size_s = line.split(';').next().unwrap_or("").trim();
+        let size = usize::from_str_radix(size_s, 16)
+            .map_err(|_| HttpError::err(400, "bad chunk size"))?;
+        if size == 0 {
+            // Trailer section: skip until the terminating empty line.
+            let mut budget = MAX_HEADER;
+            while let Some(l) = read_line(r, &mut budget, 431)? {
+                if l.is_empty() {
+                    break;
+                }
+            }
+            return Ok(body);
+        }
+        if body.len().saturating_add(size) > MAX_BODY {
+            return Err(HttpError::err(413, "HTTP body too large"));
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        r.read_exact(&mut body[start..])?;
+        let mut crlf = [0_u8; 2];
+        r.read_exact(&mut crlf)?;
+        if &crlf != b"\r\n" {
+            return Err(HttpError::err(400, "bad chunk terminator"));
+        }
+    }
+}
+
+/// Read the next request from a (possibly kept-alive) connection.
+///
+/// Waits up to `idle` for the request line; an idle timeout or a clean EOF
+/// between requests yields `Ok(None)`. Once the request line has arrived the
+/// rest must follow within [`REQUEST_TIMEOUT`].
+pub fn read_request(r: &mut BufReader<TcpStream>, idle: Duration) -> Result<Option<Request>> {
+    r.get_ref().set_read_timeout(Some(idle))?;
+
+    let mut budget = MAX_HEADER;
+    let request_line = loop {
+        let line = match read_line(r, &mut budget, 414) {
+            Ok(Some(l)) => l,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                if e.downcast_ref::<std::io::Error>().map(is_timeout).unwrap_or(false) && budget == MAX_HEADER {
+                    return Ok(None);
+                }
+                return Err(e);
+            }
+        };
+        // Tolerate stray CRLFs between pipelined requests.
+        if !line.is_empty() {
+            break line;
+        }
+    };
+    r.get_ref().set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut it = request_line.split_whitespace();
+    let method = it.next().unwrap_or("").to_string();
+    let target = it.next().unwrap_or("");
+    let version = it.next().unwrap_or("HTTP/1.0");
+    if method.is_empty() || !target.starts_with('/') {
+        return Err(HttpError::err(400, "malformed request line"));
+    }
+    let http11 = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return Err(HttpError::err(505, "unsupported HTTP version")),
+    };
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let (path, query) = (path.to_string(), query.to_string());
+
+    let mut headers = HashMap::new();
+    let mut budget = MAX_HEADER;
+    loop {
+        let line = read_line(r, &mut budget, 431)?
+            .ok_or_else(|| HttpError::err(400, "unexpected end of headers"))?;
+        if line.is_empty() {
+            break;
+        }
+        if let Some((k, v)) = line.split_once(':') {
+            headers.insert(k.trim().to_ascii_lowercase(), v.trim().to_string());
+        }
+    }
+
+    let keep_alive = if http11 {
+        !has_token(headers.get("connection"), "close")
+    } else {
+        has_token(headers.get("connection"), "keep-alive")
+    };
+
+    let chunked = headers
+        .get("transfer-encoding")
+        .and_then(|v| v.rsplit(',').next())
+        .map(|last| last.trim().eq_ignore_ascii_case("chunked"))
+        .unwrap_or(false);
+    if headers.contains_key("transfer-encoding") && !chunked {
+        return Err(HttpError::err(501, "unsupported transfer encoding"));
+    }
+    let content_length = match headers.get("content-length") {
+        Some(cl) if !chunked => Some(
+            cl.trim()
+                .parse::<usize>()
+                .map_err(|_| HttpError::err(400, "bad Content-Length"))?,
+        ),
+        _ => None,
+    };
+    if content_length.unwrap_or(0) > MAX_BODY {
+        return Err(HttpError::err(413, "HTTP body too large"));
+    }
+
+    if http11 && (chunked || content_length.unwrap_or(0) > 0) && has_token(headers.get("expect"), "100-continue") {
+        let mut s = r.get_ref();
+        s.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+    }
+
+    let body = if chunked {
+        read_chunked(r)?
+    } else if let Some(len) = content_length {
+        let mut body = vec![0_u8; len];
+        r.read_exact(&mut body)?;
+        body
+    } else {
+        Vec::new()
+    };
+
+    Ok(Some(Request { method, path, query, headers, body, keep_alive }))
+}
+
+/// Write side of one request. Handlers receive it by value and consume it
+/// with exactly one response.
+pub struct Responder {
+    stream: TcpStream,
+    keep_alive: bool,
+    reusable: Arc<AtomicBool>,
+}
+
+impl Responder {
+    /// `reusable` is raised once a keep-alive response has been fully written,
+    /// telling the connection loop it may read the next request.
+    pub fn new(stream: TcpStream, keep_alive: bool, reusable: Arc<AtomicBool>) -> Self {
+        Self { stream, keep_alive, reusable }
+    }
+
+    pub fn send(self, status: u16, content_type: Option<&str>, body: &[u8]) -> Result<()> {
+        self.send_with_headers(status, content_type, &[], body)
+    }
+
+    pub fn send_with_headers(
+        mut self,
+        status: u16,
+        content_type: Option<&str>,
+        extra: &[(&str, &str)],
+        body: &[u8],
+    ) -> Result<()> {
+        let mut hdr = format!("HTTP/1.1 {status} {}\r\n", reason_phrase(status));
+        if let Some(ct) = content_type {
+            hdr.push_str(&format!("Content-Type: {ct}\r\n"));
+        }
+        for (k, v) in extra {
+            hdr.push_str(&format!("{k}: {v}\r\n"));
+        }
+        hdr.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        if self.keep_alive {
+            hdr.push_str(&format!(
+                "Connection: keep-alive\r\nKeep-Alive: timeout={}, max={}\r\n\r\n",
+                KEEP_ALIVE_IDLE.as_secs(),
+                MAX_REQUESTS_PER_CONN
+            ));
+        } else {
+            hdr.push_str("Connection: close\r\n\r\n");
+        }
+        self.stream.write_all(hdr.as_bytes())?;
+        self.stream.write_all(body)?;
+        self.stream.flush()?;
+        if self.keep_alive {
+            self.reusable.store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+}
+
+impl Responder {
+    /// Send a response head without a length and hand back the socket for an
+    /// open-ended body (event streams). The connection is not reused afterwards.
+    pub fn start_stream(mut self, content_type: &str, extra: &[(&str, &str)]) -> Result<TcpStream> {
+        let mut hdr = format!("HTTP/1.1 200 OK\r\nContent-Type: {content_type}\r\n");
+        for (k, v) in extra {
+            hdr.push_str(&format!("{k}: {v}\r\n"));
+        }
+        hdr.push_str("Connection: close\r\n\r\n");
+        self.stream.write_all(hdr.as_bytes())?;
+        self.stream.flush()?;
+        Ok(self.stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn decodes_chunked_body_with_extensions_and_trailers() {
+        let raw = b"4;ext=1\r\nWiki\r\n5\r\npedia\r\n0\r\nX-Trailer: 1\r\n\r\nNEXT";
+        let mut r = BufReader::new(Cursor::new(&raw[..]));
+        assert_eq!(read_chunked(&mut r).unwrap(), b"Wikipedia");
+        let mut rest = String::new();
+        r.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "NEXT");
+    }
+
+    #[test]
+    fn rejects_bad_chunk_size() {
+        let mut r = BufReader::new(Cursor::new(&b"zz\r\n"[..]));
+        let err = read_chunked(&mut r).unwrap_err();
+        assert_eq!(err.downcast_ref::<HttpError>().map(|e| e.status), Some(400));
+    }
+
+    #[test]
+    fn reads_pipelined_requests_from_one_connection() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .write_all(
+                b"GET /api/status?x=1&scope=profile%3Asing-box%2Fmain HTTP/1.1\r\nHost: a\r\n\r\n\
+                  POST /api/plan/apply HTTP/1.0\r\nTransfer-Encoding: chunked\r\n\r\n2\r\n{}\r\n0\r\n\r\n",
+            )
+            .unwrap();
+        drop(client);
+        let (server, _) = listener.accept().unwrap();
+        let mut r = BufReader::new(server);
+
+        let first = read_request(&mut r, REQUEST_TIMEOUT).unwrap().unwrap();
+        assert_eq!((first.method.as_str(), first.path.as_str()), ("GET", "/api/status"));
+        assert_eq!(first.query_param("scope").as_deref(), Some("profile:sing-box/main"));
+        assert!(first.keep_alive);
+
+        let second = read_request(&mut r, REQUEST_TIMEOUT).unwrap().unwrap();
+        assert_eq!(second.path, "/api/plan/apply");
+        assert_eq!(second.body, b"{}");
+        assert!(!second.keep_alive);
+
+        assert!(read_request(&mut r, REQUEST_TIMEOUT).unwrap().is_none());
+    }
+
+    #[test]
+    fn reason_phrases_follow_status() {
+        assert_eq!(reason_phrase(200), "OK");
+        assert_eq!(reason_phrase(404), "Not Found");
+        assert_eq!(reason_phrase(429), "Too Many Requests");
+    }
+}
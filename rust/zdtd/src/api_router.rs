@@ -0,0 +1,183 @@
+use anyhow::Result;
+
+use crate::api_http::Responder;
+
+/// Route handler: gets the responder, the per-request context and the
+/// parameters captured from the path pattern.
+pub type Handler<C> = fn(Responder, &C, &Params) -> Result<()>;
+
+enum Segment {
+    Literal(&'static str),
+    /// `{name}` matches any non-empty segment, `{name:a|b}` only the listed ones.
+    Param { name: &'static str, one_of: Option<Vec<&'static str>> },
+}
+
+struct Route<C> {
+    method: &'static str,
+    segments: Vec<Segment>,
+    handler: Handler<C>,
+}
+
+/// Path parameters captured by a matched route (percent-decoded).
+#[derive(Debug, Default)]
+pub struct Params(Vec<(&'static str, String)>);
+
+impl Params {
+    /// Value of a parameter declared in the route pattern. Missing names yield
+    /// an empty string, which the safe-segment checks then reject.
+    pub fn get(&self, name: &str) -> &str {
+        self.0
+            .iter()
+            .find(|(k, _)| *k == name)
+            .map(|(_, v)| v.as_str())
+            .unwrap_or("")
+    }
+}
+
+pub enum Match<C> {
+    Found(Handler<C>, Params),
+    /// The path is known but not for this method.
+    MethodNotAllowed(Vec<&'static str>),
+    NotFound,
+}
+
+pub struct Router<C> {
+    routes: Vec<Route<C>>,
+}
+
+fn parse_pattern(pattern: &'static str) -> Vec<Segment> {
+    pattern
+        .trim_start_matches('/')
+        .split('/')
+        .map(|s| match s.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+            Some(inner) => match inner.split_once(':') {
+                Some((name, alts)) => Segment::Param { name, one_of: Some(alts.split('|').collect()) },
+                None => Segment::Param { name: inner, one_of: None },
+            },
+            None => Segment::Literal(s),
+        })
+        .collect()
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    if !s.contains('%') {
+        return Some(s.to_string());
+    }
+    let b = s.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        if b[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(b[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(out).ok()
+}
+
+impl<C> Default for Router<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C> Router<C> {
+    pub fn new() -> Self {
+        Self { routes: Vec::new() }
+    }
+
+    pub fn route(mut self, method: &'static str, pattern: &'static str, handler: Handler<C>) -> Self {
+        self.routes.push(Route { method, segments: parse_pattern(pattern), handler });
+        self
+    }
+
+    fn captures(segments: &[Segment], path: &[&str]) -> Option<Params> {
+        if segments.len() != path.len() {
+            return None;
+        }
+        let mut params = Vec::new();
+        for (seg, raw) in segments.iter().zip(path) {
+            match seg {
+                Segment::Literal(lit) => {
+                    if lit != raw {
+                        return None;
+                    }
+                }
+                Segment::Param { name, one_of } => {
+                    let v = percent_decode(raw)?;
+                    if v.is_empty() {
+                        return None;
+                    }
+                    if let Some(alts) = one_of {
+                        if !alts.contains(&v.as_str()) {
+                            return None;
+                        }
+                    }
+                    params.push((*name, v));
+                }
+            }
+        }
+        Some(Params(params))
+    }
+
+    pub fn find(&self, method: &str, path: &str) -> Match<C> {
+        let parts: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        let mut allowed = Vec::new();
+        for r in &self.routes {
+            if let Some(params) = Self::captures(&r.segments, &parts) {
+                if r.method == method {
+                    return Match::Found(r.handler, params);
+                }
+                allowed.push(r.method);
+            }
+        }
+        if allowed.is_empty() {
+            Match::NotFound
+        } else {
+            Match::MethodNotAllowed(allowed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nop(_: Responder, _: &(), _: &Params) -> Result<()> {
+        Ok(())
+    }
+
+    fn router() -> Router<()> {
+        Router::new()
+            .route("GET", "/api/plan", nop)
+            .route("PUT", "/api/programs/{id:nfqws|byedpi}/profiles/{profile}/enabled", nop)
+    }
+
+    #[test]
+    fn captures_constrained_and_free_params() {
+        match router().find("PUT", "/api/programs/byedpi/profiles/my%20profile/enabled") {
+            Match::Found(_, p) => {
+                assert_eq!(p.get("id"), "byedpi");
+                assert_eq!(p.get("profile"), "my profile");
+            }
+            _ => panic!("route not matched"),
+        }
+        assert!(matches!(
+            router().find("PUT", "/api/programs/openvpn/profiles/a/enabled"),
+            Match::NotFound
+        ));
+    }
+
+    #[test]
+    fn reports_method_mismatch() {
+        match router().find("POST", "/api/plan") {
+            Match::MethodNotAllowed(m) => assert_eq!(m, vec!["GET"]),
+            _ => panic!("expected method mismatch"),
+        }
+        assert!(matches!(router().find("GET", "/api/plan/extra"), Match::NotFound));
+    }
+}
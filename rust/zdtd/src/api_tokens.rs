@@ -0,0 +1,323 @@
+//! Named API tokens with scopes.
+//!
+//! The master token in `api/token` keeps full access (it is also what t2s
+//! instances read). Additional tokens are minted through `/api/tokens` and only
+//! their SHA-256 is stored, so `tokens.json` never contains a usable secret.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{jsonfs, settings};
+
+const MAX_TOKENS: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Read-only status, events, plan and traffic views.
+    Status,
+    /// Read and change program/profile settings.
+    Config,
+    /// Start/stop the service and apply runtime changes.
+    Control,
+    FsRead,
+    FsWrite,
+    /// Strategic files, binary uploads and anything else that decides what
+    /// runs as root (commands, scripts, exec lines).
+    Strategic,
+    /// Everything, including token management and backups.
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 7] = [
+        Scope::Status,
+        Scope::Config,
+        Scope::Control,
+        Scope::FsRead,
+        Scope::FsWrite,
+        Scope::Strategic,
+        Scope::Admin,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Scope::Status => "status",
+            Scope::Config => "config",
+            Scope::Control => "control",
+            Scope::FsRead => "fs_read",
+            Scope::FsWrite => "fs_write",
+            Scope::Strategic => "strategic",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenEntry {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub sha256: String,
+    pub created_at: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct TokenFile {
+    #[serde(default)]
+    tokens: Vec<TokenEntry>,
+}
+
+/// Who is calling.
+#[derive(Debug, Clone)]
+pub enum Grant {
+    Master,
+    Scoped { id: String, scopes: Vec<Scope> },
+}
+
+impl Grant {
+    pub fn label(&self) -> &str {
+        match self {
+            Grant::Master => "master",
+            Grant::Scoped { id, .. } => id,
+        }
+    }
+
+    pub fn allows(&self, need: Scope) -> bool {
+        let scopes = match self {
+            Grant::Master => return true,
+            Grant::Scoped { scopes, .. } => scopes,
+        };
+        scopes.contains(&Scope::Admin)
+            || scopes.contains(&need)
+            // Any valid token may read status; write access implies read access.
+            || need == Scope::Status
+            || (need == Scope::FsRead && scopes.contains(&Scope::FsWrite))
+    }
+}
+
+static CACHE: OnceLock<Mutex<Option<HashMap<String, TokenEntry>>>> = OnceLock::new();
+
+fn cache() -> &'static Mutex<Option<HashMap<String, TokenEntry>>> {
+    CACHE.get_or_init(|| Mutex::new(None))
+}
+
+pub fn tokens_path() -> PathBuf {
+    Path::new(settings::API_DIR).join("tokens.json")
+}
+
+fn sha256_hex(s: &str) -> String {
+    let digest = Sha256::digest(s.as_bytes());
+    digest.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn load() -> TokenFile {
+    let p = tokens_path();
+    if !p.is_file() {
+        return TokenFile::default();
+    }
+    match jsonfs::read_json::<TokenFile>(&p) {
+        Ok(v) => v,
+        Err(e) => {
+            log::warn!("api tokens: {e:#}; scoped tokens are disabled until the file is fixed");
+            TokenFile::default()
+        }
+    }
+}
+
+fn store(file: &TokenFile) -> Result<()> {
+    let p = tokens_path();
+    jsonfs::write_json_pretty_tmp_rename(&p, file)?;
+    settings::chmod_private(&p);
+    if let Ok(mut g) = cache().lock() {
+        *g = None;
+    }
+    Ok(())
+}
+
+/// Pull the presented token out of `X-Api-Key` or `Authorization: Bearer`.
+pub fn presented(headers: &HashMap<String, String>) -> Option<&str> {
+    if let Some(v) = headers.get("x-api-key") {
+        return Some(v.trim());
+    }
+    headers
+        .get("authorization")
+        .and_then(|v| v.trim().strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+pub fn authorize(headers: &HashMap<String, String>, master: &str) -> Option<Grant> {
+    let token = presented(headers).filter(|t| !t.is_empty())?;
+    if token == master {
+        return Some(Grant::Master);
+    }
+    let hash = sha256_hex(token);
+    let mut g = match cache().lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let by_hash = g.get_or_insert_with(|| {
+        load()
+            .tokens
+            .into_iter()
+            .map(|t| (t.sha256.clone(), t))
+            .collect()
+    });
+    by_hash
+        .get(&hash)
+        .map(|t| Grant::Scoped { id: t.id.clone(), scopes: t.scopes.clone() })
+}
+
+/// Scope an endpoint requires.
+pub fn required_scope(method: &str, path: &str) -> Scope {
+    let read = method == "GET" || method == "HEAD";
+    if path == "/api/tokens" || path.starts_with("/api/tokens/") || path.starts_with("/api/backup/") {
+        return Scope::Admin;
+    }
+    if path.starts_with("/api/fs/") {
+        return if path == "/api/fs/write_text" { Scope::FsWrite } else { Scope::FsRead };
+    }
+    if path.starts_with("/api/strategic/") || path.starts_with("/api/strategicvar/") {
+        return if read { Scope::Config } else { Scope::Strategic };
+    }
+    if !read && (path.starts_with("/api/programs/myprogram/") && path.contains("/bin/") || sets_executable(path)) {
+        return Scope::Strategic;
+    }
+    if !read
+        && (matches!(path, "/api/start" | "/api/stop" | "/api/hotspot/captive/allow" | "/api/hotspot/captive/deny")
+            || path.ends_with("/apply")
+            || path.ends_with("/restart")
+            || path.ends_with("/reload"))
+    {
+        return Scope::Control;
+    }
+    if read
+        && (matches!(
+            path,
+            "/api/status"
+                | "/api/events"
+                | "/api/plan"
+                | "/api/runtime-apply/status"
+                | "/api/hiding/status"
+                | "/api/hotspot/captive/status"
+                | "/api/system/capabilities"
+                | "/api/supervisor"
+                | "/api/quotas"
+        ) || path.starts_with("/api/traffic/"))
+    {
+        return Scope::Status;
+    }
+    Scope::Config
+}
+
+/// Writes that choose a command to run: the myprogram command line, torrc
+/// (`ClientTransportPlugin ... exec`) and OpenVPN configs (`up`/`down` scripts).
+fn sets_executable(path: &str) -> bool {
+    let parts: Vec<&str> = path.trim_end_matches('/').split('/').collect();
+    matches!(
+        parts.as_slice(),
+        ["", "api", "programs", "myprogram", "profiles", _, "command"]
+            | ["", "api", "programs", "tor", "torrc"]
+            | ["", "api", "programs", "openvpn", "profiles", _, "config" | "upload-config"]
+    )
+}
+
+fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub fn list() -> Vec<TokenEntry> {
+    load().tokens
+}
+
+/// Create a token; the secret is returned once and never stored.
+pub fn mint(name: &str, scopes: &[Scope]) -> Result<(TokenEntry, String)> {
+    let name = name.trim();
+    if name.is_empty() || name.len() > 64 {
+        anyhow::bail!("token name must be 1..64 characters");
+    }
+    if scopes.is_empty() {
+        anyhow::bail!("at least one scope is required");
+    }
+    let mut file = load();
+    if file.tokens.len() >= MAX_TOKENS {
+        anyhow::bail!("too many tokens (max {MAX_TOKENS})");
+    }
+    if file.tokens.iter().any(|t| t.name == name) {
+        anyhow::bail!("token name already exists");
+    }
+    let secret = settings::generate_token_hex(32)?;
+    let mut scopes = scopes.to_vec();
+    scopes.sort_by_key(|s| s.as_str());
+    scopes.dedup();
+    let entry = TokenEntry {
+        id: settings::generate_token_hex(6)?,
+        name: name.to_string(),
+        scopes,
+        sha256: sha256_hex(&secret),
+        created_at: now_unix(),
+    };
+    file.tokens.push(entry.clone());
+    store(&file)?;
+    Ok((entry, secret))
+}
+
+pub fn revoke(id: &str) -> Result<()> {
+    let mut file = load();
+    let before = file.tokens.len();
+    file.tokens.retain(|t| t.id != id);
+    if file.tokens.len() == before {
+        anyhow::bail!("token not found");
+    }
+    store(&file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoints_map_to_scopes() {
+        assert_eq!(required_scope("GET", "/api/status"), Scope::Status);
+        assert_eq!(required_scope("PUT", "/api/quotas"), Scope::Config);
+        assert_eq!(required_scope("POST", "/api/start"), Scope::Control);
+        assert_eq!(required_scope("POST", "/api/programs/singbox/profiles/a/restart"), Scope::Control);
+        assert_eq!(required_scope("POST", "/api/fs/write_text"), Scope::FsWrite);
+        assert_eq!(required_scope("PUT", "/api/strategic/nfqws/bin/x"), Scope::Strategic);
+        assert_eq!(required_scope("GET", "/api/strategic/nfqws"), Scope::Config);
+        assert_eq!(required_scope("POST", "/api/programs/myprogram/profiles/a/bin/upload"), Scope::Strategic);
+        assert_eq!(required_scope("DELETE", "/api/tokens/abc"), Scope::Admin);
+        assert_eq!(required_scope("GET", "/api/backup/export"), Scope::Admin);
+        assert_eq!(required_scope("PUT", "/api/programs/nfqws/profiles/a/config"), Scope::Config);
+    }
+
+    #[test]
+    fn command_routes_need_strategic() {
+        assert_eq!(required_scope("PUT", "/api/programs/myprogram/profiles/a/command"), Scope::Strategic);
+        assert_eq!(required_scope("GET", "/api/programs/myprogram/profiles/a/command"), Scope::Config);
+        assert_eq!(required_scope("PUT", "/api/programs/tor/torrc"), Scope::Strategic);
+        assert_eq!(required_scope("PUT", "/api/programs/openvpn/profiles/a/config"), Scope::Strategic);
+        assert_eq!(required_scope("POST", "/api/programs/openvpn/profiles/a/upload-config"), Scope::Strategic);
+        assert_eq!(required_scope("PUT", "/api/programs/myprogram/profiles/a/setting"), Scope::Config);
+    }
+
+    #[test]
+    fn status_token_cannot_upload() {
+        let g = Grant::Scoped { id: "w".into(), scopes: vec![Scope::Status] };
+        assert!(g.allows(Scope::Status));
+        assert!(!g.allows(Scope::Strategic));
+        assert!(!g.allows(Scope::Config));
+        let fs = Grant::Scoped { id: "f".into(), scopes: vec![Scope::FsWrite] };
+        assert!(fs.allows(Scope::FsRead));
+    }
+}
@@ -0,0 +1,497 @@
+//! Configuration export/import bundle.
+//!
+//! The bundle is one JSON document: a manifest with size and sha256 per file
+//! plus the file contents (UTF-8 text as-is, everything else hex). It covers
+//! `setting/*.json`, the whole `working_folder/` tree and
+//! `strategic/strategicvar/`, minus logs, generated uid maps and the runtime
+//! files `runtime_sanitize` knows about.
+//!
+//! Import validates everything first, stages the new files next to the module,
+//! keeps a copy of every file it is about to replace (and of every `port.json`
+//! `ports::normalize_ports` may rewrite afterwards) and renames the staged
+//! files into place. Any failure restores the previous state.
+//!
+//! Import merges: files in the bundle overwrite their counterparts, anything
+//! else on the device is left alone. Profiles that exist only on the device
+//! are listed in `ImportReport::profiles_not_in_backup` so the caller can
+//! delete them if it wants an exact restore.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Component, Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{android::pkg_uid, runtime_sanitize, settings};
+
+pub const FORMAT: &str = "zdt-d-backup";
+pub const VERSION: u32 = 1;
+
+const MAX_FILE_BYTES: u64 = 8 * 1024 * 1024;
+const MAX_TOTAL_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Directory names never exported: logs, generated uid maps, soft-deleted
+/// profiles and engine work/cache dirs.
+const SKIP_DIRS: &[&str] = &["log", "logs", ".deleted", "work"];
+const SKIP_EXTS: &[&str] = &["log", "tmp", "pid", "sock", "bak"];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileData {
+    /// `utf8` or `hex`.
+    pub encoding: String,
+    pub data: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Bundle {
+    pub format: String,
+    pub version: u32,
+    pub created_at: u64,
+    pub manifest: Vec<ManifestEntry>,
+    pub files: BTreeMap<String, FileData>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub files: usize,
+    pub replaced: usize,
+    pub created: usize,
+    /// Packages from imported app lists that are not installed on this device.
+    pub missing_packages: BTreeMap<String, Vec<String>>,
+    /// `program/profile` directories on the device that the bundle does not
+    /// contain; import keeps them.
+    pub profiles_not_in_backup: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+fn module_root() -> PathBuf {
+    PathBuf::from(settings::MODULE_DIR)
+}
+
+fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    hex::encode(Sha256::digest(bytes))
+}
+
+/// Whether a module-relative path may appear in a bundle.
+fn is_exportable(rel: &Path) -> bool {
+    let first = match rel.components().next() {
+        Some(Component::Normal(s)) => s.to_string_lossy().into_owned(),
+        _ => return false,
+    };
+    if rel.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return false;
+    }
+    let name = rel.file_name().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let ext = rel.extension().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    if SKIP_EXTS.contains(&ext.as_str()) {
+        return false;
+    }
+    let dirs: Vec<String> = rel
+        .parent()
+        .map(|p| p.components().map(|c| c.as_os_str().to_string_lossy().into_owned()).collect())
+        .unwrap_or_default();
+    if dirs.iter().any(|d| SKIP_DIRS.contains(&d.as_str())) {
+        return false;
+    }
+    // app/out/* is derived from app/uid/* and re-resolved on import.
+    if dirs.windows(2).any(|w| w[0] == "app" && w[1] == "out") {
+        return false;
+    }
+    if runtime_sanitize::is_runtime_artifact(&module_root().join(rel)) {
+        return false;
+    }
+    match first.as_str() {
+        // Only user settings: iptables baselines and capability flags are per device.
+        "setting" => dirs.len() == 1 && ext == "json",
+        "working_folder" => name != "flag.sha256",
+        "strategic" => dirs.get(1).map(|d| d == "strategicvar").unwrap_or(false),
+        _ => false,
+    }
+}
+
+fn walk(root: &Path, dir: &Path, out: &mut Vec<PathBuf>) {
+    let Ok(rd) = fs::read_dir(dir) else { return; };
+    for ent in rd.flatten() {
+        let path = ent.path();
+        let Ok(meta) = fs::symlink_metadata(&path) else { continue; };
+        if meta.is_dir() {
+            let name = ent.file_name().to_string_lossy().into_owned();
+            if !SKIP_DIRS.contains(&name.as_str()) {
+                walk(root, &path, out);
+            }
+        } else if meta.is_file() {
+            if let Ok(rel) = path.strip_prefix(root) {
+                if is_exportable(rel) {
+                    out.push(rel.to_path_buf());
+                }
+            }
+        }
+    }
+}
+
+/// Build a bundle from the live configuration. Files above the per-file or
+/// total size cap are skipped and reported.
+pub fn export() -> Result<(Bundle, Vec<String>)> {
+    let root = module_root();
+    let mut paths = Vec::new();
+    for top in ["setting", "working_folder", "strategic/strategicvar"] {
+        walk(&root, &root.join(top), &mut paths);
+    }
+    paths.sort();
+
+    let mut manifest = Vec::new();
+    let mut files = BTreeMap::new();
+    let mut skipped = Vec::new();
+    let mut total = 0u64;
+    for rel in paths {
+        let rel_s = rel.to_string_lossy().replace('\\', "/");
+        let bytes = fs::read(root.join(&rel)).with_context(|| format!("read {rel_s}"))?;
+        let size = bytes.len() as u64;
+        if size > MAX_FILE_BYTES || total + size > MAX_TOTAL_BYTES {
+            skipped.push(format!("{rel_s}: {size} bytes exceeds backup size limit"));
+            continue;
+        }
+        total += size;
+        manifest.push(ManifestEntry { path: rel_s.clone(), size, sha256: sha256_hex(&bytes) });
+        let data = match String::from_utf8(bytes) {
+            Ok(s) => FileData { encoding: "utf8".to_string(), data: s },
+            Err(e) => FileData { encoding: "hex".to_string(), data: hex::encode(e.into_bytes()) },
+        };
+        files.insert(rel_s, data);
+    }
+    Ok((
+        Bundle { format: FORMAT.to_string(), version: VERSION, created_at: now_unix(), manifest, files },
+        skipped,
+    ))
+}
+
+/// Check format, paths, sizes and checksums; return decoded contents.
+fn validate(bundle: &Bundle) -> Result<Vec<(PathBuf, Vec<u8>)>> {
+    if bundle.format != FORMAT {
+        anyhow::bail!("not a ZDT-D backup (format={:?})", bundle.format);
+    }
+    if bundle.version != VERSION {
+        anyhow::bail!("unsupported backup version {} (expected {VERSION})", bundle.version);
+    }
+    if bundle.manifest.len() != bundle.files.len() {
+        anyhow::bail!("manifest lists {} files but bundle has {}", bundle.manifest.len(), bundle.files.len());
+    }
+    let mut out = Vec::with_capacity(bundle.manifest.len());
+    let mut seen = BTreeSet::new();
+    let mut total = 0u64;
+    for m in &bundle.manifest {
+        // A second entry would be installed over the first and its saved
+        // original, so a rollback could not restore the file.
+        if !seen.insert(m.path.as_str()) {
+            anyhow::bail!("backup lists {} more than once", m.path);
+        }
+        let rel = PathBuf::from(&m.path);
+        if !is_exportable(&rel) {
+            anyhow::bail!("backup entry is not allowed: {}", m.path);
+        }
+        let fd = bundle
+            .files
+            .get(&m.path)
+            .ok_or_else(|| anyhow::anyhow!("backup entry has no data: {}", m.path))?;
+        let bytes = match fd.encoding.as_str() {
+            "utf8" => fd.data.as_bytes().to_vec(),
+            "hex" => hex::decode(&fd.data).map_err(|e| anyhow::anyhow!("{}: bad hex data: {e}", m.path))?,
+            other => anyhow::bail!("{}: unknown encoding {other}", m.path),
+        };
+        if bytes.len() as u64 != m.size || sha256_hex(&bytes) != m.sha256 {
+            anyhow::bail!("checksum mismatch: {}", m.path);
+        }
+        total += m.size;
+        if m.size > MAX_FILE_BYTES || total > MAX_TOTAL_BYTES {
+            anyhow::bail!("backup exceeds size limit at {}", m.path);
+        }
+        if m.path.ends_with(".json") && runtime_sanitize::json_bytes_are_corrupt(&bytes) {
+            anyhow::bail!("invalid JSON in backup: {}", m.path);
+        }
+        out.push((rel, bytes));
+    }
+    Ok(out)
+}
+
+/// Files replaced or created by an import, enough to undo it.
+struct Undo {
+    dir: PathBuf,
+    replaced: Vec<PathBuf>,
+    created: Vec<PathBuf>,
+}
+
+impl Undo {
+    fn rollback(&self) {
+        let root = module_root();
+        for rel in &self.created {
+            let _ = fs::remove_file(root.join(rel));
+        }
+        for rel in &self.replaced {
+            let saved = self.dir.join("old").join(rel);
+            if let Err(e) = fs::rename(&saved, root.join(rel)) {
+                log::warn!("backup import rollback: restore {} failed: {e}", rel.display());
+            }
+        }
+    }
+
+    /// Keep a copy of `path` (absolute) unless the import already tracks it.
+    fn save(&mut self, path: &Path) -> Result<()> {
+        let root = module_root();
+        let Ok(rel) = path.strip_prefix(&root) else { return Ok(()); };
+        if !path.is_file() || self.replaced.iter().chain(&self.created).any(|p| p == rel) {
+            return Ok(());
+        }
+        let saved = self.dir.join("old").join(rel);
+        fs::create_dir_all(saved.parent().unwrap_or(&self.dir))?;
+        fs::copy(path, &saved).with_context(|| format!("save {}", rel.display()))?;
+        self.replaced.push(rel.to_path_buf());
+        Ok(())
+    }
+}
+
+/// Run `ports::normalize_ports` with every file it may rewrite saved first.
+fn normalize_ports(undo: &mut Undo) -> Result<()> {
+    for path in crate::ports::adjustable_port_files()? {
+        undo.save(&path)?;
+    }
+    crate::ports::normalize_ports().context("normalize ports")
+}
+
+/// Profile directories holding at least one exportable file that no bundle
+/// entry lives under. Programs keep profiles in `<program>/profile/<name>`;
+/// older ones use `<program>/<name>` directly.
+fn profiles_not_in_backup(files: &[(PathBuf, Vec<u8>)]) -> Vec<String> {
+    let root = module_root();
+    let mut out = Vec::new();
+    let Ok(programs) = fs::read_dir(root.join("working_folder")) else { return out; };
+    for program in programs.flatten().filter(|e| e.path().is_dir()) {
+        let nested = program.path().join("profile");
+        let dir = if nested.is_dir() { nested } else { program.path() };
+        let Ok(profiles) = fs::read_dir(dir) else { continue; };
+        for profile in profiles.flatten().filter(|e| e.path().is_dir()) {
+            let Ok(rel) = profile.path().strip_prefix(&root).map(Path::to_path_buf) else { continue; };
+            if files.iter().any(|(f, _)| f.starts_with(&rel)) {
+                continue;
+            }
+            let mut exportable = Vec::new();
+            walk(&root, &profile.path(), &mut exportable);
+            if !exportable.is_empty() {
+                out.push(format!(
+                    "{}/{}",
+                    program.file_name().to_string_lossy(),
+                    profile.file_name().to_string_lossy()
+                ));
+            }
+        }
+    }
+    out.sort();
+    out
+}
+
+fn apply_files(files: &[(PathBuf, Vec<u8>)], undo: &mut Undo) -> Result<()> {
+    let root = module_root();
+    // Stage everything first so a full disk fails before anything is touched.
+    for (rel, bytes) in files {
+        let staged = undo.dir.join("new").join(rel);
+        fs::create_dir_all(staged.parent().unwrap_or(&undo.dir))?;
+        fs::write(&staged, bytes).with_context(|| format!("stage {}", rel.display()))?;
+    }
+    for (rel, _) in files {
+        let target = root.join(rel);
+        if target.is_file() {
+            let saved = undo.dir.join("old").join(rel);
+            fs::create_dir_all(saved.parent().unwrap_or(&undo.dir))?;
+            fs::copy(&target, &saved).with_context(|| format!("save {}", rel.display()))?;
+            undo.replaced.push(rel.clone());
+        } else {
+            undo.created.push(rel.clone());
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(undo.dir.join("new").join(rel), &target)
+            .with_context(|| format!("install {}", rel.display()))?;
+    }
+    Ok(())
+}
+
+/// Re-resolve every imported `app/uid/<list>` against this device's packages.
+fn remap_app_lists(files: &[(PathBuf, Vec<u8>)], report: &mut ImportReport) {
+    let tracker = pkg_uid::Sha256Tracker::new(settings::SHARED_SHA_FLAG_FILE);
+    let root = module_root();
+    for (rel, _) in files {
+        let is_uid_list = rel.parent().map(|p| p.ends_with("app/uid")).unwrap_or(false);
+        if !is_uid_list {
+            continue;
+        }
+        let input = root.join(rel);
+        let output = input
+            .parent()
+            .and_then(Path::parent)
+            .map(|app| app.join("out").join(rel.file_name().unwrap_or_default()))
+            .unwrap_or_default();
+        if let Some(parent) = output.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+        if let Err(e) = pkg_uid::unified_processing(pkg_uid::Mode::Default, &tracker, &output, &input) {
+            report.warnings.push(format!("{}: uid remap failed: {e:#}", rel.display()));
+            continue;
+        }
+        let wanted = pkg_uid::read_package_list(&input).unwrap_or_default();
+        let resolved = fs::read_to_string(&output).unwrap_or_default();
+        let missing: Vec<String> = wanted
+            .into_iter()
+            .filter(|p| !resolved.lines().any(|l| l.split_once('=').map(|(k, _)| k == p).unwrap_or(false)))
+            .collect();
+        if !missing.is_empty() {
+            report.missing_packages.insert(rel.to_string_lossy().into_owned(), missing);
+        }
+    }
+}
+
+/// Validate and apply a bundle. With `dry_run` only validation runs.
+pub fn import(bundle: &Bundle, dry_run: bool) -> Result<ImportReport> {
+    let files = validate(bundle)?;
+    let mut report = ImportReport {
+        dry_run,
+        files: files.len(),
+        profiles_not_in_backup: profiles_not_in_backup(&files),
+        ..Default::default()
+    };
+    if dry_run {
+        let root = module_root();
+        report.replaced = files.iter().filter(|(rel, _)| root.join(rel).is_file()).count();
+        report.created = files.len() - report.replaced;
+        return Ok(report);
+    }
+
+    let mut undo = Undo {
+        dir: module_root().join(format!(".backup_import.{}", std::process::id())),
+        replaced: Vec::new(),
+        created: Vec::new(),
+    };
+    let _ = fs::remove_dir_all(&undo.dir);
+    fs::create_dir_all(&undo.dir).with_context(|| format!("mkdir {}", undo.dir.display()))?;
+
+    let res = apply_files(&files, &mut undo).and_then(|_| {
+        report.replaced = undo.replaced.len();
+        report.created = undo.created.len();
+        normalize_ports(&mut undo)
+    });
+    if let Err(e) = res {
+        undo.rollback();
+        let _ = fs::remove_dir_all(&undo.dir);
+        return Err(e.context("backup import rolled back"));
+    }
+    let _ = fs::remove_dir_all(&undo.dir);
+
+    remap_app_lists(&files, &mut report);
+    log::info!(
+        "backup import: {} file(s), {} replaced, {} created, {} device-only profile(s) kept",
+        report.files,
+        report.replaced,
+        report.created,
+        report.profiles_not_in_backup.len()
+    );
+    Ok(report)
+}
+
+/// Accept either a raw bundle or `{"backup": <bundle>, "dry_run": bool}`.
+pub fn parse_import_body(body: &[u8]) -> Result<(Bundle, bool)> {
+    let v: Value = serde_json::from_slice(body).map_err(|e| anyhow::anyhow!("bad JSON body: {e}"))?;
+    let dry_run = v.get("dry_run").and_then(Value::as_bool).unwrap_or(false);
+    let raw = match v.get("backup") {
+        Some(b) => b.clone(),
+        None => v,
+    };
+    let bundle: Bundle = serde_json::from_value(raw).map_err(|e| anyhow::anyhow!("bad backup bundle: {e}"))?;
+    Ok((bundle, dry_run))
+}
+
+pub fn export_json() -> Result<Value> {
+    let (bundle, skipped) = export()?;
+    Ok(json!({"ok": true, "skipped": skipped, "backup": bundle}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exportable_paths_skip_runtime_and_device_files() {
+        assert!(is_exportable(Path::new("setting/setting.json")));
+        assert!(is_exportable(Path::new("working_folder/nfqws/active.json")));
+        assert!(is_exportable(Path::new("working_folder/nfqws/yt/app/uid/user_program")));
+        assert!(is_exportable(Path::new("strategic/strategicvar/nfqws/a.txt")));
+        assert!(!is_exportable(Path::new("setting/iptables_backup.rules")));
+        assert!(!is_exportable(Path::new("working_folder/nfqws/yt/app/out/user_program")));
+        assert!(!is_exportable(Path::new("working_folder/tor/log/tor.log")));
+        assert!(!is_exportable(Path::new("working_folder/runtime_refresh/routing.json")));
+        assert!(!is_exportable(Path::new("strategic/list/youtube.txt")));
+        assert!(!is_exportable(Path::new("working_folder/../api/token")));
+    }
+
+    #[test]
+    fn validate_rejects_tampered_content() {
+        let data = "{\"enabled\":true}";
+        let mut bundle = Bundle {
+            format: FORMAT.to_string(),
+            version: VERSION,
+            created_at: 0,
+            manifest: vec![ManifestEntry {
+                path: "setting/start.json".to_string(),
+                size: data.len() as u64,
+                sha256: sha256_hex(data.as_bytes()),
+            }],
+            files: BTreeMap::from([(
+                "setting/start.json".to_string(),
+                FileData { encoding: "utf8".to_string(), data: data.to_string() },
+            )]),
+        };
+        assert_eq!(validate(&bundle).unwrap().len(), 1);
+        bundle.files.get_mut("setting/start.json").unwrap().data = "{\"enabled\":false}".to_string();
+        assert!(validate(&bundle).is_err());
+    }
+
+    #[test]
+    fn validate_rejects_duplicate_paths() {
+        let entry = |path: &str, data: &str| {
+            (
+                ManifestEntry { path: path.to_string(), size: data.len() as u64, sha256: sha256_hex(data.as_bytes()) },
+                (path.to_string(), FileData { encoding: "utf8".to_string(), data: data.to_string() }),
+            )
+        };
+        let (start, start_data) = entry("setting/start.json", "{}");
+        let (api, api_data) = entry("setting/api.json", "{}");
+        let bundle = Bundle {
+            format: FORMAT.to_string(),
+            version: VERSION,
+            created_at: 0,
+            manifest: vec![start.clone(), start],
+            files: BTreeMap::from([start_data, api_data]),
+        };
+        assert!(validate(&bundle).unwrap_err().to_string().contains("more than once"));
+        let bundle = Bundle { manifest: vec![bundle.manifest[0].clone(), api], ..bundle };
+        assert_eq!(validate(&bundle).unwrap().len(), 2);
+    }
+}
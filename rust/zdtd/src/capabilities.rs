@@ -124,6 +124,17 @@ pub fn collect() -> Value {
         None
     };
 
+    // `nft -c` only checks the ruleset; nothing is committed.
+    let nft_usable = crate::iptables::nft::probe();
+    let firewall_setting = crate::settings::load_api_settings()
+        .map(|st| st.firewall_backend)
+        .unwrap_or_else(|_| "iptables".to_string());
+    let firewall_selected = if crate::iptables::nft::wanted(&firewall_setting, nft_usable) {
+        "nftables"
+    } else {
+        "iptables"
+    };
+
     let ip_rule = run_ok("ip", &["rule", "show"], SHORT_TIMEOUT);
     let ip_route_table_all = run_ok("ip", &["route", "show", "table", "all"], SHORT_TIMEOUT);
 
@@ -160,6 +171,9 @@ pub fn collect() -> Value {
     if iptables_available && !tproxy {
         warnings.push("TPROXY target was not found by readonly check".to_string());
     }
+    if firewall_setting == "nftables" && !nft_usable {
+        warnings.push("firewall_backend=nftables is set but nft is not usable; iptables is used".to_string());
+    }
     if ip6tables_available && !multiport_v6 {
         warnings.push("ip6tables multiport was not found by readonly check".to_string());
     }
@@ -206,6 +220,13 @@ pub fn collect() -> Value {
             "MARK": mark,
             "TPROXY": tproxy
         },
+        "firewall": {
+            "nftables_available": nft_usable,
+            "setting": firewall_setting,
+            "selected_backend": firewall_selected,
+            "nftables_active": crate::iptables::nft::active(),
+            "notes": "nftables renders the NFQUEUE, NAT redirect and captive portal chains into one atomic `nft -f` transaction; TPROXY and hotspot rules stay on iptables. The selection is applied on the next full start."
+        },
         "tproxy": {
             "selected_backend": selected_backend,
             "enabled_by_setting": tproxy_setting_enabled,
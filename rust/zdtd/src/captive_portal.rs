@@ -467,6 +467,7 @@ fn register_or_touch_device(ip: &str, user_agent: &str) -> Result<CaptiveDevice>
 /// client is waiting for approval. Failures are swallowed on purpose: per design
 /// we never retry, the request simply stays `pending` in the device list.
 fn notify_pending(short_id: &str, model: &str) {
+    crate::events::publish("captive_device_pending", json!({"short_id": short_id, "model": model}));
     if let Err(e) = crate::android::notification::send_captive_device_pending(short_id, model) {
         warn!("captive pending notify failed: {e:#}");
     }
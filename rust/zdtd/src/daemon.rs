@@ -100,6 +100,7 @@ pub fn run(_cfg: &Config) -> Result<()> {
     }));
     api_status::write_off();
     energy_saver::unfreeze_all_best_effort();
+    crate::traffic_history::start();
 
     // Start API server immediately, and perform autostart in background if enabled=true.
     if start.enabled {
@@ -161,6 +162,7 @@ pub fn handle_start_async(state: &SharedState) -> Result<bool> {
         st.start = start.clone();
     }
     api_status::write_starting();
+    crate::events::service_state("starting", false, None);
 
     logging::info("start requested -> scheduling start_full in background");
 
@@ -179,6 +181,7 @@ pub fn handle_start_async(state: &SharedState) -> Result<bool> {
                         st.start = start_now;
                     }
                     api_status::write_on(partial);
+                    crate::events::service_state("running", partial, None);
 
                     protector::activate();
                     energy_saver::refresh(true);
@@ -190,6 +193,7 @@ pub fn handle_start_async(state: &SharedState) -> Result<bool> {
                     logging::warn(&format!("start_full failed: {e:#}"));
                     crate::scan_detector::stop();
                     api_status::write_error_off(&format!("start_full failed: {e:#}"));
+                    crate::events::service_state("error", false, Some(&format!("start_full failed: {e:#}")));
                     let mut st = lock_state(&st_arc);
                     st.services_running = false;
                     st.services_partial = false;
@@ -200,6 +204,7 @@ pub fn handle_start_async(state: &SharedState) -> Result<bool> {
                 crate::logging::user_error("Ошибка запуска: внутренний сбой потока");
                 crate::scan_detector::stop();
                 api_status::write_error_off("start thread panicked");
+                crate::events::service_state("error", false, Some("start thread panicked"));
                 let mut st = lock_state(&st_arc);
                 st.services_running = false;
                 st.services_partial = false;
@@ -256,6 +261,7 @@ pub fn handle_stop_async(state: &SharedState) -> Result<bool> {
         st.start = start.clone();
     }
     api_status::write_stopping();
+    crate::events::service_state("stopping", false, None);
 
     logging::info("stop requested -> scheduling stop_full in background");
 
@@ -275,6 +281,7 @@ pub fn handle_stop_async(state: &SharedState) -> Result<bool> {
                         st.services_partial = false;
                     }
                     api_status::write_off();
+                    crate::events::service_state("stopped", false, None);
                     protector::deactivate();
                     energy_saver::stop_monitor();
                     crate::scan_detector::stop();
@@ -285,12 +292,14 @@ pub fn handle_stop_async(state: &SharedState) -> Result<bool> {
                 Err(e) => {
                     logging::warn(&format!("stop_full failed: {e:#}"));
                     api_status::write_error_off(&format!("stop_full failed: {e:#}"));
+                    crate::events::service_state("error", false, Some(&format!("stop_full failed: {e:#}")));
                 }
             },
             Err(_) => {
                 logging::warn("stop thread panicked");
                 crate::logging::user_error("Ошибка остановки: внутренний сбой потока");
                 api_status::write_error_off("stop thread panicked");
+                crate::events::service_state("error", false, Some("stop thread panicked"));
             }
         }
         let mut st = lock_state(&st_arc);
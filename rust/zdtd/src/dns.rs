@@ -0,0 +1,410 @@
+//! In-process stub resolver for endpoint hostnames.
+//!
+//! Queries go to the `dns_upstreams` from the API settings in order: plain
+//! DNS over UDP (retried over TCP when the answer is truncated), DNS over TCP
+//! (`tcp://`) or DoH (`https://`, RFC 8484 POST). Queries carry an EDNS0 OPT
+//! record. Answers are cached for their TTL; when every upstream fails the
+//! system resolver is tried last.
+
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::HashMap,
+    io::{Read, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use crate::settings;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+const EDNS_PAYLOAD: u16 = 1232;
+const MIN_TTL: u32 = 30;
+const MAX_TTL: u32 = 3600;
+const NEGATIVE_TTL: u32 = 30;
+const CACHE_MAX: usize = 512;
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const TYPE_OPT: u16 = 41;
+const CLASS_IN: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    fn qtype(self) -> u16 {
+        match self {
+            Family::V4 => TYPE_A,
+            Family::V6 => TYPE_AAAA,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Upstream {
+    Udp(SocketAddr),
+    Tcp(SocketAddr),
+    Doh(String),
+}
+
+fn parse_upstream(raw: &str) -> Result<Upstream> {
+    let raw = raw.trim();
+    if raw.starts_with("https://") {
+        return Ok(Upstream::Doh(raw.to_string()));
+    }
+    let (tcp, addr) = match raw.strip_prefix("tcp://") {
+        Some(rest) => (true, rest),
+        None => (false, raw.strip_prefix("udp://").unwrap_or(raw)),
+    };
+    let sa = match addr.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, 53),
+        Err(_) => addr.parse::<SocketAddr>().with_context(|| format!("invalid DNS upstream {raw:?}"))?,
+    };
+    Ok(if tcp { Upstream::Tcp(sa) } else { Upstream::Udp(sa) })
+}
+
+/// Validate a `dns_upstreams` entry.
+pub fn check_upstream(raw: &str) -> Result<()> {
+    parse_upstream(raw).map(|_| ())
+}
+
+struct Cached {
+    ips: Vec<IpAddr>,
+    expires: Instant,
+}
+
+static CACHE: OnceLock<Mutex<HashMap<(String, Family), Cached>>> = OnceLock::new();
+
+fn cache() -> std::sync::MutexGuard<'static, HashMap<(String, Family), Cached>> {
+    match CACHE.get_or_init(|| Mutex::new(HashMap::new())).lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn query_id() -> u16 {
+    let mut id = [0u8; 2];
+    let rc = unsafe { libc::getrandom(id.as_mut_ptr() as *mut libc::c_void, id.len(), 0) };
+    if rc == id.len() as isize {
+        return u16::from_be_bytes(id);
+    }
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or_default();
+    (nanos ^ std::process::id()) as u16
+}
+
+fn build_query(id: u16, name: &str, qtype: u16) -> Result<Vec<u8>> {
+    let mut q = Vec::with_capacity(32 + name.len());
+    q.extend_from_slice(&id.to_be_bytes());
+    // RD set; one question; one additional (OPT).
+    q.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 1]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("invalid hostname {name:?}");
+        }
+        q.push(label.len() as u8);
+        q.extend_from_slice(label.as_bytes());
+    }
+    q.push(0);
+    q.extend_from_slice(&qtype.to_be_bytes());
+    q.extend_from_slice(&CLASS_IN.to_be_bytes());
+    // OPT: root name, type, UDP payload size as class, zero TTL and rdata.
+    q.push(0);
+    q.extend_from_slice(&TYPE_OPT.to_be_bytes());
+    q.extend_from_slice(&EDNS_PAYLOAD.to_be_bytes());
+    q.extend_from_slice(&[0, 0, 0, 0, 0, 0]);
+    Ok(q)
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> Result<u16> {
+    match buf.get(pos..pos + 2) {
+        Some(b) => Ok(u16::from_be_bytes([b[0], b[1]])),
+        None => bail!("truncated DNS message"),
+    }
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> Result<u32> {
+    match buf.get(pos..pos + 4) {
+        Some(b) => Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]])),
+        None => bail!("truncated DNS message"),
+    }
+}
+
+/// Position after the (possibly compressed) name at `pos`.
+fn skip_name(buf: &[u8], mut pos: usize) -> Result<usize> {
+    loop {
+        let len = *buf.get(pos).context("truncated DNS name")? as usize;
+        match len {
+            0 => return Ok(pos + 1),
+            l if l & 0xc0 == 0xc0 => return Ok(pos + 2),
+            l => pos += 1 + l,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Answer {
+    ips: Vec<IpAddr>,
+    ttl: u32,
+    truncated: bool,
+}
+
+fn parse_response(buf: &[u8], id: u16, qtype: u16) -> Result<Answer> {
+    if read_u16(buf, 0)? != id {
+        bail!("DNS response id mismatch");
+    }
+    let flags = read_u16(buf, 2)?;
+    if flags & 0x8000 == 0 {
+        bail!("DNS message is not a response");
+    }
+    let truncated = flags & 0x0200 != 0;
+    let rcode = flags & 0x000f;
+    if rcode == RCODE_NXDOMAIN {
+        return Ok(Answer { ips: Vec::new(), ttl: NEGATIVE_TTL, truncated });
+    }
+    if rcode != 0 {
+        bail!("DNS rcode {rcode}");
+    }
+    let qdcount = read_u16(buf, 4)?;
+    let ancount = read_u16(buf, 6)?;
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        pos = skip_name(buf, pos)? + 4;
+    }
+    let mut out = Answer { ips: Vec::new(), ttl: MAX_TTL, truncated };
+    for _ in 0..ancount {
+        pos = skip_name(buf, pos)?;
+        let rtype = read_u16(buf, pos)?;
+        let ttl = read_u32(buf, pos + 4)?;
+        let rdlen = read_u16(buf, pos + 8)? as usize;
+        pos += 10;
+        let rdata = buf.get(pos..pos + rdlen).context("truncated DNS record")?;
+        pos += rdlen;
+        // CNAME targets are answered in the same section; only the address
+        // records matter here.
+        if rtype != qtype {
+            continue;
+        }
+        let ip = match (rtype, rdata.len()) {
+            (TYPE_A, 4) => IpAddr::V4(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])),
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => continue,
+        };
+        if !out.ips.contains(&ip) {
+            out.ips.push(ip);
+        }
+        out.ttl = out.ttl.min(ttl);
+    }
+    if out.ips.is_empty() {
+        out.ttl = NEGATIVE_TTL;
+    }
+    Ok(out)
+}
+
+fn exchange_udp(server: SocketAddr, query: &[u8], id: u16, qtype: u16) -> Result<Answer> {
+    let bind: SocketAddr = if server.is_ipv4() { "0.0.0.0:0".parse()? } else { "[::]:0".parse()? };
+    let sock = UdpSocket::bind(bind).context("bind DNS socket")?;
+    sock.connect(server).with_context(|| format!("connect {server}"))?;
+    sock.send(query).with_context(|| format!("send to {server}"))?;
+    let deadline = Instant::now() + QUERY_TIMEOUT;
+    let mut buf = vec![0u8; EDNS_PAYLOAD as usize];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            bail!("{server}: timeout");
+        }
+        sock.set_read_timeout(Some(left))?;
+        let n = sock.recv(&mut buf).with_context(|| format!("{server}: no answer"))?;
+        // Stray or spoofed datagrams with another id are ignored.
+        match parse_response(&buf[..n], id, qtype) {
+            Err(e) if read_u16(&buf[..n], 0).ok() != Some(id) => log::debug!("dns: {server}: {e:#}"),
+            res => return res,
+        }
+    }
+}
+
+fn exchange_tcp(server: SocketAddr, query: &[u8], id: u16, qtype: u16) -> Result<Answer> {
+    let mut s = TcpStream::connect_timeout(&server, QUERY_TIMEOUT).with_context(|| format!("connect {server}"))?;
+    s.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    s.set_write_timeout(Some(QUERY_TIMEOUT))?;
+    let mut framed = (query.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(query);
+    s.write_all(&framed).with_context(|| format!("send to {server}"))?;
+    let mut len = [0u8; 2];
+    s.read_exact(&mut len).with_context(|| format!("{server}: no answer"))?;
+    let mut buf = vec![0u8; u16::from_be_bytes(len) as usize];
+    s.read_exact(&mut buf).with_context(|| format!("{server}: short answer"))?;
+    parse_response(&buf, id, qtype)
+}
+
+fn exchange_doh(url: &str, query: &[u8], id: u16, qtype: u16) -> Result<Answer> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(QUERY_TIMEOUT)
+        .user_agent("ZDT-D/1")
+        .build()
+        .context("build DoH client")?;
+    let response = client
+        .post(url)
+        .header("content-type", "application/dns-message")
+        .header("accept", "application/dns-message")
+        .body(query.to_vec())
+        .send()
+        .with_context(|| format!("POST {url}"))?;
+    let status = response.status();
+    if !status.is_success() {
+        bail!("{url}: HTTP status {status}");
+    }
+    let body = response.bytes().with_context(|| format!("{url}: read answer"))?;
+    parse_response(&body, id, qtype)
+}
+
+fn query(upstream: &Upstream, name: &str, family: Family) -> Result<Answer> {
+    let qtype = family.qtype();
+    // RFC 8484 recommends id 0 so DoH answers stay cacheable.
+    let id = if matches!(upstream, Upstream::Doh(_)) { 0 } else { query_id() };
+    let q = build_query(id, name, qtype)?;
+    match upstream {
+        Upstream::Udp(sa) => {
+            let answer = exchange_udp(*sa, &q, id, qtype)?;
+            if answer.truncated {
+                return exchange_tcp(*sa, &q, id, qtype);
+            }
+            Ok(answer)
+        }
+        Upstream::Tcp(sa) => exchange_tcp(*sa, &q, id, qtype),
+        Upstream::Doh(url) => exchange_doh(url, &q, id, qtype),
+    }
+}
+
+fn system_lookup(name: &str, family: Family) -> Vec<IpAddr> {
+    let Ok(addrs) = (name, 0u16).to_socket_addrs() else {
+        return Vec::new();
+    };
+    let mut out: Vec<IpAddr> = Vec::new();
+    for ip in addrs.map(|sa| sa.ip()) {
+        if ip.is_ipv4() == (family == Family::V4) && !out.contains(&ip) {
+            out.push(ip);
+        }
+    }
+    out
+}
+
+fn resolve_with(upstreams: &[String], name: &str, family: Family) -> Vec<IpAddr> {
+    let key = (name.to_string(), family);
+    let now = Instant::now();
+    if let Some(hit) = cache().get(&key).filter(|c| c.expires > now) {
+        return hit.ips.clone();
+    }
+
+    let mut resolved: Option<Answer> = None;
+    for raw in upstreams {
+        let upstream = match parse_upstream(raw) {
+            Ok(u) => u,
+            Err(e) => {
+                log::warn!("dns: {e:#}");
+                continue;
+            }
+        };
+        match query(&upstream, name, family) {
+            Ok(answer) => {
+                resolved = Some(answer);
+                break;
+            }
+            Err(e) => log::debug!("dns: {name} via {raw}: {e:#}"),
+        }
+    }
+    let answer = match resolved {
+        Some(a) => a,
+        None => {
+            let ips = system_lookup(name, family);
+            log::debug!("dns: {name}: all upstreams failed; system resolver returned {}", ips.len());
+            // Not cached: the next call retries the upstreams.
+            return ips;
+        }
+    };
+
+    let ttl = answer.ttl.clamp(MIN_TTL, MAX_TTL);
+    let mut c = cache();
+    if c.len() >= CACHE_MAX {
+        c.retain(|_, v| v.expires > now);
+        if c.len() >= CACHE_MAX {
+            c.clear();
+        }
+    }
+    c.insert(key, Cached { ips: answer.ips.clone(), expires: now + Duration::from_secs(ttl as u64) });
+    answer.ips
+}
+
+/// Resolve `host` to addresses of one family. IP literals are returned as is.
+pub fn resolve(host: &str, family: Family) -> Vec<IpAddr> {
+    let host = host.trim().trim_end_matches('.');
+    if host.is_empty() {
+        return Vec::new();
+    }
+    if let Ok(ip) = host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        return if ip.is_ipv4() == (family == Family::V4) { vec![ip] } else { Vec::new() };
+    }
+    let upstreams = settings::load_api_settings().unwrap_or_default().dns_upstreams;
+    resolve_with(&upstreams, &host.to_ascii_lowercase(), family)
+}
+
+/// IPv4 addresses first, then IPv6.
+pub fn resolve_all(host: &str) -> Vec<IpAddr> {
+    let mut out = resolve(host, Family::V4);
+    out.extend(resolve(host, Family::V6));
+    out
+}
+
+pub fn resolve_ipv4_all(host: &str) -> Vec<String> {
+    resolve(host, Family::V4).into_iter().map(|ip| ip.to_string()).collect()
+}
+
+pub fn resolve_ipv4(host: &str) -> Option<String> {
+    resolve_ipv4_all(host).into_iter().next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_against_local_fake_server() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            let (n, peer) = server.recv_from(&mut buf).unwrap();
+            let q = &buf[..n];
+            // Echo id and question, then a CNAME-free answer pointing at the
+            // question name (0xc00c) with two A records.
+            let qend = skip_name(q, 12).unwrap() + 4;
+            let mut r = q[..2].to_vec();
+            r.extend_from_slice(&[0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0]);
+            r.extend_from_slice(&q[12..qend]);
+            for (ttl, last) in [(300u32, 10u8), (120, 11)] {
+                r.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1]);
+                r.extend_from_slice(&ttl.to_be_bytes());
+                r.extend_from_slice(&[0, 4, 203, 0, 113, last]);
+            }
+            server.send_to(&r, peer).unwrap();
+        });
+
+        let upstreams = vec![format!("udp://{addr}")];
+        let ips = resolve_with(&upstreams, "zdt-test.example", Family::V4);
+        assert_eq!(ips, vec![IpAddr::from([203, 0, 113, 10]), IpAddr::from([203, 0, 113, 11])]);
+        // Served from cache: the fake server only answers once.
+        assert_eq!(resolve_with(&upstreams, "zdt-test.example", Family::V4), ips);
+        assert!(cache().get(&("zdt-test.example".to_string(), Family::V4)).unwrap().expires > Instant::now() + Duration::from_secs(100));
+    }
+}
@@ -0,0 +1,145 @@
+//! In-process event bus behind `GET /api/events`.
+//!
+//! Producers (start/stop flow, partial start, proxyInfo detector, captive
+//! portal, crashed programs) call `publish`; API subscribers block on a
+//! condition variable and receive every event with an id greater than the last
+//! one they saw. A short ring buffer lets a reconnecting client resume via
+//! `Last-Event-ID` instead of re-polling the status endpoints.
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Condvar, Mutex, OnceLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Events kept for `Last-Event-ID` replay.
+const HISTORY: usize = 256;
+/// Concurrent `/api/events` streams; each holds one thread.
+pub const MAX_SUBSCRIBERS: usize = 8;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub id: u64,
+    pub ts: u64,
+    pub kind: &'static str,
+    pub data: Value,
+}
+
+struct Log {
+    next_id: u64,
+    items: VecDeque<Event>,
+}
+
+static BUS: OnceLock<(Mutex<Log>, Condvar)> = OnceLock::new();
+static SUBSCRIBERS: AtomicUsize = AtomicUsize::new(0);
+
+fn bus() -> &'static (Mutex<Log>, Condvar) {
+    BUS.get_or_init(|| (Mutex::new(Log { next_id: 1, items: VecDeque::new() }), Condvar::new()))
+}
+
+fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub fn publish(kind: &'static str, data: Value) {
+    let (lock, cv) = bus();
+    let mut log = match lock.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    let id = log.next_id;
+    log.next_id += 1;
+    log.items.push_back(Event { id, ts: now_unix(), kind, data });
+    while log.items.len() > HISTORY {
+        log.items.pop_front();
+    }
+    drop(log);
+    cv.notify_all();
+}
+
+/// Id of the newest published event (0 when none yet).
+pub fn last_id() -> u64 {
+    let (lock, _) = bus();
+    let log = match lock.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    log.next_id - 1
+}
+
+/// Result of waiting for events newer than a given id.
+pub struct Batch {
+    pub events: Vec<Event>,
+    /// Some events after the requested id already fell out of the ring buffer.
+    pub gap: bool,
+}
+
+/// Return events with `id > after`, waiting up to `timeout` if there are none.
+pub fn wait_after(after: u64, timeout: Duration) -> Batch {
+    let (lock, cv) = bus();
+    let mut log = match lock.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    };
+    if log.next_id - 1 <= after {
+        log = match cv.wait_timeout_while(log, timeout, |l| l.next_id - 1 <= after) {
+            Ok((g, _)) => g,
+            Err(poisoned) => poisoned.into_inner().0,
+        };
+    }
+    let gap = log.items.front().map(|e| e.id > after + 1).unwrap_or(false);
+    let events = log.items.iter().filter(|e| e.id > after).cloned().collect();
+    Batch { events, gap }
+}
+
+/// Slot in the subscriber limit; released on drop.
+pub struct Subscription(());
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        SUBSCRIBERS.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+pub fn subscribe() -> Option<Subscription> {
+    if SUBSCRIBERS.fetch_add(1, Ordering::AcqRel) >= MAX_SUBSCRIBERS {
+        SUBSCRIBERS.fetch_sub(1, Ordering::AcqRel);
+        return None;
+    }
+    Some(Subscription(()))
+}
+
+/// Service lifecycle transition (`starting`, `running`, `stopping`, `stopped`, `error`).
+pub fn service_state(state: &str, partial: bool, error: Option<&str>) {
+    publish("service", json!({"state": state, "partial": partial, "error": error}));
+}
+
+/// A managed program process disappeared while it was expected to run.
+pub fn program_exited(program: &str, profile: Option<&str>, pid: u32) {
+    publish("program_exited", json!({"program": program, "profile": profile, "pid": pid}));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_returns_only_newer_events() {
+        let before = last_id();
+        publish("test", json!({"n": 1}));
+        publish("test", json!({"n": 2}));
+        let batch = wait_after(before + 1, Duration::from_millis(10));
+        assert!(!batch.gap);
+        assert!(batch.events.iter().all(|e| e.id > before + 1));
+        assert!(batch.events.iter().any(|e| e.data["n"] == 2));
+        assert!(!batch.events.iter().any(|e| e.id == before + 1));
+    }
+}
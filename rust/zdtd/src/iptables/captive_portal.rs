@@ -93,6 +93,11 @@ fn append_rule(table: Option<&str>, chain: &str, args: &[String]) -> Result<()>
 }
 
 pub fn apply(allowed_ips: &[String]) -> Result<()> {
+    if crate::iptables::nft::active() {
+        crate::iptables::nft::set_captive(Some(allowed_ips.to_vec()))?;
+        info!("captive portal rules applied (nft) allowed={}", allowed_ips.len());
+        return Ok(());
+    }
     let _guard = xtables_lock::lock();
 
     ensure_chain(Some("nat"), PRE_CHAIN)?;
@@ -200,6 +205,9 @@ fn delete_chain(table: Option<&str>, chain: &str) {
 }
 
 pub fn cleanup() -> Result<()> {
+    if crate::iptables::nft::active() {
+        crate::iptables::nft::set_captive(None)?;
+    }
     let _guard = xtables_lock::lock();
     let iface = captive_portal::HOTSPOT_IFACE;
     let port = captive_portal::PORTAL_PORT.to_string();
@@ -2,7 +2,7 @@ use anyhow::{Context, Result};
 use log::{info, warn};
 use std::{collections::BTreeSet, fs, path::Path, time::Duration};
 
-use crate::{settings, shell::{self, Capture}, xtables_lock};
+use crate::{iptables::{caps, nft, port_filter, restore}, settings, shell::{self, Capture}, xtables_lock};
 
 const IPT_CMD_TIMEOUT: Duration = Duration::from_secs(5);
 const IPT_SLOW_TIMEOUT: Duration = Duration::from_secs(15);
@@ -66,7 +66,7 @@ impl Default for DpiTunnelOptions {
     }
 }
 
-fn allow_loopback_redirect_enabled() -> bool {
+pub fn allow_loopback_redirect_enabled() -> bool {
     match settings::load_api_settings() {
         Ok(st) => st.allow_loopback_redirect,
         Err(e) => {
@@ -92,18 +92,20 @@ pub fn apply(uid_file: &Path, dest_port: u16, proto_choice: ProtoChoice, ifaces_
 
     info!("DPI: port_preference={} proto_choice={:?} dpi_ports='{}'", opt.port_preference, proto_choice, opt.dpi_ports);
 
+    // Interfaces the scoped rules are bound to (`None` = any).
+    let out_ifaces: Vec<Option<&str>> = if mode == "all" {
+        vec![None]
+    } else {
+        ifaces.iter().map(|s| Some(s.as_str())).collect()
+    };
+    if nft::active() {
+        return apply_nft(uid_file, dest_port, proto_choice, ifaces_raw, &opt, allow_loopback_redirect, &out_ifaces);
+    }
+
     ensure_nat_chain_nat_dpi(allow_loopback_redirect)?;
     ensure_mangle_chain_app_once()?;
 
-    let scope = format!(
-        "nat:uid={}:dest={}:proto={:?}:ifaces={}:pref={}:ports={}",
-        uid_file.display(),
-        dest_port,
-        proto_choice,
-        ifaces_raw.unwrap_or(""),
-        opt.port_preference,
-        opt.dpi_ports,
-    );
+    let scope = scope_label(uid_file, dest_port, proto_choice, ifaces_raw, &opt);
     let uids = read_uids(uid_file)?;
     if uids.is_empty() {
         log::warn!("DPI: no valid UIDs in file: {} (remove scoped NAT chain)", uid_file.display());
@@ -115,6 +117,15 @@ pub fn apply(uid_file: &Path, dest_port: u16, proto_choice: ProtoChoice, ifaces_
         return Ok(());
     }
 
+    match apply_nat_batch(&scope, &uids, proto_choice, allow_loopback_redirect, &opt, &out_ifaces, dest_port) {
+        Ok(()) => {
+            crate::runtime_refresh::register_nat(uid_file, dest_port, proto_choice, ifaces_raw, &opt);
+            info!("DPI: NAT applied in one iptables-restore batch ({} uids)", uids.len());
+            return Ok(());
+        }
+        Err(e) => warn!("DPI: NAT batch apply failed, applying rule by rule: {e:#}"),
+    }
+
     let scoped_chain = prepare_nat_scoped_chain(&scope)?;
     let scoped_local_chain = if allow_loopback_redirect {
         Some(prepare_nat_local_scoped_chain(&scope)?)
@@ -209,6 +220,143 @@ pub fn apply(uid_file: &Path, dest_port: u16, proto_choice: ProtoChoice, ifaces_
     Ok(())
 }
 
+/// nft counterpart of the rules built below: one rule per UID/proto/iface
+/// with all ports in a set, so the multiport probing and fallbacks are not
+/// needed.
+fn apply_nft(
+    uid_file: &Path,
+    dest_port: u16,
+    proto_choice: ProtoChoice,
+    ifaces_raw: Option<&str>,
+    opt: &DpiTunnelOptions,
+    allow_loopback_redirect: bool,
+    out_ifaces: &[Option<&str>],
+) -> Result<()> {
+    let scope = scope_label(uid_file, dest_port, proto_choice, ifaces_raw, opt);
+    let chain = scoped_nat_chain_name(&scope);
+    let local_chain = scoped_nat_chain_name(&format!("local:{scope}"));
+    let uids = read_uids(uid_file)?;
+    if uids.is_empty() || !allow_loopback_redirect {
+        nft::remove_scope(&local_chain)?;
+    }
+    if uids.is_empty() {
+        log::warn!("DPI: no valid UIDs in file: {} (remove scoped NAT chain)", uid_file.display());
+        nft::remove_scope(&chain)?;
+        crate::runtime_refresh::register_nat(uid_file, dest_port, proto_choice, ifaces_raw, opt);
+        return Ok(());
+    }
+
+    let ports = if opt.port_preference == 1 {
+        Vec::new()
+    } else {
+        port_filter::parse_ranges(&normalize_ports_csv(&opt.dpi_ports))
+    };
+    let dest = [port_filter::PortRange::new(dest_port, dest_port)];
+    let verdict = nft::dnat_verdict(dest_port);
+
+    let mut rules = Vec::new();
+    let mut local = Vec::new();
+    for uid in &uids {
+        for proto in proto_choice.protos() {
+            if allow_loopback_redirect {
+                let ret = nft::owner_rule(None, Some(uid), Some(proto), &dest, "return")?;
+                let dnat = nft::owner_rule(None, Some(uid), Some(proto), &ports, &verdict)?;
+                local.push(format!("ip daddr 127.0.0.0/8 {ret}"));
+                local.push(format!("ip daddr 127.0.0.0/8 {dnat}"));
+            }
+            for iface in out_ifaces {
+                rules.push(nft::owner_rule(*iface, Some(uid), Some(proto), &ports, &verdict)?);
+            }
+        }
+    }
+    nft::set_scope(nft::Hook::NatDpi, &chain, rules)?;
+    if allow_loopback_redirect {
+        nft::set_scope(nft::Hook::NatDpiLocal, &local_chain, local)?;
+    }
+    crate::runtime_refresh::register_nat(uid_file, dest_port, proto_choice, ifaces_raw, opt);
+    info!("DPI: NAT applied (nft) dest_port={} uids={}", dest_port, uids.len());
+    Ok(())
+}
+
+/// Build the scoped NAT chain(s) and their hooks in one `iptables-restore`
+/// transaction. Same rules as the per-command path below, without the `-C`
+/// probes and argument-order retries (the chains are rebuilt from scratch).
+fn apply_nat_batch(
+    scope: &str,
+    uids: &[String],
+    proto_choice: ProtoChoice,
+    allow_loopback_redirect: bool,
+    opt: &DpiTunnelOptions,
+    out_ifaces: &[Option<&str>],
+    dest_port: u16,
+) -> Result<()> {
+    let chain = scoped_nat_chain_name(scope);
+    let local_chain = scoped_nat_chain_name(&format!("local:{scope}"));
+    let to = format!("127.0.0.1:{dest_port}");
+
+    // Port match variants: none (all ports), multiport chunks or one range per rule.
+    let port_args: Vec<Vec<String>> = if opt.port_preference == 1 {
+        vec![Vec::new()]
+    } else {
+        let ranges = port_filter::merge_ranges(port_filter::parse_ranges(&normalize_ports_csv(&opt.dpi_ports)));
+        if ranges.is_empty() {
+            anyhow::bail!("no valid dpi_ports tokens");
+        }
+        let elems = port_filter::to_multiport_elements(&ranges);
+        if caps::multiport_v4() {
+            port_filter::chunk_multiport(&elems, 15)
+                .iter()
+                .map(|chunk| vec!["-m".into(), "multiport".into(), "--dports".into(), port_filter::join_elems_csv(chunk)])
+                .collect()
+        } else {
+            elems.into_iter().map(|e| vec!["--dport".into(), e]).collect()
+        }
+    };
+    let mut batch = restore::Batch::new("iptables", "nat");
+    batch.chain(&chain);
+    if allow_loopback_redirect {
+        batch.chain(&local_chain);
+    }
+    let dport = dest_port.to_string();
+    for uid in uids {
+        for proto in proto_choice.protos() {
+            let owner: Vec<String> = vec!["-p".into(), (*proto).into(), "-m".into(), "owner".into(), "--uid-owner".into(), uid.clone()];
+            if allow_loopback_redirect {
+                let mut ret: Vec<String> = vec!["-d".into(), "127.0.0.0/8".into()];
+                ret.extend(owner.iter().cloned());
+                ret.extend(["-m".into(), (*proto).into(), "--dport".into(), dport.clone(), "-j".into(), "RETURN".into()]);
+                batch.append(&local_chain, &ret);
+            }
+            for ports in &port_args {
+                if allow_loopback_redirect {
+                    let mut rule: Vec<String> = vec!["-d".into(), "127.0.0.0/8".into()];
+                    rule.extend(owner.iter().cloned());
+                    rule.extend(ports.iter().cloned());
+                    rule.extend(["-j".into(), "DNAT".into(), "--to-destination".into(), to.clone()]);
+                    batch.append(&local_chain, &rule);
+                }
+                for iface in out_ifaces {
+                    let mut rule: Vec<String> = Vec::new();
+                    if let Some(iface) = iface {
+                        rule.extend(["-o".into(), (*iface).into()]);
+                    }
+                    rule.extend(owner.iter().cloned());
+                    rule.extend(ports.iter().cloned());
+                    rule.extend(["-j".into(), "DNAT".into(), "--to-destination".into(), to.clone()]);
+                    batch.append(&chain, &rule);
+                }
+            }
+        }
+    }
+    batch.append(&chain, &["-j".into(), "RETURN".into()]);
+    batch.append_if_missing("NAT_DPI", &["-j", chain.as_str()])?;
+    if allow_loopback_redirect {
+        batch.append(&local_chain, &["-j".into(), "RETURN".into()]);
+        batch.append_if_missing("NAT_DPI_LOCAL", &["-j", local_chain.as_str()])?;
+    }
+    batch.commit()
+}
+
 fn normalize_ifaces(ifaces_raw: Option<&str>) -> Result<(String, Vec<String>, Vec<String>)> {
     let raw_opt = ifaces_raw.map(|s| s.trim()).filter(|s| !s.is_empty());
     let mut mode: String;
@@ -568,7 +716,20 @@ fn insert_rule_at(table: &str, chain: &str, pos: usize, rule: &[&str]) -> Result
 
 
 
-fn scoped_nat_chain_name(label: &str) -> String {
+/// Scope label of one `apply` call; the scoped NAT chain name is derived from it.
+pub fn scope_label(uid_file: &Path, dest_port: u16, proto_choice: ProtoChoice, ifaces_raw: Option<&str>, opt: &DpiTunnelOptions) -> String {
+    format!(
+        "nat:uid={}:dest={}:proto={:?}:ifaces={}:pref={}:ports={}",
+        uid_file.display(),
+        dest_port,
+        proto_choice,
+        ifaces_raw.unwrap_or(""),
+        opt.port_preference,
+        opt.dpi_ports,
+    )
+}
+
+pub fn scoped_nat_chain_name(label: &str) -> String {
     let mut hash: u64 = 0xcbf29ce484222325;
     for b in label.as_bytes() {
         hash ^= *b as u64;
@@ -616,6 +777,39 @@ fn remove_nat_scoped_chain(chain: &str, parent: &str) -> Result<()> {
     Ok(())
 }
 
+/// Detach (`attached=false`) or re-hook the scoped NAT chains of one `apply`
+/// scope without touching their rules. Used by the supervisor so traffic stops
+/// being redirected into a dead port while the program is down.
+pub fn set_scope_attached(scope_label: &str, attached: bool) -> Result<()> {
+    if nft::active() {
+        nft::set_attached(&scoped_nat_chain_name(scope_label), attached)?;
+        return nft::set_attached(&scoped_nat_chain_name(&format!("local:{scope_label}")), attached);
+    }
+    let _xtables_guard = xtables_lock::lock();
+    let pairs = [
+        (scoped_nat_chain_name(scope_label), "NAT_DPI"),
+        (scoped_nat_chain_name(&format!("local:{scope_label}")), "NAT_DPI_LOCAL"),
+    ];
+    for (chain, parent) in &pairs {
+        if !attached {
+            delete_rule_all("nat", parent, &["-j", chain.as_str()])?;
+            continue;
+        }
+        let (exists, _) = ipt_run_timeout(&["-t", "nat", "-nL", chain.as_str()], Capture::None, IPT_CMD_TIMEOUT)?;
+        if exists != 0 {
+            continue;
+        }
+        let (check, _) = ipt_run_timeout(&["-t", "nat", "-C", parent, "-j", chain.as_str()], Capture::None, IPT_CMD_TIMEOUT)?;
+        if check != 0 {
+            let (add, out) = ipt_run_timeout(&["-t", "nat", "-A", parent, "-j", chain.as_str()], Capture::Both, IPT_CMD_TIMEOUT)?;
+            if add != 0 {
+                anyhow::bail!("DPI: re-hook {parent} -> {chain} failed: {}", out.trim());
+            }
+        }
+    }
+    Ok(())
+}
+
 fn prepare_nat_local_scoped_chain(scope_label: &str) -> Result<String> {
     let chain = scoped_nat_chain_name(&format!("local:{scope_label}"));
     let (exists, _) = ipt_run_timeout(&["-t", "nat", "-nL", chain.as_str()], Capture::None, IPT_CMD_TIMEOUT)?;
@@ -10,6 +10,7 @@ use std::{collections::{BTreeMap, BTreeSet}, fs, path::Path, time::Duration};
 
 use crate::{settings, shell::Capture, xtables_lock};
 use super::iptables_port::{DpiTunnelOptions, ProtoChoice};
+use super::restore;
 
 /// Legacy compatibility entrypoint for stale callers.
 /// New t2s routing should use `programs::common::apply_t2s_routing`, which
@@ -39,8 +40,8 @@ const IPT_SLOW_TIMEOUT: Duration = Duration::from_secs(15);
 const IP_CMD_TIMEOUT: Duration = Duration::from_secs(5);
 const XT_WAIT_SECS: &str = "5";
 
-const OUT_CHAIN: &str = "ZDT_TPROXY_OUT";
-const PRE_CHAIN: &str = "ZDT_TPROXY_PRE";
+pub const OUT_CHAIN: &str = "ZDT_TPROXY_OUT";
+pub const PRE_CHAIN: &str = "ZDT_TPROXY_PRE";
 const DIVERT_CHAIN: &str = "ZDT_TPROXY_DIVERT";
 
 /// IPv4 ranges that must never be TPROXY'd: they have to reach the local stack
@@ -63,10 +64,10 @@ const INTRANET_V4: &[&str] = &[
 // per-interface tables as ifindex+1000 (>=1000) and reserves 253..255 for
 // local/main/default, so a value in the 256..999 gap avoids collisions on
 // every Android release.
-const ROUTE_TABLE: u32 = 787;
+pub const ROUTE_TABLE: u32 = 787;
 // Low priority number so the ZDT-D rule sits above Android/OEM policy rules
 // (box_for_magisk uses 100 for the same reason).
-const ROUTE_PREF: &str = "100";
+pub const ROUTE_PREF: &str = "100";
 
 // Android packs netId/permission/protectedFromVpn/uidBillingDone into the low
 // fwmark bits (0..20).  ZDT-D must never touch those, so all TPROXY metadata
@@ -298,7 +299,7 @@ fn clear_slot_registry() {
 
 fn mark_hex(mark: u32) -> String { format!("0x{mark:08x}") }
 fn mark_mask_hex(mark: u32) -> String { format!("0x{mark:08x}/0x{SCOPE_MASK:08x}") }
-fn route_mask_hex() -> String { format!("0x{ROUTE_MARK:08x}/0x{ROUTE_MASK:08x}") }
+pub fn route_mask_hex() -> String { format!("0x{ROUTE_MARK:08x}/0x{ROUTE_MASK:08x}") }
 fn old_route_mask_hex() -> String { format!("0x{OLD_ROUTE_MARK:08x}/0x{OLD_ROUTE_MASK:08x}") }
 fn legacy_route_mask_hex() -> String { format!("0x{LEGACY_ROUTE_MARK:08x}/0x{LEGACY_ROUTE_MASK:08x}") }
 
@@ -344,15 +345,16 @@ fn apply_locked(uid_file: &Path, dest_port: u16, proto_choice: ProtoChoice, ifac
         return Ok(());
     }
 
-    let out_chain = prepare_scoped_chain(OUT_CHAIN, &scoped_out_chain_name(&scope)).map_err(failed)?;
-    let pre_chain = prepare_scoped_chain(PRE_CHAIN, &scoped_pre_chain_name(&scope)).map_err(failed)?;
-
+    // Collect both scoped chains first, then swap them in with one
+    // iptables-restore transaction; rule-by-rule only when that is rejected.
+    let mut out_rules: Vec<Vec<String>> = Vec::new();
+    let mut pre_rules: Vec<Vec<String>> = Vec::new();
     let protos = proto_choice.protos();
     if opt.port_preference == 1 {
         for uid in &uids {
             for proto in protos {
-                add_mark_rule(&out_chain, uid, proto, None, &mode, &ifaces, mark).map_err(failed)?;
-                add_tproxy_rule(&pre_chain, proto, None, mark, dest_port).map_err(failed)?;
+                out_rules.extend(mark_rules(uid, proto, None, &mode, &ifaces, mark));
+                pre_rules.push(tproxy_rule(proto, None, mark, dest_port));
             }
         }
     } else {
@@ -364,19 +366,36 @@ fn apply_locked(uid_file: &Path, dest_port: u16, proto_choice: ProtoChoice, ifac
         for uid in &uids {
             for proto in protos {
                 for dp in &dport_args {
-                    add_mark_rule(&out_chain, uid, proto, Some(dp.as_str()), &mode, &ifaces, mark).map_err(failed)?;
+                    out_rules.extend(mark_rules(uid, proto, Some(dp.as_str()), &mode, &ifaces, mark));
                 }
             }
         }
         for proto in protos {
             for dp in &dport_args {
-                add_tproxy_rule(&pre_chain, proto, Some(dp.as_str()), mark, dest_port).map_err(failed)?;
+                pre_rules.push(tproxy_rule(proto, Some(dp.as_str()), mark, dest_port));
             }
         }
     }
-
-    finish_scoped_chain(&out_chain).map_err(failed)?;
-    finish_scoped_chain(&pre_chain).map_err(failed)?;
+    // The same UID/port may repeat across ports tokens; keep the first copy
+    // like the `-C` check of the per-command path does.
+    dedup_rules(&mut out_rules);
+    dedup_rules(&mut pre_rules);
+
+    let out_chain = scoped_out_chain_name(&scope);
+    let pre_chain = scoped_pre_chain_name(&scope);
+    if let Err(e) = apply_scoped_batch(&out_chain, &out_rules, &pre_chain, &pre_rules) {
+        warn!("TPROXY: batch apply failed, applying rule by rule: {e:#}");
+        prepare_scoped_chain(OUT_CHAIN, &out_chain).map_err(failed)?;
+        prepare_scoped_chain(PRE_CHAIN, &pre_chain).map_err(failed)?;
+        for rule in &out_rules {
+            add_rule_idempotent(&out_chain, rule.clone()).map_err(failed)?;
+        }
+        for rule in &pre_rules {
+            add_rule_idempotent(&pre_chain, rule.clone()).map_err(failed)?;
+        }
+        finish_scoped_chain(&out_chain).map_err(failed)?;
+        finish_scoped_chain(&pre_chain).map_err(failed)?;
+    }
     // TPROXY is IPv4-only.  Block IPv6 for exactly these UIDs so their traffic
     // cannot leak straight out over IPv6 and instead falls back to IPv4 (which
     // is what gets TPROXY'd).  Best-effort: never fail the whole apply on it.
@@ -763,9 +782,10 @@ fn list_mangle_chains_with_prefix(prefix: &str) -> Vec<String> {
         .collect()
 }
 
-fn add_mark_rule(chain: &str, uid: &str, proto: &str, extra: Option<&str>, mode: &str, ifaces: &[String], mark: u32) -> Result<()> {
+fn mark_rules(uid: &str, proto: &str, extra: Option<&str>, mode: &str, ifaces: &[String], mark: u32) -> Vec<Vec<String>> {
     let extra_tokens = extra.map(|s| s.split_whitespace().map(|t| t.to_string()).collect::<Vec<_>>()).unwrap_or_default();
     let iface_list: Vec<Option<&str>> = if mode == "all" { vec![None] } else { ifaces.iter().map(|s| Some(s.as_str())).collect() };
+    let mut out = Vec::new();
     for iface in iface_list {
         let mut matcher: Vec<String> = Vec::new();
         if let Some(iface) = iface {
@@ -778,19 +798,19 @@ fn add_mark_rule(chain: &str, uid: &str, proto: &str, extra: Option<&str>, mode:
         let mut mark_rule = matcher.clone();
         let mark_mask = mark_mask_hex(mark);
         mark_rule.extend(["-j", "MARK", "--set-xmark", mark_mask.as_str()].iter().map(|s| s.to_string()));
-        add_rule_idempotent(chain, mark_rule)?;
+        out.push(mark_rule);
 
         // MARK is non-terminating. Add a matching terminating ACCEPT directly
         // after the MARK path so a UID present in multiple scoped TPROXY
         // profiles is not re-marked by a later chain.
         let mut accept_rule = matcher;
         accept_rule.extend(["-j", "ACCEPT"].iter().map(|s| s.to_string()));
-        add_rule_idempotent(chain, accept_rule)?;
+        out.push(accept_rule);
     }
-    Ok(())
+    out
 }
 
-fn add_tproxy_rule(chain: &str, proto: &str, extra: Option<&str>, mark: u32, dest_port: u16) -> Result<()> {
+fn tproxy_rule(proto: &str, extra: Option<&str>, mark: u32, dest_port: u16) -> Vec<String> {
     let extra_tokens = extra.map(|s| s.split_whitespace().map(|t| t.to_string()).collect::<Vec<_>>()).unwrap_or_default();
     let mark_match = mark_mask_hex(mark);
     let port_s = dest_port.to_string();
@@ -806,7 +826,25 @@ fn add_tproxy_rule(chain: &str, proto: &str, extra: Option<&str>, mark: u32, des
         "--tproxy-mark".into(),
         mark_match,
     ]);
-    add_rule_idempotent(chain, rule)
+    rule
+}
+
+fn dedup_rules(rules: &mut Vec<Vec<String>>) {
+    let mut seen = BTreeSet::new();
+    rules.retain(|r| seen.insert(r.clone()));
+}
+
+fn apply_scoped_batch(out_chain: &str, out_rules: &[Vec<String>], pre_chain: &str, pre_rules: &[Vec<String>]) -> Result<()> {
+    let mut batch = restore::Batch::new("iptables", "mangle");
+    for (parent, chain, rules) in [(OUT_CHAIN, out_chain, out_rules), (PRE_CHAIN, pre_chain, pre_rules)] {
+        batch.chain(chain);
+        for rule in rules {
+            batch.append(chain, rule);
+        }
+        batch.append(chain, &["-j".to_string(), "RETURN".to_string()]);
+        batch.append_if_missing(parent, &["-j", chain])?;
+    }
+    batch.commit()
 }
 
 fn add_rule_idempotent(chain: &str, rule: Vec<String>) -> Result<()> {
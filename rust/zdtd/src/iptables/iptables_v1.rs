@@ -2,7 +2,7 @@ use anyhow::Result;
 use log::{info, warn};
 use std::{fs, path::Path, time::Duration};
 
-use crate::iptables::{caps, mangle_app, port_filter};
+use crate::iptables::{caps, mangle_app, nft, port_filter};
 use crate::shell::Capture;
 use crate::xtables_lock;
 
@@ -48,6 +48,10 @@ pub fn apply(
         }
     }
 
+    if nft::active() {
+        return apply_nft(mode, queue, iface, uid_file, filter);
+    }
+
     let ipv6_avail = run_timeout_retry(
         "ip6tables",
         &["-t", "mangle", "-nL", "OUTPUT"],
@@ -57,15 +61,7 @@ pub fn apply(
     .map(|(c, _)| c == 0)
     .unwrap_or(false);
 
-    let scope = format!(
-        "nfqueue:v1:mode={}:queue={}:iface={}:uid={}",
-        mode,
-        queue,
-        iface.unwrap_or(""),
-        uid_file
-            .map(|p| p.display().to_string())
-            .unwrap_or_else(|| "global".to_string()),
-    );
+    let scope = scope_label(mode, queue, iface, uid_file);
 
     if let Some(p) = uid_file {
         if p.is_file() {
@@ -98,11 +94,9 @@ pub fn apply(
             let use_filter_v6 = filter_present && caps::multiport_v6();
 
             for uid in &uids {
-                apply_uid_or_global(&mut mangle_v4, &iopt, Some(uid.as_str()), queue, mode, filter, use_filter_v4)?;
+                apply_uid_or_global(&mut mangle_v4, &iopt, Some(uid.as_str()), queue, mode, filter, use_filter_v4);
                 if let Some(mangle_v6) = mangle_v6.as_mut() {
-                    if let Err(e) = apply_uid_or_global(mangle_v6, &iopt, Some(uid.as_str()), queue, mode, filter, use_filter_v6) {
-                        warn!("ip6tables NFQUEUE rule failed (uid={}): {e}", uid);
-                    }
+                    apply_uid_or_global(mangle_v6, &iopt, Some(uid.as_str()), queue, mode, filter, use_filter_v6);
                 }
             }
 
@@ -133,11 +127,9 @@ pub fn apply(
     let use_filter_v4 = filter_present && caps::multiport_v4();
     let use_filter_v6 = filter_present && caps::multiport_v6();
 
-    apply_uid_or_global(&mut mangle_v4, &iopt, None, queue, mode, filter, use_filter_v4)?;
+    apply_uid_or_global(&mut mangle_v4, &iopt, None, queue, mode, filter, use_filter_v4);
     if let Some(mangle_v6) = mangle_v6.as_mut() {
-        if let Err(e) = apply_uid_or_global(mangle_v6, &iopt, None, queue, mode, filter, use_filter_v6) {
-            warn!("ip6tables NFQUEUE rule failed (global): {e}");
-        }
+        apply_uid_or_global(mangle_v6, &iopt, None, queue, mode, filter, use_filter_v6);
     }
 
     mangle_app::finish_scoped(&mangle_v4)?;
@@ -154,6 +146,69 @@ pub fn apply(
     Ok(())
 }
 
+fn apply_nft(
+    mode: &str,
+    queue: u16,
+    iface: Option<&str>,
+    uid_file: Option<&Path>,
+    filter: Option<&port_filter::ProtoPortFilter>,
+) -> Result<()> {
+    let chain = mangle_app::scoped_chain_name(&scope_label(mode, queue, iface, uid_file));
+    let uids: Vec<Option<String>> = match uid_file.filter(|p| p.is_file()) {
+        Some(p) => {
+            let uids = read_uid_file(p)?;
+            if uids.is_empty() {
+                crate::runtime_refresh::register_nfqueue_v1(p, mode, queue, iface, filter);
+                nft::remove_scope(&chain)?;
+                info!("full_id_iptables applied (nft) mode={} queue={} iface={:?} empty uid list, removed scoped NFQUEUE chain", mode, queue, iface);
+                return Ok(());
+            }
+            uids.into_iter().map(Some).collect()
+        }
+        None => vec![None],
+    };
+
+    let verdict = nft::nfqueue_verdict(queue);
+    let web = [port_filter::PortRange::new(80, 80), port_filter::PortRange::new(443, 443)];
+    let mut rules = Vec::new();
+    for uid in &uids {
+        let uid = uid.as_deref();
+        if mode == "no_full" {
+            rules.push(nft::owner_rule(iface, uid, Some("tcp"), &web, &verdict)?);
+            continue;
+        }
+        match filter.filter(|f| !f.is_empty()) {
+            Some(f) => {
+                for (proto, ranges) in [("tcp", &f.tcp), ("udp", &f.udp)] {
+                    if !ranges.is_empty() {
+                        rules.push(nft::owner_rule(iface, uid, Some(proto), ranges, &verdict)?);
+                    }
+                }
+            }
+            None => rules.push(nft::owner_rule(iface, uid, None, &[], &verdict)?),
+        }
+    }
+    nft::set_scope(nft::Hook::MangleApp, &chain, rules)?;
+    if let Some(p) = uid_file {
+        crate::runtime_refresh::register_nfqueue_v1(p, mode, queue, iface, filter);
+    }
+    info!("full_id_iptables applied (nft) mode={} queue={} iface={:?} uids={}", mode, queue, iface, uids.len());
+    Ok(())
+}
+
+/// Scope label of one `apply` call; the scoped MANGLE_APP chain name is derived from it.
+pub fn scope_label(mode: &str, queue: u16, iface: Option<&str>, uid_file: Option<&Path>) -> String {
+    format!(
+        "nfqueue:v1:mode={}:queue={}:iface={}:uid={}",
+        mode,
+        queue,
+        iface.unwrap_or(""),
+        uid_file
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|| "global".to_string()),
+    )
+}
+
 fn read_uid_file(path: &Path) -> Result<Vec<String>> {
     let s = fs::read_to_string(path)?;
     let mut uids = Vec::new();
@@ -180,30 +235,32 @@ fn apply_uid_or_global(
     mode: &str,
     filter: Option<&port_filter::ProtoPortFilter>,
     use_multiport_filter: bool,
-) -> Result<()> {
+) {
     match mode {
         "full" => {
             if use_multiport_filter {
                 if let Some(f) = filter {
                     if !f.tcp.is_empty() {
-                        add_multiport_rules_with_fallback(mangle, iopt, uid, queue, "tcp", &f.tcp)?;
+                        add_multiport_rules_with_fallback(mangle, iopt, uid, queue, "tcp", &f.tcp);
                     }
                     if !f.udp.is_empty() {
-                        add_multiport_rules_with_fallback(mangle, iopt, uid, queue, "udp", &f.udp)?;
+                        add_multiport_rules_with_fallback(mangle, iopt, uid, queue, "udp", &f.udp);
                     }
-                    return Ok(());
+                    return;
                 }
             }
             add_nfqueue_rule(mangle, iopt, uid, queue, None, None, None)
         }
         "no_full" => {
-            add_nfqueue_rule(mangle, iopt, uid, queue, Some("tcp"), Some("80"), None)?;
+            add_nfqueue_rule(mangle, iopt, uid, queue, Some("tcp"), Some("80"), None);
             add_nfqueue_rule(mangle, iopt, uid, queue, Some("tcp"), Some("443"), None)
         }
         _ => unreachable!(),
     }
 }
 
+/// Multiport rules carry their per-port form; `mangle_app::finish_scoped`
+/// switches to it if the multiport match is rejected.
 fn add_multiport_rules_with_fallback(
     mangle: &mut mangle_app::PreparedScopedMangleApp,
     iopt: &[String],
@@ -211,18 +268,11 @@ fn add_multiport_rules_with_fallback(
     queue: u16,
     proto: &str,
     ranges: &[port_filter::PortRange],
-) -> Result<()> {
+) {
     if !caps::multiport_v4() {
         return add_per_port_rules(mangle, iopt, uid, queue, proto, ranges);
     }
-    match add_multiport_rules(mangle, iopt, uid, queue, proto, ranges) {
-        Ok(()) => Ok(()),
-        Err(e) => {
-            warn!("iptables NFQUEUE multiport rule failed for proto={proto} uid={uid:?}: {e:#}; falling back to per-port");
-            caps::disable_multiport_persistently(&format!("nfqueue {proto} multiport failed: {e:#}"));
-            add_per_port_rules(mangle, iopt, uid, queue, proto, ranges)
-        }
-    }
+    add_multiport_rules(mangle, iopt, uid, queue, proto, ranges)
 }
 
 fn add_multiport_rules(
@@ -232,13 +282,17 @@ fn add_multiport_rules(
     queue: u16,
     proto: &str,
     ranges: &[port_filter::PortRange],
-) -> Result<()> {
+) {
     let elems = port_filter::to_multiport_elements(ranges);
     for chunk in port_filter::chunk_multiport(&elems, 15) {
         let ports_csv = port_filter::join_elems_csv(&chunk);
-        add_nfqueue_rule(mangle, iopt, uid, queue, Some(proto), None, Some(ports_csv.as_str()))?;
+        let tail = nfqueue_tail(iopt, uid, queue, Some(proto), None, Some(ports_csv.as_str()));
+        let per_port = chunk
+            .iter()
+            .map(|dport| nfqueue_tail(iopt, uid, queue, Some(proto), Some(dport.as_str()), None))
+            .collect();
+        mangle_app::add_scoped_multiport_rule(mangle, &tail, per_port);
     }
-    Ok(())
 }
 
 fn add_per_port_rules(
@@ -248,16 +302,15 @@ fn add_per_port_rules(
     queue: u16,
     proto: &str,
     ranges: &[port_filter::PortRange],
-) -> Result<()> {
+) {
     for range in ranges {
         let dport = if range.start == range.end {
             range.start.to_string()
         } else {
             format!("{}:{}", range.start, range.end)
         };
-        add_nfqueue_rule(mangle, iopt, uid, queue, Some(proto), Some(dport.as_str()), None)?;
+        add_nfqueue_rule(mangle, iopt, uid, queue, Some(proto), Some(dport.as_str()), None);
     }
-    Ok(())
 }
 
 fn add_nfqueue_rule(
@@ -268,7 +321,18 @@ fn add_nfqueue_rule(
     proto: Option<&str>,
     dport: Option<&str>,
     multiport_csv: Option<&str>,
-) -> Result<()> {
+) {
+    mangle_app::add_scoped_rule(mangle, &nfqueue_tail(iopt, uid, queue, proto, dport, multiport_csv));
+}
+
+fn nfqueue_tail(
+    iopt: &[String],
+    uid: Option<&str>,
+    queue: u16,
+    proto: Option<&str>,
+    dport: Option<&str>,
+    multiport_csv: Option<&str>,
+) -> Vec<String> {
     let mut tail: Vec<String> = Vec::new();
     tail.extend_from_slice(iopt);
     if let Some(proto) = proto {
@@ -301,6 +365,5 @@ fn add_nfqueue_rule(
         queue.to_string(),
         "--queue-bypass".into(),
     ]);
-    mangle_app::add_scoped_rule(mangle, &tail)?;
-    Ok(())
+    tail
 }
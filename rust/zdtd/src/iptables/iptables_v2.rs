@@ -2,7 +2,7 @@ use anyhow::{Context, Result};
 use log::{info, warn};
 use std::{fs, path::Path, time::Duration};
 
-use crate::iptables::{caps, mangle_app, port_filter};
+use crate::iptables::{caps, mangle_app, nft, port_filter};
 use crate::shell::Capture;
 use crate::xtables_lock;
 
@@ -59,8 +59,11 @@ pub fn apply(
     }
 
     let total = uids.len() as u64;
-    let scope = format!("nfqueue:v2:queue={}:uid={}", port, uid_file.display());
+    let scope = scope_label(port, uid_file);
     crate::runtime_refresh::register_nfqueue_v2(uid_file, port, filter);
+    if nft::active() {
+        return apply_nft(port, &scope, &uids, filter);
+    }
     if uids.is_empty() {
         mangle_app::remove_scoped("iptables", &scope)?;
         let _ = mangle_app::remove_scoped("ip6tables", &format!("{scope}:v6"));
@@ -101,14 +104,14 @@ pub fn apply(
         let mut uid_added_v4 = false;
         if use_filter_v4 {
             if let Some(f) = filter {
-                if !f.tcp.is_empty() && add_multiport_rule_with_fallback(&mut mangle_v4, uid.as_str(), &q, "tcp", &f.tcp)? {
+                if !f.tcp.is_empty() && add_multiport_rule_with_fallback(&mut mangle_v4, uid.as_str(), &q, "tcp", &f.tcp) {
                     uid_added_v4 = true;
                 }
-                if !f.udp.is_empty() && add_multiport_rule_with_fallback(&mut mangle_v4, uid.as_str(), &q, "udp", &f.udp)? {
+                if !f.udp.is_empty() && add_multiport_rule_with_fallback(&mut mangle_v4, uid.as_str(), &q, "udp", &f.udp) {
                     uid_added_v4 = true;
                 }
             }
-        } else if add_plain_rule(&mut mangle_v4, uid.as_str(), &q)? {
+        } else if add_plain_rule(&mut mangle_v4, uid.as_str(), &q) {
             uid_added_v4 = true;
         }
 
@@ -120,27 +123,15 @@ pub fn apply(
             let mut uid_added_v6 = false;
             if use_filter_v6 {
                 if let Some(f) = filter {
-                    if !f.tcp.is_empty() {
-                        match add_multiport_rule_with_fallback(mangle_v6, uid.as_str(), &q, "tcp", &f.tcp) {
-                            Ok(true) => uid_added_v6 = true,
-                            Ok(false) => {}
-                            Err(e) => warn!("ip6tables add NFQUEUE rule failed for uid={}: {e}", uid),
-                        }
+                    if !f.tcp.is_empty() && add_multiport_rule_with_fallback(mangle_v6, uid.as_str(), &q, "tcp", &f.tcp) {
+                        uid_added_v6 = true;
                     }
-                    if !f.udp.is_empty() {
-                        match add_multiport_rule_with_fallback(mangle_v6, uid.as_str(), &q, "udp", &f.udp) {
-                            Ok(true) => uid_added_v6 = true,
-                            Ok(false) => {}
-                            Err(e) => warn!("ip6tables add NFQUEUE rule failed for uid={}: {e}", uid),
-                        }
+                    if !f.udp.is_empty() && add_multiport_rule_with_fallback(mangle_v6, uid.as_str(), &q, "udp", &f.udp) {
+                        uid_added_v6 = true;
                     }
                 }
-            } else {
-                match add_plain_rule(mangle_v6, uid.as_str(), &q) {
-                    Ok(true) => uid_added_v6 = true,
-                    Ok(false) => {}
-                    Err(e) => warn!("ip6tables add NFQUEUE rule failed for uid={}: {e}", uid),
-                }
+            } else if add_plain_rule(mangle_v6, uid.as_str(), &q) {
+                uid_added_v6 = true;
             }
             if uid_added_v6 {
                 added6 += 1;
@@ -163,24 +154,51 @@ pub fn apply(
     Ok(())
 }
 
+/// Same rules as a single nft scope; IPv4 and IPv6 share the inet chain.
+fn apply_nft(port: u16, scope: &str, uids: &[String], filter: Option<&port_filter::ProtoPortFilter>) -> Result<()> {
+    let chain = mangle_app::scoped_chain_name(scope);
+    if uids.is_empty() {
+        nft::remove_scope(&chain)?;
+        info!("iptables_v2 applied (nft): port={} empty uid list, removed scoped NFQUEUE chain", port);
+        return Ok(());
+    }
+    let verdict = nft::nfqueue_verdict(port);
+    let mut rules = Vec::new();
+    for uid in uids {
+        match filter.filter(|f| !f.is_empty()) {
+            Some(f) => {
+                for (proto, ranges) in [("tcp", &f.tcp), ("udp", &f.udp)] {
+                    if !ranges.is_empty() {
+                        rules.push(nft::owner_rule(None, Some(uid), Some(proto), ranges, &verdict)?);
+                    }
+                }
+            }
+            None => rules.push(nft::owner_rule(None, Some(uid), None, &[], &verdict)?),
+        }
+    }
+    nft::set_scope(nft::Hook::MangleApp, &chain, rules)?;
+    info!("iptables_v2 applied (nft): port={} uids={}", port, uids.len());
+    Ok(())
+}
+
+/// Scope label of one `apply` call; the scoped MANGLE_APP chain name is derived from it.
+pub fn scope_label(port: u16, uid_file: &Path) -> String {
+    format!("nfqueue:v2:queue={}:uid={}", port, uid_file.display())
+}
+
+/// Multiport rules carry their per-port form; `mangle_app::finish_scoped`
+/// switches to it if the multiport match is rejected.
 fn add_multiport_rule_with_fallback(
     mangle: &mut mangle_app::PreparedScopedMangleApp,
     uid: &str,
     q: &str,
     proto: &str,
     ranges: &[port_filter::PortRange],
-) -> Result<bool> {
+) -> bool {
     if !caps::multiport_v4() {
         return add_per_port_rule(mangle, uid, q, proto, ranges);
     }
-    match add_multiport_rule(mangle, uid, q, proto, ranges) {
-        Ok(added) => Ok(added),
-        Err(e) => {
-            warn!("NFQUEUE multiport rule failed for proto={proto} uid={uid}: {e:#}; falling back to per-port");
-            caps::disable_multiport_persistently(&format!("nfqueue {proto} multiport failed: {e:#}"));
-            add_per_port_rule(mangle, uid, q, proto, ranges)
-        }
-    }
+    add_multiport_rule(mangle, uid, q, proto, ranges)
 }
 
 fn add_multiport_rule(
@@ -189,7 +207,7 @@ fn add_multiport_rule(
     q: &str,
     proto: &str,
     ranges: &[port_filter::PortRange],
-) -> Result<bool> {
+) -> bool {
     let elems = port_filter::to_multiport_elements(ranges);
     let mut any_added = false;
     for chunk in port_filter::chunk_multiport(&elems, 15) {
@@ -211,10 +229,11 @@ fn add_multiport_rule(
             q.into(),
             "--queue-bypass".into(),
         ];
-        mangle_app::add_scoped_rule(mangle, &tail)?;
+        let per_port = chunk.iter().map(|dport| per_port_tail(uid, q, proto, dport)).collect();
+        mangle_app::add_scoped_multiport_rule(mangle, &tail, per_port);
         any_added = true;
     }
-    Ok(any_added)
+    any_added
 }
 
 fn add_per_port_rule(
@@ -223,36 +242,35 @@ fn add_per_port_rule(
     q: &str,
     proto: &str,
     ranges: &[port_filter::PortRange],
-) -> Result<bool> {
+) -> bool {
     let mut any_added = false;
-    for range in ranges {
-        let dport = if range.start == range.end {
-            range.start.to_string()
-        } else {
-            format!("{}:{}", range.start, range.end)
-        };
-        let tail: Vec<String> = vec![
-            "-p".into(),
-            proto.into(),
-            "--dport".into(),
-            dport,
-            "-m".into(),
-            "owner".into(),
-            "--uid-owner".into(),
-            uid.into(),
-            "-j".into(),
-            "NFQUEUE".into(),
-            "--queue-num".into(),
-            q.into(),
-            "--queue-bypass".into(),
-        ];
-        mangle_app::add_scoped_rule(mangle, &tail)?;
+    for dport in port_filter::to_multiport_elements(ranges) {
+        mangle_app::add_scoped_rule(mangle, &per_port_tail(uid, q, proto, &dport));
         any_added = true;
     }
-    Ok(any_added)
+    any_added
+}
+
+/// `dport` is a single port or a `start:end` range.
+fn per_port_tail(uid: &str, q: &str, proto: &str, dport: &str) -> Vec<String> {
+    vec![
+        "-p".into(),
+        proto.into(),
+        "--dport".into(),
+        dport.into(),
+        "-m".into(),
+        "owner".into(),
+        "--uid-owner".into(),
+        uid.into(),
+        "-j".into(),
+        "NFQUEUE".into(),
+        "--queue-num".into(),
+        q.into(),
+        "--queue-bypass".into(),
+    ]
 }
 
-fn add_plain_rule(mangle: &mut mangle_app::PreparedScopedMangleApp, uid: &str, q: &str) -> Result<bool> {
+fn add_plain_rule(mangle: &mut mangle_app::PreparedScopedMangleApp, uid: &str, q: &str) -> bool {
     let tail: Vec<String> = vec![
         "-m".into(),
         "owner".into(),
@@ -264,6 +282,6 @@ fn add_plain_rule(mangle: &mut mangle_app::PreparedScopedMangleApp, uid: &str, q
         q.into(),
         "--queue-bypass".into(),
     ];
-    mangle_app::add_scoped_rule(mangle, &tail)?;
-    Ok(true)
+    mangle_app::add_scoped_rule(mangle, &tail);
+    true
 }
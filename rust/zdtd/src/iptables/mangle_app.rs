@@ -2,6 +2,7 @@ use anyhow::Result;
 use log::{info, warn};
 use std::time::Duration;
 
+use crate::iptables::{caps, restore};
 use crate::shell::Capture;
 use crate::xtables_lock;
 
@@ -110,7 +111,7 @@ pub fn add_rule_idempotent(cmd: &str, rule_tail: &[String]) -> Result<bool> {
 }
 
 
-fn scoped_chain_name(label: &str) -> String {
+pub fn scoped_chain_name(label: &str) -> String {
     // iptables chain names are short on Android. Keep a deterministic compact name.
     let mut hash: u64 = 0xcbf29ce484222325;
     for b in label.as_bytes() {
@@ -124,6 +125,15 @@ fn scoped_chain_name(label: &str) -> String {
 pub struct PreparedScopedMangleApp {
     cmd: String,
     chain: String,
+    /// Rules collected by `add_scoped_rule`, committed by `finish_scoped`.
+    rules: Vec<ScopedRule>,
+}
+
+#[derive(Debug)]
+struct ScopedRule {
+    tail: Vec<String>,
+    /// Per-port rules used instead of a rejected multiport `tail`.
+    per_port: Vec<Vec<String>>,
 }
 
 /// Prepare a per-runtime-profile NFQUEUE subchain under MANGLE_APP.
@@ -143,17 +153,14 @@ pub fn prepare_scoped(cmd: &str, scope_label: &str) -> Result<PreparedScopedMang
         }
     }
 
-    let (rc, out) = run_timeout_retry(cmd, &["-t", "mangle", "-F", chain.as_str()], Capture::Both, IPT_CMD_TIMEOUT)?;
-    if rc != 0 {
-        anyhow::bail!("{cmd}: flush scoped mangle chain {chain} failed: {}", out.trim());
-    }
-
+    // The chain keeps its old rules until `finish_scoped` swaps them in.
     let jump_tail = vec!["-j".to_string(), chain.clone()];
     let _ = add_rule_prepared_idempotent(&mut parent, &jump_tail)?;
 
     Ok(PreparedScopedMangleApp {
         cmd: cmd.to_string(),
         chain,
+        rules: Vec::new(),
     })
 }
 
@@ -190,7 +197,17 @@ pub fn remove_scoped(cmd: &str, scope_label: &str) -> Result<()> {
     Ok(())
 }
 
-pub fn add_scoped_rule(prepared: &PreparedScopedMangleApp, rule_tail: &[String]) -> Result<()> {
+pub fn add_scoped_rule(prepared: &mut PreparedScopedMangleApp, rule_tail: &[String]) {
+    prepared.rules.push(ScopedRule { tail: rule_tail.to_vec(), per_port: Vec::new() });
+}
+
+/// Add a `-m multiport` rule together with its per-port equivalent, which
+/// `finish_scoped` uses if the multiport match is rejected.
+pub fn add_scoped_multiport_rule(prepared: &mut PreparedScopedMangleApp, rule_tail: &[String], per_port: Vec<Vec<String>>) {
+    prepared.rules.push(ScopedRule { tail: rule_tail.to_vec(), per_port });
+}
+
+fn append_scoped(prepared: &PreparedScopedMangleApp, rule_tail: &[String]) -> Result<()> {
     let mut add: Vec<String> = vec![
         "-t".into(),
         "mangle".into(),
@@ -205,7 +222,51 @@ pub fn add_scoped_rule(prepared: &PreparedScopedMangleApp, rule_tail: &[String])
     Ok(())
 }
 
+/// Append one collected rule, switching to its per-port form when multiport
+/// is unavailable or rejected.
+fn append_scoped_rule(prepared: &PreparedScopedMangleApp, rule: &ScopedRule) -> Result<()> {
+    let multiport = if prepared.cmd == "ip6tables" { caps::multiport_v6() } else { caps::multiport_v4() };
+    if rule.per_port.is_empty() || multiport {
+        match append_scoped(prepared, &rule.tail) {
+            Ok(()) => return Ok(()),
+            Err(e) if rule.per_port.is_empty() => return Err(e),
+            Err(e) => {
+                warn!("{}: NFQUEUE multiport rule failed in {}: {e:#}; falling back to per-port", prepared.cmd, prepared.chain);
+                caps::disable_multiport_persistently(&format!("nfqueue multiport failed: {e:#}"));
+            }
+        }
+    }
+    rule.per_port.iter().try_for_each(|tail| append_scoped(prepared, tail))
+}
+
+/// Replace the scoped chain contents with the collected rules plus the final
+/// RETURN in one `iptables-restore` transaction, falling back to flush and
+/// per-rule appends when the restore is rejected. In that fallback a rejected
+/// ip6tables rule is logged and skipped (IPv6 is best effort); a rejected
+/// iptables rule fails the chain.
 pub fn finish_scoped(prepared: &PreparedScopedMangleApp) -> Result<()> {
+    let mut batch = restore::Batch::new(&prepared.cmd, "mangle");
+    batch.chain(&prepared.chain);
+    for rule in &prepared.rules {
+        batch.append(&prepared.chain, &rule.tail);
+    }
+    batch.append(&prepared.chain, &["-j".to_string(), "RETURN".to_string()]);
+    match batch.commit() {
+        Ok(()) => return Ok(()),
+        Err(e) => warn!("{}: batch apply of {} failed, applying rule by rule: {e:#}", prepared.cmd, prepared.chain),
+    }
+
+    let (rc, out) = run_timeout_retry(&prepared.cmd, &["-t", "mangle", "-F", prepared.chain.as_str()], Capture::Both, IPT_CMD_TIMEOUT)?;
+    if rc != 0 {
+        anyhow::bail!("{}: flush scoped mangle chain {} failed: {}", prepared.cmd, prepared.chain, out.trim());
+    }
+    for rule in &prepared.rules {
+        match append_scoped_rule(prepared, rule) {
+            Ok(()) => {}
+            Err(e) if prepared.cmd == "ip6tables" => warn!("{e:#}; rule skipped"),
+            Err(e) => return Err(e),
+        }
+    }
     let (rc, out) = run_timeout_retry(
         prepared.cmd.as_str(),
         &["-t", "mangle", "-A", prepared.chain.as_str(), "-j", "RETURN"],
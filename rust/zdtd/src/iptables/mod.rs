@@ -1,6 +1,8 @@
 pub mod caps;
 pub mod port_filter;
 pub mod mangle_app;
+pub mod nft;
+pub mod restore;
 pub mod iptables_port;
 pub mod iptables_tproxy;
 pub mod iptables_v1;
@@ -0,0 +1,488 @@
+//! nftables backend for the scoped DPI rules.
+//!
+//! When selected, `iptables_v1`, `iptables_v2`, `iptables_port` and
+//! `captive_portal` hand their rules to this module instead of running one
+//! iptables command per rule. All scopes live in one `inet zdtd` table that is
+//! re-rendered and replaced by a single `nft -f` transaction on every change,
+//! so a failed apply leaves the previous ruleset in place instead of a
+//! half-built chain. Chain names are the same as on the iptables backend
+//! (MANGLE_APP, NAT_DPI, NAT_DPI_LOCAL, ZDTM_*/ZDTN_* scoped chains,
+//! ZDT_CAPTIVE_*), so plan diffs and logs read the same either way.
+//!
+//! TPROXY routing, hotspot redirects and the guard chains still use iptables;
+//! both stacks hook netfilter and coexist.
+//!
+//! The rendered scopes are persisted next to the API status files so a daemon
+//! restart does not drop chains it did not re-apply.
+
+use anyhow::{Context, Result};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, OnceLock},
+    time::Duration,
+};
+
+use crate::{
+    iptables::port_filter::{self, PortRange},
+    jsonfs, settings,
+    shell::{self, Capture},
+};
+
+const FAMILY: &str = "inet";
+const TABLE: &str = "zdtd";
+const NFT_TIMEOUT: Duration = Duration::from_secs(10);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// DNS ports that never go to NFQUEUE (same list as the MANGLE_APP RETURNs).
+const DNS_PORTS: &str = "53, 853, 5353";
+
+/// Parent chain a scoped chain is jumped to from
+/// (MANGLE_APP, NAT_DPI, NAT_DPI_LOCAL).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Hook {
+    MangleApp,
+    NatDpi,
+    NatDpiLocal,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Scope {
+    hook: Hook,
+    rules: Vec<String>,
+    #[serde(default = "default_true")]
+    attached: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct State {
+    /// Backend decided at the last full start; `None` until then.
+    #[serde(default)]
+    active: Option<bool>,
+    #[serde(default)]
+    scopes: BTreeMap<String, Scope>,
+    /// Allowed client IPs while the captive portal is on.
+    #[serde(default)]
+    captive: Option<Vec<String>>,
+}
+
+static STATE: OnceLock<Mutex<State>> = OnceLock::new();
+
+fn state_path() -> PathBuf {
+    Path::new(settings::API_DIR).join("nft_state.json")
+}
+
+fn script_path() -> PathBuf {
+    Path::new(settings::API_DIR).join("nft.rules")
+}
+
+fn state() -> MutexGuard<'static, State> {
+    let m = STATE.get_or_init(|| Mutex::new(jsonfs::read_json(&state_path()).unwrap_or_default()));
+    match m.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Whether `nft` exists and the kernel accepts the chain types this backend
+/// needs. Runs in check mode only; nothing is committed.
+pub fn probe() -> bool {
+    let listed = shell::run_timeout("nft", &["list", "tables"], Capture::None, PROBE_TIMEOUT)
+        .map(|(code, _)| code == 0)
+        .unwrap_or(false);
+    if !listed {
+        return false;
+    }
+    let check = "add table inet zdtd_probe; \
+        add chain inet zdtd_probe m { type route hook output priority mangle; }; \
+        add chain inet zdtd_probe n { type nat hook output priority dstnat; }; \
+        add rule inet zdtd_probe m meta skuid 0 queue num 0 bypass; \
+        add rule inet zdtd_probe n meta nfproto ipv4 meta skuid 0 dnat ip to 127.0.0.1:1";
+    shell::run_timeout("nft", &["-c", check], Capture::None, PROBE_TIMEOUT)
+        .map(|(code, _)| code == 0)
+        .unwrap_or(false)
+}
+
+/// Resolve the `firewall_backend` setting: `"nftables"` and `"auto"` use
+/// nftables when it is usable; anything else (the default) stays on iptables.
+pub fn wanted(setting: &str, usable: bool) -> bool {
+    matches!(setting, "nftables" | "auto") && usable
+}
+
+/// Pick the backend for this start and drop anything left from the previous one.
+pub fn select() {
+    let setting = settings::load_api_settings().map(|st| st.firewall_backend).unwrap_or_default();
+    let usable = probe();
+    if setting == "nftables" && !usable {
+        warn!("nft: firewall_backend=nftables but nft is not usable, falling back to iptables");
+    }
+    let on = wanted(&setting, usable);
+    {
+        let mut st = state();
+        *st = State { active: Some(on), ..State::default() };
+    }
+    reset_table();
+    save(&state());
+    info!("firewall backend: {}", if on { "nftables" } else { "iptables" });
+}
+
+/// Whether rules currently go to nftables.
+pub fn active() -> bool {
+    state().active.unwrap_or(false)
+}
+
+/// Remove the table and forget every scope (full stop).
+pub fn reset() {
+    {
+        let mut st = state();
+        st.scopes.clear();
+        st.captive = None;
+    }
+    reset_table();
+    let _ = fs::remove_file(state_path());
+}
+
+fn reset_table() {
+    let exists = shell::run_timeout("nft", &["list", "table", FAMILY, TABLE], Capture::None, PROBE_TIMEOUT)
+        .map(|(code, _)| code == 0)
+        .unwrap_or(false);
+    if exists {
+        if let Err(e) = shell::run_timeout("nft", &["delete", "table", FAMILY, TABLE], Capture::Both, NFT_TIMEOUT) {
+            warn!("nft: delete table {FAMILY} {TABLE} failed: {e:#}");
+        }
+    }
+}
+
+/// Whether the table with at least one hook chain is loaded (delta start anchor).
+pub fn anchors_present() -> bool {
+    active() && !live_chains().is_empty()
+}
+
+/// Replace the rules of a scoped chain. `rules` are nft rule bodies without the
+/// final `return`.
+pub fn set_scope(hook: Hook, chain: &str, rules: Vec<String>) -> Result<()> {
+    let mut st = state();
+    let prev = st.scopes.insert(chain.to_string(), Scope { hook, rules, attached: true });
+    if let Err(e) = commit(&st) {
+        match prev {
+            Some(p) => st.scopes.insert(chain.to_string(), p),
+            None => st.scopes.remove(chain),
+        };
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Drop a scoped chain. Unknown chains are not an error.
+pub fn remove_scope(chain: &str) -> Result<()> {
+    let mut st = state();
+    let Some(prev) = st.scopes.remove(chain) else {
+        return Ok(());
+    };
+    if let Err(e) = commit(&st) {
+        st.scopes.insert(chain.to_string(), prev);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Whether `chain` is a scope of this backend.
+pub fn owns(chain: &str) -> bool {
+    state().scopes.contains_key(chain)
+}
+
+/// Unhook or re-hook a scoped chain without touching its rules.
+pub fn set_attached(chain: &str, attached: bool) -> Result<()> {
+    let mut st = state();
+    match st.scopes.get_mut(chain) {
+        Some(s) if s.attached != attached => s.attached = attached,
+        _ => return Ok(()),
+    }
+    if let Err(e) = commit(&st) {
+        if let Some(s) = st.scopes.get_mut(chain) {
+            s.attached = !attached;
+        }
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Captive portal chains: `Some(allowed)` installs them, `None` removes them.
+pub fn set_captive(allowed: Option<Vec<String>>) -> Result<()> {
+    let mut st = state();
+    let prev = std::mem::replace(&mut st.captive, allowed);
+    if let Err(e) = commit(&st) {
+        st.captive = prev;
+        return Err(e);
+    }
+    Ok(())
+}
+
+fn save(st: &State) {
+    if let Err(e) = jsonfs::write_json_pretty_tmp_rename(&state_path(), st) {
+        warn!("nft: save {} failed: {e:#}", state_path().display());
+    }
+}
+
+fn commit(st: &State) -> Result<()> {
+    let script = render(st);
+    let path = script_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("mkdir {}", parent.display()))?;
+    }
+    fs::write(&path, &script).with_context(|| format!("write {}", path.display()))?;
+    let path_s = path.display().to_string();
+    let (code, out) = shell::run_timeout("nft", &["-f", path_s.as_str()], Capture::Both, NFT_TIMEOUT)?;
+    if code != 0 {
+        anyhow::bail!("nft -f {} failed rc={code}: {}", path_s, out.trim());
+    }
+    save(st);
+    Ok(())
+}
+
+/// `oifname "wlan0"` with the name checked so a bad config value cannot break
+/// the whole transaction.
+fn oifname(iface: &str) -> Result<String> {
+    let ok = !iface.is_empty()
+        && iface.len() < 16
+        && iface.chars().all(|c| c.is_ascii_alphanumeric() || "_-.@+:".contains(c));
+    if !ok {
+        anyhow::bail!("invalid interface name: {iface:?}");
+    }
+    Ok(format!("oifname \"{iface}\""))
+}
+
+/// Overlapping elements are rejected inside an nft set, so merge first.
+fn port_set(ports: &[PortRange]) -> String {
+    let elems: Vec<String> = port_filter::merge_ranges(ports.to_vec())
+        .iter()
+        .map(|r| if r.start == r.end { r.start.to_string() } else { format!("{}-{}", r.start, r.end) })
+        .collect();
+    if elems.len() == 1 {
+        elems[0].clone()
+    } else {
+        format!("{{ {} }}", elems.join(", "))
+    }
+}
+
+/// One owner-matched rule: `[oifname] [skuid] [proto [dport]] <verdict>`.
+/// `ports` is only used together with `proto`.
+pub fn owner_rule(iface: Option<&str>, uid: Option<&str>, proto: Option<&str>, ports: &[PortRange], verdict: &str) -> Result<String> {
+    let mut parts: Vec<String> = Vec::new();
+    if let Some(iface) = iface.filter(|s| !s.is_empty()) {
+        parts.push(oifname(iface)?);
+    }
+    if let Some(uid) = uid {
+        let uid: u32 = uid.parse().with_context(|| format!("invalid uid {uid:?}"))?;
+        parts.push(format!("meta skuid {uid}"));
+    }
+    match proto {
+        Some(p @ ("tcp" | "udp")) if !ports.is_empty() => parts.push(format!("{p} dport {}", port_set(ports))),
+        Some(p @ ("tcp" | "udp")) => parts.push(format!("meta l4proto {p}")),
+        Some(p) => anyhow::bail!("unsupported protocol {p:?}"),
+        None => {}
+    }
+    parts.push(verdict.to_string());
+    Ok(parts.join(" "))
+}
+
+pub fn nfqueue_verdict(queue: u16) -> String {
+    format!("queue num {queue} bypass")
+}
+
+/// DNAT to the local listener; NAT rules are IPv4-only like the iptables path.
+pub fn dnat_verdict(dest_port: u16) -> String {
+    format!("dnat ip to 127.0.0.1:{dest_port}")
+}
+
+fn render(st: &State) -> String {
+    // "add + delete" makes the delete valid even when the table is absent, so
+    // the whole replacement is one transaction.
+    let mut s = format!("table {FAMILY} {TABLE}\ndelete table {FAMILY} {TABLE}\n");
+    let has = |hook: Hook| st.scopes.values().any(|sc| sc.hook == hook);
+    if st.scopes.is_empty() && st.captive.is_none() {
+        return s;
+    }
+    let _ = writeln!(s, "table {FAMILY} {TABLE} {{");
+
+    let jumps = |s: &mut String, hook: Hook| {
+        for (name, _) in st.scopes.iter().filter(|(_, sc)| sc.hook == hook && sc.attached) {
+            let _ = writeln!(s, "\t\tjump {name}");
+        }
+    };
+
+    if has(Hook::MangleApp) {
+        let _ = writeln!(s, "\tchain MANGLE_APP {{");
+        let _ = writeln!(s, "\t\ttype route hook output priority mangle; policy accept;");
+        let _ = writeln!(s, "\t\toifname \"lo\" return");
+        let _ = writeln!(s, "\t\tip daddr 127.0.0.0/8 return");
+        let _ = writeln!(s, "\t\tip6 daddr ::1 return");
+        let _ = writeln!(s, "\t\tmeta l4proto {{ sctp, tcp, udp }} th dport {{ {DNS_PORTS} }} return");
+        jumps(&mut s, Hook::MangleApp);
+        let _ = writeln!(s, "\t}}");
+    }
+
+    if has(Hook::NatDpi) || has(Hook::NatDpiLocal) {
+        // NAT_DPI_LOCAL must run before the loopback RETURNs of NAT_DPI.
+        let _ = writeln!(s, "\tchain NAT_OUTPUT {{");
+        let _ = writeln!(s, "\t\ttype nat hook output priority dstnat; policy accept;");
+        if has(Hook::NatDpiLocal) {
+            let _ = writeln!(s, "\t\tmeta nfproto ipv4 jump NAT_DPI_LOCAL");
+        }
+        let _ = writeln!(s, "\t\tmeta nfproto ipv4 jump NAT_DPI");
+        let _ = writeln!(s, "\t}}");
+        if has(Hook::NatDpiLocal) {
+            let _ = writeln!(s, "\tchain NAT_DPI_LOCAL {{");
+            jumps(&mut s, Hook::NatDpiLocal);
+            let _ = writeln!(s, "\t}}");
+        }
+        let _ = writeln!(s, "\tchain NAT_DPI {{");
+        let _ = writeln!(s, "\t\toifname \"lo\" return");
+        let _ = writeln!(s, "\t\tip daddr 127.0.0.0/8 return");
+        jumps(&mut s, Hook::NatDpi);
+        let _ = writeln!(s, "\t}}");
+    }
+
+    for (name, sc) in &st.scopes {
+        let _ = writeln!(s, "\tchain {name} {{");
+        for rule in &sc.rules {
+            let _ = writeln!(s, "\t\t{rule}");
+        }
+        let _ = writeln!(s, "\t\treturn");
+        let _ = writeln!(s, "\t}}");
+    }
+
+    if let Some(allowed) = &st.captive {
+        render_captive(&mut s, allowed);
+    }
+
+    s.push_str("}\n");
+    s
+}
+
+fn render_captive(s: &mut String, allowed: &[String]) {
+    let port = crate::captive_portal::PORTAL_PORT;
+    let ips: Vec<Ipv4Addr> = allowed.iter().filter_map(|ip| ip.trim().parse().ok()).collect();
+    if ips.len() != allowed.iter().filter(|ip| !ip.trim().is_empty()).count() {
+        warn!("nft: captive portal skipped non-IPv4 allowed addresses");
+    }
+
+    // Earlier than the iptables hooks, like the `-I ... 1` jumps there.
+    let _ = writeln!(s, "\tchain ZDT_CAPTIVE_PRE {{");
+    let _ = writeln!(s, "\t\ttype nat hook prerouting priority dstnat - 5; policy accept;");
+    let _ = writeln!(s, "\t\tmeta nfproto != ipv4 return");
+    for ip in &ips {
+        let _ = writeln!(s, "\t\tip saddr {ip} return");
+    }
+    let _ = writeln!(s, "\t\tmeta l4proto tcp redirect to :{port}");
+    let _ = writeln!(s, "\t}}");
+
+    let _ = writeln!(s, "\tchain ZDT_CAPTIVE_IN {{");
+    let _ = writeln!(s, "\t\ttype filter hook input priority filter - 5; policy accept;");
+    let _ = writeln!(s, "\t\tmeta nfproto ipv4 tcp dport {port} accept");
+    let _ = writeln!(s, "\t}}");
+
+    let _ = writeln!(s, "\tchain ZDT_CAPTIVE_FWD {{");
+    let _ = writeln!(s, "\t\ttype filter hook forward priority filter - 5; policy accept;");
+    let _ = writeln!(s, "\t\tmeta nfproto != ipv4 return");
+    for ip in &ips {
+        let _ = writeln!(s, "\t\tip saddr {ip} return");
+    }
+    let _ = writeln!(s, "\t\tdrop");
+    let _ = writeln!(s, "\t}}");
+}
+
+/// Chains of the live table with the chains each one jumps to.
+pub fn live_chains() -> Vec<(String, Vec<String>)> {
+    match shell::run_timeout("nft", &["list", "table", FAMILY, TABLE], Capture::Stdout, PROBE_TIMEOUT) {
+        Ok((0, out)) => parse_listing(&out),
+        _ => Vec::new(),
+    }
+}
+
+fn parse_listing(text: &str) -> Vec<(String, Vec<String>)> {
+    let mut out: Vec<(String, Vec<String>)> = Vec::new();
+    for line in text.lines() {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if let ["chain", name, "{"] = tokens.as_slice() {
+            out.push((name.to_string(), Vec::new()));
+            continue;
+        }
+        if let Some(pos) = tokens.iter().position(|t| *t == "jump") {
+            if let (Some(target), Some((_, jumps))) = (tokens.get(pos + 1), out.last_mut()) {
+                jumps.push(target.to_string());
+            }
+        }
+    }
+    out
+}
+
+/// iptables table name for a chain of this backend (`nat` or `mangle`).
+pub fn table_of(chain: &str) -> &'static str {
+    if chain.starts_with("ZDTN_") || chain.starts_with("NAT_") || chain == "ZDT_CAPTIVE_PRE" {
+        "nat"
+    } else if chain.starts_with("ZDT_CAPTIVE_") {
+        "filter"
+    } else {
+        "mangle"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nftables_is_opt_in() {
+        let default = settings::ApiSettings::default().firewall_backend;
+        assert!(!wanted(&default, true));
+        assert!(wanted("nftables", true));
+        assert!(wanted("auto", true));
+        assert!(!wanted("auto", false));
+    }
+
+    #[test]
+    fn renders_scopes_into_one_table() {
+        let mut st = State::default();
+        let ports = [PortRange::new(80, 80), PortRange::new(6881, 6999)];
+        st.scopes.insert(
+            "ZDTM_1".into(),
+            Scope {
+                hook: Hook::MangleApp,
+                rules: vec![owner_rule(None, Some("10123"), Some("tcp"), &ports, &nfqueue_verdict(200)).unwrap()],
+                attached: true,
+            },
+        );
+        st.scopes.insert(
+            "ZDTN_2".into(),
+            Scope {
+                hook: Hook::NatDpi,
+                rules: vec![owner_rule(Some("wlan0"), Some("10124"), Some("tcp"), &[], &dnat_verdict(1080)).unwrap()],
+                attached: false,
+            },
+        );
+        let s = render(&st);
+        assert!(s.starts_with("table inet zdtd\ndelete table inet zdtd\n"));
+        assert!(s.contains("\t\tmeta skuid 10123 tcp dport { 80, 6881-6999 } queue num 200 bypass\n"));
+        assert!(s.contains("\t\toifname \"wlan0\" meta skuid 10124 meta l4proto tcp dnat ip to 127.0.0.1:1080\n"));
+        assert!(s.contains("\t\tjump ZDTM_1\n"));
+        // Detached scopes keep their chain but lose the jump.
+        assert!(s.contains("\tchain ZDTN_2 {"));
+        assert!(!s.contains("jump ZDTN_2"));
+        assert!(!s.contains("NAT_DPI_LOCAL"));
+
+        let live = parse_listing(&s);
+        assert_eq!(live.iter().find(|(c, _)| c == "MANGLE_APP").map(|(_, j)| j.clone()), Some(vec!["ZDTM_1".to_string()]));
+
+        assert!(owner_rule(Some("wlan0\" accept"), None, None, &[], "accept").is_err());
+    }
+}
@@ -0,0 +1,130 @@
+//! `iptables-restore --noflush` transactions.
+//!
+//! Rule builders collect a whole stage (flush + rules + final RETURN of a
+//! scoped chain, plus missing hooks) into a `Batch` and commit it with one
+//! `iptables-restore` call per table and family. The kernel swaps the table in
+//! one step, so a failed or lock-contended commit changes nothing; callers then
+//! fall back to their per-command path.
+//!
+//! Declaring a chain (`:NAME - [0:0]`) under `--noflush` creates it when
+//! missing and flushes it when present; other chains are left untouched.
+
+use anyhow::{Context, Result};
+use std::{
+    fs,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::{settings, shell::Capture, xtables_lock};
+
+const RESTORE_TIMEOUT: Duration = Duration::from_secs(15);
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+const XT_WAIT_SECS: &str = "5";
+
+static SEQ: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub struct Batch {
+    cmd: String,
+    table: String,
+    chains: Vec<String>,
+    lines: Vec<String>,
+}
+
+impl Batch {
+    /// `cmd` is `iptables` or `ip6tables`.
+    pub fn new(cmd: &str, table: &str) -> Self {
+        Self { cmd: cmd.to_string(), table: table.to_string(), chains: Vec::new(), lines: Vec::new() }
+    }
+
+    /// Create `chain` or flush it as part of the transaction.
+    pub fn chain(&mut self, chain: &str) {
+        if !self.chains.iter().any(|c| c == chain) {
+            self.chains.push(chain.to_string());
+        }
+    }
+
+    pub fn append(&mut self, chain: &str, rule: &[String]) {
+        self.lines.push(format!("-A {chain} {}", rule.join(" ")));
+    }
+
+    /// Append `rule` unless it is already present in a chain the batch does
+    /// not flush (e.g. the jump from a shared parent into a scoped chain).
+    pub fn append_if_missing(&mut self, chain: &str, rule: &[&str]) -> Result<()> {
+        let mut check: Vec<&str> = vec!["-w", XT_WAIT_SECS, "-t", self.table.as_str(), "-C", chain];
+        check.extend_from_slice(rule);
+        let (rc, _) = xtables_lock::run_timeout_retry(&self.cmd, &check, Capture::None, CHECK_TIMEOUT)?;
+        if rc != 0 {
+            let rule: Vec<String> = rule.iter().map(|s| s.to_string()).collect();
+            self.append(chain, &rule);
+        }
+        Ok(())
+    }
+
+    fn render(&self) -> Result<String> {
+        let mut out = format!("*{}\n", self.table);
+        for chain in &self.chains {
+            out.push_str(&format!(":{chain} - [0:0]\n"));
+        }
+        for line in &self.lines {
+            // Arguments are joined with spaces; an empty or quoted token would
+            // silently change the rule.
+            if line.split(' ').any(|t| t.is_empty() || t.contains(['"', '\'', '\n', '\t'])) {
+                anyhow::bail!("unsupported token in rule: {line:?}");
+            }
+            out.push_str(line);
+            out.push('\n');
+        }
+        out.push_str("COMMIT\n");
+        Ok(out)
+    }
+
+    /// Run the transaction. Nothing is changed when this fails.
+    pub fn commit(&self) -> Result<()> {
+        let text = self.render()?;
+        let path = Path::new(settings::API_DIR).join(format!(
+            "{}-restore.{}.{}.rules",
+            self.cmd,
+            std::process::id(),
+            SEQ.fetch_add(1, Ordering::Relaxed)
+        ));
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("mkdir {}", parent.display()))?;
+        }
+        fs::write(&path, text).with_context(|| format!("write {}", path.display()))?;
+        let path_s = path.display().to_string();
+        let restore = format!("{}-restore", self.cmd);
+        let res = xtables_lock::run_timeout_retry(
+            &restore,
+            &["-w", XT_WAIT_SECS, "--noflush", path_s.as_str()],
+            Capture::Both,
+            RESTORE_TIMEOUT,
+        );
+        let _ = fs::remove_file(&path);
+        let (rc, out) = res?;
+        if rc != 0 {
+            anyhow::bail!("{restore} -t {} failed rc={rc}: {}", self.table, out.trim());
+        }
+        log::debug!("{restore}: committed {} rule(s) to {}", self.lines.len(), self.table);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_chains_before_rules() {
+        let mut b = Batch::new("iptables", "nat");
+        b.chain("ZDTN_1");
+        b.chain("ZDTN_1");
+        b.append("ZDTN_1", &["-p".into(), "tcp".into(), "-j".into(), "RETURN".into()]);
+        assert_eq!(b.render().unwrap(), "*nat\n:ZDTN_1 - [0:0]\n-A ZDTN_1 -p tcp -j RETURN\nCOMMIT\n");
+
+        b.append("ZDTN_1", &["-m".into(), "comment".into(), "--comment".into(), "\"x y\"".into()]);
+        assert!(b.render().is_err());
+    }
+}
@@ -1,22 +1,29 @@
 mod android;
-mod android_dns;
+mod backup;
 mod blockedquic;
 mod capabilities;
 mod captive_portal;
 mod api;
+mod api_http;
+mod api_router;
+mod api_tokens;
 mod api_status;
 mod config;
 mod daemon;
+mod dns;
 mod energy_saver;
+mod events;
 mod iptables;
 mod iptables_backup;
 mod idle;
 mod internet_wait;
 mod jsonfs;
 mod logging;
+mod plan;
 mod ports;
 mod power_mode;
 mod proxyinfo;
+mod quota;
 mod protector;
 mod screen;
 mod scan_detector;
@@ -30,6 +37,8 @@ mod settings;
 mod shell;
 mod stats;
 mod stop;
+mod supervisor;
+mod traffic_history;
 mod traffic_total;
 mod vpn_netd;
 mod vpn_tether;
@@ -0,0 +1,1143 @@
+//! Declarative runtime plan.
+//!
+//! `build()` turns the working_folder configuration into a typed description of
+//! what a full start would produce: processes (with argv where the launch
+//! command is fully derived from config), scoped iptables chains, TUN/netd
+//! networks and the TPROXY policy rule. `diff()` compares that plan with the
+//! live system and `apply()` converges only the difference, so a restart with
+//! one missing or changed profile does not need to flush nat/mangle and
+//! relaunch every program.
+//!
+//! Programs whose launch is fully config-driven (nfqws, nfqws2, byedpi,
+//! dpitunnel) are planned per profile with exact argv and routing. The other
+//! programs are planned per profile with program-level liveness and the routing
+//! recorded by runtime_refresh while they were applied.
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    iptables::{iptables_port, iptables_tproxy, iptables_v1, iptables_v2, mangle_app, nft},
+    programs::{
+        amneziawg, byedpi, dpitunnel, hysteria2, mihomo, mieru, myprogram, myproxy, myvpn, nfqws, nfqws2, openvpn,
+        operaproxy, singbox, tgwsproxy, tor, tun2socks, wireproxy,
+    },
+    runtime_refresh::RoutingSnapshot,
+    settings,
+    shell::{self, Capture},
+    stats, xtables_lock,
+};
+
+const BIN_DIR: &str = "/data/adb/modules/ZDT-D/bin";
+const IPT_CMD_TIMEOUT: Duration = Duration::from_secs(5);
+const IP_CMD_TIMEOUT: Duration = Duration::from_secs(3);
+const SCOPED_CHAIN_PREFIXES: [&str; 4] = ["ZDTN_", "ZDTM_", "ZDTP", "ZDTV6_"];
+
+/// Tables holding scoped chains, per command. IPv6 only carries the MANGLE_APP
+/// scopes and the TPROXY leak-block chains in `filter`.
+const SCOPED_TABLES: [(&str, &str); 4] =
+    [("iptables", "nat"), ("iptables", "mangle"), ("ip6tables", "mangle"), ("ip6tables", "filter")];
+
+/// Desired processes and routing of one profile, as produced by a program's
+/// `plan_profile`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ProfilePlan {
+    pub processes: Vec<PlannedProcess>,
+    pub routing: Vec<RoutingSnapshot>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedProcess {
+    pub binary: String,
+    pub argv: Vec<String>,
+}
+
+impl PlannedProcess {
+    pub fn new(binary: impl Into<String>, argv: Vec<String>) -> Self {
+        Self { binary: binary.into(), argv }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedUnit {
+    pub program: String,
+    pub profile: Option<String>,
+    /// true: processes/routing derived from config and matched by argv + workdir.
+    /// false: matched by program liveness, routing taken from the runtime cache.
+    pub exact: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub workdir: Option<String>,
+    pub processes: Vec<PlannedProcess>,
+    pub routing: Vec<RoutingSnapshot>,
+}
+
+impl PlannedUnit {
+    fn label(&self) -> String {
+        match self.profile.as_deref() {
+            Some(p) => format!("{}/{}", self.program, p),
+            None => self.program.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct PlannedChain {
+    /// `iptables` or `ip6tables`.
+    pub cmd: String,
+    pub table: String,
+    pub parent: String,
+    pub chain: String,
+    pub uid_file: String,
+    pub unit: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedNetwork {
+    pub program: String,
+    pub profile: String,
+    pub tun: String,
+    /// Hotspot-only selections are tethered by vpn_tether and have no netd owner.
+    pub netd: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PlannedIpRule {
+    pub pref: String,
+    pub fwmark: String,
+    pub table: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Plan {
+    pub generated_at: u64,
+    pub units: Vec<PlannedUnit>,
+    pub chains: Vec<PlannedChain>,
+    pub networks: Vec<PlannedNetwork>,
+    pub ip_rules: Vec<PlannedIpRule>,
+    /// Problems that only a full start (port normalization, netd re-apply) can fix.
+    pub conflicts: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LiveProcess {
+    pub pid: i32,
+    pub argv: Vec<String>,
+    pub cwd: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExtraProcess {
+    pub unit: String,
+    pub pid: i32,
+    pub argv: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct StaleChain {
+    pub cmd: String,
+    pub table: String,
+    pub chain: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PlanDiff {
+    pub in_sync: bool,
+    pub missing_units: Vec<String>,
+    pub changed_units: Vec<String>,
+    pub extra_processes: Vec<ExtraProcess>,
+    pub missing_chains: Vec<PlannedChain>,
+    pub stale_chains: Vec<StaleChain>,
+    pub missing_networks: Vec<PlannedNetwork>,
+    pub missing_ip_rules: Vec<PlannedIpRule>,
+    pub conflicts: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ApplyReport {
+    pub requires_full_start: bool,
+    pub reasons: Vec<String>,
+    pub actions: Vec<String>,
+    pub failures: Vec<String>,
+}
+
+impl ApplyReport {
+    pub fn ok(&self) -> bool {
+        !self.requires_full_start && self.failures.is_empty()
+    }
+}
+
+/// `restart_profile` refused before touching anything: the profile can't be
+/// started on its own. The API answers 409.
+#[derive(Debug)]
+pub struct NotRestartable(pub String);
+
+impl std::fmt::Display for NotRestartable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for NotRestartable {}
+
+type PlanFn = fn(&str) -> Result<Option<ProfilePlan>>;
+type StartProfileFn = fn(&str) -> Result<()>;
+type StartFn = fn() -> Result<()>;
+
+/// Programs planned per profile with exact argv. Profiles live in `<root>/<name>`
+/// and every process is spawned with that directory as its cwd.
+const EXACT_PROGRAMS: [(&str, PlanFn, StartProfileFn); 4] = [
+    ("nfqws", nfqws::plan_profile, nfqws::start_profile_by_name),
+    ("nfqws2", nfqws2::plan_profile, nfqws2::start_profile_by_name),
+    ("byedpi", byedpi::plan_profile, byedpi::start_profile_by_name),
+    ("dpitunnel", dpitunnel::plan_profile, dpitunnel::start_profile_by_name),
+];
+
+/// Profile programs planned with program-level liveness. Profiles live in
+/// `<root>/profile/<name>`.
+const PROFILE_PROGRAMS: [&str; 11] = [
+    "singbox", "hysteria2", "wireproxy", "myproxy", "myprogram", "openvpn", "amneziawg", "tun2socks", "mihomo",
+    "mieru", "myvpn",
+];
+
+/// Programs that can be started on their own while the rest of the runtime keeps
+/// running. Anything else that is missing needs a full start.
+const STANDALONE_STARTERS: [(&str, StartFn); 6] = [
+    ("wireproxy", wireproxy::start_if_enabled),
+    ("myproxy", myproxy::start_if_enabled),
+    ("myprogram", myprogram::start_if_enabled),
+    ("tgwsproxy", tgwsproxy::start_if_enabled),
+    ("tor", tor::start_if_enabled),
+    ("operaproxy", operaproxy::start_if_enabled),
+];
+
+fn now_unix() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0))
+        .as_secs()
+}
+
+fn enabled_profiles(program: &str) -> Vec<String> {
+    let path = settings::working_program_root_path(program).join("active.json");
+    let Ok(raw) = fs::read_to_string(path) else { return Vec::new(); };
+    let Ok(v) = serde_json::from_str::<serde_json::Value>(&raw) else { return Vec::new(); };
+    v.get("profiles")
+        .and_then(|p| p.as_object())
+        .map(|m| {
+            m.iter()
+                .filter(|(_, st)| crate::jsonfs::json_enabled(st.get("enabled")))
+                .map(|(name, _)| name.clone())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn uid_file_has_uids(path: &str) -> bool {
+    crate::programs::common::count_valid_uid_pairs(Path::new(path)).unwrap_or(0) > 0
+}
+
+fn cached_routing_under(cache: &[RoutingSnapshot], dir: &Path) -> Vec<RoutingSnapshot> {
+    let prefix = format!("{}/", dir.display());
+    cache
+        .iter()
+        .filter(|s| s.uid_file().starts_with(&prefix))
+        .cloned()
+        .collect()
+}
+
+/// Build the desired runtime state from the current configuration.
+///
+/// Read-only: app lists are taken as already resolved in `app/out`, nothing is
+/// spawned and no iptables state is touched.
+pub fn build() -> Plan {
+    let mut units = Vec::new();
+    let mut conflicts = Vec::new();
+    let cache = crate::runtime_refresh::cached_routing();
+
+    for (program, plan_profile, _) in EXACT_PROGRAMS {
+        let root = settings::working_program_root_path(program);
+        for profile in enabled_profiles(program) {
+            match plan_profile(&profile) {
+                Ok(Some(p)) => units.push(PlannedUnit {
+                    program: program.to_string(),
+                    profile: Some(profile.clone()),
+                    exact: true,
+                    workdir: Some(root.join(&profile).display().to_string()),
+                    processes: p.processes,
+                    routing: p.routing,
+                }),
+                Ok(None) => {}
+                Err(e) => conflicts.push(format!("{program}/{profile}: {e:#}")),
+            }
+        }
+    }
+
+    for program in PROFILE_PROGRAMS {
+        let root = settings::working_program_root_path(program);
+        for profile in enabled_profiles(program) {
+            units.push(PlannedUnit {
+                program: program.to_string(),
+                profile: Some(profile.clone()),
+                exact: false,
+                workdir: None,
+                processes: Vec::new(),
+                routing: cached_routing_under(&cache, &root.join("profile").join(&profile)),
+            });
+        }
+    }
+
+    for (program, enabled) in [
+        ("dnscrypt", crate::runtime::dnscrypt_enabled()),
+        ("tor", crate::runtime::tor_enabled()),
+        ("operaproxy", crate::runtime::operaproxy_enabled()),
+        ("tgwsproxy", crate::runtime::tgwsproxy_enabled()),
+    ] {
+        if !enabled {
+            continue;
+        }
+        units.push(PlannedUnit {
+            program: program.to_string(),
+            profile: None,
+            exact: false,
+            workdir: None,
+            processes: Vec::new(),
+            routing: cached_routing_under(&cache, &settings::working_program_root_path(program)),
+        });
+    }
+
+    conflicts.extend(port_conflicts(&units));
+
+    let allow_loopback = iptables_port::allow_loopback_redirect_enabled();
+    let mut chains = Vec::new();
+    let mut tproxy_expected = false;
+    for unit in &units {
+        for snapshot in &unit.routing {
+            tproxy_expected |= matches!(snapshot, RoutingSnapshot::Tproxy { .. });
+            chains.extend(chains_for(snapshot, &unit.label(), allow_loopback));
+        }
+    }
+    chains.sort();
+    chains.dedup();
+
+    let ip_rules = if tproxy_expected {
+        vec![PlannedIpRule {
+            pref: iptables_tproxy::ROUTE_PREF.to_string(),
+            fwmark: iptables_tproxy::route_mask_hex(),
+            table: iptables_tproxy::ROUTE_TABLE,
+        }]
+    } else {
+        Vec::new()
+    };
+
+    Plan {
+        generated_at: now_unix(),
+        units,
+        chains,
+        networks: planned_networks(),
+        ip_rules,
+        conflicts,
+    }
+}
+
+fn port_conflicts(units: &[PlannedUnit]) -> Vec<String> {
+    let mut owners: BTreeMap<u16, BTreeSet<String>> = BTreeMap::new();
+    for unit in units.iter().filter(|u| u.exact) {
+        for snapshot in &unit.routing {
+            let port = match snapshot {
+                RoutingSnapshot::NfqV1 { queue, .. } => *queue,
+                RoutingSnapshot::NfqV2 { port, .. } => *port,
+                RoutingSnapshot::Nat { dest_port, .. } | RoutingSnapshot::Tproxy { dest_port, .. } => *dest_port,
+            };
+            owners.entry(port).or_default().insert(unit.label());
+        }
+    }
+    owners
+        .into_iter()
+        .filter(|(_, o)| o.len() > 1)
+        .map(|(port, o)| format!("port/queue {port} is used by {}", o.into_iter().collect::<Vec<_>>().join(", ")))
+        .collect()
+}
+
+/// Scoped chains a routing entry creates, named exactly as the iptables
+/// backends name them. An empty UID list removes the scoped chain, so it is not
+/// expected in that case. The IPv6 chains only exist on the iptables backend.
+fn chains_for(snapshot: &RoutingSnapshot, unit: &str, allow_loopback: bool) -> Vec<PlannedChain> {
+    let uid_file = snapshot.uid_file();
+    if !uid_file_has_uids(uid_file) {
+        return Vec::new();
+    }
+    let v6 = !nft::active();
+    let chain = |cmd: &str, table: &str, parent: &str, chain: String| PlannedChain {
+        cmd: cmd.to_string(),
+        table: table.to_string(),
+        parent: parent.to_string(),
+        chain,
+        uid_file: uid_file.to_string(),
+        unit: unit.to_string(),
+    };
+    let path = Path::new(uid_file);
+    let mangle_chains = |label: &str| {
+        let mut out = vec![chain("iptables", "mangle", "MANGLE_APP", mangle_app::scoped_chain_name(label))];
+        if v6 {
+            out.push(chain("ip6tables", "mangle", "MANGLE_APP", mangle_app::scoped_chain_name(&format!("{label}:v6"))));
+        }
+        out
+    };
+    match snapshot {
+        RoutingSnapshot::NfqV1 { mode, queue, iface, .. } => {
+            let label = iptables_v1::scope_label(mode, *queue, iface.as_deref(), Some(path));
+            mangle_chains(&label)
+        }
+        RoutingSnapshot::NfqV2 { port, .. } => {
+            let label = iptables_v2::scope_label(*port, path);
+            mangle_chains(&label)
+        }
+        RoutingSnapshot::Nat { dest_port, proto_choice, ifaces_raw, port_preference, dpi_ports, .. } => {
+            let opt = iptables_port::DpiTunnelOptions { port_preference: *port_preference, dpi_ports: dpi_ports.clone() };
+            let proto = iptables_port::ProtoChoice::from_str(proto_choice);
+            let label = iptables_port::scope_label(path, *dest_port, proto, ifaces_raw.as_deref(), &opt);
+            let mut out = vec![chain("iptables", "nat", "NAT_DPI", iptables_port::scoped_nat_chain_name(&label))];
+            if allow_loopback {
+                out.push(chain(
+                    "iptables",
+                    "nat",
+                    "NAT_DPI_LOCAL",
+                    iptables_port::scoped_nat_chain_name(&format!("local:{label}")),
+                ));
+            }
+            out
+        }
+        RoutingSnapshot::Tproxy { dest_port, proto_choice, ifaces_raw, port_preference, dpi_ports, .. } => {
+            let opt = iptables_port::DpiTunnelOptions { port_preference: *port_preference, dpi_ports: dpi_ports.clone() };
+            let proto = iptables_port::ProtoChoice::from_str(proto_choice);
+            let label = iptables_tproxy::scope_label(path, *dest_port, proto, ifaces_raw.as_deref(), &opt);
+            let mut out = vec![
+                chain("iptables", "mangle", iptables_tproxy::OUT_CHAIN, iptables_tproxy::scoped_out_chain_name(&label)),
+                chain("iptables", "mangle", iptables_tproxy::PRE_CHAIN, iptables_tproxy::scoped_pre_chain_name(&label)),
+            ];
+            if v6 {
+                out.push(chain("ip6tables", "filter", "OUTPUT", iptables_tproxy::scoped_v6_chain_name(&label)));
+            }
+            out
+        }
+    }
+}
+
+fn planned_networks() -> Vec<PlannedNetwork> {
+    let hotspot = settings::load_api_settings()
+        .ok()
+        .and_then(|st| st.hotspot_vpn_selection().map(|(p, n)| (p.to_string(), n.to_string())));
+    let claims = [
+        openvpn::enabled_tun_claims(),
+        amneziawg::enabled_tun_claims(),
+        tun2socks::enabled_tun_claims(),
+        myvpn::enabled_tun_claims(),
+        mihomo::enabled_tun_claims(),
+        mieru::enabled_tun_claims(),
+        singbox::enabled_tun_claims(),
+        hysteria2::enabled_tun_claims(),
+    ];
+    let mut out = Vec::new();
+    for (owner, tun) in claims.into_iter().flatten() {
+        let Some((program, profile)) = owner.split_once('/') else { continue; };
+        let hotspot_only = hotspot.as_ref().map(|(p, n)| p == program && n == profile).unwrap_or(false)
+            && !profile_app_list_requires_netd(program, profile);
+        out.push(PlannedNetwork {
+            program: program.to_string(),
+            profile: profile.to_string(),
+            tun,
+            netd: !hotspot_only,
+        });
+    }
+    out
+}
+
+fn profile_app_list_requires_netd(program: &str, profile: &str) -> bool {
+    let path = settings::working_program_root_path(program)
+        .join("profile")
+        .join(profile)
+        .join("app/uid/user_program");
+    !crate::programs::common::enabled_app_list_empty(&path)
+}
+
+#[derive(Debug, Default)]
+struct LiveTable {
+    chains: BTreeSet<String>,
+    /// (parent, target) -> rule arguments after `-A`.
+    hooks: BTreeMap<(String, String), Vec<Vec<String>>>,
+}
+
+/// Parse one table of `iptables-save` output into chain names and jump rules.
+fn parse_iptables_save(text: &str) -> LiveTable {
+    let mut out = LiveTable::default();
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(rest) = line.strip_prefix(':') {
+            if let Some(name) = rest.split_whitespace().next() {
+                out.chains.insert(name.to_string());
+            }
+            continue;
+        }
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.len() < 4 || tokens[0] != "-A" {
+            continue;
+        }
+        let Some(pos) = tokens.iter().rposition(|t| *t == "-j" || *t == "--jump") else { continue; };
+        let Some(target) = tokens.get(pos + 1) else { continue; };
+        out.hooks
+            .entry((tokens[1].to_string(), target.to_string()))
+            .or_default()
+            .push(tokens[1..].iter().map(|s| s.to_string()).collect());
+    }
+    out
+}
+
+fn read_live_tables() -> BTreeMap<(String, String), LiveTable> {
+    SCOPED_TABLES
+        .iter()
+        .map(|(cmd, table)| ((cmd.to_string(), table.to_string()), read_live_table(cmd, table)))
+        .collect()
+}
+
+fn read_live_table(cmd: &str, table: &str) -> LiveTable {
+    let mut live = read_iptables_table(cmd, table);
+    if cmd == "iptables" && nft::active() {
+        for (chain, jumps) in nft::live_chains() {
+            if nft::table_of(&chain) != table {
+                continue;
+            }
+            for target in jumps {
+                live.hooks.entry((chain.clone(), target)).or_default();
+            }
+            live.chains.insert(chain);
+        }
+    }
+    live
+}
+
+fn read_iptables_table(cmd: &str, table: &str) -> LiveTable {
+    let save = format!("{cmd}-save");
+    match shell::run_timeout(&save, &["-t", table], Capture::Stdout, IPT_CMD_TIMEOUT) {
+        Ok((0, out)) => parse_iptables_save(&out),
+        Ok((code, _)) => {
+            log::warn!("plan: {save} -t {table} exited with {code}");
+            LiveTable::default()
+        }
+        Err(e) => {
+            log::warn!("plan: {save} -t {table} failed: {e:#}");
+            LiveTable::default()
+        }
+    }
+}
+
+/// Processes launched from the module bin directory, with their argv and cwd.
+fn live_module_processes() -> Vec<LiveProcess> {
+    let Ok(rd) = fs::read_dir("/proc") else { return Vec::new(); };
+    let mut out = Vec::new();
+    for entry in rd.flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|s| s.parse::<i32>().ok()) else { continue; };
+        if pid <= 1 {
+            continue;
+        }
+        let Ok(raw) = fs::read(entry.path().join("cmdline")) else { continue; };
+        let argv: Vec<String> = raw
+            .split(|b| *b == 0)
+            .filter(|s| !s.is_empty())
+            .map(|s| String::from_utf8_lossy(s).into_owned())
+            .collect();
+        if !argv.first().map(|a| a.starts_with(BIN_DIR)).unwrap_or(false) {
+            continue;
+        }
+        let cwd = fs::read_link(entry.path().join("cwd"))
+            .map(|p| p.display().to_string())
+            .unwrap_or_default();
+        out.push(LiveProcess { pid, argv, cwd });
+    }
+    out.sort_by_key(|p| p.pid);
+    out
+}
+
+fn program_running(program: &str, r: Option<&stats::StatusReport>) -> bool {
+    let count = |f: fn(&stats::StatusReport) -> u32| r.map(f).unwrap_or(0) > 0;
+    match program {
+        "singbox" => count(|r| r.sing_box.count) || singbox::is_running(),
+        "hysteria2" => count(|r| r.hysteria2.count) || hysteria2::is_running(),
+        "wireproxy" => count(|r| r.wireproxy.count),
+        "myproxy" => count(|r| r.myproxy.count),
+        "myprogram" => count(|r| r.myprogram.count),
+        "openvpn" => count(|r| r.openvpn.count) || openvpn::is_running(),
+        "amneziawg" => count(|r| r.amneziawg.count) || amneziawg::is_running(),
+        "tun2socks" => count(|r| r.tun2socks.count) || tun2socks::is_running(),
+        "mihomo" => count(|r| r.mihomo.count) || mihomo::is_running(),
+        "mieru" => count(|r| r.mieru.count) || mieru::is_running(),
+        "myvpn" => netd_owner_applied("myvpn", None),
+        "dnscrypt" => count(|r| r.dnscrypt.count),
+        "tor" => count(|r| r.tor.count),
+        "operaproxy" => count(|r| r.opera.opera.count),
+        "tgwsproxy" => tgwsproxy::is_running(),
+        _ => false,
+    }
+}
+
+fn netd_owner_applied(program: &str, profile: Option<&str>) -> bool {
+    crate::vpn_netd::read_applied_snapshot()
+        .map(|s| {
+            s.profiles
+                .iter()
+                .any(|p| p.owner_program == program && profile.map(|n| p.profile == n).unwrap_or(true))
+        })
+        .unwrap_or(false)
+}
+
+fn policy_rule_present(rules: &str, rule: &PlannedIpRule) -> bool {
+    let table = format!("lookup {}", rule.table);
+    let pref = format!("{}:", rule.pref);
+    rules
+        .lines()
+        .any(|l| l.trim_start().starts_with(&pref) && l.contains("fwmark") && l.contains(&table))
+}
+
+/// Compare the plan with the live system. Read-only.
+pub fn diff(plan: &Plan) -> PlanDiff {
+    let mut d = PlanDiff { conflicts: plan.conflicts.clone(), ..PlanDiff::default() };
+    let live = live_module_processes();
+    let report = stats::collect_status().ok();
+
+    // Exact units: ownership by cwd, identity by argv.
+    let mut planned_dirs: BTreeSet<String> = BTreeSet::new();
+    for unit in plan.units.iter().filter(|u| u.exact) {
+        let Some(workdir) = unit.workdir.as_deref() else { continue; };
+        planned_dirs.insert(workdir.to_string());
+        let owned: Vec<&LiveProcess> = live.iter().filter(|p| p.cwd == workdir).collect();
+        if owned.is_empty() {
+            d.missing_units.push(unit.label());
+            continue;
+        }
+        let all_match = unit.processes.iter().all(|want| {
+            owned
+                .iter()
+                .any(|p| p.argv.first().map(String::as_str) == Some(want.binary.as_str()) && p.argv[1..] == want.argv[..])
+        }) && owned.len() == unit.processes.len();
+        if !all_match {
+            d.changed_units.push(unit.label());
+        }
+    }
+    for (program, _, _) in EXACT_PROGRAMS {
+        let root = format!("{}/", settings::working_program_root_path(program).display());
+        for p in &live {
+            let Some(profile) = p.cwd.strip_prefix(&root) else { continue; };
+            if profile.contains('/') || planned_dirs.contains(&p.cwd) {
+                continue;
+            }
+            d.extra_processes.push(ExtraProcess {
+                unit: format!("{program}/{profile}"),
+                pid: p.pid,
+                argv: p.argv.clone(),
+            });
+        }
+    }
+
+    // Other units: program-level liveness.
+    for unit in plan.units.iter().filter(|u| !u.exact) {
+        if !program_running(&unit.program, report.as_ref()) {
+            d.missing_units.push(unit.label());
+        }
+    }
+
+    // Chains: declared and hooked from the expected parent. IPv6 is best
+    // effort, so its chains are only expected where ip6tables is usable.
+    let tables = read_live_tables();
+    let wanted: BTreeSet<(String, String, String)> =
+        plan.chains.iter().map(|c| (c.cmd.clone(), c.table.clone(), c.chain.clone())).collect();
+    for c in &plan.chains {
+        let live = tables.get(&(c.cmd.clone(), c.table.clone()));
+        if c.cmd == "ip6tables" && live.is_none_or(|t| t.chains.is_empty()) {
+            continue;
+        }
+        let present = live
+            .map(|t| t.chains.contains(&c.chain) && t.hooks.contains_key(&(c.parent.clone(), c.chain.clone())))
+            .unwrap_or(false);
+        if !present {
+            d.missing_chains.push(c.clone());
+        }
+    }
+    for ((cmd, table), t) in &tables {
+        for chain in &t.chains {
+            if SCOPED_CHAIN_PREFIXES.iter().any(|p| chain.starts_with(p))
+                && !wanted.contains(&(cmd.clone(), table.clone(), chain.clone()))
+            {
+                d.stale_chains.push(StaleChain { cmd: cmd.clone(), table: table.clone(), chain: chain.clone() });
+            }
+        }
+    }
+
+    for n in &plan.networks {
+        let link_up = Path::new("/sys/class/net").join(&n.tun).exists();
+        if !link_up || (n.netd && !netd_owner_applied(&n.program, Some(&n.profile))) {
+            d.missing_networks.push(n.clone());
+        }
+    }
+
+    if !plan.ip_rules.is_empty() {
+        let rules = shell::run_timeout("ip", &["rule", "show"], Capture::Stdout, IP_CMD_TIMEOUT)
+            .map(|(_, out)| out)
+            .unwrap_or_default();
+        for r in &plan.ip_rules {
+            if !policy_rule_present(&rules, r) {
+                d.missing_ip_rules.push(r.clone());
+            }
+        }
+    }
+
+    d.in_sync = d.missing_units.is_empty()
+        && d.changed_units.is_empty()
+        && d.extra_processes.is_empty()
+        && d.missing_chains.is_empty()
+        && d.stale_chains.is_empty()
+        && d.missing_networks.is_empty()
+        && d.missing_ip_rules.is_empty()
+        && d.conflicts.is_empty();
+    d
+}
+
+fn unit_by_label<'a>(plan: &'a Plan, label: &str) -> Option<&'a PlannedUnit> {
+    plan.units.iter().find(|u| u.label() == label)
+}
+
+/// Decide up front whether the delta can be applied without a full start, so
+/// that nothing is touched when it cannot.
+fn full_start_reasons(plan: &Plan, d: &PlanDiff) -> Vec<String> {
+    let mut reasons: Vec<String> = d.conflicts.iter().map(|c| format!("conflict: {c}")).collect();
+    for n in &d.missing_networks {
+        reasons.push(format!("network {}/{} ({}) is not applied", n.program, n.profile, n.tun));
+    }
+    let mut standalone_needed: BTreeSet<&str> = BTreeSet::new();
+    for label in &d.missing_units {
+        let Some(unit) = unit_by_label(plan, label) else { continue; };
+        if unit.exact {
+            continue;
+        }
+        let startable = STANDALONE_STARTERS.iter().any(|(p, _)| *p == unit.program);
+        if startable {
+            standalone_needed.insert(unit.program.as_str());
+        } else {
+            reasons.push(format!("{label} is not running and can only be started by a full start"));
+        }
+    }
+    // Standalone starters launch every enabled profile of the program, so they
+    // are only safe when none of its profiles is running.
+    for program in standalone_needed {
+        let all_missing = plan
+            .units
+            .iter()
+            .filter(|u| u.program == program)
+            .all(|u| d.missing_units.contains(&u.label()));
+        if !all_missing {
+            reasons.push(format!("{program} is partially running"));
+        }
+    }
+    reasons
+}
+
+fn remove_stale_chain(cmd: &str, table: &str, chain: &str, live: &LiveTable) -> Result<()> {
+    if cmd == "iptables" && nft::owns(chain) {
+        return nft::remove_scope(chain);
+    }
+    let _guard = xtables_lock::lock();
+    for ((_, target), rules) in &live.hooks {
+        if target != chain {
+            continue;
+        }
+        for rule in rules {
+            let mut args: Vec<String> = vec!["-w".into(), "5".into(), "-t".into(), table.into(), "-D".into()];
+            args.extend(rule.iter().cloned());
+            let _ = xtables_lock::runv_timeout_retry(cmd, &args, Capture::None, IPT_CMD_TIMEOUT);
+        }
+    }
+    let _ = xtables_lock::run_timeout_retry(cmd, &["-w", "5", "-t", table, "-F", chain], Capture::None, IPT_CMD_TIMEOUT);
+    let (rc, out) = xtables_lock::run_timeout_retry(cmd, &["-w", "5", "-t", table, "-X", chain], Capture::Both, IPT_CMD_TIMEOUT)?;
+    if rc != 0 {
+        bail!("delete chain {cmd} {table}/{chain} failed: {}", out.trim());
+    }
+    Ok(())
+}
+
+fn start_exact_unit(program: &str, profile: &str) -> Result<()> {
+    match EXACT_PROGRAMS.iter().find(|(p, _, _)| *p == program) {
+        Some((_, _, start)) => start(profile),
+        None => bail!("no profile starter for {program}"),
+    }
+}
+
+/// Converge the live system towards the plan.
+///
+/// Only the difference is touched: extra/changed processes are stopped, stale
+/// scoped chains removed, missing profiles started and missing chains of
+/// running profiles re-applied from the routing cache. When the difference
+/// includes something only a full start can fix, nothing is changed and the
+/// report says so.
+pub fn apply(plan: &Plan, d: &PlanDiff) -> ApplyReport {
+    let mut report = ApplyReport { reasons: full_start_reasons(plan, d), ..ApplyReport::default() };
+    if !report.reasons.is_empty() {
+        report.requires_full_start = true;
+        return report;
+    }
+
+    let live = live_module_processes();
+
+    // 1) processes of disabled profiles and of profiles whose argv changed
+    let extra: Vec<i32> = d.extra_processes.iter().map(|p| p.pid).collect();
+    if !extra.is_empty() {
+        let _ = crate::stop::kill_pids_with_escalation("plan: extra processes", &extra);
+        report.actions.push(format!("stopped {} extra process(es)", extra.len()));
+    }
+    for label in &d.changed_units {
+        let Some(workdir) = unit_by_label(plan, label).and_then(|u| u.workdir.as_deref()) else { continue; };
+        let pids: Vec<i32> = live.iter().filter(|p| p.cwd == workdir).map(|p| p.pid).collect();
+        let _ = crate::stop::kill_pids_with_escalation(&format!("plan: {label}"), &pids);
+        report.actions.push(format!("stopped changed {label}"));
+    }
+
+    // 2) stale scoped chains
+    if !d.stale_chains.is_empty() {
+        let tables = read_live_tables();
+        for c in &d.stale_chains {
+            let Some(live_table) = tables.get(&(c.cmd.clone(), c.table.clone())) else { continue; };
+            match remove_stale_chain(&c.cmd, &c.table, &c.chain, live_table) {
+                Ok(()) => report.actions.push(format!("removed chain {} {}/{}", c.cmd, c.table, c.chain)),
+                Err(e) => report.failures.push(format!("{e:#}")),
+            }
+        }
+    }
+
+    // 3) missing and changed units
+    let mut restarted: BTreeSet<String> = BTreeSet::new();
+    let mut standalone: BTreeSet<String> = BTreeSet::new();
+    for label in d.missing_units.iter().chain(d.changed_units.iter()) {
+        let Some(unit) = unit_by_label(plan, label) else { continue; };
+        if !unit.exact {
+            standalone.insert(unit.program.clone());
+            continue;
+        }
+        let Some(profile) = unit.profile.as_deref() else { continue; };
+        match start_exact_unit(&unit.program, profile) {
+            Ok(()) => report.actions.push(format!("started {label}")),
+            Err(e) => report.failures.push(format!("{label}: {e:#}")),
+        }
+        restarted.insert(label.clone());
+    }
+    for program in standalone {
+        let Some((_, start)) = STANDALONE_STARTERS.iter().find(|(p, _)| *p == program) else { continue; };
+        match start() {
+            Ok(()) => report.actions.push(format!("started {program}")),
+            Err(e) => report.failures.push(format!("{program}: {e:#}")),
+        }
+        for unit in plan.units.iter().filter(|u| u.program == program) {
+            restarted.insert(unit.label());
+        }
+    }
+
+    // 4) missing chains of profiles that kept running
+    let mut refreshed: BTreeSet<&str> = BTreeSet::new();
+    for c in &d.missing_chains {
+        if restarted.contains(&c.unit) || !refreshed.insert(c.uid_file.as_str()) {
+            continue;
+        }
+        match crate::runtime_refresh::refresh_routing_by_uid_file(Path::new(&c.uid_file)) {
+            Ok(crate::runtime_refresh::RefreshOutcome::Applied) => {
+                report.actions.push(format!("re-applied routing {}", c.uid_file));
+            }
+            Ok(crate::runtime_refresh::RefreshOutcome::NoActiveRuntime) => {
+                // Not in the routing cache: restart the owning exact profile so it
+                // re-applies its own rules.
+                let Some(unit) = unit_by_label(plan, &c.unit).filter(|u| u.exact) else {
+                    report.failures.push(format!("{}: no cached routing for {}", c.unit, c.uid_file));
+                    continue;
+                };
+                if let Some(workdir) = unit.workdir.as_deref() {
+                    let pids: Vec<i32> = live.iter().filter(|p| p.cwd == workdir).map(|p| p.pid).collect();
+                    let _ = crate::stop::kill_pids_with_escalation(&format!("plan: {}", c.unit), &pids);
+                }
+                match start_exact_unit(&unit.program, unit.profile.as_deref().unwrap_or_default()) {
+                    Ok(()) => report.actions.push(format!("restarted {}", c.unit)),
+                    Err(e) => report.failures.push(format!("{}: {e:#}", c.unit)),
+                }
+                restarted.insert(c.unit.clone());
+            }
+            Err(e) => report.failures.push(format!("{}: {e:#}", c.uid_file)),
+        }
+    }
+
+    // 5) TPROXY policy rule: any TPROXY re-apply restores it.
+    if !d.missing_ip_rules.is_empty() {
+        let tproxy_uid_file = plan
+            .units
+            .iter()
+            .flat_map(|u| u.routing.iter())
+            .find(|s| matches!(s, RoutingSnapshot::Tproxy { .. }))
+            .map(|s| PathBuf::from(s.uid_file()));
+        if let Some(path) = tproxy_uid_file {
+            match crate::runtime_refresh::refresh_routing_by_uid_file(&path) {
+                Ok(_) => report.actions.push("re-applied TPROXY policy rule".to_string()),
+                Err(e) => report.failures.push(format!("TPROXY policy rule: {e:#}")),
+            }
+        }
+    }
+
+    report
+}
+
+/// Programs whose single profile can be started on its own; every other
+/// profile program needs a full start.
+const PROFILE_STARTERS: [(&str, StartProfileFn); 2] = [
+    ("singbox", singbox::start_construction_profile),
+    ("wireproxy", wireproxy::start_construction_profile),
+];
+
+/// Engines that re-read their configuration/lists on SIGHUP, with the binary
+/// name the signal is sent to.
+const SIGHUP_RELOAD: [(&str, &str); 3] = [("nfqws", "nfqws"), ("nfqws2", "nfqws2"), ("singbox", "sing-box")];
+
+/// Directory/starter key of an API program id (`/api/programs/sing-box/...`).
+pub fn program_key(id: &str) -> &str {
+    match id {
+        "sing-box" => "singbox",
+        other => other,
+    }
+}
+
+/// Name the program passes to t2s as `--program`.
+fn t2s_program_label(program: &str) -> &str {
+    match program {
+        "singbox" => "sing-box",
+        other => other,
+    }
+}
+
+/// Live processes that belong to one profile: by cwd for exact programs, by a
+/// path under the profile directory or by the t2s `--program/--profile` pair
+/// for the others.
+fn profile_processes(program: &str, profile: &str, live: &[LiveProcess]) -> Vec<LiveProcess> {
+    let root = settings::working_program_root_path(program);
+    let exact = EXACT_PROGRAMS.iter().any(|(p, _, _)| *p == program);
+    let dir = if exact { root.join(profile) } else { root.join("profile").join(profile) };
+    let dir_s = dir.display().to_string();
+    let prefix = format!("{dir_s}/");
+    let t2s_label = t2s_program_label(program);
+    live.iter()
+        .filter(|p| {
+            if exact {
+                return p.cwd == dir_s;
+            }
+            let t2s_owned = p.argv.windows(2).any(|w| w[0] == "--program" && w[1] == t2s_label)
+                && p.argv.windows(2).any(|w| w[0] == "--profile" && w[1] == profile);
+            t2s_owned || p.cwd == dir_s || p.argv.iter().any(|a| a == &dir_s || a.starts_with(&prefix))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Routing the profile applied: planned for exact programs, recorded by
+/// runtime_refresh for the others.
+fn profile_routing(program: &str, profile: &str) -> Vec<RoutingSnapshot> {
+    if let Some((_, plan_profile, _)) = EXACT_PROGRAMS.iter().find(|(p, _, _)| *p == program) {
+        return plan_profile(profile).ok().flatten().map(|p| p.routing).unwrap_or_default();
+    }
+    let dir = settings::working_program_root_path(program).join("profile").join(profile);
+    cached_routing_under(&crate::runtime_refresh::cached_routing(), &dir)
+}
+
+/// Stop one profile, drop only its scoped chains and start it again. Every
+/// other program, profile, netd network and hotspot rule is left alone.
+pub fn restart_profile(program: &str, profile: &str) -> Result<ApplyReport> {
+    let refuse = |msg: String| -> Result<ApplyReport> { Err(NotRestartable(msg).into()) };
+    let start: StartProfileFn = match EXACT_PROGRAMS.iter().find(|(p, _, _)| *p == program) {
+        Some((_, plan_profile, start)) => {
+            if plan_profile(profile)?.is_none() {
+                return refuse(format!("profile {program}/{profile} has nothing to start (no resolved apps)"));
+            }
+            *start
+        }
+        None => match PROFILE_STARTERS.iter().find(|(p, _)| *p == program) {
+            Some((_, start)) => *start,
+            None => return refuse(format!("{program} profiles can only be restarted by a full restart")),
+        },
+    };
+    if !enabled_profiles(program).iter().any(|p| p == profile) {
+        return refuse(format!("profile {program}/{profile} is not enabled"));
+    }
+    // VPN-mode sing-box profiles own a netd network; only a full restart
+    // brings one back.
+    if program == "singbox" && !singbox::read_setting(profile)?.mode.is_t2s() {
+        return refuse(format!("profile {program}/{profile} runs in VPN mode; use a full restart"));
+    }
+    let label = format!("{program}/{profile}");
+    let mut report = ApplyReport::default();
+
+    let pids: Vec<i32> = profile_processes(program, profile, &live_module_processes()).iter().map(|p| p.pid).collect();
+    if !pids.is_empty() {
+        let _ = crate::stop::kill_pids_with_escalation(&format!("restart {label}"), &pids);
+        report.actions.push(format!("stopped {} process(es)", pids.len()));
+    }
+
+    let allow_loopback = iptables_port::allow_loopback_redirect_enabled();
+    let chains: BTreeSet<(String, String, String)> = profile_routing(program, profile)
+        .iter()
+        .flat_map(|s| chains_for(s, &label, allow_loopback))
+        .map(|c| (c.cmd, c.table, c.chain))
+        .collect();
+    let mut tables: BTreeMap<(String, String), LiveTable> = BTreeMap::new();
+    for (cmd, table, chain) in &chains {
+        let live_table = tables
+            .entry((cmd.clone(), table.clone()))
+            .or_insert_with(|| read_live_table(cmd, table));
+        if !live_table.chains.contains(chain) {
+            continue;
+        }
+        match remove_stale_chain(cmd, table, chain, live_table) {
+            Ok(()) => report.actions.push(format!("removed chain {cmd} {table}/{chain}")),
+            Err(e) => report.failures.push(format!("{e:#}")),
+        }
+    }
+
+    match start(profile) {
+        Ok(()) => report.actions.push(format!("started {label}")),
+        Err(e) => report.failures.push(format!("{label}: {e:#}")),
+    }
+    for a in &report.actions {
+        log::info!("restart {label}: {a}");
+    }
+    for f in &report.failures {
+        log::warn!("restart {label}: {f}");
+    }
+    if profile_processes(program, profile, &live_module_processes()).is_empty() {
+        match report.failures.last() {
+            Some(f) => bail!("{label} was stopped but nothing was started: {f}"),
+            None => bail!("{label} was stopped but nothing was started"),
+        }
+    }
+    Ok(report)
+}
+
+/// Ask a running profile to re-read its configuration (SIGHUP) without
+/// dropping connections or touching iptables.
+pub fn reload_profile(program: &str, profile: &str) -> Result<ApplyReport> {
+    let Some((_, binary)) = SIGHUP_RELOAD.iter().find(|(p, _)| *p == program) else {
+        bail!("{program} does not support reload; use restart");
+    };
+    let pids: Vec<i32> = profile_processes(program, profile, &live_module_processes())
+        .into_iter()
+        .filter(|p| {
+            p.argv
+                .first()
+                .and_then(|a| Path::new(a).file_name())
+                .map(|n| n == *binary)
+                .unwrap_or(false)
+        })
+        .map(|p| p.pid)
+        .collect();
+    if pids.is_empty() {
+        bail!("{program}/{profile} is not running");
+    }
+    let mut report = ApplyReport::default();
+    for pid in pids {
+        if unsafe { libc::kill(pid, libc::SIGHUP) } == 0 {
+            report.actions.push(format!("sent SIGHUP to {binary} pid={pid}"));
+        } else {
+            report.failures.push(format!("SIGHUP {binary} pid={pid}: {}", std::io::Error::last_os_error()));
+        }
+    }
+    Ok(report)
+}
+
+/// Plan, diff and apply in one step; used by runtime start and the API.
+pub fn reconcile() -> (ApplyReport, PlanDiff) {
+    let plan = build();
+    let before = diff(&plan);
+    if before.in_sync {
+        return (ApplyReport::default(), before);
+    }
+    log::info!(
+        "plan: missing={:?} changed={:?} extra={} missing_chains={} stale_chains={} networks={} ip_rules={} conflicts={:?}",
+        before.missing_units,
+        before.changed_units,
+        before.extra_processes.len(),
+        before.missing_chains.len(),
+        before.stale_chains.len(),
+        before.missing_networks.len(),
+        before.missing_ip_rules.len(),
+        before.conflicts,
+    );
+    let report = apply(&plan, &before);
+    for a in &report.actions {
+        log::info!("plan: {a}");
+    }
+    for f in &report.failures {
+        log::warn!("plan: {f}");
+    }
+    if report.requires_full_start {
+        log::info!("plan: delta apply not possible: {}", report.reasons.join("; "));
+        return (report, before);
+    }
+    (report, diff(&build()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_chains_and_hooks_from_iptables_save() {
+        let text = "# Generated by iptables-save\n*nat\n:PREROUTING ACCEPT [0:0]\n:OUTPUT ACCEPT [0:0]\n:NAT_DPI - [0:0]\n:ZDTN_00000000000000aa - [0:0]\n-A OUTPUT -j NAT_DPI\n-A NAT_DPI -j ZDTN_00000000000000aa\n-A ZDTN_00000000000000aa -p tcp -m owner --uid-owner 10123 -j DNAT --to-destination 127.0.0.1:1080\nCOMMIT\n";
+        let t = parse_iptables_save(text);
+        assert!(t.chains.contains("ZDTN_00000000000000aa"));
+        assert!(t.hooks.contains_key(&("NAT_DPI".to_string(), "ZDTN_00000000000000aa".to_string())));
+        assert!(t.hooks.contains_key(&("OUTPUT".to_string(), "NAT_DPI".to_string())));
+        assert_eq!(
+            t.hooks[&("NAT_DPI".to_string(), "ZDTN_00000000000000aa".to_string())][0],
+            vec!["NAT_DPI", "-j", "ZDTN_00000000000000aa"]
+        );
+    }
+
+    #[test]
+    fn api_program_ids_map_to_starter_keys() {
+        assert_eq!(program_key("sing-box"), "singbox");
+        assert_eq!(program_key("wireproxy"), "wireproxy");
+        assert!(PROFILE_STARTERS.iter().any(|(p, _)| *p == program_key("sing-box")));
+        assert!(SIGHUP_RELOAD.iter().any(|(p, _)| *p == program_key("sing-box")));
+        assert_eq!(t2s_program_label(program_key("sing-box")), "sing-box");
+    }
+
+    #[test]
+    fn t2s_process_is_matched_by_program_and_profile() {
+        let live = vec![
+            LiveProcess {
+                pid: 10,
+                argv: ["/data/adb/modules/ZDT-D/bin/t2s", "--program", "sing-box", "--profile", "a", "--scope", "profile/sing-box/a"]
+                    .map(String::from)
+                    .to_vec(),
+                cwd: "/".to_string(),
+            },
+            LiveProcess {
+                pid: 11,
+                argv: ["/data/adb/modules/ZDT-D/bin/t2s", "--program", "sing-box", "--profile", "ab"].map(String::from).to_vec(),
+                cwd: "/".to_string(),
+            },
+        ];
+        let pids: Vec<i32> = profile_processes("singbox", "a", &live).iter().map(|p| p.pid).collect();
+        assert_eq!(pids, vec![10]);
+    }
+
+    #[test]
+    fn policy_rule_matches_unpadded_fwmark() {
+        let rule = PlannedIpRule { pref: "100".to_string(), fwmark: "0x01000000/0x01000000".to_string(), table: 787 };
+        assert!(policy_rule_present("0:\tfrom all lookup local\n100:\tfrom all fwmark 0x1000000/0x1000000 lookup 787\n", &rule));
+        assert!(!policy_rule_present("0:\tfrom all lookup local\n", &rule));
+    }
+}
@@ -179,6 +179,11 @@ pub fn collect_used_ports_for_conflict_check_excluding_programs(
     Ok(used)
 }
 
+/// Every file [`normalize_ports`] may rewrite.
+pub fn adjustable_port_files() -> Result<Vec<PathBuf>> {
+    Ok(collect_adjustable_ports()?.into_iter().map(|e| e.port_path).collect())
+}
+
 fn collect_adjustable_ports() -> Result<Vec<PortEntry>> {
     let mut out = Vec::new();
 
@@ -16,7 +16,7 @@ use std::{
 
 use crate::{
     android::pkg_uid,
-    android_dns,
+    dns,
     shell::{self, Capture},
     vpn_netd::VpnNetdProfile,
     vpn_tether::VpnTetherProfile,
@@ -1023,6 +1023,7 @@ fn spawn_amneziawg(plan: &ProfilePlan) -> Result<()> {
     let proc_path = PathBuf::from("/proc").join(child.id().to_string());
     if !proc_path.is_dir() {
         warn!("amneziawg: profile={} pid={} exited quickly; check log {}", plan.name, child.id(), plan.go_log_path.display());
+        crate::events::program_exited("amneziawg", Some(&plan.name), child.id());
     }
     Ok(())
 }
@@ -1106,7 +1107,7 @@ fn resolve_endpoint_lines(raw: &str) -> Result<String> {
                     out.push(line.to_string());
                     continue;
                 }
-                let ip = android_dns::resolve_ipv4(&host)
+                let ip = dns::resolve_ipv4(&host)
                     .ok_or_else(|| anyhow::anyhow!("cannot resolve Endpoint host {host} to IPv4"))?;
                 out.push(format!("Endpoint = {ip}:{port}"));
                 continue;
@@ -1150,7 +1151,7 @@ fn collect_endpoint_escape_ips(plan: &ProfilePlan) -> Vec<String> {
         if is_ipv4(&host) {
             ips.push(host);
         } else if plan.setting.endpoint_resolve {
-            match android_dns::resolve_ipv4(&host) {
+            match dns::resolve_ipv4(&host) {
                 Some(ip) => ips.push(ip),
                 None => warn!(
                     "amneziawg: profile={} cannot resolve Endpoint host for endpoint escape: {}",
@@ -15,6 +15,8 @@ use std::os::unix::process::CommandExt;
 use crate::android::pkg_uid::{self, Mode, Sha256Tracker};
 use crate::settings;
 use crate::iptables::iptables_port::{self, DpiTunnelOptions, ProtoChoice};
+use crate::plan::{PlannedProcess, ProfilePlan};
+use crate::runtime_refresh::RoutingSnapshot;
 
 const MODULE_DIR: &str = "/data/adb/modules/ZDT-D";
 const WORKING_DIR: &str = "/data/adb/modules/ZDT-D/working_folder";
@@ -59,6 +61,49 @@ pub fn start_active_profiles() -> Result<()> {
     Ok(())
 }
 
+/// Start a single enabled profile outside of the full start sequence.
+pub fn start_profile_by_name(profile_name: &str) -> Result<()> {
+    start_profile(profile_name, &Sha256Tracker::new(SHA_FLAG_FILE))
+}
+
+/// Desired process and routing of one profile, derived the same way
+/// `start_profile` launches it. Uses the already resolved app list and does
+/// not spawn or touch iptables. `None` means the profile would be skipped.
+pub fn plan_profile(profile_name: &str) -> Result<Option<ProfilePlan>> {
+    let profile_dir = Path::new(BYEDPI_ROOT).join(profile_name);
+    let port_path = profile_dir.join("port.json");
+    let port_cfg = read_json::<PortJson>(&port_path)
+        .with_context(|| format!("read {}", port_path.display()))?;
+
+    let in_user = profile_dir.join("app/uid/user_program");
+    let out_user = profile_dir.join("app/out/user_program");
+    let resolved = count_valid_uid_pairs(&out_user).unwrap_or(0);
+    if resolved == 0 && !pkg_uid::file_has_launch_marker(&in_user).unwrap_or(false) {
+        return Ok(None);
+    }
+
+    let config_path = profile_dir.join("config/config.txt");
+    let raw = fs::read_to_string(&config_path)
+        .with_context(|| format!("read {}", config_path.display()))?;
+    let bin = find_byedpi_bin()?;
+
+    let mut argv: Vec<String> = ["-i", "127.0.0.1", "-p"].iter().map(|s| s.to_string()).collect();
+    argv.push(port_cfg.port.to_string());
+    argv.extend(["-x", "2", "-E"].iter().map(|s| s.to_string()));
+    argv.extend(normalize_config_args(&raw));
+
+    Ok(Some(ProfilePlan {
+        processes: vec![PlannedProcess::new(bin.display().to_string(), argv)],
+        routing: vec![RoutingSnapshot::nat(
+            &out_user,
+            port_cfg.port,
+            ProtoChoice::TcpUdp,
+            None,
+            &DpiTunnelOptions { port_preference: 1, ..DpiTunnelOptions::default() },
+        )],
+    }))
+}
+
 fn start_profile(profile_name: &str, tracker: &Sha256Tracker) -> Result<()> {
     let profile_dir = Path::new(BYEDPI_ROOT).join(profile_name);
     ensure_dir(profile_dir.to_string_lossy().as_ref())?;
@@ -102,24 +147,29 @@ fn start_profile(profile_name: &str, tracker: &Sha256Tracker) -> Result<()> {
 
     let bin = find_byedpi_bin().with_context(|| "byedpi binary not found in /data/adb/modules/ZDT-D/bin")?;
     crate::logging::user_info(&format!("byedpi[{profile_name}]: запуск"));
-    spawn_byedpi(&profile_dir, &bin, port_cfg.port, &config_args, &log_path)?;
+    let pid = spawn_byedpi(&profile_dir, &bin, port_cfg.port, &config_args, &log_path)?;
 
     crate::logging::user_info(&format!("byedpi[{profile_name}]: iptables"));
     // Apply iptables_port: uid_file, port, proto=tcp_udp, ifaces=None
-    iptables_port::apply(
-        &out_user,
-        port_cfg.port,
-        ProtoChoice::TcpUdp,
-        None,
-        DpiTunnelOptions { port_preference: 1, ..DpiTunnelOptions::default() },
-    )?;
+    let opt = DpiTunnelOptions { port_preference: 1, ..DpiTunnelOptions::default() };
+    let scope = iptables_port::scope_label(&out_user, port_cfg.port, ProtoChoice::TcpUdp, None, &opt);
+    iptables_port::apply(&out_user, port_cfg.port, ProtoChoice::TcpUdp, None, opt)?;
+
+    let port = port_cfg.port;
+    crate::supervisor::watch(
+        "byedpi",
+        Some(profile_name),
+        pid,
+        vec![scope],
+        Box::new(move || spawn_byedpi(&profile_dir, &bin, port, &config_args, &log_path)),
+    );
 
     info!("byedpi profile started: {} port={}", profile_name, port_cfg.port);
     Ok(())
 }
 
 
-fn spawn_byedpi(workdir: &Path, bin: &Path, port: u16, extra_args: &[String], log_path: &Path) -> Result<()> {
+fn spawn_byedpi(workdir: &Path, bin: &Path, port: u16, extra_args: &[String], log_path: &Path) -> Result<u32> {
     // ciadpi-zdt -i 127.0.0.1 -p "$DPI_PORT" -x 2 -E "$@"
     let port_s = port.to_string();
 
@@ -163,7 +213,7 @@ fn spawn_byedpi(workdir: &Path, bin: &Path, port: u16, extra_args: &[String], lo
         info!("byedpi pid={} is not running after spawn (check log {})", pid, log_path.display());
     }
 
-    Ok(())
+    Ok(pid)
 }
 
 fn find_byedpi_bin() -> Result<PathBuf> {
@@ -107,6 +107,14 @@ pub fn ensure_d2s_config_exists() -> Result<()> {
     }
 }
 
+/// d2s refuses a control listener without a token. It gets its own random
+/// one through `--control-token-file`, which overrides any `control_token`
+/// in d2s.toml; the user's file is never rewritten.
+fn ensure_d2s_control_token() -> Result<std::path::PathBuf> {
+    crate::settings::read_or_create_d2s_control_token()?;
+    Ok(crate::settings::d2s_control_token_path())
+}
+
 pub fn active_listen_port() -> Result<Option<u16>> {
     ensure_dir(MODULE_DIR)?;
     ensure_dir(WORKING_DIR)?;
@@ -226,6 +234,7 @@ pub fn start_if_enabled() -> Result<()> {
         DnscryptWaitResult::Ready => {}
         DnscryptWaitResult::Exited(status) => {
             warn!("dnscrypt exited during startup with status {status}");
+            crate::events::program_exited("dnscrypt", None, dnscrypt_child.id());
             crate::logging::user_error("DNSCrypt: ошибка запуска — ожидание готовности прекращено");
             stop_started_d2s(&mut d2s_child);
             return Ok(());
@@ -250,12 +259,43 @@ pub fn start_if_enabled() -> Result<()> {
         return Ok(());
     }
 
+    supervise(toml_path, listen_port, &dnscrypt_child, d2s_child.as_ref());
     info!("dnscrypt started and iptables rules applied (listen port={})", listen_port);
     Ok(())
 }
 
+/// Hand the running dnscrypt (and its d2s front, if any) to the supervisor.
+fn supervise(toml_path: &Path, listen_port: u16, dnscrypt: &Child, d2s: Option<&Child>) {
+    let toml = toml_path.to_path_buf();
+    crate::supervisor::watch(
+        "dnscrypt",
+        None,
+        dnscrypt.id(),
+        Vec::new(),
+        Box::new(move || {
+            spawn_dnscrypt(&toml, listen_port)?
+                .map(|child| child.id())
+                .ok_or_else(|| anyhow::anyhow!("dnscrypt binary missing"))
+        }),
+    );
+    let Some(d2s) = d2s else { return; };
+    let toml = toml_path.to_path_buf();
+    crate::supervisor::watch(
+        "d2s",
+        None,
+        d2s.id(),
+        Vec::new(),
+        Box::new(move || {
+            let listener = parse_active_d2s_listener(&toml)?
+                .ok_or_else(|| anyhow::anyhow!("d2s listener no longer configured"))?;
+            spawn_d2s(&toml, listener).map(|child| child.id())
+        }),
+    );
+}
+
 fn spawn_d2s(dnscrypt_toml: &Path, listener: SocketAddr) -> Result<Child> {
     ensure_d2s_config_exists()?;
+    let control_token = ensure_d2s_control_token()?;
 
     let bin = Path::new(BIN_DIR).join("d2s");
     if !bin.is_file() {
@@ -282,6 +322,8 @@ fn spawn_d2s(dnscrypt_toml: &Path, listener: SocketAddr) -> Result<Child> {
         .arg(d2s_config)
         .arg("--dnscrypt-config")
         .arg(dnscrypt_toml)
+        .arg("--control-token-file")
+        .arg(&control_token)
         .arg("run")
         .stdin(Stdio::null())
         .stdout(Stdio::from(log_file))
@@ -402,6 +444,22 @@ fn sleep_while_dnscrypt_running(child: &mut Child, delay: Duration) -> Result<Op
     Ok(None)
 }
 
+/// Ask a running d2s to re-read d2s.toml (SIGHUP). Open DNS relays survive
+/// and unchanged backends keep their health state. Returns how many
+/// processes were signalled; 0 means d2s is not running.
+pub fn reload_d2s() -> Result<usize> {
+    let mut signalled = 0;
+    for pid in crate::stop::pidof_any(&["d2s"]) {
+        if unsafe { libc::kill(pid, libc::SIGHUP) } == 0 {
+            info!("sent SIGHUP to d2s pid={pid}");
+            signalled += 1;
+        } else {
+            anyhow::bail!("SIGHUP d2s pid={pid}: {}", std::io::Error::last_os_error());
+        }
+    }
+    Ok(signalled)
+}
+
 fn stop_started_d2s(child: &mut Option<Child>) {
     let Some(mut process) = child.take() else { return; };
     let pid = process.id();
@@ -15,6 +15,8 @@ use std::os::unix::process::CommandExt;
 use crate::android::pkg_uid::{self, Mode, Sha256Tracker};
 use crate::settings;
 use crate::iptables::iptables_port::{self, DpiTunnelOptions, ProtoChoice};
+use crate::plan::{PlannedProcess, ProfilePlan};
+use crate::runtime_refresh::RoutingSnapshot;
 use crate::shell;
 
 const MODULE_DIR: &str = "/data/adb/modules/ZDT-D";
@@ -72,6 +74,62 @@ pub fn start_active_profiles() -> Result<()> {
     Ok(())
 }
 
+/// Start a single enabled profile outside of the full start sequence.
+pub fn start_profile_by_name(profile_name: &str) -> Result<()> {
+    ensure_file(DPITUNNEL_BIN)?;
+    start_profile(profile_name, &Sha256Tracker::new(SHA_FLAG_FILE))
+}
+
+/// Desired process and routing of one profile, derived the same way
+/// `start_profile` launches it. Uses the already resolved app lists and does
+/// not spawn or touch iptables. `None` means the profile would be skipped.
+pub fn plan_profile(profile_name: &str) -> Result<Option<ProfilePlan>> {
+    let profile_dir = Path::new(DPITUNNEL_ROOT).join(profile_name);
+    let port_path = profile_dir.join("port.json");
+    let port_cfg = read_json::<PortJson>(&port_path)
+        .with_context(|| format!("read {}", port_path.display()))?;
+
+    let uid_dir = profile_dir.join("app/uid");
+    let out_dir = profile_dir.join("app/out");
+    let out_user = out_dir.join("user_program");
+    let out_mobile = out_dir.join("mobile_program");
+    let out_wifi = out_dir.join("wifi_program");
+
+    let resolved_total = count_valid_uid_pairs(&out_user).unwrap_or(0)
+        + count_valid_uid_pairs(&out_mobile).unwrap_or(0)
+        + count_valid_uid_pairs(&out_wifi).unwrap_or(0);
+    let has_launch_marker = ["user_program", "mobile_program", "wifi_program"]
+        .iter()
+        .any(|f| pkg_uid::file_has_launch_marker(&uid_dir.join(f)).unwrap_or(false));
+    if resolved_total == 0 && !has_launch_marker {
+        return Ok(None);
+    }
+
+    let config_path = profile_dir.join("config/config.txt");
+    let raw = fs::read_to_string(&config_path)
+        .with_context(|| format!("read {}", config_path.display()))?;
+    let config_args = normalize_config_args(&raw);
+
+    let mut argv = Vec::new();
+    if !args_contain_ip(&config_args) {
+        argv.push("--ip".to_string());
+        argv.push("127.0.0.1".to_string());
+    }
+    argv.push("--port".to_string());
+    argv.push(port_cfg.port.to_string());
+    argv.extend(config_args);
+
+    let opt = DpiTunnelOptions { port_preference: 1, ..DpiTunnelOptions::default() };
+    Ok(Some(ProfilePlan {
+        processes: vec![PlannedProcess::new(DPITUNNEL_BIN, argv)],
+        routing: vec![
+            RoutingSnapshot::nat(&out_user, port_cfg.port, ProtoChoice::TcpUdp, None, &opt),
+            RoutingSnapshot::nat(&out_mobile, port_cfg.port, ProtoChoice::TcpUdp, Some(port_cfg.iface_mobile.as_str()), &opt),
+            RoutingSnapshot::nat(&out_wifi, port_cfg.port, ProtoChoice::TcpUdp, Some(port_cfg.iface_wifi.as_str()), &opt),
+        ],
+    }))
+}
+
 fn start_profile(profile_name: &str, tracker: &Sha256Tracker) -> Result<()> {
     let profile_dir = Path::new(DPITUNNEL_ROOT).join(profile_name);
     ensure_dir(profile_dir.to_string_lossy().as_ref())?;
@@ -142,14 +200,13 @@ fn start_profile(profile_name: &str, tracker: &Sha256Tracker) -> Result<()> {
 
     let bin = Path::new(DPITUNNEL_BIN);
     crate::logging::user_info(&format!("dpitunnel[{profile_name}]: запуск"));
-    let running = spawn_dpitunnel(&profile_dir, bin, port_cfg.port, &config_args, &log_path)?;
-    if !running {
+    let Some(pid) = spawn_dpitunnel(&profile_dir, bin, port_cfg.port, &config_args, &log_path)? else {
         crate::logging::user(&format!(
             "dpitunnel: профиль {} не запустился (проверь dpitunnel.log). Пропускаю iptables",
             profile_name
         ));
         return Ok(());
-    }
+    };
 
     crate::logging::user_info(&format!("dpitunnel[{profile_name}]: iptables"));
 
@@ -180,7 +237,25 @@ fn start_profile(profile_name: &str, tracker: &Sha256Tracker) -> Result<()> {
         DpiTunnelOptions { port_preference: 1, ..DpiTunnelOptions::default() },
     )?;
 
-    info!("dpitunnel profile started: {} port={}", profile_name, port_cfg.port);
+    let opt = DpiTunnelOptions { port_preference: 1, ..DpiTunnelOptions::default() };
+    let scopes = vec![
+        iptables_port::scope_label(&out_user, port_cfg.port, ProtoChoice::TcpUdp, None, &opt),
+        iptables_port::scope_label(&out_mobile, port_cfg.port, ProtoChoice::TcpUdp, Some(port_cfg.iface_mobile.as_str()), &opt),
+        iptables_port::scope_label(&out_wifi, port_cfg.port, ProtoChoice::TcpUdp, Some(port_cfg.iface_wifi.as_str()), &opt),
+    ];
+    let port = port_cfg.port;
+    crate::supervisor::watch(
+        "dpitunnel",
+        Some(profile_name),
+        pid,
+        scopes,
+        Box::new(move || {
+            spawn_dpitunnel(&profile_dir, Path::new(DPITUNNEL_BIN), port, &config_args, &log_path)?
+                .ok_or_else(|| anyhow::anyhow!("dpitunnel exited right after spawn"))
+        }),
+    );
+
+    info!("dpitunnel profile started: {} port={}", profile_name, port);
     Ok(())
 }
 
@@ -203,7 +278,7 @@ fn spawn_dpitunnel(
     port: u16,
     extra_args: &[String],
     log_path: &Path,
-) -> Result<bool> {
+) -> Result<Option<u32>> {
     // Command template (dpitunnel-cli):
     //   dpitunnel-cli --port <PORT> <ARGS_FROM_config.txt...>
     //
@@ -264,7 +339,7 @@ fn spawn_dpitunnel(
         );
     }
 
-    Ok(running)
+    Ok(running.then_some(pid))
 }
 
 fn args_contain_ip(args: &[String]) -> bool {
@@ -197,7 +197,8 @@ pub fn start_t2s_if_enabled() -> Result<()> {
         let ports_csv = plan.servers.iter().map(|s| s.setting.socks5_port.to_string()).collect::<Vec<_>>().join(",");
         for srv in &plan.servers {
             truncate_file(&srv.log_path)?;
-            spawn_hysteria2(&srv.config_path, &srv.log_path, &srv.setting.log_level)?;
+            let pid = spawn_hysteria2(&srv.config_path, &srv.log_path, &srv.setting.log_level)?;
+            supervise(&format!("{}/{}", plan.name, srv.name), srv, pid);
             wait_tcp_port("127.0.0.1", srv.setting.socks5_port, PORT_WAIT)?;
         }
         if plan.needs_t2s {
@@ -228,7 +229,8 @@ pub fn start_profiles_for_netd() -> Result<Vec<VpnNetdProfile>> {
     crate::logging::user_info("hysteria2 VPN: запуск");
     for plan in &plans {
         truncate_file(&plan.server.log_path)?;
-        spawn_hysteria2(&plan.server.config_path, &plan.server.log_path, &plan.server.setting.log_level)?;
+        let pid = spawn_hysteria2(&plan.server.config_path, &plan.server.log_path, &plan.server.setting.log_level)?;
+        supervise(&format!("{}/{}", plan.name, plan.server.name), &plan.server, pid);
         wait_tcp_port("127.0.0.1", plan.server.setting.socks5_port, PORT_WAIT)?;
         truncate_file(&plan.tun2socks_log)?;
         spawn_tun2socks_for_vpn(&tun2socks_bin, plan)?;
@@ -298,15 +300,25 @@ fn normalize_hysteria2_config_for_socks5(path: &Path, port: u16) -> Result<()> {
 }
 fn validate_hysteria2_config_json(path: &Path) -> Result<()> { let raw = fs::read_to_string(path)?; let v: Value = serde_json::from_str(&raw)?; if v.get("server").and_then(|x| x.as_str()).map(str::trim).unwrap_or("").is_empty() { bail!("hysteria2 config requires server"); } Ok(()) }
 
-fn spawn_hysteria2(config: &Path, log: &Path, log_level: &str) -> Result<()> {
-    if process_running_with_config(HYSTERIA2_BIN, config) { return Ok(()); }
+/// Hand a freshly spawned hysteria2 client to the supervisor.
+fn supervise(unit: &str, srv: &ServerPlan, pid: Option<u32>) {
+    let Some(pid) = pid else { return; };
+    let (config, log, level) = (srv.config_path.clone(), srv.log_path.clone(), srv.setting.log_level.clone());
+    crate::supervisor::watch("hysteria2", Some(unit), pid, Vec::new(), Box::new(move || {
+        spawn_hysteria2(&config, &log, &level)?.ok_or_else(|| anyhow::anyhow!("hysteria2 already running for {}", config.display()))
+    }));
+}
+
+/// Returns the new PID, or `None` when a matching process was already running.
+fn spawn_hysteria2(config: &Path, log: &Path, log_level: &str) -> Result<Option<u32>> {
+    if process_running_with_config(HYSTERIA2_BIN, config) { return Ok(None); }
     let logf = OpenOptions::new().create(true).write(true).truncate(true).open(log)?; let logf_err = logf.try_clone()?;
     let mut cmd = Command::new(HYSTERIA2_BIN);
     cmd.arg("--disable-update-check").arg("-f").arg("console").arg("-l").arg(normalize_log_level(log_level, "info")).arg("-c").arg(config).arg("client").stdin(Stdio::null()).stdout(Stdio::from(logf)).stderr(Stdio::from(logf_err));
     unsafe { cmd.pre_exec(|| { let _ = libc::setsid(); Ok(()) }); }
     let child = cmd.spawn().with_context(|| format!("spawn {HYSTERIA2_BIN}"))?;
     info!("hysteria2: spawned pid={} config={}", child.id(), config.display());
-    thread::sleep(Duration::from_millis(150)); Ok(())
+    thread::sleep(Duration::from_millis(150)); Ok(Some(child.id()))
 }
 fn spawn_tun2socks_for_vpn(bin: &Path, plan: &VpnPlan) -> Result<()> {
     let proxy = format!("socks5://127.0.0.1:{}", plan.server.setting.socks5_port);
@@ -895,10 +895,22 @@ fn first_profile_name(v: Option<&Value>) -> Option<String> {
     None
 }
 
+/// Spawn the profile's mieru client and hand it to the supervisor.
 fn spawn_mieru(plan: &ProfilePlan) -> Result<()> {
+    let Some(pid) = spawn_mieru_process(plan)? else { return Ok(()); };
+    let (unit, plan) = (plan.name.clone(), plan.clone());
+    crate::supervisor::watch("mieru", Some(&unit), pid, Vec::new(), Box::new(move || {
+        spawn_mieru_process(&plan)?
+            .ok_or_else(|| anyhow::anyhow!("mieru already running for {}", plan.runtime_config_path.display()))
+    }));
+    Ok(())
+}
+
+/// Returns the new PID, or `None` when a matching process was already running.
+fn spawn_mieru_process(plan: &ProfilePlan) -> Result<Option<u32>> {
     if mieru_profile_process_running(&plan.runtime_config_path) {
         info!("mieru: profile={} already running for runtime_config={}, skip spawn", plan.name, plan.runtime_config_path.display());
-        return Ok(());
+        return Ok(None);
     }
     fs::create_dir_all(plan.profile_dir.join("log"))?;
     let logf = OpenOptions::new().create(true).write(true).truncate(true).open(&plan.mieru_log_path)
@@ -918,8 +930,9 @@ fn spawn_mieru(plan: &ProfilePlan) -> Result<()> {
     let proc_path = PathBuf::from("/proc").join(child.id().to_string());
     if !proc_path.is_dir() {
         warn!("mieru: profile={} pid={} exited quickly; check log {}", plan.name, child.id(), plan.mieru_log_path.display());
+        crate::events::program_exited("mieru", Some(&plan.name), child.id());
     }
-    Ok(())
+    Ok(Some(child.id()))
 }
 
 fn spawn_tun2proxy(plan: &ProfilePlan) -> Result<()> {
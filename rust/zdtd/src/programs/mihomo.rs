@@ -997,13 +997,30 @@ fn is_forbidden_scalar(key: &str) -> bool {
 }
 
 fn spawn_mihomo(plan: &ProfilePlan) -> Result<()> {
+    let Some(pid) = spawn_mihomo_process(plan)? else { return Ok(()); };
+    let plan = plan.clone();
+    crate::supervisor::watch(
+        "mihomo",
+        Some(&plan.name.clone()),
+        pid,
+        Vec::new(),
+        Box::new(move || {
+            spawn_mihomo_process(&plan)?
+                .ok_or_else(|| anyhow::anyhow!("mihomo profile={} already running", plan.name))
+        }),
+    );
+    Ok(())
+}
+
+/// Returns the new PID, or `None` when the profile was already running.
+fn spawn_mihomo_process(plan: &ProfilePlan) -> Result<Option<u32>> {
     if mihomo_profile_process_running(&plan.work_dir, &plan.runtime_config_path) {
         info!(
             "mihomo: profile={} already running for runtime_config={}, skip spawn",
             plan.name,
             plan.runtime_config_path.display()
         );
-        return Ok(());
+        return Ok(None);
     }
 
     fs::create_dir_all(&plan.work_dir)?;
@@ -1046,8 +1063,9 @@ fn spawn_mihomo(plan: &ProfilePlan) -> Result<()> {
     let proc_path = PathBuf::from("/proc").join(child.id().to_string());
     if !proc_path.is_dir() {
         warn!("mihomo: profile={} pid={} exited quickly; check log {}", plan.name, child.id(), plan.mihomo_log_path.display());
+        crate::events::program_exited("mihomo", Some(&plan.name), child.id());
     }
-    Ok(())
+    Ok(Some(child.id()))
 }
 
 fn spawn_tun2socks(plan: &ProfilePlan) -> Result<()> {
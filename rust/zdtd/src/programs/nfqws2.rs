@@ -15,6 +15,8 @@ use std::os::unix::process::CommandExt;
 use crate::android::pkg_uid::{self, Mode, Sha256Tracker};
 use crate::settings;
 use crate::iptables::{iptables_v1, iptables_v2};
+use crate::plan::{PlannedProcess, ProfilePlan};
+use crate::runtime_refresh::RoutingSnapshot;
 
 const MODULE_DIR: &str = "/data/adb/modules/ZDT-D";
 const WORKING_DIR: &str = "/data/adb/modules/ZDT-D/working_folder";
@@ -62,6 +64,56 @@ pub fn start_active_profiles() -> Result<()> {
     Ok(())
 }
 
+/// Start a single enabled profile outside of the full start sequence.
+pub fn start_profile_by_name(profile_name: &str) -> Result<()> {
+    ensure_file(NFQWS2_BIN)?;
+    start_profile(profile_name, &Sha256Tracker::new(SHA_FLAG_FILE))
+}
+
+/// Desired process and routing of one profile, derived the same way
+/// `start_profile` launches it. Uses the already resolved app lists and does
+/// not spawn or touch iptables. `None` means the profile would be skipped.
+pub fn plan_profile(profile_name: &str) -> Result<Option<ProfilePlan>> {
+    let profile_dir = Path::new(NFQWS2_ROOT).join(profile_name);
+    let port_path = profile_dir.join("port.json");
+    let port_cfg = read_json::<PortJson>(&port_path)
+        .with_context(|| format!("read {}", port_path.display()))?;
+
+    let uid_dir = profile_dir.join("app/uid");
+    let out_dir = profile_dir.join("app/out");
+    let out_mobile = out_dir.join("mobile_program");
+    let out_wifi = out_dir.join("wifi_program");
+    let out_user = out_dir.join("user_program");
+
+    let resolved_total = count_valid_uid_pairs(&out_mobile).unwrap_or(0)
+        + count_valid_uid_pairs(&out_wifi).unwrap_or(0)
+        + count_valid_uid_pairs(&out_user).unwrap_or(0);
+    let has_launch_marker = ["mobile_program", "wifi_program", "user_program"]
+        .iter()
+        .any(|f| pkg_uid::file_has_launch_marker(&uid_dir.join(f)).unwrap_or(false));
+    if resolved_total == 0 && !has_launch_marker {
+        return Ok(None);
+    }
+
+    let config_path = profile_dir.join("config/config.txt");
+    let raw = fs::read_to_string(&config_path)
+        .with_context(|| format!("read {}", config_path.display()))?;
+    let port_filter = crate::programs::nfqws_filters::extract_proto_port_filter(&raw);
+    let port_filter_ref = if port_filter.is_empty() { None } else { Some(&port_filter) };
+
+    let mut argv = vec!["--uid=0:0".to_string(), format!("--qnum={}", port_cfg.port)];
+    argv.extend(normalize_config_args(&raw));
+
+    Ok(Some(ProfilePlan {
+        processes: vec![PlannedProcess::new(NFQWS2_BIN, argv)],
+        routing: vec![
+            RoutingSnapshot::nfqueue_v2(&out_user, port_cfg.port, port_filter_ref),
+            RoutingSnapshot::nfqueue_v1(&out_mobile, "full", port_cfg.port, Some(port_cfg.iface_mobile.as_str()), port_filter_ref),
+            RoutingSnapshot::nfqueue_v1(&out_wifi, "full", port_cfg.port, Some(port_cfg.iface_wifi.as_str()), port_filter_ref),
+        ],
+    }))
+}
+
 fn start_profile(profile_name: &str, tracker: &Sha256Tracker) -> Result<()> {
     let profile_dir = Path::new(NFQWS2_ROOT).join(profile_name);
     ensure_dir(profile_dir.to_string_lossy().as_ref())?;
@@ -121,7 +173,7 @@ let port_filter_ref = if port_filter.is_empty() { None } else { Some(&port_filte
 
 
     crate::logging::user_info(&format!("zapret2[{profile_name}]: запуск"));
-spawn_nfqws(&profile_dir, port_cfg.port, &config_args, &log_path)?;
+let pid = spawn_nfqws(&profile_dir, port_cfg.port, &config_args, &log_path)?;
 
     // Apply iptables:
     crate::logging::user_info(&format!("zapret2[{profile_name}]: iptables"));
@@ -131,12 +183,21 @@ spawn_nfqws(&profile_dir, port_cfg.port, &config_args, &log_path)?;
     iptables_v1::apply("full", port_cfg.port, Some(port_cfg.iface_mobile.as_str()), Some(&out_mobile), port_filter_ref)?;
     iptables_v1::apply("full", port_cfg.port, Some(port_cfg.iface_wifi.as_str()), Some(&out_wifi), port_filter_ref)?;
 
-    info!("nfqws profile started: {} port={}", profile_name, port_cfg.port);
+    let port = port_cfg.port;
+    crate::supervisor::watch(
+        "nfqws2",
+        Some(profile_name),
+        pid,
+        Vec::new(),
+        Box::new(move || spawn_nfqws(&profile_dir, port, &config_args, &log_path)),
+    );
+
+    info!("nfqws profile started: {} port={}", profile_name, port);
     Ok(())
 }
 
 
-fn spawn_nfqws(workdir: &Path, port: u16, config_args: &[String], log_path: &Path) -> Result<()> {
+fn spawn_nfqws(workdir: &Path, port: u16, config_args: &[String], log_path: &Path) -> Result<u32> {
     let q = format!("--qnum={}", port);
 
     // Open log file (append) for stdout/stderr so we can debug early exits.
@@ -178,7 +239,7 @@ fn spawn_nfqws(workdir: &Path, port: u16, config_args: &[String], log_path: &Pat
         info!("nfqws pid={} is not running after spawn (check log {})", pid, log_path.display());
     }
 
-    Ok(())
+    Ok(pid)
 }
 
 fn read_json<T: for<'de> Deserialize<'de>>(path: &Path) -> Result<T> {
@@ -14,7 +14,7 @@ use std::{
 
 use crate::{
     android::pkg_uid,
-    android_dns,
+    dns,
     shell::{self, Capture},
     vpn_netd::VpnNetdProfile,
     vpn_tether::VpnTetherProfile,
@@ -851,6 +851,7 @@ fn spawn_openvpn(plan: &ProfilePlan) -> Result<()> {
     let proc_path = PathBuf::from("/proc").join(child.id().to_string());
     if !proc_path.is_dir() {
         warn!("openvpn: profile={} pid={} exited quickly; check log {}", plan.name, child.id(), plan.log_path.display());
+        crate::events::program_exited("openvpn", Some(&plan.name), child.id());
     }
     Ok(())
 }
@@ -1060,7 +1061,7 @@ fn resolve_remote_lines(raw: &str, endpoint_resolve: bool) -> Result<(String, Ve
         let resolved = if let Some(cached) = cache.get(&host) {
             cached.clone()
         } else {
-            let values = android_dns::resolve_ipv4_all(&host);
+            let values = dns::resolve_ipv4_all(&host);
             cache.insert(host.clone(), values.clone());
             values
         };
@@ -7,7 +7,7 @@ use std::{
     collections::BTreeSet,
     fs::{self, OpenOptions},
     io::{Read, Write},
-    net::{TcpStream, ToSocketAddrs},
+    net::{SocketAddr, TcpStream},
     str::FromStr,
     os::unix::process::CommandExt,
     path::{Path, PathBuf},
@@ -1166,7 +1166,7 @@ fn check_api_proxy_candidate(candidate: &ApiProxyCandidate, timeout: Duration) -
 }
 
 fn connect_proxy_tcp(candidate: &ApiProxyCandidate, timeout: Duration) -> std::io::Result<TcpStream> {
-    let addrs = (candidate.host.as_str(), candidate.port).to_socket_addrs()?;
+    let addrs = crate::dns::resolve_all(&candidate.host).into_iter().map(|ip| SocketAddr::new(ip, candidate.port));
     let mut last_err = None;
     for addr in addrs {
         match TcpStream::connect_timeout(&addr, timeout) {
@@ -366,8 +366,7 @@ pub fn start_construction_profile(profile: &str) -> Result<()> {
     let own_used = collect_enabled_t2s_local_ports(&other_enabled_names);
 
     let Some(plan) = build_t2s_profile_plan(profile, &external_used, &own_used, hotspot_profile.as_deref())? else {
-        info!("sing-box: construction profile '{}' has no runnable plan", profile);
-        return Ok(());
+        bail!("sing-box: construction profile '{}' has no runnable plan", profile);
     };
 
     let t2s_bin = if plan.needs_t2s {
@@ -385,8 +384,9 @@ pub fn start_construction_profile(profile: &str) -> Result<()> {
     for server in &plan.servers {
         ensure_parent_dir(&server.log_path)?;
         truncate_file(&server.log_path)?;
-        spawn_singbox(&server.config_path, &server.log_path)
+        let pid = spawn_singbox(&server.config_path, &server.log_path)
             .with_context(|| format!("spawn sing-box profile={} server={}", plan.name, server.name))?;
+        supervise(&format!("{}/{}", plan.name, server.name), &server.config_path, &server.log_path, pid);
     }
 
     let hotspot_for_plan = hotspot_profile.as_deref() == Some(plan.name.as_str());
@@ -551,8 +551,9 @@ pub fn start_t2s_if_enabled() -> Result<()> {
         for server in &plan.servers {
             ensure_parent_dir(&server.log_path)?;
             truncate_file(&server.log_path)?;
-            spawn_singbox(&server.config_path, &server.log_path)
+            let pid = spawn_singbox(&server.config_path, &server.log_path)
                 .with_context(|| format!("spawn sing-box profile={} server={}", plan.name, server.name))?;
+            supervise(&format!("{}/{}", plan.name, server.name), &server.config_path, &server.log_path, pid);
         }
 
         let hotspot_for_plan = hotspot_profile.as_deref() == Some(plan.name.as_str());
@@ -705,8 +706,9 @@ pub fn start_profiles_for_netd() -> Result<Vec<VpnNetdProfile>> {
 
             ensure_parent_dir(&plan.log_path)?;
             truncate_file(&plan.log_path)?;
-            spawn_singbox(&plan.config_path, &plan.log_path)
+            let pid = spawn_singbox(&plan.config_path, &plan.log_path)
                 .with_context(|| format!("spawn sing-box vpn profile={} server={}", plan.name, plan.server_name))?;
+            supervise(&format!("{}/{}", plan.name, plan.server_name), &plan.config_path, &plan.log_path, pid);
             wait_tcp_port("127.0.0.1", plan.server_port, PORT_WAIT)
                 .with_context(|| format!("sing-box vpn profile={} wait server port={}", plan.name, plan.server_port))?;
 
@@ -1857,13 +1859,30 @@ fn version_supports_tun_dns_mode(version: &str) -> bool {
     }
 }
 
-fn spawn_singbox(config_path: &Path, log_path: &Path) -> Result<()> {
+/// Hand a freshly spawned sing-box server to the supervisor.
+fn supervise(unit: &str, config_path: &Path, log_path: &Path, pid: Option<u32>) {
+    let Some(pid) = pid else { return; };
+    let (config_path, log_path) = (config_path.to_path_buf(), log_path.to_path_buf());
+    crate::supervisor::watch(
+        "sing-box",
+        Some(unit),
+        pid,
+        Vec::new(),
+        Box::new(move || {
+            spawn_singbox(&config_path, &log_path)?
+                .ok_or_else(|| anyhow::anyhow!("sing-box already running for {}", config_path.display()))
+        }),
+    );
+}
+
+/// Returns the new PID, or `None` when a matching process was already running.
+fn spawn_singbox(config_path: &Path, log_path: &Path) -> Result<Option<u32>> {
     if singbox_profile_process_running(config_path) {
         info!(
             "sing-box: already running for config={}, skip spawn",
             config_path.display()
         );
-        return Ok(());
+        return Ok(None);
     }
 
     let logf = OpenOptions::new()
@@ -1903,8 +1922,9 @@ fn spawn_singbox(config_path: &Path, log_path: &Path) -> Result<()> {
     let proc_path = PathBuf::from("/proc").join(child.id().to_string());
     if !proc_path.is_dir() {
         warn!("sing-box pid={} exited quickly; check log {}", child.id(), log_path.display());
+        crate::events::program_exited("sing-box", None, child.id());
     }
-    Ok(())
+    Ok(Some(child.id()))
 }
 
 fn spawn_tun2socks_for_vpn(bin: &Path, plan: &VpnProfilePlan) -> Result<()> {
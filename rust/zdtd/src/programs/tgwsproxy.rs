@@ -341,13 +341,42 @@ fn validate_hex_secret(secret: &str) -> Result<()> {
 }
 
 fn validate_dc_ip(item: &str) -> Result<()> {
-    let (dc, ip) = item.split_once(':').ok_or_else(|| anyhow::anyhow!("dc_ip must be DC:IP"))?;
+    let (dc, ip) = item.split_once(':').ok_or_else(|| anyhow::anyhow!("dc_ip must be DC:IP or DC:HOST"))?;
     let dc_num: u16 = dc.trim().parse().map_err(|_| anyhow::anyhow!("invalid DC number in {item}"))?;
     if dc_num == 0 { anyhow::bail!("invalid DC number in {item}"); }
-    ip.trim().parse::<IpAddr>().map_err(|_| anyhow::anyhow!("invalid IP in {item}"))?;
+    let host = ip.trim();
+    let hostname = !host.is_empty()
+        && host.split('.').all(|l| !l.is_empty() && l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+    if host.parse::<IpAddr>().is_err() && !hostname {
+        anyhow::bail!("invalid IP in {item}");
+    }
     Ok(())
 }
 
+/// Replace `--dc-ip DC:HOST` values with the host's IPv4 address; tg-ws-proxy
+/// only accepts literal addresses. Entries that do not resolve are dropped.
+fn resolve_dc_hosts(args: Vec<String>) -> Vec<String> {
+    let mut out = Vec::with_capacity(args.len());
+    let mut it = args.into_iter();
+    while let Some(arg) = it.next() {
+        if arg != "--dc-ip" {
+            out.push(arg);
+            continue;
+        }
+        let Some(value) = it.next() else { break };
+        let Some((dc, host)) = value.split_once(':') else { continue };
+        if host.parse::<IpAddr>().is_ok() {
+            push_opt(&mut out, "--dc-ip", value);
+            continue;
+        }
+        match crate::dns::resolve_ipv4(host) {
+            Some(ip) => push_opt(&mut out, "--dc-ip", format!("{dc}:{ip}")),
+            None => log::warn!("tgwsproxy: cannot resolve dc_ip host {host}; entry skipped"),
+        }
+    }
+    out
+}
+
 fn validate_mtproto_proxy(item: &str) -> Result<()> {
     let parts: Vec<&str> = item.rsplitn(3, ':').collect();
     if parts.len() != 3 {
@@ -519,7 +548,7 @@ pub fn start_if_enabled() -> Result<()> {
     }
     let setting = read_setting()?;
     validate_setting(&setting)?;
-    let args = build_args(&setting);
+    let args = resolve_dc_hosts(build_args(&setting));
     fs::create_dir_all(LOG_DIR).ok();
     let logf = OpenOptions::new().create(true).append(true).open(LOG_FILE)?;
     let logf_err = logf.try_clone()?;
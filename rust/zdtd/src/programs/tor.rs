@@ -546,6 +546,7 @@ fn spawn_tor(torrc: &Path, log_path: &Path) -> Result<()> {
     let proc_path = PathBuf::from("/proc").join(child.id().to_string());
     if !proc_path.is_dir() {
         warn!("tor pid={} exited quickly; check log {}", child.id(), log_path.display());
+        crate::events::program_exited("tor", None, child.id());
     }
     Ok(())
 }
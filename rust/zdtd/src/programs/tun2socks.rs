@@ -544,6 +544,7 @@ fn spawn_tun2socks(plan: &ProfilePlan) -> Result<()> {
     let proc_path = PathBuf::from("/proc").join(child.id().to_string());
     if !proc_path.is_dir() {
         warn!("tun2socks: profile={} pid={} exited quickly; check log {}", plan.name, child.id(), plan.log_path.display());
+        crate::events::program_exited("tun2socks", Some(&plan.name), child.id());
     }
     Ok(())
 }
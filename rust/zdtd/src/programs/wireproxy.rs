@@ -186,8 +186,9 @@ pub fn start_if_enabled() -> Result<()> {
         for server in &plan.servers {
             ensure_parent_dir(&server.log_path)?;
             truncate_file(&server.log_path)?;
-            spawn_wireproxy(&server.config_path, &server.log_path)
+            let pid = spawn_wireproxy(&server.config_path, &server.log_path)
                 .with_context(|| format!("spawn wireproxy profile={} server={}", plan.name, server.name))?;
+            supervise(&format!("{}/{}", plan.name, server.name), &server.config_path, &server.log_path, pid);
         }
 
         let hotspot_for_plan = hotspot_profile.as_deref() == Some(plan.name.as_str());
@@ -336,8 +337,7 @@ pub fn start_construction_profile(profile: &str) -> Result<()> {
     let own_used = collect_enabled_local_ports(&other_enabled_names);
 
     let Some(plan) = build_profile_plan(profile, &external_used, &own_used, hotspot_profile.as_deref())? else {
-        info!("wireproxy: construction profile '{}' has no runnable plan", profile);
-        return Ok(());
+        anyhow::bail!("wireproxy: construction profile '{}' has no runnable plan", profile);
     };
 
     let t2s_bin = if plan.needs_t2s {
@@ -355,8 +355,9 @@ pub fn start_construction_profile(profile: &str) -> Result<()> {
     for server in &plan.servers {
         ensure_parent_dir(&server.log_path)?;
         truncate_file(&server.log_path)?;
-        spawn_wireproxy(&server.config_path, &server.log_path)
+        let pid = spawn_wireproxy(&server.config_path, &server.log_path)
             .with_context(|| format!("spawn wireproxy profile={} server={}", plan.name, server.name))?;
+        supervise(&format!("{}/{}", plan.name, server.name), &server.config_path, &server.log_path, pid);
     }
 
     let hotspot_for_plan = hotspot_profile.as_deref() == Some(plan.name.as_str());
@@ -712,7 +713,19 @@ fn ensure_valid_profile_name(name: &str) -> Result<()> {
     Ok(())
 }
 
-fn spawn_wireproxy(config_path: &Path, log_path: &Path) -> Result<()> {
+/// Hand a freshly spawned wireproxy server to the supervisor.
+fn supervise(unit: &str, config_path: &Path, log_path: &Path, pid: u32) {
+    let (config_path, log_path) = (config_path.to_path_buf(), log_path.to_path_buf());
+    crate::supervisor::watch(
+        "wireproxy",
+        Some(unit),
+        pid,
+        Vec::new(),
+        Box::new(move || spawn_wireproxy(&config_path, &log_path)),
+    );
+}
+
+fn spawn_wireproxy(config_path: &Path, log_path: &Path) -> Result<u32> {
     let logf = OpenOptions::new()
         .create(true)
         .write(true)
@@ -749,8 +762,9 @@ fn spawn_wireproxy(config_path: &Path, log_path: &Path) -> Result<()> {
     let proc_path = PathBuf::from("/proc").join(child.id().to_string());
     if !proc_path.is_dir() {
         warn!("wireproxy pid={} exited quickly; check log {}", child.id(), log_path.display());
+        crate::events::program_exited("wireproxy", None, child.id());
     }
-    Ok(())
+    Ok(child.id())
 }
 
 fn find_bin(name: &str) -> Result<PathBuf> {
@@ -0,0 +1,715 @@
+//! Data usage quotas per program/profile.
+//!
+//! Usage is read from the `traffic_history` series `profile:<program>/<profile>`
+//! (or `program:<program>` for a program-wide quota) after every history
+//! sample; the sampler keeps running while quotas exist even if the history
+//! itself is switched off. Crossing `warn_percent` sends one notification per
+//! period; reaching the limit notifies again and runs the quota's action:
+//! - `warn`: nothing more;
+//! - `disable`: mark the profile(s) disabled in active.json and reconcile;
+//! - `switch`: move the profile's app list to profile `switch_to`;
+//! - `block`: reject all traffic of the profile's apps (`ZDT_QUOTA` chain).
+//!
+//! With `restore_on_reset` the action is undone when the next daily/monthly
+//! period starts, or for a rolling quota once usage is back under the limit.
+//! Per-quota state lives in `quota/state.json`, so an action is neither
+//! repeated nor forgotten across daemon restarts; an action that failed is
+//! tried again after the next sample.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, OnceLock},
+    time::Duration,
+};
+
+use crate::{
+    android::notification::{self, NotificationType},
+    config::NotificationsConfig,
+    jsonfs, settings,
+    shell::Capture,
+    traffic_history::{self, Resolution},
+    xtables_lock,
+};
+
+const QUOTA_CHAIN: &str = "ZDT_QUOTA";
+const IPT_TIMEOUT: Duration = Duration::from_secs(5);
+const MB: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Period {
+    Daily,
+    Monthly,
+    Rolling,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Warn,
+    Switch,
+    Disable,
+    Block,
+}
+
+impl Action {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Warn => "warn",
+            Self::Switch => "switch",
+            Self::Disable => "disable",
+            Self::Block => "block",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Quota {
+    pub program: String,
+    /// Without a profile the quota covers the whole program.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    pub period: Period,
+    /// Day of month a monthly period starts on (1-28, UTC).
+    #[serde(default = "default_reset_day")]
+    pub reset_day: u32,
+    /// Length of a rolling period in days (1-60).
+    #[serde(default = "default_rolling_days")]
+    pub rolling_days: u32,
+    pub limit_mb: u64,
+    #[serde(default = "default_warn_percent")]
+    pub warn_percent: u64,
+    #[serde(default = "default_action")]
+    pub action: Action,
+    /// Profile of the same program that receives the apps on `switch`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub switch_to: Option<String>,
+    #[serde(default = "default_true")]
+    pub restore_on_reset: bool,
+}
+
+fn default_reset_day() -> u32 {
+    1
+}
+
+fn default_rolling_days() -> u32 {
+    30
+}
+
+fn default_warn_percent() -> u64 {
+    90
+}
+
+fn default_action() -> Action {
+    Action::Warn
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Quota {
+    fn key(&self) -> String {
+        match &self.profile {
+            Some(profile) => format!("{}/{}", self.program, profile),
+            None => self.program.clone(),
+        }
+    }
+
+    fn series(&self) -> String {
+        match &self.profile {
+            Some(profile) => format!("profile:{}/{}", self.program, profile),
+            None => format!("program:{}", self.program),
+        }
+    }
+
+    /// Start of the current period, and the bucket size that sums it exactly
+    /// (rolling periods are rounded down to the hour).
+    fn period_start(&self, now: u64) -> (u64, Resolution) {
+        match self.period {
+            Period::Daily => (Resolution::Day.bucket_start(now), Resolution::Day),
+            Period::Monthly => {
+                let offset = u64::from(self.reset_day - 1) * 86_400;
+                let this_month = Resolution::Month.bucket_start(now);
+                let start = if this_month + offset <= now {
+                    this_month + offset
+                } else {
+                    Resolution::Month.bucket_start(this_month.saturating_sub(1)) + offset
+                };
+                (start, Resolution::Day)
+            }
+            Period::Rolling => (now.saturating_sub(u64::from(self.rolling_days) * 86_400), Resolution::Hour),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct QuotaConfig {
+    #[serde(default)]
+    pub quotas: Vec<Quota>,
+}
+
+fn ensure_segment(raw: &str, what: &str) -> Result<()> {
+    if raw.is_empty() || raw == "." || raw == ".." || raw.contains('/') || raw.contains('\\') {
+        bail!("invalid {what}: {raw:?}");
+    }
+    Ok(())
+}
+
+impl QuotaConfig {
+    fn validate(&mut self) -> Result<()> {
+        let mut keys = BTreeSet::new();
+        for q in &mut self.quotas {
+            q.program = q.program.trim().to_ascii_lowercase();
+            q.profile = q.profile.take().map(|p| p.trim().to_string()).filter(|p| !p.is_empty());
+            q.switch_to = q.switch_to.take().map(|p| p.trim().to_string()).filter(|p| !p.is_empty());
+            ensure_segment(&q.program, "program")?;
+            if let Some(profile) = &q.profile {
+                ensure_segment(profile, "profile")?;
+            }
+            if !keys.insert(q.key()) {
+                bail!("duplicate quota for {}", q.key());
+            }
+            if q.limit_mb == 0 {
+                bail!("{}: limit_mb must be > 0", q.key());
+            }
+            if !(1..=28).contains(&q.reset_day) {
+                bail!("{}: reset_day must be 1..28", q.key());
+            }
+            if !(1..=60).contains(&q.rolling_days) {
+                bail!("{}: rolling_days must be 1..60", q.key());
+            }
+            if !(1..=100).contains(&q.warn_percent) {
+                bail!("{}: warn_percent must be 1..100", q.key());
+            }
+            if q.action == Action::Switch {
+                let (Some(profile), Some(to)) = (&q.profile, &q.switch_to) else {
+                    bail!("{}: switch needs profile and switch_to", q.key());
+                };
+                ensure_segment(to, "switch_to")?;
+                if profile == to {
+                    bail!("{}: switch_to must be another profile", q.key());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What an action changed, so it can be undone.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum Applied {
+    Disabled { profiles: Vec<String> },
+    Switched { to: String, apps: Vec<String> },
+    Blocked,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct QuotaState {
+    period_start: u64,
+    warned: bool,
+    exceeded: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    applied: Option<Applied>,
+}
+
+fn root() -> PathBuf {
+    settings::working_root_path().join("quota")
+}
+
+fn config_path() -> PathBuf {
+    root().join("quotas.json")
+}
+
+fn state_path() -> PathBuf {
+    root().join("state.json")
+}
+
+pub fn load_config() -> Result<QuotaConfig> {
+    let path = config_path();
+    if !path.exists() {
+        return Ok(QuotaConfig::default());
+    }
+    jsonfs::read_json(&path)
+}
+
+pub fn save_config(mut cfg: QuotaConfig) -> Result<QuotaConfig> {
+    cfg.validate()?;
+    jsonfs::write_json_pretty_tmp_rename(&config_path(), &cfg)?;
+    Ok(cfg)
+}
+
+static STATE: OnceLock<Mutex<BTreeMap<String, QuotaState>>> = OnceLock::new();
+/// UIDs currently in `ZDT_QUOTA`; `None` when the chain is not installed.
+static BLOCKED: Mutex<Option<BTreeSet<u32>>> = Mutex::new(None);
+
+fn state() -> MutexGuard<'static, BTreeMap<String, QuotaState>> {
+    let m = STATE.get_or_init(|| {
+        let path = state_path();
+        Mutex::new(if path.exists() { jsonfs::read_json(&path).unwrap_or_default() } else { BTreeMap::new() })
+    });
+    match m.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn services_running() -> bool {
+    crate::api_status::read().ok().flatten().is_some_and(|s| s.running && !s.busy)
+}
+
+/// Side effect decided by `evaluate` under the state lock and run after it is
+/// released: undo/apply reconcile the plan and notices spawn `cmd`, and
+/// neither should block `GET /api/quotas`. `retry` marks another attempt at
+/// an action that failed before; the limit was already announced then.
+enum Step {
+    Undo { key: String, applied: Applied, reset: bool },
+    Apply { quota: Quota, used: u64, retry: bool },
+    Notify { quota: Quota, stage: &'static str, used: u64 },
+}
+
+/// Whether any quota is configured; the history sampler keeps running for
+/// them even with `traffic_history_enabled` off.
+pub fn any_configured() -> bool {
+    load_config().is_ok_and(|cfg| !cfg.quotas.is_empty())
+}
+
+/// Evaluate every quota; called by the history sampler after each sample.
+pub fn check(now: u64) {
+    let cfg = match load_config() {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            log::warn!("quota: {e:#}");
+            return;
+        }
+    };
+    let running = services_running();
+    let mut steps = Vec::new();
+    {
+        let mut states = state();
+        let before = serde_json::to_value(&*states).unwrap_or_default();
+        let keys: BTreeSet<String> = cfg.quotas.iter().map(Quota::key).collect();
+        // A removed quota gives back whatever it took.
+        let removed: Vec<String> = states.keys().filter(|k| !keys.contains(*k)).cloned().collect();
+        for key in removed {
+            if let Some(applied) = states.remove(&key).and_then(|st| st.applied) {
+                steps.push(Step::Undo { key, applied, reset: false });
+            }
+        }
+        for q in &cfg.quotas {
+            evaluate(q, states.entry(q.key()).or_default(), now, &mut steps);
+        }
+        save_state_if_changed(&states, &before);
+    }
+
+    for step in steps {
+        match step {
+            Step::Undo { key, applied, reset } => {
+                let program = key.split('/').next().unwrap_or(&key).to_string();
+                let profile = key.split_once('/').map(|(_, p)| p.to_string());
+                undo(&program, profile.as_deref(), applied, running);
+                if reset {
+                    crate::logging::user_info(&format!("{key}: лимит трафика сброшен, действие отменено"));
+                }
+            }
+            Step::Apply { quota, used, retry } => {
+                let label = quota.key();
+                if !retry {
+                    crate::logging::user_warn(&format!("{label}: лимит трафика {} МБ исчерпан", quota.limit_mb));
+                }
+                let applied = match apply(&quota, running) {
+                    Ok(applied) => applied,
+                    Err(e) => {
+                        log::warn!("quota {label}: {} failed: {e:#}", quota.action.as_str());
+                        None
+                    }
+                };
+                if applied.is_some() {
+                    let mut states = state();
+                    let before = serde_json::to_value(&*states).unwrap_or_default();
+                    if let Some(st) = states.get_mut(&label) {
+                        st.applied = applied;
+                    }
+                    save_state_if_changed(&states, &before);
+                }
+                if !retry {
+                    notify(&quota, "exceeded", used);
+                }
+            }
+            Step::Notify { quota, stage, used } => notify(&quota, stage, used),
+        }
+    }
+
+    let blocked: BTreeSet<u32> = {
+        let states = state();
+        cfg.quotas
+            .iter()
+            .filter(|q| matches!(states.get(&q.key()).and_then(|st| st.applied.as_ref()), Some(Applied::Blocked)))
+            .flat_map(|q| profile_uids(&q.program, q.profile.as_deref()))
+            .collect()
+    };
+    sync_block_rules(if running { blocked } else { BTreeSet::new() });
+}
+
+fn save_state_if_changed(states: &BTreeMap<String, QuotaState>, before: &serde_json::Value) {
+    if serde_json::to_value(states).unwrap_or_default() != *before {
+        if let Err(e) = jsonfs::write_json_pretty_tmp_rename(&state_path(), states) {
+            log::warn!("quota: save state failed: {e:#}");
+        }
+    }
+}
+
+fn evaluate(q: &Quota, st: &mut QuotaState, now: u64, steps: &mut Vec<Step>) {
+    let (start, resolution) = q.period_start(now);
+    let used = traffic_history::usage(&q.series(), resolution, start);
+    evaluate_usage(q, st, start, used, steps);
+}
+
+fn evaluate_usage(q: &Quota, st: &mut QuotaState, start: u64, used: u64, steps: &mut Vec<Step>) {
+    let limit = q.limit_mb.saturating_mul(MB);
+
+    let reset = match q.period {
+        Period::Rolling => st.exceeded && used < limit,
+        _ => st.period_start != start,
+    };
+    if reset {
+        if let Some(applied) = st.applied.take() {
+            if q.restore_on_reset {
+                steps.push(Step::Undo { key: q.key(), applied, reset: true });
+            }
+        }
+        *st = QuotaState { period_start: start, ..QuotaState::default() };
+    }
+    st.period_start = start;
+    if q.period == Period::Rolling && st.warned && !st.exceeded && used.saturating_mul(100) < limit.saturating_mul(q.warn_percent) {
+        st.warned = false;
+    }
+
+    if !st.warned && used.saturating_mul(100) >= limit.saturating_mul(q.warn_percent) && used < limit {
+        st.warned = true;
+        steps.push(Step::Notify { quota: q.clone(), stage: "warn", used });
+    }
+    if !st.exceeded && used >= limit {
+        st.exceeded = true;
+        st.warned = true;
+        steps.push(Step::Apply { quota: q.clone(), used, retry: false });
+    } else if st.exceeded && st.applied.is_none() && q.action != Action::Warn && used >= limit {
+        // The action failed last time (or the daemon stopped before it ran);
+        // `applied` is only set once it succeeds.
+        steps.push(Step::Apply { quota: q.clone(), used, retry: true });
+    }
+}
+
+fn notify(q: &Quota, stage: &str, used: u64) {
+    let label = q.key();
+    let action = if stage == "warn" { "warn" } else { q.action.as_str() };
+    crate::events::publish(
+        "quota",
+        json!({"quota": label, "stage": stage, "used_bytes": used, "limit_mb": q.limit_mb, "action": action}),
+    );
+    let ty = if stage == "warn" {
+        NotificationType::Info { msg: &format!("{label}: израсходовано {} из {} МБ", used / MB, q.limit_mb) }
+    } else {
+        NotificationType::Error { msg: &format!("{label}: лимит {} МБ исчерпан, действие: {action}", q.limit_mb) }
+    };
+    // Quotas are opted into one by one, so their notices are sent even when
+    // the daemon-wide notifications are off.
+    let ncfg = NotificationsConfig { enabled: true, ..NotificationsConfig::default() };
+    if let Err(e) = notification::send_with(&ncfg, ty) {
+        log::warn!("quota {label}: notification failed: {e:#}");
+    }
+}
+
+fn apply(q: &Quota, running: bool) -> Result<Option<Applied>> {
+    match q.action {
+        Action::Warn => Ok(None),
+        Action::Block => Ok(Some(Applied::Blocked)),
+        Action::Disable => {
+            let profiles = set_profiles_enabled(&q.program, q.profile.as_deref(), false)?;
+            reconcile(running);
+            Ok(Some(Applied::Disabled { profiles }))
+        }
+        Action::Switch => {
+            let (Some(from), Some(to)) = (q.profile.as_deref(), q.switch_to.as_deref()) else {
+                bail!("switch needs profile and switch_to");
+            };
+            let apps = move_apps(&q.program, from, to, None)?;
+            schedule_apps(&q.program, &[from, to], running);
+            Ok(Some(Applied::Switched { to: to.to_string(), apps }))
+        }
+    }
+}
+
+fn undo(program: &str, profile: Option<&str>, applied: Applied, running: bool) {
+    let res = match applied {
+        // The chain is rebuilt from the remaining states by the caller.
+        Applied::Blocked => Ok(()),
+        Applied::Disabled { profiles } => profiles
+            .iter()
+            .try_for_each(|p| set_profiles_enabled(program, Some(p), true).map(|_| ()))
+            .map(|_| reconcile(running)),
+        Applied::Switched { to, apps } => match profile {
+            Some(from) => move_apps(program, &to, from, Some(&apps)).map(|_| schedule_apps(program, &[from, &to], running)),
+            None => Ok(()),
+        },
+    };
+    if let Err(e) = res {
+        log::warn!("quota {program}: restore failed: {e:#}");
+    }
+}
+
+fn profile_dir(program: &str, profile: &str) -> PathBuf {
+    let root = settings::working_program_root_path(program);
+    let nested = root.join("profile").join(profile);
+    if nested.is_dir() { nested } else { root.join(profile) }
+}
+
+/// Set `enabled` of one profile, or of every profile of the program, in
+/// active.json. Returns the profiles that actually changed.
+fn set_profiles_enabled(program: &str, profile: Option<&str>, enabled: bool) -> Result<Vec<String>> {
+    let path = settings::working_program_root_path(program).join("active.json");
+    let mut active: serde_json::Value = jsonfs::read_json(&path)?;
+    let profiles = active
+        .get_mut("profiles")
+        .and_then(|p| p.as_object_mut())
+        .with_context(|| format!("{} has no profiles", path.display()))?;
+    if let Some(p) = profile.filter(|p| !profiles.contains_key(*p)) {
+        bail!("profile {program}/{p} not found");
+    }
+    let mut changed = Vec::new();
+    for (name, st) in profiles.iter_mut() {
+        if profile.is_some_and(|p| p != name) || jsonfs::json_enabled(st.get("enabled")) == enabled {
+            continue;
+        }
+        st["enabled"] = json!(enabled);
+        changed.push(name.clone());
+    }
+    jsonfs::write_json_pretty_tmp_rename(&path, &active)?;
+    Ok(changed)
+}
+
+fn reconcile(running: bool) {
+    if !running {
+        return;
+    }
+    let (report, diff) = crate::plan::reconcile();
+    if !diff.in_sync && report.failures.is_empty() && report.actions.is_empty() {
+        log::warn!("quota: profile change needs a full restart to take effect");
+    }
+    for f in &report.failures {
+        log::warn!("quota: reconcile: {f}");
+    }
+}
+
+fn app_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#'))
+}
+
+/// Move app list entries from `from` to `to`: all of them, or only `only`.
+/// Returns the entries moved.
+fn move_apps(program: &str, from: &str, to: &str, only: Option<&[String]>) -> Result<Vec<String>> {
+    let src = profile_dir(program, from).join("app/uid/user_program");
+    let dst = profile_dir(program, to).join("app/uid/user_program");
+    if !dst.parent().is_some_and(Path::is_dir) {
+        bail!("profile {program}/{to} has no app list");
+    }
+    let src_text = fs::read_to_string(&src).unwrap_or_default();
+    let dst_text = fs::read_to_string(&dst).unwrap_or_default();
+    let (moved, kept): (Vec<&str>, Vec<&str>) = if only.is_some() {
+        // Restoring: take back only the entries the switch moved.
+        app_lines(&src_text).partition(|l| only.is_some_and(|o| o.iter().any(|a| a == l)))
+    } else {
+        (app_lines(&src_text).collect(), Vec::new())
+    };
+    let mut merged: Vec<&str> = app_lines(&dst_text).collect();
+    for line in &moved {
+        if !merged.contains(line) {
+            merged.push(line);
+        }
+    }
+    write_lines(&src, &kept)?;
+    write_lines(&dst, &merged)?;
+    Ok(moved.into_iter().map(str::to_string).collect())
+}
+
+fn write_lines(path: &Path, lines: &[&str]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut text = lines.join("\n");
+    if !text.is_empty() {
+        text.push('\n');
+    }
+    fs::write(&tmp, text).with_context(|| format!("write {}", tmp.display()))?;
+    fs::rename(&tmp, path).with_context(|| format!("rename {}", path.display()))?;
+    Ok(())
+}
+
+fn schedule_apps(program: &str, profiles: &[&str], running: bool) {
+    for profile in profiles {
+        let _ = crate::runtime_apply::schedule_after_app_save(running, program, Some(profile), "common");
+    }
+}
+
+/// Resolved UIDs routed through the profile (or every profile of the program).
+fn profile_uids(program: &str, profile: Option<&str>) -> BTreeSet<u32> {
+    let dirs: Vec<PathBuf> = match profile {
+        Some(p) => vec![profile_dir(program, p)],
+        None => {
+            let root = settings::working_program_root_path(program);
+            let nested = fs::read_dir(root.join("profile")).into_iter().flatten();
+            let flat = fs::read_dir(&root).into_iter().flatten();
+            nested.chain(flat).flatten().map(|e| e.path()).filter(|p| p.join("app/out").is_dir()).collect()
+        }
+    };
+    let mut out = BTreeSet::new();
+    for dir in dirs {
+        for file in fs::read_dir(dir.join("app/out")).into_iter().flatten().flatten() {
+            let text = fs::read_to_string(file.path()).unwrap_or_default();
+            out.extend(
+                app_lines(&text)
+                    .filter_map(|l| l.rsplit_once('=').and_then(|(_, uid)| uid.trim().parse::<u32>().ok()))
+                    .filter(|uid| *uid > 0),
+            );
+        }
+    }
+    out
+}
+
+fn ipt(cmd: &str, args: &[&str]) -> bool {
+    matches!(xtables_lock::run_timeout_retry(cmd, args, Capture::Both, IPT_TIMEOUT), Ok((0, _)))
+}
+
+fn clear_rules_unlocked() {
+    for cmd in ["iptables", "ip6tables"] {
+        while ipt(cmd, &["-D", "OUTPUT", "-j", QUOTA_CHAIN]) {}
+        ipt(cmd, &["-F", QUOTA_CHAIN]);
+        ipt(cmd, &["-X", QUOTA_CHAIN]);
+    }
+}
+
+/// Remove the block chain; the next check installs it again if needed.
+pub fn clear_rules() -> Result<()> {
+    let mut blocked = BLOCKED.lock().unwrap_or_else(|p| p.into_inner());
+    let _guard = xtables_lock::lock();
+    clear_rules_unlocked();
+    *blocked = None;
+    Ok(())
+}
+
+fn sync_block_rules(uids: BTreeSet<u32>) {
+    let mut blocked = BLOCKED.lock().unwrap_or_else(|p| p.into_inner());
+    if blocked.as_ref() == Some(&uids) || (blocked.is_none() && uids.is_empty()) {
+        return;
+    }
+    let _guard = xtables_lock::lock();
+    clear_rules_unlocked();
+    *blocked = None;
+    if uids.is_empty() {
+        return;
+    }
+    for cmd in ["iptables", "ip6tables"] {
+        let mut ok = ipt(cmd, &["-N", QUOTA_CHAIN]);
+        for uid in &uids {
+            let uid = uid.to_string();
+            ok &= ipt(cmd, &["-A", QUOTA_CHAIN, "-m", "owner", "--uid-owner", &uid, "-j", "REJECT"]);
+        }
+        ok &= ipt(cmd, &["-I", "OUTPUT", "1", "-j", QUOTA_CHAIN]);
+        if !ok {
+            log::warn!("quota: {cmd} block rules incomplete");
+        }
+    }
+    *blocked = Some(uids);
+}
+
+/// `GET /api/quotas`: configuration plus current usage and state.
+pub fn status_json() -> Result<serde_json::Value> {
+    let cfg = load_config()?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let states = state();
+    let quotas: Vec<serde_json::Value> = cfg
+        .quotas
+        .iter()
+        .map(|q| {
+            let (start, resolution) = q.period_start(now);
+            let st = states.get(&q.key()).cloned().unwrap_or_default();
+            json!({
+                "quota": q,
+                "key": q.key(),
+                "period_start": start,
+                "used_bytes": traffic_history::usage(&q.series(), resolution, start),
+                "limit_bytes": q.limit_mb.saturating_mul(MB),
+                "warned": st.warned,
+                "exceeded": st.exceeded,
+                "applied": st.applied,
+            })
+        })
+        .collect();
+    Ok(json!({"ok": true, "quotas": quotas}))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quota(raw: serde_json::Value) -> Quota {
+        serde_json::from_value(raw).unwrap()
+    }
+
+    #[test]
+    fn periods_and_validation() {
+        // 2024-03-04 12:00:00 UTC
+        let now = 1_709_553_600;
+        let monthly = quota(json!({"program": "singbox", "profile": "main", "period": "monthly", "reset_day": 5, "limit_mb": 1}));
+        // Before the 5th: the period began on 2024-02-05.
+        assert_eq!(monthly.period_start(now).0, 1_707_091_200);
+        assert_eq!(monthly.series(), "profile:singbox/main");
+        let daily = quota(json!({"program": "tun2socks", "period": "daily", "limit_mb": 1}));
+        assert_eq!(daily.period_start(now), (1_709_510_400, Resolution::Day));
+
+        let mut cfg = QuotaConfig {
+            quotas: vec![quota(json!({"program": "singbox", "profile": "main", "period": "daily", "limit_mb": 1, "action": "switch"}))],
+        };
+        assert!(cfg.validate().is_err(), "switch without switch_to");
+        cfg.quotas[0].switch_to = Some(" backup ".to_string());
+        cfg.validate().unwrap();
+        assert_eq!(cfg.quotas[0].switch_to.as_deref(), Some("backup"));
+        cfg.quotas.push(cfg.quotas[0].clone());
+        assert!(cfg.validate().is_err(), "duplicate");
+    }
+
+    #[test]
+    fn failed_action_is_retried() {
+        let q = quota(json!({"program": "singbox", "profile": "main", "period": "daily", "limit_mb": 1, "action": "disable"}));
+        let mut st = QuotaState::default();
+        let retries = |steps: &[Step]| -> Vec<bool> {
+            steps.iter().filter_map(|s| if let Step::Apply { retry, .. } = s { Some(*retry) } else { None }).collect()
+        };
+
+        let mut steps = Vec::new();
+        evaluate_usage(&q, &mut st, 0, 2 * MB, &mut steps);
+        assert_eq!(retries(&steps), [false]);
+        // `apply` failed, so nothing was recorded: the next sample tries again.
+        let mut steps = Vec::new();
+        evaluate_usage(&q, &mut st, 0, 2 * MB, &mut steps);
+        assert_eq!(retries(&steps), [true]);
+
+        st.applied = Some(Applied::Disabled { profiles: vec!["main".to_string()] });
+        let mut steps = Vec::new();
+        evaluate_usage(&q, &mut st, 0, 2 * MB, &mut steps);
+        assert!(steps.is_empty());
+
+        let warn = Quota { action: Action::Warn, ..q };
+        let mut st = QuotaState { exceeded: true, warned: true, ..QuotaState::default() };
+        let mut steps = Vec::new();
+        evaluate_usage(&warn, &mut st, 0, 2 * MB, &mut steps);
+        assert!(steps.is_empty());
+    }
+}
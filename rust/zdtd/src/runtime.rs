@@ -25,8 +25,9 @@ fn reset_start_partial() {
     START_PARTIAL.store(false, Ordering::SeqCst);
 }
 
-fn mark_start_partial() {
+fn mark_start_partial(reason: &str) {
     START_PARTIAL.store(true, Ordering::SeqCst);
+    crate::events::publish("start_partial", serde_json::json!({"reason": reason}));
 }
 
 pub fn last_start_partial() -> bool {
@@ -60,6 +61,14 @@ pub fn start_full() -> Result<()> {
         return Ok(());
     }
 
+    if try_delta_start() {
+        crate::captive_portal::sync_from_settings_best_effort();
+        final_sync_runtime_settings_best_effort("delta start");
+        crate::runtime_state::write_running(last_start_partial(), true).ok();
+        crate::logging::user_info("Инициализация завершена");
+        return Ok(());
+    }
+
     crate::internet_wait::wait_before_start_if_needed();
 
     truncate_profile_logs();
@@ -93,6 +102,7 @@ pub fn start_full() -> Result<()> {
     } else {
         log::warn!("iptables backup missing -> skipping restore baseline");
     }
+    crate::iptables::nft::select();
     crate::runtime_refresh::clear_routing_cache();
     crate::runtime_apply::clear();
 
@@ -179,14 +189,14 @@ pub fn start_full() -> Result<()> {
                     Ok(items) => vpn_profiles.extend(items),
                     Err(e) => {
                         log::warn!("{log_label} startup failed, continuing: {e:#}");
-                        mark_start_partial();
+                        mark_start_partial(&format!("{log_label} startup failed"));
                         crate::logging::user_warn(user_message);
                     }
                 }
             }
             if let Err(e) = crate::vpn_netd::start_profiles(vpn_profiles) {
                 log::warn!("vpn_netd apply failed, continuing: {e:#}");
-                mark_start_partial();
+                mark_start_partial("vpn_netd apply failed");
                 if vpn_expected {
                     crate::logging::user_warn("VPN/netd: ошибка применения, запуск продолжен");
                 }
@@ -203,7 +213,7 @@ pub fn start_full() -> Result<()> {
                 Some((program, profile)) => match vpn_tether_starters.iter().find(|(id, _)| *id == program) {
                     Some((_, start_profile)) => match start_profile(profile) {
                         Ok(item) => item,
-                        Err(e) => { log::warn!("vpn_tether {program} startup failed, continuing: {e:#}"); mark_start_partial(); None }
+                        Err(e) => { log::warn!("vpn_tether {program} startup failed, continuing: {e:#}"); mark_start_partial(&format!("vpn_tether {program} startup failed")); None }
                     },
                     None => {
                         log::warn!("vpn_tether unsupported selection program={} profile={}", program, profile);
@@ -214,7 +224,7 @@ pub fn start_full() -> Result<()> {
             };
             if let Err(e) = crate::vpn_tether::sync(vpn_tether_profile) {
                 log::warn!("vpn_tether apply failed, continuing: {e:#}");
-                mark_start_partial();
+                mark_start_partial("vpn_tether apply failed");
                 if hotspot_vpn_selection.is_some() {
                     crate::logging::user_warn("VPN-раздача: ошибка применения, запуск продолжен");
                 }
@@ -222,7 +232,7 @@ pub fn start_full() -> Result<()> {
         }
         Err(e) => {
             log::warn!("vpn profile claim conflict, skipping VPN/netd profiles and continuing: {e:#}");
-            mark_start_partial();
+            mark_start_partial("vpn profile claim conflict");
             if vpn_expected {
                 crate::logging::user_warn("VPN/netd: конфликт профилей, запуск продолжен");
             }
@@ -433,6 +443,46 @@ fn can_adopt_existing_runtime() -> bool {
     true
 }
 
+/// Converge a still-running runtime that could not be adopted as-is.
+///
+/// The previous runtime must be marked running and its iptables anchors must be
+/// in place; then only the plan difference (missing/changed profiles, stale
+/// chains) is applied instead of flushing nat/mangle and restarting everything.
+/// Returns false whenever a full start is needed; nothing is changed in the
+/// cases the plan cannot handle.
+fn try_delta_start() -> bool {
+    let running_marker = matches!(crate::runtime_state::read(), Ok(Some(st)) if st.state == "running");
+    if !running_marker || !actual_runtime_has_services() {
+        return false;
+    }
+    if runtime_uses_iptables_paths() && !iptables_runtime_anchors_present() {
+        log::info!("delta start: iptables runtime anchors are missing");
+        return false;
+    }
+
+    crate::logging::user_info("Запуск: применение изменений");
+    let (report, after) = crate::plan::reconcile();
+    if report.requires_full_start {
+        return false;
+    }
+    if !report.failures.is_empty() {
+        mark_start_partial("delta start failures");
+    }
+    if !after.in_sync || !enabled_runtime_processes_look_complete() {
+        log::info!("delta start: runtime still differs from plan, falling back to full start");
+        reset_start_partial();
+        if !report.actions.is_empty() {
+            // Processes started by the delta would be launched twice otherwise.
+            if let Err(e) = stop::stop_services() {
+                log::warn!("delta start: stop before full start failed: {e:#}");
+            }
+        }
+        return false;
+    }
+    log::info!("delta start: runtime converged actions={}", report.actions.len());
+    true
+}
+
 fn enabled_runtime_processes_look_complete() -> bool {
     let Ok(r) = stats::collect_status() else {
         return false;
@@ -545,19 +595,19 @@ fn simple_enabled_json(program: &str, file: &str) -> bool {
     crate::jsonfs::json_enabled(v.get("enabled"))
 }
 
-fn dnscrypt_enabled() -> bool {
+pub fn dnscrypt_enabled() -> bool {
     simple_enabled_json("dnscrypt", "active.json")
 }
 
-fn operaproxy_enabled() -> bool {
+pub fn operaproxy_enabled() -> bool {
     simple_enabled_json("operaproxy", "active.json")
 }
 
-fn tgwsproxy_enabled() -> bool {
+pub fn tgwsproxy_enabled() -> bool {
     tgwsproxy::load_effective_enabled().map(|v| v.enabled).unwrap_or(false)
 }
 
-fn tor_enabled() -> bool {
+pub fn tor_enabled() -> bool {
     simple_enabled_json("tor", "enabled.json")
 }
 
@@ -696,7 +746,7 @@ fn iptables_runtime_anchors_present() -> bool {
     .map(|(code, _)| code == 0)
     .unwrap_or(false);
 
-    nat || mangle
+    nat || mangle || crate::iptables::nft::anchors_present()
 }
 
 fn wait_android_runtime_ready_best_effort() {
@@ -729,10 +779,10 @@ fn wait_android_runtime_ready_best_effort() {
     });
 
     if !join_boot_probe("package-manager", package_manager) {
-        mark_start_partial();
+        mark_start_partial("package-manager probe failed");
     }
     if !join_boot_probe("netd", netd) {
-        mark_start_partial();
+        mark_start_partial("netd probe failed");
     }
 }
 
@@ -789,11 +839,11 @@ where
     match panic::catch_unwind(AssertUnwindSafe(f)) {
         Ok(Ok(())) => log::info!("startup service={name} finished"),
         Ok(Err(e)) => {
-            mark_start_partial();
+            mark_start_partial(&format!("{name} failed"));
             log::warn!("startup service={name} failed, continuing: {e:#}");
         }
         Err(_) => {
-            mark_start_partial();
+            mark_start_partial(&format!("{name} panicked"));
             log::warn!("startup service={name} panicked, continuing");
         }
     }
@@ -826,7 +876,7 @@ fn validate_start_plan_best_effort() {
     }
 
     if had_warning {
-        mark_start_partial();
+        mark_start_partial("start plan warning");
     }
 }
 fn validate_vpn_claims_unique() -> Result<()> {
@@ -1021,7 +1071,7 @@ fn wait_start_group(stage_name: &str, handles: Vec<(&'static str, thread::JoinHa
     }
 
     if !failures.is_empty() {
-        mark_start_partial();
+        mark_start_partial(&format!("{stage_name}: {}", failures.join(", ")));
         log::warn!(
             "startup stage={stage_name} completed with failures: {}",
             failures.join(", ")
@@ -60,7 +60,7 @@ pub enum RoutingSnapshot {
 }
 
 impl RoutingSnapshot {
-    fn uid_file(&self) -> &str {
+    pub fn uid_file(&self) -> &str {
         match self {
             RoutingSnapshot::NfqV1 { uid_file, .. } => uid_file,
             RoutingSnapshot::NfqV2 { uid_file, .. } => uid_file,
@@ -104,6 +104,10 @@ fn read_routing_cache() -> Vec<RoutingSnapshot> {
         .unwrap_or_default()
 }
 
+/// Routing entries applied by the current runtime, in apply order.
+pub fn cached_routing() -> Vec<RoutingSnapshot> {
+    read_routing_cache()
+}
 
 pub fn clear_routing_cache() {
     let path = Path::new(ROUTING_CACHE);
@@ -132,6 +136,61 @@ fn register_snapshot(snapshot: RoutingSnapshot) {
     }
 }
 
+fn proto_choice_name(proto_choice: crate::iptables::iptables_port::ProtoChoice) -> &'static str {
+    match proto_choice {
+        crate::iptables::iptables_port::ProtoChoice::Tcp => "tcp",
+        crate::iptables::iptables_port::ProtoChoice::Udp => "udp",
+        crate::iptables::iptables_port::ProtoChoice::TcpUdp => "tcp_udp",
+    }
+}
+
+impl RoutingSnapshot {
+    pub fn nfqueue_v1(
+        uid_file: &Path,
+        mode: &str,
+        queue: u16,
+        iface: Option<&str>,
+        filter: Option<&crate::iptables::port_filter::ProtoPortFilter>,
+    ) -> Self {
+        RoutingSnapshot::NfqV1 {
+            uid_file: uid_file.display().to_string(),
+            mode: mode.to_string(),
+            queue,
+            iface: iface.map(str::to_string).filter(|s| !s.is_empty()),
+            filter: filter_to_snapshot(filter),
+        }
+    }
+
+    pub fn nfqueue_v2(
+        uid_file: &Path,
+        port: u16,
+        filter: Option<&crate::iptables::port_filter::ProtoPortFilter>,
+    ) -> Self {
+        RoutingSnapshot::NfqV2 {
+            uid_file: uid_file.display().to_string(),
+            port,
+            filter: filter_to_snapshot(filter),
+        }
+    }
+
+    pub fn nat(
+        uid_file: &Path,
+        dest_port: u16,
+        proto_choice: crate::iptables::iptables_port::ProtoChoice,
+        ifaces_raw: Option<&str>,
+        opt: &crate::iptables::iptables_port::DpiTunnelOptions,
+    ) -> Self {
+        RoutingSnapshot::Nat {
+            uid_file: uid_file.display().to_string(),
+            dest_port,
+            proto_choice: proto_choice_name(proto_choice).to_string(),
+            ifaces_raw: ifaces_raw.map(str::to_string).filter(|s| !s.trim().is_empty()),
+            port_preference: opt.port_preference,
+            dpi_ports: opt.dpi_ports.clone(),
+        }
+    }
+}
+
 pub fn register_nfqueue_v1(
     uid_file: &Path,
     mode: &str,
@@ -139,13 +198,7 @@ pub fn register_nfqueue_v1(
     iface: Option<&str>,
     filter: Option<&crate::iptables::port_filter::ProtoPortFilter>,
 ) {
-    register_snapshot(RoutingSnapshot::NfqV1 {
-        uid_file: uid_file.display().to_string(),
-        mode: mode.to_string(),
-        queue,
-        iface: iface.map(str::to_string).filter(|s| !s.is_empty()),
-        filter: filter_to_snapshot(filter),
-    });
+    register_snapshot(RoutingSnapshot::nfqueue_v1(uid_file, mode, queue, iface, filter));
 }
 
 pub fn register_nfqueue_v2(
@@ -153,11 +206,7 @@ pub fn register_nfqueue_v2(
     port: u16,
     filter: Option<&crate::iptables::port_filter::ProtoPortFilter>,
 ) {
-    register_snapshot(RoutingSnapshot::NfqV2 {
-        uid_file: uid_file.display().to_string(),
-        port,
-        filter: filter_to_snapshot(filter),
-    });
+    register_snapshot(RoutingSnapshot::nfqueue_v2(uid_file, port, filter));
 }
 
 pub fn register_nat(
@@ -167,19 +216,7 @@ pub fn register_nat(
     ifaces_raw: Option<&str>,
     opt: &crate::iptables::iptables_port::DpiTunnelOptions,
 ) {
-    let proto_choice = match proto_choice {
-        crate::iptables::iptables_port::ProtoChoice::Tcp => "tcp",
-        crate::iptables::iptables_port::ProtoChoice::Udp => "udp",
-        crate::iptables::iptables_port::ProtoChoice::TcpUdp => "tcp_udp",
-    };
-    register_snapshot(RoutingSnapshot::Nat {
-        uid_file: uid_file.display().to_string(),
-        dest_port,
-        proto_choice: proto_choice.to_string(),
-        ifaces_raw: ifaces_raw.map(str::to_string).filter(|s| !s.trim().is_empty()),
-        port_preference: opt.port_preference,
-        dpi_ports: opt.dpi_ports.clone(),
-    });
+    register_snapshot(RoutingSnapshot::nat(uid_file, dest_port, proto_choice, ifaces_raw, opt));
 }
 
 
@@ -192,15 +229,10 @@ pub fn register_tproxy(
     mark: u32,
     table: u32,
 ) {
-    let proto_choice = match proto_choice {
-        crate::iptables::iptables_port::ProtoChoice::Tcp => "tcp",
-        crate::iptables::iptables_port::ProtoChoice::Udp => "udp",
-        crate::iptables::iptables_port::ProtoChoice::TcpUdp => "tcp_udp",
-    };
     register_snapshot(RoutingSnapshot::Tproxy {
         uid_file: uid_file.display().to_string(),
         dest_port,
-        proto_choice: proto_choice.to_string(),
+        proto_choice: proto_choice_name(proto_choice).to_string(),
         ifaces_raw: ifaces_raw.map(str::to_string).filter(|s| !s.trim().is_empty()),
         port_preference: opt.port_preference,
         dpi_ports: opt.dpi_ports.clone(),
@@ -92,6 +92,19 @@ fn known_working_text_runtime_files() -> Vec<PathBuf> {
     Vec::new()
 }
 
+/// Whether `path` is one of the runtime/cache files above (or t2s metadata,
+/// traffic history and quota period state): recreated or owned by the
+/// daemon, never user configuration.
+pub fn is_runtime_artifact(path: &Path) -> bool {
+    known_working_json_runtime_files()
+        .iter()
+        .chain(known_working_text_runtime_files().iter())
+        .any(|p| p == path)
+        || path.starts_with(Path::new(API_ROOT).join("t2s"))
+        || path.starts_with(Path::new(WORKING_ROOT).join("traffic_history"))
+        || path == Path::new(WORKING_ROOT).join("quota/state.json")
+}
+
 /// Remove corrupted runtime/cache files that are safe for the daemon or child
 /// services to recreate. This deliberately avoids user configuration files
 /// such as profile setting.json/config.json and active.json. vpn_netd owns its
@@ -283,6 +283,20 @@ fn detector_tick(state: &mut DetectorState, poll_interval: Duration) -> Result<T
             }
         }
 
+        crate::events::publish(
+            "proxyinfo_probe",
+            serde_json::json!({
+                "type": event_type.as_str(),
+                "package": package,
+                "packages": packages,
+                "uid": uid,
+                "proto": proto,
+                "ports_hint": ports_hint,
+                "hits": rule_hits,
+                "packets": total_packets,
+                "window_secs": window.as_secs(),
+            }),
+        );
         let _ = notification::send_proxyinfo_probe_detected(
             event_type.as_str(),
             &package,
@@ -29,6 +29,12 @@ pub fn api_token_path() -> PathBuf {
     Path::new(API_DIR).join("token")
 }
 
+/// d2s control API token. Kept next to the API token, outside working_folder,
+/// so neither the fs endpoints nor backups can reach it.
+pub fn d2s_control_token_path() -> PathBuf {
+    Path::new(API_DIR).join("d2s_control_token")
+}
+
 pub fn api_setting_json_path() -> PathBuf {
     Path::new(SETTING_DIR).join("setting.json")
 }
@@ -134,6 +140,41 @@ pub struct ApiSettings {
     pub selinux_permissive_enabled: bool,
     #[serde(default)]
     pub ip_forward_enabled: bool,
+    /// Restart crashed program processes (see `supervisor`).
+    #[serde(default = "default_true")]
+    pub supervisor_enabled: bool,
+    /// Unhook a crashed program's redirect rules until it is back up.
+    #[serde(default)]
+    pub supervisor_bypass_when_down: bool,
+    /// `iptables` (default), `nftables` or `auto` (see `iptables::nft`).
+    /// nftables is opt-in: the iptables path is the one proven in the field.
+    #[serde(default = "default_firewall_backend")]
+    pub firewall_backend: String,
+    /// Resolvers for endpoint hostnames, tried in order (see `dns`):
+    /// `IP[:port]`, `tcp://IP[:port]` or a DoH `https://` URL.
+    #[serde(default = "default_dns_upstreams")]
+    pub dns_upstreams: Vec<String>,
+    /// Sample traffic counters into the on-disk history (see `traffic_history`).
+    #[serde(default = "default_true")]
+    pub traffic_history_enabled: bool,
+    #[serde(default = "default_traffic_history_interval_secs")]
+    pub traffic_history_interval_secs: u64,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_firewall_backend() -> String {
+    "iptables".to_string()
+}
+
+fn default_traffic_history_interval_secs() -> u64 {
+    60
+}
+
+fn default_dns_upstreams() -> Vec<String> {
+    vec!["1.1.1.1".to_string(), "8.8.8.8".to_string()]
 }
 
 impl Default for ApiSettings {
@@ -153,6 +194,12 @@ impl Default for ApiSettings {
             tproxy_enabled: false,
             selinux_permissive_enabled: false,
             ip_forward_enabled: false,
+            supervisor_enabled: true,
+            supervisor_bypass_when_down: false,
+            firewall_backend: default_firewall_backend(),
+            dns_upstreams: default_dns_upstreams(),
+            traffic_history_enabled: true,
+            traffic_history_interval_secs: default_traffic_history_interval_secs(),
         }
     }
 }
@@ -160,6 +207,9 @@ impl Default for ApiSettings {
 impl ApiSettings {
     pub fn normalize(&mut self) {
         self.hotspot_mode = normalize_hotspot_mode(&self.hotspot_mode);
+        self.firewall_backend = normalize_firewall_backend(&self.firewall_backend);
+        self.dns_upstreams = normalize_dns_upstreams(&self.dns_upstreams);
+        self.traffic_history_interval_secs = self.traffic_history_interval_secs.clamp(15, 3600);
         self.hotspot_program = self.hotspot_program.trim().to_ascii_lowercase();
         self.hotspot_profile = self.hotspot_profile.trim().to_string();
         self.hotspot_t2s_target = normalize_hotspot_t2s_target(&self.hotspot_t2s_target);
@@ -298,6 +348,31 @@ fn normalize_hotspot_mode(raw: &str) -> String {
     }
 }
 
+fn normalize_firewall_backend(raw: &str) -> String {
+    match raw.trim().to_ascii_lowercase().as_str() {
+        "nftables" | "nft" => "nftables".to_string(),
+        "auto" => "auto".to_string(),
+        _ => "iptables".to_string(),
+    }
+}
+
+fn normalize_dns_upstreams(raw: &[String]) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+    for item in raw.iter().map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        if crate::dns::check_upstream(item).is_err() {
+            log::warn!("settings: ignoring invalid dns upstream {item:?}");
+            continue;
+        }
+        if !out.iter().any(|s| s == item) {
+            out.push(item.to_string());
+        }
+    }
+    if out.is_empty() {
+        return default_dns_upstreams();
+    }
+    out
+}
+
 fn normalize_hotspot_t2s_target(raw: &str) -> String {
     match raw.trim().to_ascii_lowercase().as_str() {
         "operaproxy" | "opera-proxy" | "opera_proxy" => "operaproxy".to_string(),
@@ -481,7 +556,7 @@ pub fn save_start_settings(st: &StartSettings) -> Result<()> {
 }
 
 
-fn chmod_private(path: &Path) {
+pub fn chmod_private(path: &Path) {
     // Best-effort: do not fail daemon startup if chmod fails.
     match fs::set_permissions(path, fs::Permissions::from_mode(0o600)) {
         Ok(()) => {}
@@ -515,20 +590,29 @@ pub fn generate_token_hex(len_bytes: usize) -> Result<String> {
 
 /// Read token from file or create a new one and persist it.
 pub fn read_or_create_token() -> Result<String> {
+    read_or_create_secret(&api_token_path())
+}
+
+/// Separate from the API token: d2s only needs to trust zdtd, not the other
+/// way round.
+pub fn read_or_create_d2s_control_token() -> Result<String> {
+    read_or_create_secret(&d2s_control_token_path())
+}
+
+fn read_or_create_secret(path: &Path) -> Result<String> {
     ensure_dirs()?;
-    let path = api_token_path();
 
-    if let Ok(s) = fs::read_to_string(&path) {
+    if let Ok(s) = fs::read_to_string(path) {
         let t = s.trim().to_string();
         if !t.is_empty() {
-            chmod_private(&path);
+            chmod_private(path);
             return Ok(t);
         }
     }
 
     let t = generate_token_hex(32)?;
-    fs::write(&path, &t).with_context(|| format!("write {}", path.display()))?;
-    chmod_private(&path);
+    fs::write(path, &t).with_context(|| format!("write {}", path.display()))?;
+    chmod_private(path);
     Ok(t)
 }
 
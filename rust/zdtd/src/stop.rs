@@ -21,7 +21,7 @@ fn pidof(name: &str) -> Vec<i32> {
         .collect()
 }
 
-fn pidof_any(names: &[&str]) -> Vec<i32> {
+pub fn pidof_any(names: &[&str]) -> Vec<i32> {
     let mut all: Vec<i32> = Vec::new();
     for &n in names {
         all.extend(pidof(n));
@@ -35,10 +35,11 @@ fn pid_alive(pid: i32) -> bool {
     Path::new("/proc").join(pid.to_string()).is_dir()
 }
 
-fn kill_pids_with_escalation(label: &str, pids: &[i32]) -> Result<()> {
+pub fn kill_pids_with_escalation(label: &str, pids: &[i32]) -> Result<()> {
     if pids.is_empty() {
         return Ok(());
     }
+    crate::supervisor::forget_pids(pids);
 
     for pid in pids {
         let _ = shell::ok_sh(&format!("kill -15 {}", pid));
@@ -185,6 +186,8 @@ fn stop_process_groups_parallel() -> Result<()> {
 }
 
 pub fn stop_services_and_restore_iptables() -> Result<()> {
+    // Nothing below may be restarted behind our back.
+    crate::supervisor::clear();
     crate::programs::dnscrypt::request_stop();
     crate::programs::dnscrypt::clear_ipv6_resetprops();
     // Clean routing/iptables hooks before killing services. This prevents clients from
@@ -230,11 +233,13 @@ pub fn stop_services_and_restore_iptables() -> Result<()> {
     // 2) remove runtime guard chains before restore
     let _ = crate::proxyinfo::clear_rules();
     let _ = crate::blockedquic::clear_rules();
+    let _ = crate::quota::clear_rules();
 
     // 3) flush nat/mangle and restore baseline backups independently for IPv4 and IPv6
     crate::runtime_refresh::clear_routing_cache();
     let restored_v4 = iptables_backup::reset_restore_v4_if_present()?;
     let _restored_v6 = iptables_backup::reset_restore_v6_if_present()?;
+    crate::iptables::nft::reset();
 
     if !restored_v4 {
         log::warn!(
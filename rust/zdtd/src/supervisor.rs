@@ -0,0 +1,414 @@
+//! Crash supervision for spawned program processes.
+//!
+//! Engines register every process they spawn together with a closure that
+//! spawns it again. A background thread polls the registered PIDs once per
+//! second; when one exits it is restarted with exponential backoff. More than
+//! `CRASH_LIMIT` crashes inside `CRASH_WINDOW` gives up on that process until
+//! the next full start. While anything is down the API status is reported as
+//! partial, and with `supervisor_bypass_when_down` the profile's scoped NAT
+//! chain is unhooked so apps fall back to a direct connection instead of
+//! hanging on a dead port.
+//!
+//! Intentional kills go through `stop::kill_pids_with_escalation`, which calls
+//! `forget_pids` first; a full stop calls `clear`.
+//!
+//! Processes that own a TUN device handed to netd are not registered (see
+//! `UNSUPERVISED`): respawning one recreates the interface, which drops the
+//! netd network bound to it, so only a full restart can recover them.
+
+use anyhow::Result;
+use serde::Serialize;
+use serde_json::json;
+use std::{
+    collections::VecDeque,
+    fs,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, Once, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{api_status, events, iptables::iptables_port, settings};
+
+const TICK: Duration = Duration::from_secs(1);
+const BACKOFF_MIN: Duration = Duration::from_secs(1);
+const BACKOFF_MAX: Duration = Duration::from_secs(60);
+/// Uptime after which the backoff starts again from `BACKOFF_MIN`.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+const CRASH_LIMIT: usize = 5;
+const CRASH_WINDOW: Duration = Duration::from_secs(300);
+
+pub type Restart = Box<dyn Fn() -> Result<u32> + Send>;
+
+/// Spawned processes deliberately left out of supervision, with the reason.
+/// Reported next to the supervised ones by `GET /api/supervisor`.
+pub const UNSUPERVISED: &[(&str, &str)] = &[
+    ("openvpn", "owns a netd VPN tun"),
+    ("amneziawg", "owns a netd VPN tun; has its own health checker"),
+    ("myvpn", "tun is created by an external app"),
+    ("tun2socks", "owns a netd VPN tun"),
+    ("sing-box tun2socks", "owns the netd VPN tun of sing-box VPN mode"),
+    ("hysteria2 tun2socks", "owns the netd VPN tun of hysteria2 VPN mode"),
+    ("mieru tun2proxy", "owns the netd VPN tun of mieru"),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Running,
+    /// Exited; waiting for the backoff to elapse.
+    Backoff,
+    /// Crash loop; no more restarts until the next start.
+    Failed,
+}
+
+struct Entry {
+    /// Finds the entry again after the table lock was released for a restart.
+    id: u64,
+    program: &'static str,
+    profile: Option<String>,
+    pid: u32,
+    /// Taken out while the restart runs outside the table lock.
+    restart: Option<Restart>,
+    /// `iptables_port::scope_label`s of the profile's NAT redirects.
+    nat_scopes: Vec<String>,
+    phase: Phase,
+    crashes: VecDeque<Instant>,
+    backoff: Duration,
+    next_try: Instant,
+    up_since: Instant,
+    restarts: u32,
+    bypassed: bool,
+}
+
+#[derive(Default)]
+struct Table {
+    entries: Vec<Entry>,
+    /// PIDs killed on purpose; reaped here so they do not linger as zombies.
+    orphans: Vec<u32>,
+    degraded: bool,
+}
+
+static TABLE: OnceLock<Mutex<Table>> = OnceLock::new();
+static THREAD: Once = Once::new();
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+fn table() -> std::sync::MutexGuard<'static, Table> {
+    match TABLE.get_or_init(|| Mutex::new(Table::default())).lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+/// Start supervising `pid`. A previous entry for the same program/profile is
+/// replaced (e.g. when a profile is restarted by a plan apply).
+pub fn watch(program: &'static str, profile: Option<&str>, pid: u32, nat_scopes: Vec<String>, restart: Restart) {
+    THREAD.call_once(|| {
+        let _ = thread::Builder::new().name("supervisor".into()).spawn(run);
+    });
+    let now = Instant::now();
+    let mut t = table();
+    t.entries.retain(|e| !(e.program == program && e.profile.as_deref() == profile));
+    t.entries.push(Entry {
+        id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+        program,
+        profile: profile.map(str::to_string),
+        pid,
+        restart: Some(restart),
+        nat_scopes,
+        phase: Phase::Running,
+        crashes: VecDeque::new(),
+        backoff: BACKOFF_MIN,
+        next_try: now,
+        up_since: now,
+        restarts: 0,
+        bypassed: false,
+    });
+}
+
+/// Stop supervising these PIDs; they are about to be killed on purpose.
+pub fn forget_pids(pids: &[i32]) {
+    let mut t = table();
+    let before = t.entries.len();
+    t.entries.retain(|e| !pids.contains(&(e.pid as i32)));
+    if t.entries.len() != before {
+        t.orphans.extend(pids.iter().filter(|p| **p > 1).map(|p| *p as u32));
+    }
+}
+
+/// Drop every entry (full stop).
+pub fn clear() {
+    let mut t = table();
+    let pids: Vec<u32> = t.entries.drain(..).map(|e| e.pid).collect();
+    t.orphans.extend(pids);
+    t.degraded = false;
+}
+
+#[derive(Debug, Serialize)]
+pub struct EntryView {
+    pub program: &'static str,
+    pub profile: Option<String>,
+    pub pid: u32,
+    pub state: &'static str,
+    pub restarts: u32,
+    pub recent_crashes: usize,
+    pub retry_in_ms: Option<u64>,
+    pub bypassed: bool,
+}
+
+pub fn snapshot() -> Vec<EntryView> {
+    let now = Instant::now();
+    table()
+        .entries
+        .iter()
+        .map(|e| EntryView {
+            program: e.program,
+            profile: e.profile.clone(),
+            pid: e.pid,
+            state: match e.phase {
+                Phase::Running => "running",
+                Phase::Backoff => "backoff",
+                Phase::Failed => "failed",
+            },
+            restarts: e.restarts,
+            recent_crashes: e.crashes.len(),
+            retry_in_ms: (e.phase == Phase::Backoff)
+                .then(|| e.next_try.saturating_duration_since(now).as_millis() as u64),
+            bypassed: e.bypassed,
+        })
+        .collect()
+}
+
+/// Whether any supervised process is currently down.
+pub fn degraded() -> bool {
+    table().degraded
+}
+
+/// `waitpid` for our own children (reaps the zombie), `/proc` for adopted ones.
+fn exited(pid: u32) -> bool {
+    let mut status = 0;
+    let rc = unsafe { libc::waitpid(pid as libc::pid_t, &mut status, libc::WNOHANG) };
+    if rc == pid as libc::pid_t {
+        return true;
+    }
+    if rc == 0 {
+        return false;
+    }
+    match fs::read_to_string(format!("/proc/{pid}/stat")) {
+        // State follows the parenthesised comm: "123 (name) S ...".
+        Ok(stat) => stat
+            .rsplit_once(')')
+            .map(|(_, rest)| rest.trim_start().starts_with('Z'))
+            .unwrap_or(false),
+        Err(_) => true,
+    }
+}
+
+fn label(e: &Entry) -> String {
+    match &e.profile {
+        Some(p) => format!("{}[{p}]", e.program),
+        None => e.program.to_string(),
+    }
+}
+
+/// A redirect toggle decided under the table lock and run after it.
+struct Bypass {
+    id: u64,
+    label: String,
+    nat_scopes: Vec<String>,
+    on: bool,
+}
+
+impl Bypass {
+    fn wanted(e: &Entry, on: bool) -> Option<Self> {
+        (!e.nat_scopes.is_empty() && e.bypassed != on)
+            .then(|| Self { id: e.id, label: label(e), nat_scopes: e.nat_scopes.clone(), on })
+    }
+
+    fn run(self) {
+        let on = self.on;
+        let res: Result<()> = self.nat_scopes.iter().try_for_each(|scope| iptables_port::set_scope_attached(scope, !on));
+        match res {
+            Ok(()) => {
+                if let Some(e) = table().entries.iter_mut().find(|e| e.id == self.id) {
+                    e.bypassed = on;
+                }
+                log::info!("supervisor: {} redirect {}", self.label, if on { "unhooked" } else { "restored" });
+            }
+            Err(err) => log::warn!("supervisor: {} redirect toggle failed: {err:#}", self.label),
+        }
+    }
+}
+
+/// Record a crash and schedule the next attempt. Returns false when the
+/// process is given up on.
+fn on_down(e: &mut Entry, now: Instant, st: &settings::ApiSettings) -> bool {
+    e.crashes.push_back(now);
+    while e.crashes.front().map(|t| now.duration_since(*t) > CRASH_WINDOW).unwrap_or(false) {
+        e.crashes.pop_front();
+    }
+    if !st.supervisor_enabled || e.crashes.len() > CRASH_LIMIT {
+        e.phase = Phase::Failed;
+        let why = if st.supervisor_enabled { "crash loop" } else { "supervision disabled" };
+        log::warn!("supervisor: {} not restarted ({why}, {} crashes)", label(e), e.crashes.len());
+        return false;
+    }
+    e.phase = Phase::Backoff;
+    e.next_try = now + e.backoff;
+    log::warn!("supervisor: {} down, restart in {:?}", label(e), e.backoff);
+    e.backoff = (e.backoff * 2).min(BACKOFF_MAX);
+    true
+}
+
+fn give_up(e: &Entry) {
+    crate::logging::user_warn(&format!("{}: перезапуск остановлен после {} сбоев", label(e), e.crashes.len()));
+    events::publish("program_failed", json!({"program": e.program, "profile": e.profile, "crashes": e.crashes.len()}));
+}
+
+/// Handle a process that exited or failed to restart.
+fn down(e: &mut Entry, now: Instant, st: &settings::ApiSettings, bypass: &mut Vec<Bypass>) {
+    if st.supervisor_bypass_when_down {
+        bypass.extend(Bypass::wanted(e, true));
+    }
+    if !on_down(e, now, st) {
+        give_up(e);
+    }
+}
+
+/// One poll. Exits are detected under the table lock; restarts and redirect
+/// toggles run after it is released, since either can block for seconds and
+/// `watch`, `forget_pids` and `snapshot` would wait behind them.
+fn tick() {
+    let now = Instant::now();
+    let mut st: Option<settings::ApiSettings> = None;
+    let mut due: Vec<(u64, Restart)> = Vec::new();
+    let mut bypass: Vec<Bypass> = Vec::new();
+    {
+        let mut t = table();
+        t.orphans.retain(|pid| !exited(*pid));
+        if t.entries.is_empty() {
+            return;
+        }
+        for e in t.entries.iter_mut() {
+            match e.phase {
+                Phase::Running => {
+                    if !exited(e.pid) {
+                        if e.backoff > BACKOFF_MIN && now.duration_since(e.up_since) >= STABLE_AFTER {
+                            e.backoff = BACKOFF_MIN;
+                        }
+                        continue;
+                    }
+                    events::program_exited(e.program, e.profile.as_deref(), e.pid);
+                    crate::logging::user_warn(&format!("{}: процесс завершился (pid={})", label(e), e.pid));
+                    let st = st.get_or_insert_with(|| settings::load_api_settings().unwrap_or_default());
+                    down(e, now, st, &mut bypass);
+                }
+                Phase::Backoff if now >= e.next_try => {
+                    if let Some(restart) = e.restart.take() {
+                        due.push((e.id, restart));
+                    }
+                }
+                Phase::Backoff | Phase::Failed => {}
+            }
+        }
+    }
+
+    for (id, restart) in due {
+        let res = restart();
+        let mut t = table();
+        let Some(e) = t.entries.iter_mut().find(|e| e.id == id) else {
+            // Forgotten or replaced while restarting: the new process is unwanted.
+            if let Ok(pid) = res {
+                log::info!("supervisor: entry dropped during restart, stopping pid={pid}");
+                unsafe {
+                    libc::kill(pid as libc::pid_t, libc::SIGTERM);
+                }
+                t.orphans.push(pid);
+            }
+            continue;
+        };
+        e.restart = Some(restart);
+        match res {
+            Ok(pid) => {
+                e.pid = pid;
+                e.phase = Phase::Running;
+                e.up_since = now;
+                e.restarts += 1;
+                bypass.extend(Bypass::wanted(e, false));
+                log::info!("supervisor: {} restarted pid={pid} (restart #{})", label(e), e.restarts);
+                crate::logging::user_info(&format!("{}: перезапущен", label(e)));
+                events::publish("program_restarted", json!({"program": e.program, "profile": e.profile, "pid": pid}));
+            }
+            Err(err) => {
+                log::warn!("supervisor: {} restart failed: {err:#}", label(e));
+                let st = st.get_or_insert_with(|| settings::load_api_settings().unwrap_or_default());
+                down(e, now, st, &mut bypass);
+            }
+        }
+    }
+
+    for b in bypass {
+        b.run();
+    }
+
+    let mut t = table();
+    let degraded = t.entries.iter().any(|e| e.phase != Phase::Running);
+    if degraded != t.degraded {
+        t.degraded = degraded;
+        drop(t);
+        publish_status(degraded);
+    }
+}
+
+/// Reflect supervisor state in `api/status.json`, but only while the service
+/// is up: start/stop own the status file otherwise.
+fn publish_status(degraded: bool) {
+    let state = api_status::read().ok().flatten().map(|s| s.state).unwrap_or_default();
+    if state != "on" && state != "partial" {
+        return;
+    }
+    let partial = degraded || crate::runtime::last_start_partial();
+    api_status::write_on(partial);
+    events::service_state("running", partial, None);
+}
+
+fn run() {
+    loop {
+        thread::sleep(TICK);
+        tick();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crash_loop_stops_restarts() {
+        let st = settings::ApiSettings::default();
+        let now = Instant::now();
+        let mut e = Entry {
+            id: 0,
+            program: "test",
+            profile: None,
+            pid: 0,
+            restart: Some(Box::new(|| Ok(1))),
+            nat_scopes: Vec::new(),
+            phase: Phase::Running,
+            crashes: VecDeque::new(),
+            backoff: BACKOFF_MIN,
+            next_try: now,
+            up_since: now,
+            restarts: 0,
+            bypassed: false,
+        };
+        for i in 0..CRASH_LIMIT {
+            assert!(on_down(&mut e, now, &st), "crash {i}");
+            assert_eq!(e.phase, Phase::Backoff);
+        }
+        assert_eq!(e.backoff, BACKOFF_MIN * 32);
+        assert!(!on_down(&mut e, now, &st));
+        assert_eq!(e.phase, Phase::Failed);
+    }
+}
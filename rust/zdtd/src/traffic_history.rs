@@ -0,0 +1,506 @@
+//! Persistent traffic history.
+//!
+//! `traffic_total` reads live counters that start again from zero whenever
+//! the chains are recreated (every start) and after a reboot. A background
+//! thread samples them every `traffic_history_interval_secs`, turns each raw
+//! counter into a delta and adds the deltas to minute, hour, day and month
+//! buckets per series. A counter that went backwards was reset, so its whole
+//! value is new traffic.
+//!
+//! The store is one JSON file rewritten every few samples. The last raw value
+//! of every counter is saved with it, so a daemon restart does not count the
+//! same bytes twice.
+//!
+//! Series keys are `program:<id>`, `profile:<id>/<profile>`, `iface:<name>`
+//! and `app:<uid>`. App series are keyed by UID so sampling never has to list
+//! packages; names are resolved when history is queried. VPN profiles are
+//! accounted through their tun interface, which cannot be split per app.
+//! Buckets are aligned to UTC. Nothing is sampled while the services are not
+//! running; with `traffic_history_enabled` off sampling continues only while
+//! traffic quotas are configured, since they are computed from these series.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard, Once, OnceLock},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{jsonfs, settings, traffic_total::{self, TrafficRuleReport}};
+
+/// Samples between two writes of the store.
+const PERSIST_EVERY: u32 = 5;
+/// Upper bound of points per series in one history response.
+const MAX_POINTS: u64 = 1500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Resolution {
+    Minute,
+    Hour,
+    Day,
+    Month,
+}
+
+const RESOLUTIONS: [Resolution; 4] = [Resolution::Minute, Resolution::Hour, Resolution::Day, Resolution::Month];
+
+impl Resolution {
+    pub fn parse(raw: &str) -> Option<Self> {
+        match raw.trim().to_ascii_lowercase().as_str() {
+            "minute" => Some(Self::Minute),
+            "hour" => Some(Self::Hour),
+            "day" => Some(Self::Day),
+            "month" => Some(Self::Month),
+            _ => None,
+        }
+    }
+
+    /// Nominal bucket width in seconds.
+    fn span(self) -> u64 {
+        match self {
+            Self::Minute => 60,
+            Self::Hour => 3600,
+            Self::Day => 86_400,
+            Self::Month => 31 * 86_400,
+        }
+    }
+
+    /// Buckets kept: 1 day of minutes, ~2 months of hours, ~2 years of days.
+    fn retention(self) -> usize {
+        match self {
+            Self::Minute => 1440,
+            Self::Hour => 24 * 62,
+            Self::Day => 800,
+            Self::Month => 240,
+        }
+    }
+
+    pub fn bucket_start(self, t: u64) -> u64 {
+        match self {
+            Self::Month => {
+                let (y, m, _) = civil_from_days((t / 86_400) as i64);
+                days_from_civil(y, m, 1).max(0) as u64 * 86_400
+            }
+            r => t - t % r.span(),
+        }
+    }
+}
+
+/// (year, month, day) of a day count since 1970-01-01.
+fn civil_from_days(z: i64) -> (i64, u32, u32) {
+    let z = z + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + i64::from(m <= 2), m, d)
+}
+
+fn days_from_civil(y: i64, m: u32, d: u32) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y.rem_euclid(400);
+    let mp = i64::from(if m > 2 { m - 3 } else { m + 9 });
+    let doy = (153 * mp + 2) / 5 + i64::from(d) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Bucket {
+    t: u64,
+    /// (series index, bytes)
+    v: Vec<(u32, u64)>,
+}
+
+impl Bucket {
+    fn add(&mut self, series: u32, bytes: u64) {
+        match self.v.iter_mut().find(|(s, _)| *s == series) {
+            Some((_, total)) => *total = total.saturating_add(bytes),
+            None => self.v.push((series, bytes)),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Store {
+    /// Series keys; buckets refer to them by index.
+    #[serde(default)]
+    series: Vec<String>,
+    /// Last raw value of every counter, keyed by counter identity.
+    #[serde(default)]
+    last: HashMap<String, u64>,
+    #[serde(default)]
+    minute: Vec<Bucket>,
+    #[serde(default)]
+    hour: Vec<Bucket>,
+    #[serde(default)]
+    day: Vec<Bucket>,
+    #[serde(default)]
+    month: Vec<Bucket>,
+}
+
+/// One raw counter of a sample and the series it belongs to.
+struct Counter {
+    bytes: u64,
+    series: BTreeSet<String>,
+}
+
+impl Store {
+    fn buckets(&self, r: Resolution) -> &Vec<Bucket> {
+        match r {
+            Resolution::Minute => &self.minute,
+            Resolution::Hour => &self.hour,
+            Resolution::Day => &self.day,
+            Resolution::Month => &self.month,
+        }
+    }
+
+    fn buckets_mut(&mut self, r: Resolution) -> &mut Vec<Bucket> {
+        match r {
+            Resolution::Minute => &mut self.minute,
+            Resolution::Hour => &mut self.hour,
+            Resolution::Day => &mut self.day,
+            Resolution::Month => &mut self.month,
+        }
+    }
+
+    fn series_index(&mut self, key: &str) -> u32 {
+        if let Some(i) = self.series.iter().position(|s| s == key) {
+            return i as u32;
+        }
+        self.series.push(key.to_string());
+        (self.series.len() - 1) as u32
+    }
+
+    /// Add one sample taken at `now`. The very first sample of an empty
+    /// store only sets the baseline; counters that existed before history
+    /// was enabled are not attributed to a single minute.
+    fn record(&mut self, now: u64, counters: HashMap<String, Counter>) {
+        let baseline = self.last.is_empty();
+        let mut deltas: BTreeMap<String, u64> = BTreeMap::new();
+        for (id, c) in &counters {
+            let delta = match self.last.get(id) {
+                Some(&last) if c.bytes >= last => c.bytes - last,
+                Some(_) => c.bytes,
+                None if baseline => 0,
+                None => c.bytes,
+            };
+            if delta == 0 {
+                continue;
+            }
+            for key in &c.series {
+                let total = deltas.entry(key.clone()).or_default();
+                *total = total.saturating_add(delta);
+            }
+        }
+        // Counters missing from this sample were deleted; if they come back
+        // they start again from zero.
+        self.last = counters.into_iter().map(|(id, c)| (id, c.bytes)).collect();
+
+        let deltas: Vec<(u32, u64)> = deltas.into_iter().map(|(key, bytes)| (self.series_index(&key), bytes)).collect();
+        if deltas.is_empty() {
+            return;
+        }
+        for r in RESOLUTIONS {
+            let t = r.bucket_start(now);
+            let buckets = self.buckets_mut(r);
+            if buckets.last().is_none_or(|b| b.t != t) {
+                buckets.push(Bucket { t, v: Vec::new() });
+            }
+            let bucket = buckets.last_mut().expect("bucket pushed above");
+            for &(series, bytes) in &deltas {
+                bucket.add(series, bytes);
+            }
+            let excess = buckets.len().saturating_sub(r.retention());
+            buckets.drain(..excess);
+        }
+    }
+
+    /// Points of every series matching `scope` between `from` and `to`.
+    fn points(&self, scope: &str, r: Resolution, from: u64, to: u64) -> BTreeMap<&str, Vec<(u64, u64)>> {
+        let first = r.bucket_start(from);
+        let mut out: BTreeMap<&str, Vec<(u64, u64)>> = BTreeMap::new();
+        for b in self.buckets(r).iter().filter(|b| b.t >= first && b.t <= to) {
+            for &(series, bytes) in &b.v {
+                let Some(key) = self.series.get(series as usize) else { continue; };
+                if scope_matches(scope, key) {
+                    out.entry(key.as_str()).or_default().push((b.t, bytes));
+                }
+            }
+        }
+        out
+    }
+}
+
+/// `app:<package>` selects the UID series of that package (and the series
+/// stored under the package name by older versions); every other scope is
+/// used as is.
+fn app_scopes(scope: &str, packages: &HashMap<u32, Vec<String>>) -> Vec<String> {
+    let Some(name) = scope.strip_prefix("app:").filter(|n| n.parse::<u32>().is_err()) else {
+        return vec![scope.to_string()];
+    };
+    let mut out = vec![scope.to_string()];
+    out.extend(
+        packages
+            .iter()
+            .filter(|(_, pkgs)| pkgs.iter().any(|p| p == name))
+            .map(|(uid, _)| format!("app:{uid}")),
+    );
+    out
+}
+
+/// `all`, a series kind (`program`, `profile`, `iface`, `app`) or one exact
+/// series key such as `profile:sing-box/main`.
+fn scope_matches(scope: &str, key: &str) -> bool {
+    scope == "all" || key == scope || key.split_once(':').is_some_and(|(kind, _)| kind == scope)
+}
+
+fn counters_from_report(report: &TrafficRuleReport) -> HashMap<String, Counter> {
+    let mut out: HashMap<String, Counter> = HashMap::new();
+    let mut add = |id: String, bytes: u64, series: Vec<String>| {
+        let c = out.entry(id).or_insert_with(|| Counter { bytes, series: BTreeSet::new() });
+        c.series.extend(series);
+    };
+    for rule in report.rules.iter().filter(|r| r.action_counter) {
+        let mut series = Vec::new();
+        if let Some(program) = &rule.program_id {
+            series.push(format!("program:{program}"));
+            if let Some(profile) = &rule.profile {
+                series.push(format!("profile:{program}/{profile}"));
+            }
+        }
+        if let Some(uid) = rule.uid {
+            series.push(format!("app:{uid}"));
+        }
+        if !series.is_empty() {
+            add(format!("rule:{}/{}/{}/{}", rule.family, rule.table, rule.chain, rule.raw_rule), rule.bytes, series);
+        }
+    }
+    for iface in report.interfaces.iter().filter(|i| i.iface != "lo") {
+        add(format!("iface:{}", iface.iface), iface.total_bytes, vec![format!("iface:{}", iface.iface)]);
+    }
+    for vpn in &report.vpn {
+        add(
+            format!("iface:{}", vpn.tun),
+            vpn.total_bytes,
+            vec![
+                format!("iface:{}", vpn.tun),
+                format!("program:{}", vpn.owner_program),
+                format!("profile:{}/{}", vpn.owner_program, vpn.profile),
+            ],
+        );
+    }
+    out
+}
+
+fn store_path() -> PathBuf {
+    settings::working_root_path().join("traffic_history/history.json")
+}
+
+static STORE: OnceLock<Mutex<Store>> = OnceLock::new();
+static THREAD: Once = Once::new();
+
+fn store() -> MutexGuard<'static, Store> {
+    let m = STORE.get_or_init(|| {
+        let path = store_path();
+        let store = if path.exists() {
+            jsonfs::read_json::<Store>(&path).unwrap_or_else(|e| {
+                log::warn!("traffic_history: starting a new store: {e:#}");
+                Store::default()
+            })
+        } else {
+            Store::default()
+        };
+        Mutex::new(store)
+    });
+    match m.lock() {
+        Ok(g) => g,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn persist(store: &Store) {
+    if let Err(e) = write_compact(store) {
+        log::warn!("traffic_history: save failed: {e:#}");
+    }
+}
+
+/// The store can hold tens of thousands of small buckets, so it is written
+/// without pretty-printing.
+fn write_compact(store: &Store) -> Result<()> {
+    let path = store_path();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec(store)?)?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+fn now_unix() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_secs()
+}
+
+/// Start the sampler thread once.
+pub fn start() {
+    THREAD.call_once(|| {
+        let _ = thread::Builder::new().name("traffic_history".into()).spawn(run);
+    });
+}
+
+/// Chains are only complete (and their counters meaningful) once a start has
+/// finished.
+fn services_running() -> bool {
+    crate::api_status::read().ok().flatten().is_some_and(|s| s.running && !s.busy)
+}
+
+fn run() {
+    let mut unsaved = 0;
+    loop {
+        let setting = settings::load_api_settings().unwrap_or_default();
+        if (setting.traffic_history_enabled || crate::quota::any_configured()) && services_running() {
+            match traffic_total::collect_counter_snapshot() {
+                Ok(report) => {
+                    let now = now_unix();
+                    {
+                        let mut store = store();
+                        store.record(now, counters_from_report(&report));
+                        unsaved += 1;
+                        if unsaved >= PERSIST_EVERY {
+                            persist(&store);
+                            unsaved = 0;
+                        }
+                    }
+                    crate::quota::check(now);
+                }
+                Err(e) => log::warn!("traffic_history: sample failed: {e:#}"),
+            }
+        }
+        thread::sleep(Duration::from_secs(setting.traffic_history_interval_secs));
+    }
+}
+
+/// Bytes of series `key` since the `r` bucket holding `from`.
+pub fn usage(key: &str, r: Resolution, from: u64) -> u64 {
+    let store = store();
+    let Some(series) = store.series.iter().position(|s| s == key) else { return 0; };
+    let first = r.bucket_start(from);
+    store
+        .buckets(r)
+        .iter()
+        .filter(|b| b.t >= first)
+        .flat_map(|b| b.v.iter().filter(|(s, _)| *s as usize == series).map(|(_, bytes)| *bytes))
+        .fold(0, u64::saturating_add)
+}
+
+/// Finest resolution that still holds `from` and returns at most
+/// `MAX_POINTS` points.
+fn auto_resolution(now: u64, from: u64, to: u64) -> Resolution {
+    RESOLUTIONS
+        .into_iter()
+        .find(|r| {
+            let kept_since = now.saturating_sub(r.span() * r.retention() as u64);
+            from >= kept_since && to.saturating_sub(from) / r.span() <= MAX_POINTS
+        })
+        .unwrap_or(Resolution::Month)
+}
+
+/// `GET /api/traffic/history`. `from`/`to` are unix seconds; the default
+/// range is the last 30 days.
+pub fn history_json(scope: &str, from: Option<u64>, to: Option<u64>, resolution: Option<Resolution>) -> Result<serde_json::Value> {
+    let scope = scope.trim();
+    let known_kind = matches!(scope, "all" | "program" | "profile" | "iface" | "app");
+    if !known_kind && !scope.contains(':') {
+        bail!("scope must be all, program, profile, iface, app or a series key like profile:<program>/<profile>");
+    }
+    let now = now_unix();
+    let to = to.unwrap_or(now);
+    let from = from.unwrap_or(to.saturating_sub(30 * 86_400));
+    if from > to {
+        bail!("from must not be after to");
+    }
+    let resolution = resolution.unwrap_or_else(|| auto_resolution(now, from, to));
+
+    let packages = traffic_total::cached_uid_packages();
+    let store = store();
+    let mut points = BTreeMap::new();
+    for scope in app_scopes(scope, &packages) {
+        points.extend(store.points(&scope, resolution, from, to));
+    }
+    let mut series: Vec<serde_json::Value> = points
+        .into_iter()
+        .map(|(key, points)| {
+            let total: u64 = points.iter().map(|(_, b)| b).sum();
+            let mut entry = json!({"key": key, "total_bytes": total, "points": points});
+            if let Some(uid) = key.strip_prefix("app:").and_then(|u| u.parse::<u32>().ok()) {
+                entry["uid"] = json!(uid);
+                entry["packages"] = json!(packages.get(&uid).cloned().unwrap_or_default());
+            }
+            entry
+        })
+        .collect();
+    series.sort_by_key(|s| std::cmp::Reverse(s["total_bytes"].as_u64().unwrap_or(0)));
+    Ok(json!({
+        "ok": true,
+        "scope": scope,
+        "resolution": resolution,
+        "from": from,
+        "to": to,
+        "series": series,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(values: &[(&str, u64, &[&str])]) -> HashMap<String, Counter> {
+        values
+            .iter()
+            .map(|(id, bytes, series)| {
+                (id.to_string(), Counter { bytes: *bytes, series: series.iter().map(|s| s.to_string()).collect() })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn deltas_survive_counter_resets_and_roll_up() {
+        // 2024-01-31 23:59:00 UTC
+        let t0 = 1_706_745_540;
+        assert_eq!(Resolution::Month.bucket_start(t0), 1_704_067_200);
+        assert_eq!(Resolution::Month.bucket_start(t0 + 60), 1_706_745_600);
+
+        let mut store = Store::default();
+        let keys: &[&str] = &["profile:sing-box/main", "program:sing-box"];
+        store.record(t0, sample(&[("iface:tun0", 5_000, keys)]));
+        assert!(store.minute.is_empty(), "first sample is only a baseline");
+
+        store.record(t0 + 30, sample(&[("iface:tun0", 7_000, keys)]));
+        // Chains recreated: the counter starts again from zero.
+        store.record(t0 + 60, sample(&[("iface:tun0", 300, keys)]));
+
+        let month = store.points("profile", Resolution::Month, t0 - 86_400 * 40, t0 + 60);
+        assert_eq!(month["profile:sing-box/main"], vec![(1_704_067_200, 2_000), (1_706_745_600, 300)]);
+        let day = store.points("profile:sing-box/main", Resolution::Day, t0, t0 + 60);
+        assert_eq!(day["profile:sing-box/main"].iter().map(|(_, b)| b).sum::<u64>(), 2_300);
+        assert!(store.points("app", Resolution::Hour, 0, t0 + 60).is_empty());
+    }
+
+    #[test]
+    fn package_scope_selects_uid_series() {
+        let packages = HashMap::from([(10123, vec!["org.example".to_string()]), (10124, vec!["org.other".to_string()])]);
+        assert_eq!(app_scopes("app:org.example", &packages), vec!["app:org.example", "app:10123"]);
+        assert_eq!(app_scopes("app:10124", &packages), vec!["app:10124"]);
+        assert_eq!(app_scopes("app", &packages), vec!["app"]);
+    }
+}
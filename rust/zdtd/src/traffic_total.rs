@@ -4,30 +4,38 @@ use std::{
     collections::{BTreeMap, HashMap},
     fs,
     path::{Path, PathBuf},
-    sync::atomic::{AtomicBool, Ordering},
-    time::{Duration, SystemTime, UNIX_EPOCH},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{shell::{self, Capture}, xtables_lock};
 
 const IPT_SAVE_TIMEOUT: Duration = Duration::from_secs(8);
 const PKG_LIST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long `cached_uid_packages` reuses one `cmd package` listing.
+const PKG_CACHE_TTL: Duration = Duration::from_secs(300);
 const PROC_NET_DEV: &str = "/proc/net/dev";
 const ROUTING_CACHE: &str = "/data/adb/modules/ZDT-D/working_folder/runtime_refresh/routing.json";
 const VPN_NETD_APPLIED: &str = "/data/adb/modules/ZDT-D/working_folder/vpn_netd/applied.json";
 const XT_WAIT_SECS: &str = "5";
 
 static TRAFFIC_COLLECTING: AtomicBool = AtomicBool::new(false);
+static PKG_CACHE: Mutex<Option<(Instant, UidPackages)>> = Mutex::new(None);
+
+type UidPackages = Arc<HashMap<u32, Vec<String>>>;
 
 /// Read-only, on-demand ZDT-D rule traffic collector.
 ///
 /// This module is intentionally passive:
-/// - no background thread;
-/// - no periodic polling;
+/// - no background thread of its own;
 /// - no iptables/netd/runtime mutations;
 /// - no counter reset (`iptables -Z` is never used).
 ///
-/// The snapshot is collected only when the API endpoint requests it.  The main
+/// The snapshot is collected when the API endpoint requests it and, for the
+/// on-disk history, by the `traffic_history` sampler.  The main
 /// purpose is to show whether traffic actually matched a concrete ZDT-D rule
 /// (DNAT/NFQUEUE/DROP/REJECT/etc.) after the user assigns an app/profile.
 #[derive(Debug, Clone, Serialize, Default)]
@@ -283,6 +291,18 @@ pub fn try_collect_rule_snapshot() -> Result<Option<TrafficRuleReport>> {
 
 /// Collect a single on-demand traffic snapshot for ZDT-D rules.
 pub fn collect_rule_snapshot() -> Result<TrafficRuleReport> {
+    collect(true)
+}
+
+/// Rule, VPN and interface counters only, for the history sampler. Skips the
+/// package lookup and the t2s/dnscrypt/proxy sections, and does not take the
+/// slot `try_collect_rule_snapshot` reserves for the UI, so a sample never
+/// makes the UI wait. `package`/`packages` fields are left empty.
+pub fn collect_counter_snapshot() -> Result<TrafficRuleReport> {
+    collect(false)
+}
+
+fn collect(full: bool) -> Result<TrafficRuleReport> {
     let mut report = TrafficRuleReport {
         updated_at_unix: now_unix(),
         source: "on_demand",
@@ -290,16 +310,18 @@ pub fn collect_rule_snapshot() -> Result<TrafficRuleReport> {
     };
 
     let route_meta = load_route_meta(&mut report.warnings);
-    let uid_packages = load_uid_package_map(&mut report.warnings);
-    let uid_file_packages = load_uid_file_packages(&route_meta);
+    let uid_packages = if full { load_uid_package_map(&mut report.warnings) } else { HashMap::new() };
+    let uid_file_packages = if full { load_uid_file_packages(&route_meta) } else { HashMap::new() };
     let interfaces = read_interfaces(&mut report.warnings);
     let working_root = Path::new("/data/adb/modules/ZDT-D/working_folder");
     let local_registry = build_local_port_registry(working_root);
     report.vpn = read_vpn_traffic(&interfaces, &uid_packages, &uid_file_packages, &local_registry, &mut report.warnings);
-    report.proxy_endpoints = local_registry.values().cloned().collect();
-    report.proxy_endpoints.sort_by(|a, b| a.label.cmp(&b.label).then(a.port.cmp(&b.port)));
-    report.t2s_instances = load_t2s_instances(&mut report.warnings);
-    report.dnscrypt = collect_dnscrypt_layer(&mut report.warnings);
+    if full {
+        report.proxy_endpoints = local_registry.values().cloned().collect();
+        report.proxy_endpoints.sort_by(|a, b| a.label.cmp(&b.label).then(a.port.cmp(&b.port)));
+        report.t2s_instances = load_t2s_instances(&mut report.warnings);
+        report.dnscrypt = collect_dnscrypt_layer(&mut report.warnings);
+    }
     report.interfaces = interfaces.into_values().collect();
     report.interfaces.sort_by(|a, b| a.iface.cmp(&b.iface));
 
@@ -1244,6 +1266,23 @@ fn read_uid_file_packages(uid_file: &str) -> HashMap<u32, Vec<String>> {
     out
 }
 
+/// UID -> package names, listed at most once per `PKG_CACHE_TTL`.
+pub fn cached_uid_packages() -> UidPackages {
+    let mut cache = PKG_CACHE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((at, map)) = cache.as_ref() {
+        if at.elapsed() < PKG_CACHE_TTL {
+            return map.clone();
+        }
+    }
+    let mut warnings = Vec::new();
+    let map = Arc::new(load_uid_package_map(&mut warnings));
+    for w in warnings {
+        log::warn!("traffic_total: {w}");
+    }
+    *cache = Some((Instant::now(), map.clone()));
+    map
+}
+
 fn load_uid_package_map(warnings: &mut Vec<String>) -> HashMap<u32, Vec<String>> {
     let mut out: HashMap<u32, Vec<String>> = HashMap::new();
     let cmd_out = shell::run_timeout(